@@ -1,6 +1,8 @@
 use crate::{
-    record::{HashedKey, Key, Record},
-    topology::{self, ReactorMetadata, Topology},
+    datastore::{error::DataStoreError, expiry::Ttl, ObjectInfo},
+    record::{HashedKey, Key, Record, ValueType},
+    redis::types::stream::{NewId, StreamId},
+    topology::{ReactorMetadata, Topology},
 };
 
 #[derive(Debug)]
@@ -14,11 +16,46 @@ pub enum DataCommand {
     Get(Get),
     Delete(Delete),
     Set(Set),
+    Expire(Expire),
+    Ttl(GetTtl),
+    Incr(Incr),
+    Type(GetType),
+    HSet(HSet),
+    HDel(HDel),
+    Push(Push),
+    Pop(Pop),
+    SAdd(SAdd),
+    SRem(SRem),
+    SIsMember(SIsMember),
+    ZAdd(ZAdd),
+    GetRange(GetRange),
+    SetRange(SetRange),
+    StrLen(StrLen),
+    Object(Object),
+    Unlink(Unlink),
+    PfMerge(PfMerge),
+    XAdd(XAdd),
+    Counter(Counter),
+    Concat(Concat),
 }
 
 #[derive(Debug)]
 pub enum ClusterCommand {
     Join(Join),
+    Reset(Reset),
+    Shutdown(Shutdown),
+}
+
+#[derive(Debug)]
+pub struct Shutdown {
+    /// Flush the memtables before stopping
+    pub save: bool,
+}
+
+#[derive(Debug)]
+pub struct Reset {
+    /// Also wipe data and generate a new node id
+    pub hard: bool,
 }
 
 #[derive(Debug)]
@@ -27,27 +64,95 @@ pub struct Join {
 }
 
 impl DataCommand {
-    pub fn get_hash(&self) -> &HashedKey {
+    pub fn get_key(&self) -> &Key {
         match self {
-            DataCommand::Get(c) => &c.key.hash,
-            DataCommand::Delete(c) => &c.key.hash,
-            DataCommand::Set(c) => &c.record.key.hash,
+            DataCommand::Get(c) => &c.key,
+            DataCommand::Delete(c) => &c.key,
+            DataCommand::Set(c) => &c.record.key,
+            DataCommand::Expire(c) => &c.key,
+            DataCommand::Ttl(c) => &c.key,
+            DataCommand::Incr(c) => &c.key,
+            DataCommand::Type(c) => &c.key,
+            DataCommand::HSet(c) => &c.key,
+            DataCommand::HDel(c) => &c.key,
+            DataCommand::Push(c) => &c.key,
+            DataCommand::Pop(c) => &c.key,
+            DataCommand::SAdd(c) => &c.key,
+            DataCommand::SRem(c) => &c.key,
+            DataCommand::SIsMember(c) => &c.key,
+            DataCommand::ZAdd(c) => &c.key,
+            DataCommand::GetRange(c) => &c.key,
+            DataCommand::SetRange(c) => &c.key,
+            DataCommand::StrLen(c) => &c.key,
+            DataCommand::Object(c) => &c.key,
+            DataCommand::Unlink(c) => &c.key,
+            DataCommand::PfMerge(c) => &c.key,
+            DataCommand::XAdd(c) => &c.key,
+            DataCommand::Counter(c) => &c.key,
+            DataCommand::Concat(c) => &c.key,
         }
     }
 
+    /// Whether the command writes a value to the memtables. Deletes are not
+    /// included: they only write a tombstone and free space once flushed
+    pub fn writes_value(&self) -> bool {
+        match self {
+            DataCommand::Set(_)
+            | DataCommand::Incr(_)
+            | DataCommand::HSet(_)
+            | DataCommand::HDel(_)
+            | DataCommand::Push(_)
+            | DataCommand::Pop(_)
+            | DataCommand::SAdd(_)
+            | DataCommand::SRem(_)
+            | DataCommand::ZAdd(_)
+            | DataCommand::SetRange(_)
+            | DataCommand::PfMerge(_)
+            | DataCommand::XAdd(_)
+            | DataCommand::Counter(_)
+            | DataCommand::Concat(_)
+            | DataCommand::Expire(_) => true,
+            DataCommand::Get(_)
+            | DataCommand::Delete(_)
+            | DataCommand::Unlink(_)
+            | DataCommand::Ttl(_)
+            | DataCommand::Type(_)
+            | DataCommand::SIsMember(_)
+            | DataCommand::GetRange(_)
+            | DataCommand::StrLen(_)
+            | DataCommand::Object(_) => false,
+        }
+    }
+
+    pub fn get_hash(&self) -> &HashedKey {
+        &self.get_key().hash
+    }
+
     /// get the shard number between 0 and 16384 (`cluster::MAX_RANGE`) using crc16
     pub fn get_slot(&self) -> u16 {
-        self.get_crc16() % topology::MAX_RANGE
+        key_slot(self.get_key())
     }
 
     // TODO: maybe pre-calculate it?
     pub fn get_crc16(&self) -> u16 {
-        let key = match self {
-            DataCommand::Get(c) => &c.key.string,
-            DataCommand::Delete(c) => &c.key.string,
-            DataCommand::Set(c) => &c.record.key.string,
-        };
-        return crc16_xmodem_fast::hash(key.as_bytes()) as u16;
+        return crc16_xmodem_fast::hash(self.get_key().string.as_bytes()) as u16;
+    }
+}
+
+/// Slot of a key, for operations involving several keys
+pub fn key_slot(key: &Key) -> u16 {
+    crate::record::key_slot(key.string.as_bytes())
+}
+
+pub const CROSS_SLOT_MESSAGE: &str = "Keys in request don't hash to the same slot";
+
+/// Multi-key commands are only accepted when all their keys are in the same
+/// slot, like with redis cluster
+pub fn same_slot<'a, I: IntoIterator<Item = &'a str>>(keys: I) -> bool {
+    let mut slots = keys.into_iter().map(|key| crate::record::key_slot(key.as_bytes()));
+    match slots.next() {
+        Some(first) => slots.all(|slot| slot == first),
+        None => true,
     }
 }
 
@@ -61,26 +166,547 @@ pub struct Delete {
     pub key: Key,
 }
 
+/// Delete that leaves the tombstone and the storage cleanup to the shard
+/// background tasks
+#[derive(Debug)]
+pub struct Unlink {
+    pub key: Key,
+}
+
+#[derive(Debug)]
+pub struct XAdd {
+    pub key: Key,
+    pub id: NewId,
+    /// Flattened field/value pairs
+    pub fields: Vec<Vec<u8>>,
+    /// Trim the stream to this number of entries after adding
+    pub max_len: Option<usize>,
+    /// Create the stream if it doesn't exist (no NOMKSTREAM)
+    pub create: bool,
+}
+
+/// Memcached incr/decr on an unsigned 64 bits value: incrementing wraps
+/// around and decrementing stops at 0. Missing keys are not created
+#[derive(Debug)]
+pub struct Counter {
+    pub key: Key,
+    pub delta: u64,
+    pub decrement: bool,
+}
+
+/// Memcached append/prepend, missing keys are not created
+#[derive(Debug)]
+pub struct Concat {
+    pub key: Key,
+    pub value: Vec<u8>,
+    pub prepend: bool,
+}
+
+/// Merge a HyperLogLog into the one of the key, creating it if needed
+#[derive(Debug)]
+pub struct PfMerge {
+    pub key: Key,
+    /// Encoded HyperLogLog
+    pub hll: Vec<u8>,
+}
+
 #[derive(Debug)]
 pub struct Set {
     pub record: Record,
+    pub options: SetOptions,
+    /// The expiration of the record comes from a relative ttl (EX/PX,
+    /// memcached exptime), the ttl jitter of the datastore is added to it
+    pub relative_ttl: bool,
+}
+
+/// Write the record only if the key is missing (NX) or exists (XX)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SetCondition {
+    #[default]
+    Always,
+    IfMissing,
+    IfExists,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SetOptions {
+    pub condition: SetCondition,
+    /// Return the previous value (GET)
+    pub get: bool,
+    /// Keep the ttl of the previous value (KEEPTTL)
+    pub keep_ttl: bool,
+}
+
+#[derive(Debug)]
+pub struct Expire {
+    pub key: Key,
+    /// New expiration date, None to make the key persistent
+    pub expire_at: Option<u64>,
+    /// The expiration date comes from a relative ttl (EXPIRE/PEXPIRE)
+    pub relative_ttl: bool,
+}
+
+#[derive(Debug)]
+pub struct GetTtl {
+    pub key: Key,
+}
+
+#[derive(Debug)]
+pub struct GetType {
+    pub key: Key,
+}
+
+#[derive(Debug)]
+pub struct HSet {
+    pub key: Key,
+    pub pairs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+#[derive(Debug)]
+pub struct HDel {
+    pub key: Key,
+    pub fields: Vec<Vec<u8>>,
+}
+
+/// Add values at the head (`front`) or the tail of a list
+#[derive(Debug)]
+pub struct Push {
+    pub key: Key,
+    pub values: Vec<Vec<u8>>,
+    pub front: bool,
+}
+
+/// Remove up to `count` values from the head (`front`) or the tail of a list
+#[derive(Debug)]
+pub struct Pop {
+    pub key: Key,
+    pub count: usize,
+    pub front: bool,
+}
+
+#[derive(Debug)]
+pub struct SAdd {
+    pub key: Key,
+    pub members: Vec<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct SRem {
+    pub key: Key,
+    pub members: Vec<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct SIsMember {
+    pub key: Key,
+    pub member: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct ZAdd {
+    pub key: Key,
+    /// Score and member pairs
+    pub members: Vec<(f64, Vec<u8>)>,
+}
+
+#[derive(Debug)]
+pub struct GetRange {
+    pub key: Key,
+    /// Inclusive offsets, negative ones count from the end
+    pub start: i64,
+    pub end: i64,
+}
+
+#[derive(Debug)]
+pub struct SetRange {
+    pub key: Key,
+    pub offset: usize,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct StrLen {
+    pub key: Key,
+}
+
+#[derive(Debug)]
+pub struct Object {
+    pub key: Key,
+}
+
+/// Add `by` to the number stored at `key` (a missing key counts as 0)
+#[derive(Debug)]
+pub struct Incr {
+    pub key: Key,
+    pub by: Number,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Integer(i64),
+    Float(f64),
+}
+
+impl std::fmt::Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Number::Integer(i) => write!(f, "{}", i),
+            Number::Float(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// The command doesn't apply to the type of the value of the key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WrongType;
+
+pub const WRONG_TYPE_MESSAGE: &str = "Operation against a key holding the wrong kind of value";
+
+/// Errors of the commands reading or rewriting a value of a given type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueError {
+    WrongType,
+    /// The value can't be read or written
+    Storage(DataStoreError),
+}
+
+impl From<WrongType> for ValueError {
+    fn from(_: WrongType) -> ValueError {
+        ValueError::WrongType
+    }
+}
+
+impl From<DataStoreError> for ValueError {
+    fn from(e: DataStoreError) -> ValueError {
+        ValueError::Storage(e)
+    }
+}
+
+impl ValueError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ValueError::WrongType => "WRONGTYPE",
+            ValueError::Storage(e) => e.code(),
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ValueError::WrongType => WRONG_TYPE_MESSAGE,
+            ValueError::Storage(e) => e.message(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IncrError {
+    WrongType,
+    NotAnInteger,
+    NotAFloat,
+    Overflow,
+    NanOrInfinity,
+    /// The value can't be read or written
+    Storage(DataStoreError),
+}
+
+impl From<DataStoreError> for IncrError {
+    fn from(e: DataStoreError) -> IncrError {
+        IncrError::Storage(e)
+    }
+}
+
+impl IncrError {
+    /// Same error codes and messages as redis
+    pub fn code(&self) -> &'static str {
+        match self {
+            IncrError::WrongType => "WRONGTYPE",
+            IncrError::Storage(e) => e.code(),
+            _ => "ERR",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            IncrError::WrongType => WRONG_TYPE_MESSAGE,
+            IncrError::NotAnInteger => "value is not an integer or out of range",
+            IncrError::NotAFloat => "value is not a valid float",
+            IncrError::Overflow => "increment or decrement would overflow",
+            IncrError::NanOrInfinity => "increment would produce NaN or Infinity",
+            IncrError::Storage(e) => e.message(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XAddError {
+    WrongType,
+    /// The id is not greater than the last one of the stream
+    IdTooSmall,
+    /// The stream can't be read or written
+    Storage(DataStoreError),
+}
+
+impl From<DataStoreError> for XAddError {
+    fn from(e: DataStoreError) -> XAddError {
+        XAddError::Storage(e)
+    }
+}
+
+impl XAddError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            XAddError::WrongType => "WRONGTYPE",
+            XAddError::IdTooSmall => "ERR",
+            XAddError::Storage(e) => e.code(),
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            XAddError::WrongType => WRONG_TYPE_MESSAGE,
+            XAddError::IdTooSmall => "The ID specified in XADD is equal or smaller than the target stream top item",
+            XAddError::Storage(e) => e.message(),
+        }
+    }
+}
+
+/// Errors of the commands moving a value to another key (RENAME, COPY)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenameError {
+    NoSuchKey,
+    /// The keys are not in the same slot
+    CrossSlot,
+    /// COPY onto the source key
+    SameKey,
+    /// The value can't be read or written
+    Storage(DataStoreError),
+}
+
+impl From<DataStoreError> for RenameError {
+    fn from(e: DataStoreError) -> RenameError {
+        RenameError::Storage(e)
+    }
+}
+
+impl RenameError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RenameError::NoSuchKey => "ERR",
+            RenameError::CrossSlot => "CROSSSLOT",
+            RenameError::SameKey => "ERR",
+            RenameError::Storage(_) => "ERR",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            RenameError::NoSuchKey => "no such key",
+            RenameError::CrossSlot => CROSS_SLOT_MESSAGE,
+            RenameError::SameKey => "source and destination objects are the same",
+            RenameError::Storage(e) => e.message(),
+        }
+    }
+}
+
+impl Incr {
+    /// Compute the new value from the current one
+    pub fn apply(&self, current: Option<&[u8]>) -> Result<Number, IncrError> {
+        let current = current.map(|v| std::str::from_utf8(v).unwrap_or_default());
+        match self.by {
+            Number::Integer(by) => {
+                let value = match current {
+                    Some(v) => v.parse::<i64>().map_err(|_| IncrError::NotAnInteger)?,
+                    None => 0,
+                };
+                value.checked_add(by).map(Number::Integer).ok_or(IncrError::Overflow)
+            }
+            Number::Float(by) => {
+                let value = match current {
+                    Some(v) => v.parse::<f64>().ok().filter(|x| x.is_finite()).ok_or(IncrError::NotAFloat)?,
+                    None => 0.0,
+                };
+                Some(value + by)
+                    .filter(|x| x.is_finite())
+                    .map(Number::Float)
+                    .ok_or(IncrError::NanOrInfinity)
+            }
+        }
+    }
 }
 
 pub enum Response {
     Get(GetResp),
     Delete(DeleteResp),
     Set(SetResp),
+    Expire(ExpireResp),
+    Ttl(TtlResp),
+    Incr(IncrResp),
+    Type(TypeResp),
+    HSet(HSetResp),
+    HDel(HDelResp),
+    Push(PushResp),
+    Pop(PopResp),
+    SAdd(SAddResp),
+    SRem(SRemResp),
+    SIsMember(SIsMemberResp),
+    ZAdd(ZAddResp),
+    GetRange(GetRangeResp),
+    SetRange(SetRangeResp),
+    StrLen(StrLenResp),
+    Object(ObjectResp),
+    PfMerge(PfMergeResp),
+    XAdd(XAddResp),
+    Counter(CounterResp),
+    Concat(ConcatResp),
     ClusterTopology(ClusterTopologyResp),
 }
 
 pub struct GetResp {
-    pub record: Option<Record>,
+    /// Err if the record is in a disktable that can't be read
+    pub record: Result<Option<Record>, DataStoreError>,
 }
 
-pub struct SetResp {}
+pub struct SetResp {
+    /// False if the condition wasn't met, Err if the record can't be written
+    pub applied: Result<bool, DataStoreError>,
+    /// Previous value, only read with the GET option
+    pub old_value: Result<Option<Vec<u8>>, WrongType>,
+}
 
-pub struct DeleteResp {}
+pub struct DeleteResp {
+    /// False if the key didn't exist, Err if the tombstone can't be written
+    pub deleted: Result<bool, DataStoreError>,
+}
+
+pub struct ExpireResp {
+    /// False if the key doesn't exist (or had no expiration to remove), Err
+    /// if the expiration can't be written
+    pub updated: Result<bool, DataStoreError>,
+}
+
+pub struct TtlResp {
+    pub ttl: Ttl,
+}
+
+pub struct TypeResp {
+    /// None if the key doesn't exist
+    pub value_type: Option<ValueType>,
+}
+
+pub struct HSetResp {
+    /// Number of fields added
+    pub added: Result<usize, ValueError>,
+}
+
+pub struct HDelResp {
+    /// Number of fields removed
+    pub removed: Result<usize, ValueError>,
+}
+
+pub struct PushResp {
+    /// Length of the list after the push
+    pub len: Result<usize, ValueError>,
+}
+
+pub struct PopResp {
+    /// Removed values, None if the key doesn't exist
+    pub values: Result<Option<Vec<Vec<u8>>>, ValueError>,
+}
+
+pub struct SAddResp {
+    /// Number of members added
+    pub added: Result<usize, ValueError>,
+}
+
+pub struct SRemResp {
+    /// Number of members removed
+    pub removed: Result<usize, ValueError>,
+}
+
+pub struct SIsMemberResp {
+    pub is_member: Result<bool, ValueError>,
+}
+
+pub struct ZAddResp {
+    /// Number of members added, updated scores are not counted
+    pub added: Result<usize, ValueError>,
+}
+
+pub struct GetRangeResp {
+    pub value: Result<Vec<u8>, ValueError>,
+}
+
+pub struct SetRangeResp {
+    /// Length of the string after the update
+    pub len: Result<usize, ValueError>,
+}
+
+pub struct StrLenResp {
+    pub len: Result<usize, ValueError>,
+}
+
+pub struct CounterResp {
+    /// New value of the key, None if it doesn't exist
+    pub value: Result<Option<u64>, IncrError>,
+}
+
+pub struct ConcatResp {
+    /// Length of the new value, None if the key doesn't exist
+    pub len: Result<Option<usize>, ValueError>,
+}
+
+pub struct XAddResp {
+    /// Id of the new entry, None if the stream doesn't exist and can't be
+    /// created
+    pub id: Result<Option<StreamId>, XAddError>,
+}
+
+pub struct PfMergeResp {
+    /// False if no register changed and the key already existed. Values
+    /// that are not HyperLogLogs are of the wrong type
+    pub updated: Result<bool, ValueError>,
+}
+
+pub struct ObjectResp {
+    /// Err if the value can't be read to find its encoding
+    pub info: Result<Option<ObjectInfo>, DataStoreError>,
+    /// Redis name of the encoding of the value
+    pub encoding: &'static str,
+}
+
+pub struct IncrResp {
+    /// New value of the key
+    pub value: Result<Number, IncrError>,
+}
 
 pub struct ClusterTopologyResp {
     pub topology: Topology,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incr(by: Number, current: Option<&str>) -> Result<Number, IncrError> {
+        let cmd = Incr {
+            key: Key::new("test".to_string()),
+            by,
+        };
+        cmd.apply(current.map(|v| v.as_bytes()))
+    }
+
+    #[test]
+    fn test_incr_apply() {
+        assert_eq!(incr(Number::Integer(1), None), Ok(Number::Integer(1)));
+        assert_eq!(incr(Number::Integer(-5), Some("10")), Ok(Number::Integer(5)));
+        assert_eq!(incr(Number::Integer(1), Some("foo")), Err(IncrError::NotAnInteger));
+        assert_eq!(incr(Number::Integer(1), Some("1.5")), Err(IncrError::NotAnInteger));
+        assert_eq!(incr(Number::Integer(1), Some(&i64::MAX.to_string())), Err(IncrError::Overflow));
+
+        assert_eq!(incr(Number::Float(0.5), Some("10")), Ok(Number::Float(10.5)));
+        assert_eq!(incr(Number::Float(0.5), Some("foo")), Err(IncrError::NotAFloat));
+        assert_eq!(incr(Number::Float(f64::MAX), Some(&f64::MAX.to_string())), Err(IncrError::NanOrInfinity));
+        assert_eq!(Number::Float(10.5).to_string(), "10.5");
+        assert_eq!(Number::Float(3.0).to_string(), "3");
+    }
+}
@@ -1,4 +1,10 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
 
 use crate::{
     api::{self, ClusterTopologyResp, Response},
@@ -6,10 +12,67 @@ use crate::{
     topology::{self, ReactorMetadata, Topology},
 };
 
+/// File of the data directory storing the id of the node
+pub const NODE_ID_FILE: &str = "NODE_ID";
+
+/// Read the id of the node from the data directory, a new one is generated
+/// and saved on the first start
+pub fn load_node_id(data_dir: &Path) -> Uuid {
+    match fs::read_to_string(data_dir.join(NODE_ID_FILE)) {
+        Ok(node_id) => node_id.trim().parse().unwrap(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let node_id = Uuid::new_v4();
+            save_node_id(data_dir, node_id);
+            node_id
+        }
+        Err(err) => panic!("Cannot read the node id: {}", err),
+    }
+}
+
+/// Write then rename so a crash never leaves a torn file
+fn save_node_id(data_dir: &Path, node_id: Uuid) {
+    fs::create_dir_all(data_dir).unwrap();
+    let path = data_dir.join(NODE_ID_FILE);
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, format!("{}\n", node_id)).unwrap();
+    fs::rename(tmp_path, path).unwrap();
+}
+
 pub struct ClusterManager {
-    mesh: HashMap<u8, async_channel::Sender<Topology>>,
+    mesh: HashMap<u8, async_channel::Sender<MeshMessage>>,
     topology: Topology,
     receiver: async_channel::Receiver<ClusterMessage>,
+    local_reactors: Vec<ReactorMetadata>,
+    shards_total: u16,
+    /// Where the node id is persisted
+    data_dir: PathBuf,
+}
+
+/// Messages sent by the cluster manager to the reactors of the node
+#[derive(Debug)]
+pub enum MeshMessage {
+    /// New topology to apply
+    Topology(Topology),
+    /// Return the reactor to a fresh state before a new topology is sent
+    Reset(ResetMessage),
+    /// Message published on another reactor, to deliver to local subscribers
+    Publish(redis::pubsub::PublishMessage),
+    /// Stop the reactor
+    Shutdown(ShutdownMessage),
+}
+
+#[derive(Debug)]
+pub struct ShutdownMessage {
+    /// Flush the memtables of the reactor before stopping
+    pub save: bool,
+}
+
+#[derive(Debug)]
+pub struct ResetMessage {
+    /// Metadata the reactor must use from now on (node id may have changed)
+    pub metadata: ReactorMetadata,
+    /// Delete all the data owned by the reactor
+    pub wipe_data: bool,
 }
 
 pub struct ClusterMessage {
@@ -18,20 +81,22 @@ pub struct ClusterMessage {
 }
 
 pub struct ClusterManagerBuilder {
-    mesh: HashMap<u8, async_channel::Sender<Topology>>,
+    mesh: HashMap<u8, async_channel::Sender<MeshMessage>>,
     receiver: async_channel::Receiver<ClusterMessage>,
     local_reactors: Vec<ReactorMetadata>,
     shards_total: u16,
     contact_point: Option<String>,
+    data_dir: PathBuf,
 }
 
 impl ClusterManagerBuilder {
     pub fn new(
         local_reactors: Vec<ReactorMetadata>,
         shards_total: u16,
-        mesh: HashMap<u8, async_channel::Sender<Topology>>,
+        mesh: HashMap<u8, async_channel::Sender<MeshMessage>>,
         receiver: async_channel::Receiver<ClusterMessage>,
         contact_point: Option<String>,
+        data_dir: PathBuf,
     ) -> ClusterManagerBuilder {
         ClusterManagerBuilder {
             mesh,
@@ -39,6 +104,7 @@ impl ClusterManagerBuilder {
             local_reactors,
             contact_point,
             shards_total,
+            data_dir,
         }
     }
 
@@ -49,6 +115,7 @@ impl ClusterManagerBuilder {
             self.mesh.clone(),
             self.receiver.clone(),
             self.contact_point.clone(),
+            self.data_dir.clone(),
         )
         .await
     }
@@ -59,16 +126,24 @@ impl ClusterManager {
     pub async fn new(
         local_reactors: Vec<ReactorMetadata>,
         shards_total: u16,
-        mesh: HashMap<u8, async_channel::Sender<Topology>>,
+        mesh: HashMap<u8, async_channel::Sender<MeshMessage>>,
         receiver: async_channel::Receiver<ClusterMessage>,
         contact_point: Option<String>,
+        data_dir: PathBuf,
     ) -> ClusterManager {
         let topology = match contact_point {
-            Some(cp) => ClusterManager::gather_topology(local_reactors, cp).await,
-            None => ClusterManager::init_topology(local_reactors, shards_total),
+            Some(cp) => ClusterManager::gather_topology(local_reactors.clone(), cp).await,
+            None => ClusterManager::init_topology(local_reactors.clone(), shards_total),
         };
 
-        ClusterManager { mesh, topology, receiver }
+        ClusterManager {
+            mesh,
+            topology,
+            receiver,
+            local_reactors,
+            shards_total,
+            data_dir,
+        }
     }
 
     fn init_topology(local_reactors: Vec<ReactorMetadata>, shards_total: u16) -> Topology {
@@ -88,6 +163,18 @@ impl ClusterManager {
             let msg = self.receiver.recv().await.unwrap();
             match msg.command {
                 api::ClusterCommand::Join(join) => self.join_new_node(join.reactors),
+                api::ClusterCommand::Reset(reset) => self.reset(reset.hard).await,
+                api::ClusterCommand::Shutdown(shutdown) => {
+                    self.shutdown(shutdown.save).await;
+                    // The reactor of the client may already be stopped
+                    let _ = msg
+                        .response_chan
+                        .send(Response::ClusterTopology(ClusterTopologyResp {
+                            topology: self.topology.clone(),
+                        }))
+                        .await;
+                    return;
+                }
             }
             msg.response_chan
                 .send(Response::ClusterTopology(ClusterTopologyResp {
@@ -104,10 +191,57 @@ impl ClusterManager {
         self.topology.rebalance();
     }
 
+    /// Forget about other nodes and go back to a topology made only of the
+    /// local reactors. A hard reset also wipes the data and generates a new
+    /// node id, making the node ready to join another cluster.
+    async fn reset(&mut self, hard: bool) {
+        if hard {
+            let node_id = Uuid::new_v4();
+            println!("Hard reset: new node ID: {}", node_id);
+            save_node_id(&self.data_dir, node_id);
+            self.local_reactors.iter_mut().for_each(|r| r.node_id = node_id);
+        }
+        for reactor in &self.local_reactors {
+            let msg = MeshMessage::Reset(ResetMessage {
+                metadata: reactor.clone(),
+                wipe_data: hard,
+            });
+            self.mesh.get(&reactor.id).unwrap().send(msg).await.unwrap();
+        }
+        self.topology = ClusterManager::init_topology(self.local_reactors.clone(), self.shards_total);
+    }
+
+    /// Stop every reactor of the node, this one included
+    async fn shutdown(&self, save: bool) {
+        println!("Shutting down the node (save: {})", save);
+        for reactor in &self.local_reactors {
+            let msg = MeshMessage::Shutdown(ShutdownMessage { save });
+            self.mesh.get(&reactor.id).unwrap().send(msg).await.unwrap();
+        }
+    }
+
     async fn broadcast_topology(&self) {
         println!("{:?}", self.topology);
         for (_, local_peer) in &self.mesh {
-            local_peer.send(self.topology.clone()).await.unwrap();
+            local_peer.send(MeshMessage::Topology(self.topology.clone())).await.unwrap();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_id_is_persisted() {
+        let directory = PathBuf::from(r"./data/test/test_node_id_is_persisted");
+        let _ = fs::remove_dir_all(&directory);
+
+        let node_id = load_node_id(&directory);
+        assert_eq!(load_node_id(&directory), node_id);
+
+        let new_node_id = Uuid::new_v4();
+        save_node_id(&directory, new_node_id);
+        assert_eq!(load_node_id(&directory), new_node_id);
+    }
+}
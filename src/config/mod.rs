@@ -0,0 +1,196 @@
+use std::sync::atomic::{AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::datastore;
+
+pub const MEMTABLE_MAX_SIZE: &str = "memtable-max-size";
+pub const DISKTABLE_TARGET_USAGE_RATIO: &str = "disktable-target-usage-ratio";
+pub const SLOWLOG_LOG_SLOWER_THAN: &str = "slowlog-log-slower-than";
+pub const MAXMEMORY: &str = "maxmemory";
+pub const BACKGROUND_IO_LIMIT: &str = "background-io-limit";
+
+/// Names of the parameters, as used by CONFIG GET/SET
+pub const PARAMETERS: [&str; 5] = [
+    MEMTABLE_MAX_SIZE,
+    DISKTABLE_TARGET_USAGE_RATIO,
+    SLOWLOG_LOG_SLOWER_THAN,
+    MAXMEMORY,
+    BACKGROUND_IO_LIMIT,
+];
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    UnknownParameter(String),
+    InvalidValue(String),
+}
+
+impl ConfigError {
+    /// Message as returned by CONFIG SET
+    pub fn message(&self) -> String {
+        match self {
+            ConfigError::UnknownParameter(name) => format!("Unknown option or number of arguments for CONFIG SET - '{}'", name),
+            ConfigError::InvalidValue(name) => format!(
+                "CONFIG SET failed (possibly related to argument '{}') - argument couldn't be parsed",
+                name
+            ),
+        }
+    }
+}
+
+enum Setting {
+    MemtableMaxSize(usize),
+    DisktableTargetUsageRatio(f32),
+    SlowlogLogSlowerThan(i64),
+    MaxMemory(u64),
+    BackgroundIoLimit(u64),
+}
+
+/// Settings of the node that can be changed while it is running, shared by
+/// every reactor. Shards pick up the changes in their background loops
+#[derive(Debug)]
+pub struct RuntimeConfig {
+    memtable_max_size_bytes: AtomicUsize,
+    /// Bits of the f32 ratio
+    disktable_target_usage_ratio: AtomicU32,
+    /// In microseconds, negative disables the slowlog
+    slowlog_log_slower_than: AtomicI64,
+    /// In bytes, 0 means no limit
+    maxmemory: AtomicU64,
+    /// In bytes/sec per shard, 0 means no limit
+    background_io_limit: AtomicU64,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        let datastore_config = datastore::Config::default();
+        Self {
+            memtable_max_size_bytes: AtomicUsize::new(datastore_config.memtable_max_size_bytes),
+            disktable_target_usage_ratio: AtomicU32::new(datastore_config.disktable_target_usage_ratio.to_bits()),
+            slowlog_log_slower_than: AtomicI64::new(10000),
+            maxmemory: AtomicU64::new(0),
+            background_io_limit: AtomicU64::new(datastore_config.background_io_bytes_per_sec),
+        }
+    }
+}
+
+/// Parse a number of bytes with an optional unit (e.g. 1gb or 100k)
+fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn parse_setting(name: &str, value: &str) -> Result<Setting, ConfigError> {
+    let invalid = || ConfigError::InvalidValue(String::from(name));
+    match name.to_lowercase().as_str() {
+        MEMTABLE_MAX_SIZE => match parse_memory(value) {
+            Some(size) if size > 0 => Ok(Setting::MemtableMaxSize(size as usize)),
+            _ => Err(invalid()),
+        },
+        DISKTABLE_TARGET_USAGE_RATIO => match value.parse::<f32>() {
+            Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(Setting::DisktableTargetUsageRatio(ratio)),
+            _ => Err(invalid()),
+        },
+        SLOWLOG_LOG_SLOWER_THAN => value.parse().map(Setting::SlowlogLogSlowerThan).map_err(|_| invalid()),
+        MAXMEMORY => parse_memory(value).map(Setting::MaxMemory).ok_or_else(invalid),
+        BACKGROUND_IO_LIMIT => parse_memory(value).map(Setting::BackgroundIoLimit).ok_or_else(invalid),
+        _ => Err(ConfigError::UnknownParameter(String::from(name))),
+    }
+}
+
+impl RuntimeConfig {
+    pub fn new() -> RuntimeConfig {
+        RuntimeConfig::default()
+    }
+
+    pub fn memtable_max_size_bytes(&self) -> usize {
+        self.memtable_max_size_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn disktable_target_usage_ratio(&self) -> f32 {
+        f32::from_bits(self.disktable_target_usage_ratio.load(Ordering::Relaxed))
+    }
+
+    pub fn slowlog_log_slower_than(&self) -> i64 {
+        self.slowlog_log_slower_than.load(Ordering::Relaxed)
+    }
+
+    pub fn maxmemory(&self) -> u64 {
+        self.maxmemory.load(Ordering::Relaxed)
+    }
+
+    pub fn background_io_limit(&self) -> u64 {
+        self.background_io_limit.load(Ordering::Relaxed)
+    }
+
+    /// Current value of a parameter, formatted for CONFIG GET
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            MEMTABLE_MAX_SIZE => Some(self.memtable_max_size_bytes().to_string()),
+            DISKTABLE_TARGET_USAGE_RATIO => Some(self.disktable_target_usage_ratio().to_string()),
+            SLOWLOG_LOG_SLOWER_THAN => Some(self.slowlog_log_slower_than().to_string()),
+            MAXMEMORY => Some(self.maxmemory().to_string()),
+            BACKGROUND_IO_LIMIT => Some(self.background_io_limit().to_string()),
+            _ => None,
+        }
+    }
+
+    /// Set parameters from (name, value) pairs. Nothing is changed if one of
+    /// them is invalid
+    pub fn set(&self, pairs: &[(String, String)]) -> Result<(), ConfigError> {
+        let settings = pairs
+            .iter()
+            .map(|(name, value)| parse_setting(name, value))
+            .collect::<Result<Vec<Setting>, ConfigError>>()?;
+        for setting in settings {
+            match setting {
+                Setting::MemtableMaxSize(size) => self.memtable_max_size_bytes.store(size, Ordering::Relaxed),
+                Setting::DisktableTargetUsageRatio(ratio) => self.disktable_target_usage_ratio.store(ratio.to_bits(), Ordering::Relaxed),
+                Setting::SlowlogLogSlowerThan(threshold) => self.slowlog_log_slower_than.store(threshold, Ordering::Relaxed),
+                Setting::MaxMemory(size) => self.maxmemory.store(size, Ordering::Relaxed),
+                Setting::BackgroundIoLimit(limit) => self.background_io_limit.store(limit, Ordering::Relaxed),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (String::from(name), String::from(value))
+    }
+
+    #[test]
+    fn test_runtime_config() {
+        let config = RuntimeConfig::new();
+        assert_eq!(config.get(MEMTABLE_MAX_SIZE).as_deref(), Some("4194304"));
+        assert_eq!(config.get("foo"), None);
+
+        config
+            .set(&[pair("maxmemory", "2mb"), pair("DISKTABLE-TARGET-USAGE-RATIO", "0.5")])
+            .unwrap();
+        assert_eq!(config.maxmemory(), 2 * 1024 * 1024);
+        config.set(&[pair("background-io-limit", "10m")]).unwrap();
+        assert_eq!(config.get(BACKGROUND_IO_LIMIT).as_deref(), Some("10000000"));
+        assert_eq!(config.disktable_target_usage_ratio(), 0.5);
+
+        let err = config.set(&[pair("slowlog-log-slower-than", "-1"), pair("memtable-max-size", "0")]);
+        assert_eq!(err, Err(ConfigError::InvalidValue(String::from("memtable-max-size"))));
+        assert_eq!(config.slowlog_log_slower_than(), 10000);
+        let err = config.set(&[pair("foo", "1")]);
+        assert_eq!(err, Err(ConfigError::UnknownParameter(String::from("foo"))));
+    }
+}
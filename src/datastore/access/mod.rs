@@ -0,0 +1,89 @@
+use std::cell::Cell;
+
+/// Frequency given to a newly indexed record, so it does not look colder than
+/// records that were never read since the start
+const LFU_INIT_VAL: u8 = 5;
+/// The higher the factor, the more hits are needed to saturate the counter
+/// (with 10, ~1M hits are needed to reach 255)
+const LFU_LOG_FACTOR: u32 = 10;
+/// Number of minutes without access needed to decrement the counter by one
+const LFU_DECAY_TIME_MINUTES: u32 = 1;
+
+thread_local! {
+    static RNG: Cell<u64> = Cell::new(crate::time::now() | 1);
+}
+
+/// Cheap xorshift generator: the counter increment only needs to be
+/// probabilistic, not cryptographically random
+pub(crate) fn next_random() -> f64 {
+    RNG.with(|x| {
+        let mut val = x.get();
+        val ^= val << 13;
+        val ^= val >> 7;
+        val ^= val << 17;
+        x.set(val);
+        (val >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+/// Access clock with a precision of a second
+pub fn clock() -> u32 {
+    (crate::time::now() / 1_000_000_000) as u32
+}
+
+/// Approximate access information kept for every entry of the index.
+///
+/// It must stay small as there is one per key: the last access is stored in
+/// seconds and the frequency is a logarithmic counter (like Redis LFU)
+/// decaying with time when the key is not accessed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccessStats {
+    last_access: u32,
+    frequency: u8,
+}
+
+impl Default for AccessStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessStats {
+    pub fn new() -> AccessStats {
+        AccessStats {
+            last_access: clock(),
+            frequency: LFU_INIT_VAL,
+        }
+    }
+
+    /// Record an access: decay the counter for the time spent idle then
+    /// increment it with a probability decreasing as the counter grows
+    pub fn touch(&mut self) {
+        let now = clock();
+        let mut frequency = self.decayed_frequency(now);
+        if frequency < u8::MAX {
+            let base = frequency.saturating_sub(LFU_INIT_VAL) as u32;
+            let probability = 1.0 / (base * LFU_LOG_FACTOR + 1) as f64;
+            if next_random() < probability {
+                frequency += 1;
+            }
+        }
+        self.frequency = frequency;
+        self.last_access = now;
+    }
+
+    /// Number of seconds since the last access
+    pub fn idle_time(&self) -> u32 {
+        clock().saturating_sub(self.last_access)
+    }
+
+    /// Logarithmic access frequency (0-255) with decay applied
+    pub fn frequency(&self) -> u8 {
+        self.decayed_frequency(clock())
+    }
+
+    fn decayed_frequency(&self, now: u32) -> u8 {
+        let periods = now.saturating_sub(self.last_access) / 60 / LFU_DECAY_TIME_MINUTES;
+        self.frequency.saturating_sub(periods.min(u8::MAX as u32) as u8)
+    }
+}
@@ -0,0 +1,60 @@
+use crate::record::HashedKey;
+
+/// Bits per key, about 1% of false positives with `PROBES` probes
+const BITS_PER_KEY: usize = 10;
+const PROBES: u64 = 7;
+
+/// Bloom filter over key hashes: a key that was inserted is always found,
+/// one that wasn't is found with a probability of about 1%
+pub struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// Filter sized for `capacity` keys, more can be inserted at the cost of
+    /// more false positives
+    pub fn new(capacity: usize) -> BloomFilter {
+        BloomFilter {
+            bits: vec![0; (capacity.max(1) * BITS_PER_KEY).div_ceil(64)],
+        }
+    }
+
+    /// Bits set for a key, derived from two parts of its hash (double hashing).
+    /// Key hashes (see `record::hasher`) are uniform enough to be used as they are
+    fn positions(&self, hash: &HashedKey) -> impl Iterator<Item = usize> {
+        let h1 = u64::from_le_bytes(hash[0..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(hash[8..16].try_into().unwrap()) | 1;
+        let size = self.bits.len() as u64 * 64;
+        (0..PROBES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % size) as usize)
+    }
+
+    pub fn insert(&mut self, hash: &HashedKey) {
+        for position in self.positions(hash).collect::<Vec<usize>>() {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    pub fn may_contain(&self, hash: &HashedKey) -> bool {
+        self.positions(hash)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::hasher::hash_key;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(1000);
+        for i in 0..1000 {
+            filter.insert(&hash_key(format!("key{}", i).as_bytes()));
+        }
+        assert!((0..1000).all(|i| filter.may_contain(&hash_key(format!("key{}", i).as_bytes()))));
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(&hash_key(format!("other{}", i).as_bytes())))
+            .count();
+        assert!(false_positives < 300, "false positives: {}", false_positives);
+    }
+}
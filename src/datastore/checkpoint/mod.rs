@@ -0,0 +1,182 @@
+//! Snapshot of the index entries of the records stored in disktables, so a
+//! restart only scans the tables written since. Entries of the records in
+//! memtables are not part of it, they are replayed from the WAL.
+//!
+//! |version(u8)|table count(u32)|tables|entries|checksum(u32)|
+//! table: |name size(u16)|name|
+//! entry: |table(u32)|block(u32)|entry(u16)|timestamp(u64)|value size(u32)|value type(u8)|expire_at(u64)|hash(20)|key size(u16)|key|
+
+use std::{collections::HashMap, fs, io::ErrorKind, path::Path, rc::Rc};
+
+use super::access::AccessStats;
+use super::disktable::block::{append_checksum, verify_checksum, Corruption};
+use super::{DiskPointer, RecordMetadata, RecordPtr};
+use crate::record::{key_slot, HashedKey, Key, ValueType};
+
+/// File of the checkpoint, in the directory of the WAL of the keyspace
+pub const CHECKPOINT_FILE: &str = "index.checkpoint";
+const VERSION: u8 = 1;
+
+pub struct Checkpoint {
+    /// Tables whose entries are all in the checkpoint
+    pub tables: Vec<Rc<String>>,
+    pub entries: Vec<(Key, RecordMetadata)>,
+}
+
+/// Entries must point to one of `tables`
+pub fn encode(checkpoint: &Checkpoint) -> Vec<u8> {
+    let mut buf = vec![VERSION];
+    buf.extend((checkpoint.tables.len() as u32).to_le_bytes());
+    let positions: HashMap<&Rc<String>, u32> = checkpoint.tables.iter().zip(0..).collect();
+    for table in &checkpoint.tables {
+        buf.extend((table.len() as u16).to_le_bytes());
+        buf.extend(table.as_bytes());
+    }
+    for (key, meta) in &checkpoint.entries {
+        let RecordPtr::DiskTable(ptr) = &meta.data_ptr else {
+            panic!("only the entries of disktable records are checkpointed")
+        };
+        buf.extend(positions[&ptr.disktable].to_le_bytes());
+        buf.extend(ptr.block.to_le_bytes());
+        buf.extend(ptr.entry.to_le_bytes());
+        buf.extend(meta.timestamp.to_le_bytes());
+        buf.extend(meta.value_size.to_le_bytes());
+        buf.push(meta.value_type as u8);
+        buf.extend(meta.expire_at.to_le_bytes());
+        buf.extend(key.hash);
+        buf.extend((key.string.len() as u16).to_le_bytes());
+        buf.extend(key.string.as_bytes());
+    }
+    append_checksum(&mut buf);
+    buf
+}
+
+pub fn decode(buf: Vec<u8>) -> Result<Checkpoint, Corruption> {
+    let buf = verify_checksum(buf)?;
+    match buf.first() {
+        Some(&VERSION) => (),
+        Some(version) => return Err(Corruption::UnsupportedVersion(*version as u32)),
+        None => return Err(Corruption::ShortRead),
+    }
+    let num_tables = u32::from_le_bytes(buf[1..5].try_into().unwrap()) as usize;
+    let mut cursor = 5;
+    let mut tables = Vec::with_capacity(num_tables);
+    for _ in 0..num_tables {
+        let size = u16::from_le_bytes(buf[cursor..cursor + 2].try_into().unwrap()) as usize;
+        let name = std::str::from_utf8(&buf[cursor + 2..cursor + 2 + size]).map_err(|_| Corruption::InvalidFormat)?;
+        tables.push(Rc::new(name.to_string()));
+        cursor += 2 + size;
+    }
+    let mut entries = vec![];
+    while cursor < buf.len() {
+        let entry = &buf[cursor..];
+        let hash: HashedKey = entry[31..51].try_into().unwrap();
+        let key_size = u16::from_le_bytes(entry[51..53].try_into().unwrap());
+        let string = std::str::from_utf8(&entry[53..53 + key_size as usize]).map_err(|_| Corruption::InvalidFormat)?;
+        // Written with another key hash, the index is rebuilt from the tables
+        if hash != crate::record::hasher::hash_key(string.as_bytes()) {
+            return Err(Corruption::InvalidFormat);
+        }
+        let table = tables
+            .get(u32::from_le_bytes(entry[0..4].try_into().unwrap()) as usize)
+            .ok_or(Corruption::InvalidFormat)?;
+        let meta = RecordMetadata {
+            key_size,
+            value_size: u32::from_le_bytes(entry[18..22].try_into().unwrap()),
+            timestamp: u64::from_le_bytes(entry[10..18].try_into().unwrap()),
+            hash,
+            slot: key_slot(string.as_bytes()),
+            data_ptr: RecordPtr::DiskTable(DiskPointer {
+                disktable: table.clone(),
+                block: u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+                entry: u16::from_le_bytes(entry[8..10].try_into().unwrap()),
+            }),
+            value_type: ValueType::from_u8(entry[22]),
+            access: AccessStats::new(),
+            expire_at: u64::from_le_bytes(entry[23..31].try_into().unwrap()),
+        };
+        entries.push((
+            Key {
+                string: string.to_string(),
+                hash,
+            },
+            meta,
+        ));
+        cursor += 53 + key_size as usize;
+    }
+    Ok(Checkpoint { tables, entries })
+}
+
+/// Checkpoint written at `path`, None if there is none or it can't be used
+pub fn read(path: &Path) -> Option<Checkpoint> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => panic!("can't read {:?}: {}", path, e),
+    };
+    match decode(buf) {
+        Ok(checkpoint) => Some(checkpoint),
+        Err(e) => {
+            println!("Ignoring corrupted index checkpoint {:?}: {:?}", path, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::hasher::{KeyHasher, Sha1Hasher};
+
+    #[test]
+    fn test_checkpoint_encoding() {
+        let table = Rc::new("1-v10.data".to_string());
+        let key = Key::new("key".to_string());
+        let meta = RecordMetadata {
+            key_size: 3,
+            value_size: 5,
+            timestamp: 42,
+            hash: key.hash,
+            slot: key_slot(b"key"),
+            data_ptr: RecordPtr::DiskTable(DiskPointer {
+                disktable: table.clone(),
+                block: 7,
+                entry: 3,
+            }),
+            value_type: ValueType::Merge,
+            access: AccessStats::new(),
+            expire_at: 1000,
+        };
+        let checkpoint = Checkpoint {
+            tables: vec![Rc::new("0-v10.data".to_string()), table],
+            entries: vec![(key.clone(), meta.clone())],
+        };
+        let mut buf = encode(&checkpoint);
+        let decoded = decode(buf.clone()).unwrap();
+        assert_eq!(decoded.tables, checkpoint.tables);
+        let (decoded_key, decoded_meta) = &decoded.entries[0];
+        assert_eq!((decoded_key.string.as_str(), decoded_key.hash), ("key", key.hash));
+        assert_eq!(
+            (decoded_meta.timestamp, decoded_meta.value_size, decoded_meta.expire_at, decoded_meta.slot),
+            (42, 5, 1000, meta.slot)
+        );
+        assert_eq!(decoded_meta.value_type, ValueType::Merge);
+        assert_eq!(format!("{:?}", decoded_meta.data_ptr), format!("{:?}", meta.data_ptr));
+
+        buf[10] ^= 1;
+        assert!(matches!(decode(buf), Err(Corruption::ChecksumMismatch)));
+
+        // Written with another key hash
+        let sha1_key = Key {
+            string: "key".to_string(),
+            hash: Sha1Hasher.hash(b"key"),
+        };
+        let other_hash = Checkpoint {
+            tables: checkpoint.tables.clone(),
+            entries: vec![(sha1_key, meta)],
+        };
+        if crate::record::hasher::algorithm() != crate::record::hasher::KeyHashAlgorithm::Sha1 {
+            assert!(matches!(decode(encode(&other_hash)), Err(Corruption::InvalidFormat)));
+        }
+    }
+}
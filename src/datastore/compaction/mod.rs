@@ -0,0 +1,156 @@
+use std::{cell::Cell, rc::Rc, time::Duration};
+
+use super::disktable::{DiskTableStats, DisktableStatus, FORMAT_VERSION};
+use crate::record::Record;
+
+/// Choose the disktables to compact next. Compacting a table copies the
+/// records still in the index to a memtable, the table is deleted once they
+/// are flushed
+pub trait CompactionPicker {
+    /// Tables to compact in the next run, among every table with its stats
+    fn pick(&self, tables: &[(Rc<String>, DiskTableStats)]) -> Vec<Rc<String>>;
+
+    /// Called when the target usage ratio is changed at runtime (CONFIG SET),
+    /// pickers not using it ignore it
+    fn set_target_usage_ratio(&self, _ratio: f32) {}
+}
+
+/// Compact one active table whose ratio of records still in the index is
+/// under the target, once there are at least `min_tables` active tables and
+/// at most once per `cooldown`. Tables in an older format or not encrypted
+/// with the current key are compacted first, to be rewritten in the current
+/// format with the current key, whatever the number of tables.
+/// Suspect tables (a read failed) are skipped
+pub struct UsageRatioPicker {
+    target_ratio: Cell<f32>,
+    min_tables: usize,
+    cooldown: Duration,
+    /// Time of the last pick, 0 if none
+    last_pick: Cell<u64>,
+}
+
+impl UsageRatioPicker {
+    pub fn new(target_ratio: f32, min_tables: usize, cooldown: Duration) -> UsageRatioPicker {
+        UsageRatioPicker {
+            target_ratio: Cell::new(target_ratio),
+            min_tables,
+            cooldown,
+            last_pick: Cell::new(0),
+        }
+    }
+}
+
+impl CompactionPicker for UsageRatioPicker {
+    fn pick(&self, tables: &[(Rc<String>, DiskTableStats)]) -> Vec<Rc<String>> {
+        let now = crate::time::now();
+        if self.last_pick.get() > 0 && now - self.last_pick.get() < self.cooldown.as_nanos() as u64 {
+            return vec![];
+        }
+        let mut active = tables
+            .iter()
+            .filter(|(_, stats)| stats.status == DisktableStatus::Active && !stats.suspect);
+        let outdated = active.clone().find(|(_, stats)| stats.version < FORMAT_VERSION || stats.stale_key);
+        let enough_tables = active.clone().count() >= self.min_tables;
+        let picked: Vec<Rc<String>> = outdated
+            .or_else(|| active.find(|(_, stats)| enough_tables && stats.usage_ratio < self.target_ratio.get()))
+            .map(|(name, _)| vec![name.clone()])
+            .unwrap_or_default();
+        if !picked.is_empty() {
+            self.last_pick.set(now);
+        }
+        picked
+    }
+
+    fn set_target_usage_ratio(&self, ratio: f32) {
+        self.target_ratio.set(ratio);
+    }
+}
+
+/// What a `CompactionFilter` does with a record
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterDecision {
+    /// Copy the record forward as it is
+    Keep,
+    /// Delete the key, a tombstone is written in place of the record
+    Remove,
+    /// Copy the record forward with this value instead
+    ChangeValue(Vec<u8>),
+}
+
+/// Called during reclaim for the current version of each key still live
+/// (neither deleted nor expired), before it is copied forward. Tombstones,
+/// deltas written by `DataStore::merge` and their bases are not filtered
+pub trait CompactionFilter {
+    fn filter(&self, record: &Record) -> FilterDecision;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(usage_ratio: f32, status: DisktableStatus) -> DiskTableStats {
+        DiskTableStats {
+            usage_ratio,
+            references: 0,
+            count: 0,
+            status,
+            version: FORMAT_VERSION,
+            suspect: false,
+            keyspace: 0,
+            stale_key: false,
+        }
+    }
+
+    #[test]
+    fn test_usage_ratio_picker() {
+        let picker = UsageRatioPicker::new(0.5, 0, Duration::ZERO);
+        let tables = vec![
+            (Rc::new("full".to_string()), stats(1.0, DisktableStatus::Active)),
+            (Rc::new("flushing".to_string()), stats(0.1, DisktableStatus::PendingReclaimFlush)),
+            (Rc::new("sparse".to_string()), stats(0.4, DisktableStatus::Active)),
+        ];
+        assert_eq!(picker.pick(&tables), vec![Rc::new("sparse".to_string())]);
+        picker.set_target_usage_ratio(0.3);
+        assert!(picker.pick(&tables).is_empty());
+
+        // Rewritten in the current format whatever its usage
+        let old = DiskTableStats {
+            version: FORMAT_VERSION - 1,
+            ..stats(1.0, DisktableStatus::Active)
+        };
+        let tables = vec![
+            (Rc::new("full".to_string()), stats(1.0, DisktableStatus::Active)),
+            (Rc::new("old".to_string()), old),
+        ];
+        assert_eq!(picker.pick(&tables), vec![Rc::new("old".to_string())]);
+        // Rewritten with the current key
+        let rotated = DiskTableStats {
+            stale_key: true,
+            ..stats(1.0, DisktableStatus::Active)
+        };
+        assert_eq!(
+            picker.pick(&[(Rc::new("rotated".to_string()), rotated)]),
+            vec![Rc::new("rotated".to_string())]
+        );
+
+        let suspect = DiskTableStats {
+            suspect: true,
+            ..stats(0.1, DisktableStatus::Active)
+        };
+        assert!(picker.pick(&[(Rc::new("suspect".to_string()), suspect)]).is_empty());
+    }
+
+    #[test]
+    fn test_usage_ratio_picker_limits() {
+        let sparse = || (Rc::new("sparse".to_string()), stats(0.1, DisktableStatus::Active));
+        let full = || (Rc::new("full".to_string()), stats(1.0, DisktableStatus::Active));
+
+        let picker = UsageRatioPicker::new(0.5, 2, Duration::ZERO);
+        assert!(picker.pick(&[sparse()]).is_empty());
+        assert_eq!(picker.pick(&[sparse(), full()]), vec![Rc::new("sparse".to_string())]);
+
+        let picker = UsageRatioPicker::new(0.5, 0, Duration::from_secs(3600));
+        assert_eq!(picker.pick(&[sparse()]).len(), 1);
+        assert!(picker.pick(&[sparse()]).is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    alloc::{self, Layout},
+    io,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+
+use monoio::{
+    buf::{IoBuf, IoBufMut},
+    fs::File,
+};
+
+/// Alignment of the buffers, offsets and sizes of the I/O done with O_DIRECT
+pub const ALIGNMENT: usize = 4096;
+
+/// Flag opening a file without going through the page cache
+pub const O_DIRECT: i32 = libc::O_DIRECT;
+
+pub fn align_down(offset: u64) -> u64 {
+    offset / ALIGNMENT as u64 * ALIGNMENT as u64
+}
+
+pub fn align_up(size: usize) -> usize {
+    size.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+/// Zeroed buffer aligned on `ALIGNMENT`, as required by O_DIRECT
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl AlignedBuf {
+    /// `len` is rounded up to the alignment
+    pub fn zeroed(len: usize) -> AlignedBuf {
+        let len = align_up(len.max(1));
+        // SAFETY: the layout is not empty
+        let ptr = unsafe { alloc::alloc_zeroed(Self::layout(len)) };
+        AlignedBuf {
+            ptr: NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(Self::layout(len))),
+            len,
+        }
+    }
+
+    /// Aligned copy of `data`, padded with zeros
+    pub fn from_slice(data: &[u8]) -> AlignedBuf {
+        let mut buf = AlignedBuf::zeroed(data.len());
+        buf[..data.len()].copy_from_slice(data);
+        buf
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, ALIGNMENT).unwrap()
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `zeroed` with the same layout
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `len` bytes allocated and zeroed
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: `len` bytes allocated and zeroed, borrowed mutably
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+// SAFETY: the buffer is owned, every byte is initialized
+unsafe impl IoBuf for AlignedBuf {
+    fn read_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.len
+    }
+}
+
+// SAFETY: the buffer is owned, every byte is initialized
+unsafe impl IoBufMut for AlignedBuf {
+    fn write_ptr(&mut self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    fn bytes_total(&mut self) -> usize {
+        self.len
+    }
+
+    unsafe fn set_init(&mut self, _pos: usize) {}
+}
+
+/// Read `size` bytes at `offset` from a file opened with O_DIRECT: the
+/// aligned pages holding them are read. The end of the file can be in the
+/// middle of a page, reading past it is a short read
+pub async fn read_exact_at(fd: &File, size: usize, offset: u64) -> io::Result<Vec<u8>> {
+    let start = align_down(offset);
+    let skip = (offset - start) as usize;
+    let (res, buf) = fd.read_at(AlignedBuf::zeroed(skip + size), start).await;
+    if res? < skip + size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf[skip..skip + size].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aligned_buf() {
+        let buf = AlignedBuf::from_slice(b"foo");
+        assert_eq!(buf.as_ptr() as usize % ALIGNMENT, 0);
+        assert_eq!(buf.len(), ALIGNMENT);
+        assert_eq!(&buf[..4], b"foo\0");
+        assert_eq!((align_down(4097), align_up(4097)), (4096, 8192));
+    }
+}
@@ -0,0 +1,741 @@
+//! Block-based layout of the disktables
+//!
+//! |         header          | data block | ... | index block | footer |
+//! |count(u32le)|timestamp(u64le)|
+//!
+//! Data blocks hold about `BLOCK_SIZE` bytes of entries followed by the offset
+//! of one entry every `RESTART_INTERVAL` (the restart points), so an entry is
+//! found from its number without going through the whole block
+//!
+//! |                          data block                          |
+//! |entry|entry|...|restart(u32le)|restart(u32le)|...|num_restarts(u32le)|
+//!
+//! The index block has the handle of every data block, then the position,
+//! header and key of every entry: the metadata of a table is read without
+//! going through the values
+//!
+//! |                          index block                                  |
+//! |num_blocks(u32le)|offset(u64le)|size(u32le)|...|index entry|index entry|...|
+//!
+//! |                   index entry                   |
+//! |block(u32le)|entry(u16le)|header of the entry|key|
+//!
+//! |                                     footer                                      |
+//! |index_offset(u64le)|codec(u8)|keyspace(u16le)|key_id(u32le)|version(u32le)|magic(u32le)|
+//!
+//! Footers of the tables written up to `LEGACY_VERSION` have no version and
+//! another magic, the ones written before `KEYSPACE_VERSION` have no keyspace
+//! (their records are in the default one) and the ones written before
+//! `ENCRYPTION_VERSION` have no key id (they are not encrypted).
+//!
+//! Data blocks are compressed with the codec of the footer (see `Compression`).
+//! With a key id, the data blocks (once compressed) and the index block are
+//! sealed with the key (see `encryption`), bound to the timestamp of the table
+//! and their offset (see `block_aad`).
+//! Every block, the index block included, is followed by the CRC32C of its
+//! bytes as written: the handles give the compressed size, checksum included
+
+use std::ops::Range;
+
+use super::FORMAT_VERSION;
+use crate::datastore::encryption::{Cipher, NO_KEY};
+use crate::record::RECORD_HEADER_SIZE;
+
+/// Size of the header at the start of a table
+pub const TABLE_HEADER_SIZE: usize = 4 + 8;
+/// A new data block is started once this size is reached, larger entries
+/// get a block of their own
+pub const BLOCK_SIZE: usize = 4096;
+/// Number of entries between two restart points
+pub const RESTART_INTERVAL: usize = 16;
+/// Size of the footer at the end of a table
+pub const FOOTER_SIZE: usize = 8 + 1 + 2 + 4 + 4 + 4;
+/// Size of the footer of the tables written before `ENCRYPTION_VERSION`
+const UNKEYED_FOOTER_SIZE: usize = 8 + 1 + 2 + 4 + 4;
+/// Size of the footer of the tables written before `KEYSPACE_VERSION`
+const UNTAGGED_FOOTER_SIZE: usize = 8 + 1 + 4 + 4;
+/// Size of the footer of the tables written up to `LEGACY_VERSION`
+const LEGACY_FOOTER_SIZE: usize = 8 + 1 + 4;
+/// Last bytes of every table
+const MAGIC: u32 = 0x4c534d46;
+/// Last bytes of the tables written up to `LEGACY_VERSION`
+const LEGACY_MAGIC: u32 = 0x4c534d54;
+/// Last format whose footer has no version
+pub const LEGACY_VERSION: u32 = 8;
+/// First format whose footer has the keyspace of the table
+pub const KEYSPACE_VERSION: u32 = 10;
+/// First format whose footer has the id of the encryption key
+pub const ENCRYPTION_VERSION: u32 = 11;
+/// First format whose sealed blocks are bound to their table and offset
+pub const AAD_VERSION: u32 = 12;
+/// Compression level of the zstd codec, low to keep flushes fast
+const ZSTD_LEVEL: i32 = 3;
+/// Size of the CRC32C following every block
+pub const CHECKSUM_SIZE: usize = 4;
+
+/// Error returned when a table doesn't hold what its index says
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Corruption {
+    /// The file is shorter than what its footer or index point to
+    ShortRead,
+    ChecksumMismatch,
+    /// Not a table (wrong magic) or unknown codec
+    InvalidFormat,
+    /// Written in a format newer than `FORMAT_VERSION`
+    UnsupportedVersion(u32),
+    /// An encrypted block was modified or sealed with another key
+    AuthenticationFailed,
+}
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            // Castagnoli polynomial, reversed
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f63b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc32c(buf: &[u8]) -> u32 {
+    !buf.iter()
+        .fold(!0u32, |crc, byte| CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Append the checksum of `buf` to it
+pub fn append_checksum(buf: &mut Vec<u8>) {
+    let checksum = crc32c(buf);
+    buf.extend(checksum.to_le_bytes());
+}
+
+/// Check and remove the checksum at the end of `buf`
+pub fn verify_checksum(mut buf: Vec<u8>) -> Result<Vec<u8>, Corruption> {
+    if buf.len() < CHECKSUM_SIZE {
+        return Err(Corruption::ShortRead);
+    }
+    let end = buf.len() - CHECKSUM_SIZE;
+    if crc32c(&buf[..end]) != u32::from_le_bytes(buf[end..].try_into().unwrap()) {
+        return Err(Corruption::ChecksumMismatch);
+    }
+    buf.truncate(end);
+    Ok(buf)
+}
+
+/// Associated data of the block at `offset` of the table whose header has
+/// `timestamp` (unique per table), empty before `AAD_VERSION`
+pub fn block_aad(version: u32, timestamp: u64, offset: u64) -> Vec<u8> {
+    match version >= AAD_VERSION {
+        true => [timestamp.to_le_bytes(), offset.to_le_bytes()].concat(),
+        false => vec![],
+    }
+}
+
+/// Decrypt a block of a table encrypted with `cipher`, if any
+pub fn open(buf: Vec<u8>, cipher: Option<&Cipher>, aad: &[u8]) -> Result<Vec<u8>, Corruption> {
+    match cipher {
+        Some(cipher) => cipher.open(&buf, aad),
+        None => Ok(buf),
+    }
+}
+
+/// Codec of the data blocks of a table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None = 0,
+    Lz4 = 1,
+    Zstd = 2,
+}
+
+impl Compression {
+    pub fn from_u8(codec: u8) -> Option<Compression> {
+        match codec {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compress(&self, block: Vec<u8>) -> Vec<u8> {
+        match self {
+            Compression::None => block,
+            Compression::Lz4 => lz4_flex::compress_prepend_size(&block),
+            Compression::Zstd => zstd::bulk::compress(&block, ZSTD_LEVEL).unwrap(),
+        }
+    }
+
+    pub fn decompress(&self, block: Vec<u8>) -> Result<Vec<u8>, Corruption> {
+        match self {
+            Compression::None => Ok(block),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&block).map_err(|_| Corruption::InvalidFormat),
+            Compression::Zstd => zstd::decode_all(block.as_slice()).map_err(|_| Corruption::InvalidFormat),
+        }
+    }
+}
+
+/// Location of a data block in the table file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub size: u32,
+}
+
+/// Group the consecutive `blocks` in ranges of about `max_size` bytes, read at
+/// once when going through a whole table. A block larger than `max_size` is
+/// alone in its range
+pub fn group_blocks(blocks: &[BlockHandle], max_size: usize) -> Vec<Range<usize>> {
+    let mut groups = vec![];
+    let mut first = 0;
+    while first < blocks.len() {
+        let start = blocks[first].offset;
+        let mut last = first + 1;
+        while last < blocks.len() && (blocks[last].offset + blocks[last].size as u64 - start) as usize <= max_size {
+            last += 1;
+        }
+        groups.push(first..last);
+        first = last;
+    }
+    groups
+}
+
+/// Position of an entry: number of its block and its number in the block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntryPosition {
+    pub block: u32,
+    pub entry: u16,
+}
+
+/// Key size and value size of an entry, from its header
+fn entry_sizes(entry: &[u8]) -> (usize, usize) {
+    (
+        u16::from_le_bytes(entry[0..2].try_into().unwrap()) as usize,
+        u32::from_le_bytes(entry[2..6].try_into().unwrap()) as usize,
+    )
+}
+
+/// Size of the entry at the start of `buf`
+fn entry_size(buf: &[u8]) -> usize {
+    let (key_size, value_size) = entry_sizes(buf);
+    RECORD_HEADER_SIZE + key_size + value_size
+}
+
+fn footer_size(version: u32) -> usize {
+    match version {
+        v if v <= LEGACY_VERSION => LEGACY_FOOTER_SIZE,
+        v if v < KEYSPACE_VERSION => UNTAGGED_FOOTER_SIZE,
+        v if v < ENCRYPTION_VERSION => UNKEYED_FOOTER_SIZE,
+        _ => FOOTER_SIZE,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Footer {
+    pub index_offset: u64,
+    pub compression: Compression,
+    /// Format of the table
+    pub version: u32,
+    /// Keyspace of the records of the table
+    pub keyspace: u16,
+    /// Key the blocks are encrypted with, `NO_KEY` if they are not
+    pub key_id: u32,
+}
+
+impl Footer {
+    /// Number of bytes of the footer, the index block ends before it
+    pub fn size(&self) -> usize {
+        footer_size(self.version)
+    }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.index_offset.to_le_bytes());
+        buf.push(self.compression as u8);
+        if self.version <= LEGACY_VERSION {
+            buf.extend(LEGACY_MAGIC.to_le_bytes());
+            return;
+        }
+        if self.version >= KEYSPACE_VERSION {
+            buf.extend(self.keyspace.to_le_bytes());
+        }
+        if self.version >= ENCRYPTION_VERSION {
+            buf.extend(self.key_id.to_le_bytes());
+        }
+        buf.extend(self.version.to_le_bytes());
+        buf.extend(MAGIC.to_le_bytes());
+    }
+
+    /// Parse the footer at the end of `buf`, its last `FOOTER_SIZE` bytes (or
+    /// more) of the table
+    pub fn parse(buf: &[u8]) -> Result<Footer, Corruption> {
+        let end = buf.len();
+        if end < LEGACY_FOOTER_SIZE {
+            return Err(Corruption::ShortRead);
+        }
+        let version = match u32::from_le_bytes(buf[end - 4..].try_into().unwrap()) {
+            MAGIC if end >= UNTAGGED_FOOTER_SIZE => u32::from_le_bytes(buf[end - 8..end - 4].try_into().unwrap()),
+            LEGACY_MAGIC => LEGACY_VERSION,
+            _ => return Err(Corruption::InvalidFormat),
+        };
+        if version > FORMAT_VERSION {
+            return Err(Corruption::UnsupportedVersion(version));
+        }
+        let start = end.checked_sub(footer_size(version)).ok_or(Corruption::ShortRead)?;
+        Ok(Footer {
+            index_offset: u64::from_le_bytes(buf[start..start + 8].try_into().unwrap()),
+            compression: Compression::from_u8(buf[start + 8]).ok_or(Corruption::InvalidFormat)?,
+            version,
+            keyspace: match version >= KEYSPACE_VERSION {
+                true => u16::from_le_bytes(buf[start + 9..start + 11].try_into().unwrap()),
+                false => 0,
+            },
+            key_id: match version >= ENCRYPTION_VERSION {
+                true => u32::from_le_bytes(buf[start + 11..start + 15].try_into().unwrap()),
+                false => NO_KEY,
+            },
+        })
+    }
+}
+
+/// Build the content of a table file from its entries (header, key and value)
+pub struct TableBuilder {
+    buf: Vec<u8>,
+    /// Timestamp of the header, the blocks are sealed with it
+    timestamp: u64,
+    blocks: Vec<BlockHandle>,
+    /// Data block being filled
+    block: Vec<u8>,
+    restarts: Vec<u32>,
+    /// Number of entries of the block being filled
+    entries: u16,
+    index: Vec<u8>,
+    count: u32,
+    compression: Compression,
+    keyspace: u16,
+    /// Seals the blocks, None to write them in clear
+    cipher: Option<Cipher>,
+}
+
+impl TableBuilder {
+    pub fn new(timestamp: u64, compression: Compression, keyspace: u16, cipher: Option<Cipher>) -> TableBuilder {
+        let mut buf = Vec::new();
+        buf.extend(0u32.to_le_bytes());
+        buf.extend(timestamp.to_le_bytes());
+        TableBuilder {
+            buf,
+            timestamp,
+            blocks: vec![],
+            block: vec![],
+            restarts: vec![],
+            entries: 0,
+            index: vec![],
+            count: 0,
+            compression,
+            keyspace,
+            cipher,
+        }
+    }
+
+    /// Add an entry, return its position in the table
+    pub fn add(&mut self, entry: &[u8]) -> EntryPosition {
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block();
+        }
+        if self.entries as usize % RESTART_INTERVAL == 0 {
+            self.restarts.push(self.block.len() as u32);
+        }
+        let position = EntryPosition {
+            block: self.blocks.len() as u32,
+            entry: self.entries,
+        };
+        let (key_size, _) = entry_sizes(entry);
+        self.index.extend(position.block.to_le_bytes());
+        self.index.extend(position.entry.to_le_bytes());
+        self.index.extend_from_slice(&entry[..RECORD_HEADER_SIZE + key_size]);
+        self.block.extend_from_slice(entry);
+        self.entries += 1;
+        self.count += 1;
+        position
+    }
+
+    fn finish_block(&mut self) {
+        if self.entries == 0 {
+            return;
+        }
+        let num_restarts = self.restarts.len() as u32;
+        for restart in self.restarts.drain(..) {
+            self.block.extend(restart.to_le_bytes());
+        }
+        self.block.extend(num_restarts.to_le_bytes());
+        let block = self.compression.compress(std::mem::take(&mut self.block));
+        let mut block = self.seal(block, self.buf.len() as u64);
+        append_checksum(&mut block);
+        self.blocks.push(BlockHandle {
+            offset: self.buf.len() as u64,
+            size: block.len() as u32,
+        });
+        self.buf.append(&mut block);
+        self.entries = 0;
+    }
+
+    /// Seal the block written at `offset`
+    fn seal(&self, block: Vec<u8>, offset: u64) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => cipher.seal(&block, &block_aad(FORMAT_VERSION, self.timestamp, offset)),
+            None => block,
+        }
+    }
+
+    /// Return the content of the table and the handles of its data blocks
+    pub fn finish(mut self) -> (Vec<u8>, Vec<BlockHandle>) {
+        self.finish_block();
+        self.buf[0..4].copy_from_slice(&self.count.to_le_bytes());
+        let index_offset = self.buf.len() as u64;
+        let mut index = Vec::with_capacity(4 + self.blocks.len() * 12 + self.index.len());
+        index.extend((self.blocks.len() as u32).to_le_bytes());
+        for handle in &self.blocks {
+            index.extend(handle.offset.to_le_bytes());
+            index.extend(handle.size.to_le_bytes());
+        }
+        index.extend(&self.index);
+        let mut index = self.seal(index, index_offset);
+        append_checksum(&mut index);
+        self.buf.append(&mut index);
+        Footer {
+            index_offset,
+            compression: self.compression,
+            version: FORMAT_VERSION,
+            keyspace: self.keyspace,
+            key_id: self.cipher.as_ref().map_or(NO_KEY, |cipher| cipher.key_id()),
+        }
+        .write(&mut self.buf);
+        (self.buf, self.blocks)
+    }
+}
+
+/// Entry of the index block
+pub struct IndexEntry<'a> {
+    pub position: EntryPosition,
+    /// Header of the entry, see `RECORD_HEADER_SIZE`
+    pub header: &'a [u8],
+    pub key: &'a [u8],
+}
+
+/// Parse the handles of the data blocks at the start of an index block
+pub fn parse_block_handles(index: &[u8]) -> Vec<BlockHandle> {
+    let num_blocks = u32::from_le_bytes(index[0..4].try_into().unwrap()) as usize;
+    index[4..4 + num_blocks * 12]
+        .chunks(12)
+        .map(|handle| BlockHandle {
+            offset: u64::from_le_bytes(handle[0..8].try_into().unwrap()),
+            size: u32::from_le_bytes(handle[8..12].try_into().unwrap()),
+        })
+        .collect()
+}
+
+/// Parse the entries of an index block
+pub fn parse_index_entries(index: &[u8]) -> Vec<IndexEntry> {
+    let num_blocks = u32::from_le_bytes(index[0..4].try_into().unwrap()) as usize;
+    let mut cursor = 4 + num_blocks * 12;
+    let mut entries = vec![];
+    while cursor < index.len() {
+        let header_start = cursor + 6;
+        let key_start = header_start + RECORD_HEADER_SIZE;
+        let (key_size, _) = entry_sizes(&index[header_start..key_start]);
+        entries.push(IndexEntry {
+            position: EntryPosition {
+                block: u32::from_le_bytes(index[cursor..cursor + 4].try_into().unwrap()),
+                entry: u16::from_le_bytes(index[cursor + 4..cursor + 6].try_into().unwrap()),
+            },
+            header: &index[header_start..key_start],
+            key: &index[key_start..key_start + key_size],
+        });
+        cursor = key_start + key_size;
+    }
+    entries
+}
+
+/// Data block read from a table
+pub struct Block {
+    buf: Vec<u8>,
+}
+
+impl Block {
+    pub fn new(buf: Vec<u8>) -> Block {
+        Block { buf }
+    }
+
+    /// Size of the decoded block
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Verify, decrypt (see `block_aad`) and decompress a block as read from
+    /// its handle
+    pub fn decode(buf: Vec<u8>, compression: Compression, cipher: Option<&Cipher>, aad: &[u8]) -> Result<Block, Corruption> {
+        Ok(Block::new(compression.decompress(open(verify_checksum(buf)?, cipher, aad)?)?))
+    }
+
+    fn num_restarts(&self) -> usize {
+        u32::from_le_bytes(self.buf[self.buf.len() - 4..].try_into().unwrap()) as usize
+    }
+
+    /// End of the entries, where the restart points start
+    fn entries_end(&self) -> usize {
+        self.buf.len() - 4 - self.num_restarts() * 4
+    }
+
+    fn restart(&self, n: usize) -> usize {
+        let position = self.entries_end() + n * 4;
+        u32::from_le_bytes(self.buf[position..position + 4].try_into().unwrap()) as usize
+    }
+
+    /// Entry number `n` of the block
+    pub fn entry(&self, n: u16) -> &[u8] {
+        let mut offset = self.restart(n as usize / RESTART_INTERVAL);
+        for _ in 0..n as usize % RESTART_INTERVAL {
+            offset += entry_size(&self.buf[offset..]);
+        }
+        &self.buf[offset..offset + entry_size(&self.buf[offset..])]
+    }
+
+    /// Every entry of the block, in order
+    pub fn entries(&self) -> Vec<&[u8]> {
+        let end = self.entries_end();
+        let mut entries = vec![];
+        let mut offset = 0;
+        while offset < end {
+            let size = entry_size(&self.buf[offset..]);
+            entries.push(&self.buf[offset..offset + size]);
+            offset += size;
+        }
+        entries
+    }
+
+    /// Like `entries`, but checks that the sizes of the entries and the
+    /// restart points match the size of the block instead of trusting them
+    pub fn checked_entries(&self) -> Result<Vec<&[u8]>, Corruption> {
+        if self.buf.len() < 4 {
+            return Err(Corruption::ShortRead);
+        }
+        let num_restarts = self.num_restarts();
+        let end = num_restarts
+            .checked_mul(4)
+            .and_then(|size| self.buf.len().checked_sub(size + 4))
+            .ok_or(Corruption::InvalidFormat)?;
+        let mut entries = vec![];
+        let mut offset = 0;
+        while offset < end {
+            if end - offset < RECORD_HEADER_SIZE {
+                return Err(Corruption::InvalidFormat);
+            }
+            let size = entry_size(&self.buf[offset..]);
+            if size > end - offset {
+                return Err(Corruption::InvalidFormat);
+            }
+            if entries.len() % RESTART_INTERVAL == 0 && self.restart(entries.len() / RESTART_INTERVAL) != offset {
+                return Err(Corruption::InvalidFormat);
+            }
+            entries.push(&self.buf[offset..offset + size]);
+            offset += size;
+        }
+        if entries.len().div_ceil(RESTART_INTERVAL) != num_restarts {
+            return Err(Corruption::InvalidFormat);
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::datastore::encryption::{Encryption, StaticKeys};
+
+    fn entry(key: &str, value_size: usize) -> Vec<u8> {
+        let mut entry = vec![];
+        entry.extend((key.len() as u16).to_le_bytes());
+        entry.extend((value_size as u32).to_le_bytes());
+        entry.extend([0u8; RECORD_HEADER_SIZE - 6]);
+        entry.extend(key.as_bytes());
+        entry.extend(vec![b'v'; value_size]);
+        entry
+    }
+
+    /// Footer and index block (without its checksum) of a table
+    fn index_of(table: &[u8]) -> (Footer, &[u8]) {
+        let footer = Footer::parse(table).unwrap();
+        let index = verify_checksum(table[footer.index_offset as usize..table.len() - footer.size()].to_vec()).unwrap();
+        let index_end = footer.index_offset as usize + index.len();
+        (footer, &table[footer.index_offset as usize..index_end])
+    }
+
+    fn block_at(table: &[u8], handle: BlockHandle, compression: Compression) -> Result<Block, Corruption> {
+        Block::decode(
+            table[handle.offset as usize..(handle.offset + handle.size as u64) as usize].to_vec(),
+            compression,
+            None,
+            &[],
+        )
+    }
+
+    #[test]
+    fn test_table_blocks() {
+        let mut builder = TableBuilder::new(42, Compression::None, 0, None);
+        let entries: Vec<Vec<u8>> = (0..100).map(|i| entry(&format!("key{}", i), i * 10)).collect();
+        let positions: Vec<EntryPosition> = entries.iter().map(|e| builder.add(e)).collect();
+        let (table, blocks) = builder.finish();
+        assert!(blocks.len() > 1);
+        assert_eq!(u32::from_le_bytes(table[0..4].try_into().unwrap()), 100);
+
+        let (_, index) = index_of(&table);
+        assert_eq!(parse_block_handles(index), blocks);
+        let index_entries = parse_index_entries(index);
+        assert_eq!(index_entries.len(), 100);
+        assert_eq!(index_entries[57].key, b"key57");
+        assert_eq!(index_entries[57].position, positions[57]);
+
+        for (i, position) in positions.iter().enumerate() {
+            let block = block_at(&table, blocks[position.block as usize], Compression::None).unwrap();
+            assert_eq!(block.entry(position.entry), entries[i].as_slice());
+        }
+        let block = block_at(&table, blocks[0], Compression::None).unwrap();
+        assert_eq!(block.entries().len(), positions.iter().filter(|p| p.block == 0).count());
+        assert_eq!(block.checked_entries(), Ok(block.entries()));
+        // An entry larger than what is left of the block
+        let mut buf = block.buf.clone();
+        buf[5] = 0x7f;
+        assert_eq!(Block::new(buf).checked_entries(), Err(Corruption::InvalidFormat));
+
+        let groups = group_blocks(&blocks, 3 * BLOCK_SIZE);
+        assert!(groups.len() > 1 && groups.len() < blocks.len());
+        assert_eq!(groups.iter().map(|g| g.len()).sum::<usize>(), blocks.len());
+        // Larger than the maximum
+        assert_eq!(group_blocks(&blocks, 1), (0..blocks.len()).map(|n| n..n + 1).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_table_block_compression() {
+        let entries: Vec<Vec<u8>> = (0..100).map(|i| entry(&format!("key{}", i), i * 10)).collect();
+        let mut sizes = vec![];
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let mut builder = TableBuilder::new(42, compression, 0, None);
+            let positions: Vec<EntryPosition> = entries.iter().map(|e| builder.add(e)).collect();
+            let (table, blocks) = builder.finish();
+            let (footer, _) = index_of(&table);
+            assert_eq!(footer.compression, compression);
+
+            let block = block_at(&table, blocks[positions[99].block as usize], footer.compression).unwrap();
+            assert_eq!(block.entry(positions[99].entry), entries[99].as_slice());
+            sizes.push(table.len());
+        }
+        assert!(sizes[1] < sizes[0] && sizes[2] < sizes[0], "sizes: {:?}", sizes);
+    }
+
+    #[test]
+    fn test_table_corruption() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+
+        let mut builder = TableBuilder::new(42, Compression::Lz4, 0, None);
+        builder.add(&entry("key", 100));
+        let (mut table, blocks) = builder.finish();
+        table[blocks[0].offset as usize + 5] ^= 1;
+        assert_eq!(block_at(&table, blocks[0], Compression::Lz4).err(), Some(Corruption::ChecksumMismatch));
+        assert_eq!(verify_checksum(vec![1, 2]), Err(Corruption::ShortRead));
+
+        let last = table.len() - 1;
+        table[last] ^= 1;
+        assert_eq!(Footer::parse(&table), Err(Corruption::InvalidFormat));
+    }
+
+    #[test]
+    fn test_table_encryption() {
+        let encryption = Encryption::new(1, Rc::new(StaticKeys::new(vec![(1, vec![1; 32]), (2, vec![2; 32])]))).unwrap();
+        let mut builder = TableBuilder::new(42, Compression::Lz4, 0, Some(encryption.current()));
+        let position = builder.add(&entry("key", 100));
+        // Fills the first block, the next entry is in a second one
+        builder.add(&entry("large", BLOCK_SIZE));
+        builder.add(&entry("other", 100));
+        let (table, blocks) = builder.finish();
+        let footer = Footer::parse(&table).unwrap();
+        assert_eq!(footer.key_id, 1);
+        // Neither the keys nor the values are written in clear
+        assert!(!table.windows(3).any(|w| w == b"key" || w == b"vvv"));
+
+        let index = verify_checksum(table[footer.index_offset as usize..table.len() - footer.size()].to_vec()).unwrap();
+        let cipher = encryption.current();
+        let index = open(index, Some(&cipher), &block_aad(FORMAT_VERSION, 42, footer.index_offset)).unwrap();
+        assert_eq!(parse_block_handles(&index), blocks);
+        let buf = table[blocks[0].offset as usize..(blocks[0].offset + blocks[0].size as u64) as usize].to_vec();
+        let aad = block_aad(FORMAT_VERSION, 42, blocks[0].offset);
+        let block = Block::decode(buf.clone(), Compression::Lz4, Some(&cipher), &aad).unwrap();
+        assert_eq!(block.entry(position.entry), entry("key", 100).as_slice());
+        assert_eq!(
+            Block::decode(buf.clone(), Compression::Lz4, Some(&encryption.cipher(2).unwrap()), &aad).err(),
+            Some(Corruption::AuthenticationFailed)
+        );
+
+        // A block moved to another offset or table doesn't authenticate
+        for aad in [
+            block_aad(FORMAT_VERSION, 42, blocks[1].offset),
+            block_aad(FORMAT_VERSION, 43, blocks[0].offset),
+        ] {
+            assert_eq!(
+                Block::decode(buf.clone(), Compression::Lz4, Some(&cipher), &aad).err(),
+                Some(Corruption::AuthenticationFailed)
+            );
+        }
+        assert!(block_aad(AAD_VERSION - 1, 42, blocks[0].offset).is_empty());
+    }
+
+    #[test]
+    fn test_footer_versions() {
+        let mut footer = Footer {
+            index_offset: 42,
+            compression: Compression::Zstd,
+            version: FORMAT_VERSION,
+            keyspace: 3,
+            key_id: 5,
+        };
+        let mut buf = vec![0; 10];
+        footer.write(&mut buf);
+        assert_eq!(buf.len(), 10 + FOOTER_SIZE);
+        assert_eq!(Footer::parse(&buf), Ok(footer));
+
+        // Tables written before the key id was in the footer are not encrypted
+        footer.version = ENCRYPTION_VERSION - 1;
+        let mut buf = vec![0; 10];
+        footer.write(&mut buf);
+        assert_eq!(buf.len(), 10 + footer.size());
+        footer.key_id = NO_KEY;
+        assert_eq!(Footer::parse(&buf), Ok(footer));
+
+        // Tables written before the keyspace was in the footer are in the default one
+        footer.version = KEYSPACE_VERSION - 1;
+        let mut buf = vec![0; 10];
+        footer.write(&mut buf);
+        assert_eq!(buf.len(), 10 + footer.size());
+        footer.keyspace = 0;
+        assert_eq!(Footer::parse(&buf), Ok(footer));
+
+        // Tables written before the version was in the footer
+        footer.version = LEGACY_VERSION;
+        let mut buf = vec![0; 10];
+        footer.write(&mut buf);
+        assert_eq!(buf.len(), 10 + footer.size());
+        assert_eq!(Footer::parse(&buf), Ok(footer));
+
+        footer.version = FORMAT_VERSION + 1;
+        let mut buf = vec![];
+        footer.write(&mut buf);
+        assert_eq!(Footer::parse(&buf), Err(Corruption::UnsupportedVersion(FORMAT_VERSION + 1)));
+    }
+}
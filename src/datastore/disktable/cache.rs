@@ -0,0 +1,135 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use super::block::Block;
+
+/// Table name and block number
+type BlockId = (Rc<String>, u32);
+
+/// LRU cache of the decoded data blocks of the tables, bounded in bytes.
+/// Tables are never modified so a cached block is valid until its table is
+/// removed
+pub struct BlockCache {
+    capacity: usize,
+    size: Cell<usize>,
+    /// Bumped on each access, orders the blocks from the least recently used
+    clock: Cell<u64>,
+    blocks: RefCell<HashMap<BlockId, (Rc<Block>, u64)>>,
+    lru: RefCell<BTreeMap<u64, BlockId>>,
+    hits: Cell<usize>,
+    misses: Cell<usize>,
+}
+
+impl BlockCache {
+    /// Cache of at most `capacity` bytes of blocks, 0 disables it
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            capacity,
+            size: Cell::new(0),
+            clock: Cell::new(0),
+            blocks: RefCell::new(HashMap::new()),
+            lru: RefCell::new(BTreeMap::new()),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.set(self.clock.get() + 1);
+        self.clock.get()
+    }
+
+    pub fn get(&self, table: &Rc<String>, block: u32) -> Option<Rc<Block>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let id = (table.clone(), block);
+        let mut blocks = self.blocks.borrow_mut();
+        let Some((cached, last_access)) = blocks.get_mut(&id) else {
+            self.misses.set(self.misses.get() + 1);
+            return None;
+        };
+        let mut lru = self.lru.borrow_mut();
+        lru.remove(last_access);
+        *last_access = self.tick();
+        lru.insert(*last_access, id);
+        self.hits.set(self.hits.get() + 1);
+        Some(cached.clone())
+    }
+
+    /// Cache a block, evicting the least recently used ones to make room.
+    /// Blocks larger than the cache are not kept
+    pub fn insert(&self, table: Rc<String>, block: u32, data: Rc<Block>) {
+        if data.size() > self.capacity {
+            return;
+        }
+        let id = (table, block);
+        self.remove(&id);
+        while self.size.get() + data.size() > self.capacity {
+            let (_, oldest) = self.lru.borrow_mut().pop_first().unwrap();
+            let (evicted, _) = self.blocks.borrow_mut().remove(&oldest).unwrap();
+            self.size.set(self.size.get() - evicted.size());
+        }
+        let last_access = self.tick();
+        self.size.set(self.size.get() + data.size());
+        self.lru.borrow_mut().insert(last_access, id.clone());
+        self.blocks.borrow_mut().insert(id, (data, last_access));
+    }
+
+    fn remove(&self, id: &BlockId) {
+        if let Some((block, last_access)) = self.blocks.borrow_mut().remove(id) {
+            self.lru.borrow_mut().remove(&last_access);
+            self.size.set(self.size.get() - block.size());
+        }
+    }
+
+    /// Drop the blocks of a removed table
+    pub fn remove_table(&self, table: &Rc<String>) {
+        let ids: Vec<BlockId> = self.blocks.borrow().keys().filter(|(t, _)| t == table).cloned().collect();
+        for id in ids {
+            self.remove(&id);
+        }
+    }
+
+    /// Bytes of blocks cached
+    pub fn size(&self) -> usize {
+        self.size.get()
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.get()
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_cache() {
+        let cache = BlockCache::new(30);
+        let table = Rc::new("table".to_string());
+        for block in 0..3 {
+            cache.insert(table.clone(), block, Rc::new(Block::new(vec![0; 10])));
+        }
+        assert!(cache.get(&table, 0).is_some());
+        // Block 1 is the least recently used
+        cache.insert(table.clone(), 3, Rc::new(Block::new(vec![0; 10])));
+        assert!(cache.get(&table, 1).is_none());
+        assert!(cache.get(&table, 0).is_some());
+        assert_eq!((cache.size(), cache.hits(), cache.misses()), (30, 2, 1));
+
+        cache.insert(table.clone(), 4, Rc::new(Block::new(vec![0; 31])));
+        assert!(cache.get(&table, 4).is_none());
+        cache.remove_table(&table);
+        assert_eq!(cache.size(), 0);
+        assert!(cache.get(&table, 0).is_none());
+    }
+}
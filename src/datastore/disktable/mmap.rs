@@ -0,0 +1,42 @@
+use std::{fs::File, io, os::fd::AsRawFd, path::Path};
+
+/// Read-only mapping of a whole table file, unmapped when dropped. Tables are
+/// never modified once written, so the mapping never changes under the reads
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    pub fn open(path: &Path) -> io::Result<Mmap> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        // SAFETY: a new read-only mapping, the kernel picks the address. It
+        // stays valid once the file is closed
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr, len })
+    }
+
+    /// `size` bytes at `offset`, None past the end of the file
+    pub fn get(&self, offset: u64, size: usize) -> Option<&[u8]> {
+        let start = offset as usize;
+        self.as_slice().get(start..start.checked_add(size)?)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: `len` bytes mapped readable until `self` is dropped
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: mapped in `open`, no slice outlives `self`
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
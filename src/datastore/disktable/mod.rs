@@ -1,30 +1,119 @@
-use crate::record::{hash_sha1_bytes, Key, Record};
-use monoio::fs::File;
+pub mod block;
+mod cache;
+mod mmap;
+
+use crate::record::{key_slot, HashedKey, Key, Record, ValueType, RECORD_HEADER_SIZE};
+use futures::future::join_all;
+use monoio::fs::{File, OpenOptions};
 use std::cell::{Cell, RefCell};
-use std::{collections::HashMap, path::PathBuf, rc::Rc};
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
+use self::block::{Block, BlockHandle, Compression, Corruption, EntryPosition, Footer, IndexEntry, TableBuilder, FOOTER_SIZE, TABLE_HEADER_SIZE};
+use self::cache::BlockCache;
+use self::mmap::Mmap;
+use super::access::AccessStats;
+use super::bloom::BloomFilter;
+use super::direct_io::{self, AlignedBuf};
+use super::encryption::{self, Cipher, Encryption, NO_KEY};
+use super::error::DataStoreError;
+use super::expiry::NO_EXPIRY;
+use super::verify::Problem;
 use super::DiskPointer;
 use super::{memtable::MemTable, RecordMetadata};
 
-/// Represent an on-disk table
-///
-/// | metadata      |         data          |
-/// |num_of_elements|entry|entry|entry|entry|
+/// Represent an on-disk table, see `block` for the layout of the file
 ///
-/// |                         entry                          |
-/// |timestamp(u64le)|keysize(u16le)|valsize(u32le)|key|value|
+/// |                                      entry                                        |
+/// |keysize(u16le)|valsize(u32le)|timestamp(u64le)|type(u8)|flags(u32le)|expire_at(u64le)|key|value|
 pub struct DiskTable {
     name: Rc<String>,
     path: PathBuf,
     timestamp: u64,
-    fd: File,
+    file: TableFile,
+    /// Mapping of the file, blocks are read from it instead of `file` when set
+    mmap: Option<Mmap>,
+    /// Handles of the data blocks, from the index block
+    blocks: Vec<BlockHandle>,
+    /// Number of the first record of each data block, records are numbered
+    /// in the order of the index block
+    block_ordinals: Vec<u32>,
+    /// Codec of the data blocks, from the footer
+    compression: Compression,
+    /// Format of the table, from the footer
+    version: u32,
+    /// Keyspace of the records, from the footer
+    keyspace: u16,
+    /// Key of the blocks, from the footer, None if they are not encrypted
+    cipher: Option<Cipher>,
+    /// Not written with the key of the new tables, rewritten by compaction
+    stale_key: bool,
     /// Count the number of records physically within the disktables
-    count: Cell<u16>,
+    count: Cell<u32>,
     /// Count the number of references to disktable from the index
     /// 0 means that the table is safe for deletion
-    references: Cell<u16>,
+    references: Cell<u32>,
     /// Mark the disktable for deletion
     status: Cell<DisktableStatus>,
+    /// A read failed, the table is not compacted anymore
+    suspect: Cell<bool>,
+    /// Records whose index entry was evicted, None until the first one
+    evicted: RefCell<Option<Evicted>>,
+}
+
+/// Records of a table whose index entry was evicted from memory (see
+/// `DataStore::evict_cold_entries`), they keep their reference on the table
+/// until the entry is loaded back
+struct Evicted {
+    /// Hashes of the keys, checked before reading the index block
+    filter: BloomFilter,
+    /// One bit per record
+    records: Vec<u64>,
+    count: u32,
+}
+
+/// Version of the table format, part of the table file name
+pub const FORMAT_VERSION: u32 = 12;
+
+/// Bytes read at once by `read_all_data`, consecutive blocks are read
+/// together instead of one read per block
+const READAHEAD_SIZE: usize = 1024 * 1024;
+
+/// Fixed size part of an entry
+struct RecordHeader {
+    key_size: u16,
+    value_size: u32,
+    timestamp: u64,
+    value_type: ValueType,
+    flags: u32,
+    /// `NO_EXPIRY` if the record never expires
+    expire_at: u64,
+}
+
+impl RecordHeader {
+    fn parse(buf: &[u8]) -> Result<RecordHeader, Corruption> {
+        Ok(RecordHeader {
+            key_size: u16::from_le_bytes(buf[0..2].try_into().expect("incorrect length")),
+            value_size: u32::from_le_bytes(buf[2..6].try_into().expect("incorrect length")),
+            timestamp: u64::from_le_bytes(buf[6..14].try_into().expect("incorrect length")),
+            value_type: ValueType::try_from_u8(buf[14]).ok_or(Corruption::InvalidFormat)?,
+            flags: u32::from_le_bytes(buf[15..19].try_into().expect("incorrect length")),
+            expire_at: u64::from_le_bytes(buf[19..27].try_into().expect("incorrect length")),
+        })
+    }
+
+    fn write(record: &Record, buf: &mut Vec<u8>) {
+        buf.extend((record.key.string.len() as u16).to_le_bytes());
+        buf.extend((record.value.len() as u32).to_le_bytes());
+        buf.extend(record.timestamp.to_le_bytes());
+        buf.push(record.value_type as u8);
+        buf.extend(record.flags.to_le_bytes());
+        buf.extend(record.expire_at.unwrap_or(NO_EXPIRY).to_le_bytes());
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -40,184 +129,607 @@ pub struct DiskTableStats {
     pub references: usize,
     pub count: usize,
     pub status: DisktableStatus,
+    /// Format of the table, older ones are rewritten by compaction
+    pub version: u32,
+    /// A read of the table failed
+    pub suspect: bool,
+    /// Keyspace of the records of the table
+    pub keyspace: u16,
+    /// Not encrypted with the key of the new tables (or encrypted while
+    /// encryption is disabled), the table is rewritten by compaction
+    pub stale_key: bool,
 }
 
-impl DiskTable {
-    pub async fn new_from_memtable(name: Rc<String>, path: PathBuf, timestamp: u64, memtable: &MemTable) -> (DiskTable, Vec<RecordMetadata>) {
-        let file = File::create(path.clone()).await.unwrap();
+/// How the tables of a manager are written and read
+#[derive(Debug, Clone)]
+pub struct TableOptions {
+    /// Codec of the data blocks of the new tables
+    pub compression: Compression,
+    /// Read the blocks from a mapping of the files
+    pub mmap: bool,
+    /// Open the files with O_DIRECT, bypassing the page cache
+    pub direct_io: bool,
+    /// Bytes of decoded data blocks kept in memory by the manager (0
+    /// disables the cache)
+    pub block_cache_bytes: usize,
+    /// Keys of the tables, the new tables are encrypted with the current one
+    pub encryption: Option<Encryption>,
+}
+
+impl TableOptions {
+    /// Id of the key of the new tables
+    fn key_id(&self) -> u32 {
+        self.encryption.as_ref().map_or(NO_KEY, |encryption| encryption.key_id())
+    }
+}
+
+/// File of a table, read through the page cache or with O_DIRECT
+struct TableFile {
+    fd: File,
+    direct_io: bool,
+}
+
+impl TableFile {
+    async fn open(path: &Path, direct_io: bool) -> std::io::Result<TableFile> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        if direct_io {
+            options.custom_flags(direct_io::O_DIRECT);
+        }
+        Ok(TableFile {
+            fd: options.open(path).await?,
+            direct_io,
+        })
+    }
 
-        let mut offsets = Vec::with_capacity(memtable.len());
-        let mut buf: Vec<u8> = Vec::with_capacity(memtable.get_byte_size());
+    /// Read `size` bytes at `offset`, a file shorter than expected is corrupted
+    async fn read_exact_at(&self, size: usize, offset: u64) -> Result<Vec<u8>, DataStoreError> {
+        let res = match self.direct_io {
+            true => direct_io::read_exact_at(&self.fd, size, offset).await,
+            false => {
+                let (res, buf) = self.fd.read_exact_at(vec![0u8; size], offset).await;
+                res.map(|_| buf)
+            }
+        };
+        match res {
+            Ok(buf) => Ok(buf),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(Corruption::ShortRead.into()),
+            Err(e) => Err(DataStoreError::from(e)),
+        }
+    }
+}
+
+/// Write the content of a new table to `path` and persist it. With O_DIRECT
+/// it is written padded to the alignment then truncated to its size
+async fn write_table_file(path: &Path, buf: Vec<u8>, direct_io: bool) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    if direct_io {
+        options.custom_flags(direct_io::O_DIRECT);
+    }
+    let file = options.open(path).await?;
+    let size = buf.len() as u64;
+    let res = match direct_io {
+        true => file.write_all_at(AlignedBuf::from_slice(&buf), 0).await.0,
+        false => file.write_all_at(buf, 0).await.0,
+    };
+    res?;
+    if direct_io {
+        std::fs::OpenOptions::new().write(true).open(path)?.set_len(size)?;
+    }
+    file.sync_all().await
+}
+
+/// Number of the first record of each data block, from the index block
+fn block_ordinals(index: &[u8]) -> Vec<u32> {
+    let mut ordinals = vec![];
+    for (ordinal, entry) in block::parse_index_entries(index).iter().enumerate() {
+        if entry.position.block as usize == ordinals.len() {
+            ordinals.push(ordinal as u32);
+        }
+    }
+    ordinals
+}
+
+/// Read the footer and the index block of a table, still sealed if the table
+/// is encrypted (see `block::open`)
+async fn read_index_block(file: &TableFile, path: &Path) -> Result<(Footer, Vec<u8>), DataStoreError> {
+    let file_size = std::fs::metadata(path)?.len();
+    if file_size < (TABLE_HEADER_SIZE + FOOTER_SIZE) as u64 {
+        return Err(Corruption::ShortRead.into());
+    }
+    let footer = Footer::parse(&file.read_exact_at(FOOTER_SIZE, file_size - FOOTER_SIZE as u64).await?)?;
+    let index_size = (file_size - footer.size() as u64)
+        .checked_sub(footer.index_offset)
+        .ok_or(Corruption::InvalidFormat)?;
+    let index = block::verify_checksum(file.read_exact_at(index_size as usize, footer.index_offset).await?)?;
+    Ok((footer, index))
+}
+
+/// Map the file of a table if `mmap` is set
+fn map_table(path: &Path, mmap: bool) -> Result<Option<Mmap>, DataStoreError> {
+    match mmap {
+        true => Ok(Some(Mmap::open(path)?)),
+        false => Ok(None),
+    }
+}
+
+/// Persist the creations and renames of files in `directory`
+pub fn sync_directory(directory: &Path) -> std::io::Result<()> {
+    std::fs::File::open(directory)?.sync_all()
+}
+
+/// Header, key and value of a record as written in a data block
+pub fn encode_entry(record: &Record) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(record.size_of());
+    RecordHeader::write(record, &mut entry);
+    entry.extend(record.key.string.as_bytes());
+    entry.extend(&record.value);
+    entry
+}
+
+fn decode_entry(entry: &[u8]) -> Result<Record, Corruption> {
+    let header = RecordHeader::parse(entry)?;
+    let key_end = RECORD_HEADER_SIZE + header.key_size as usize;
+    let key = std::str::from_utf8(&entry[RECORD_HEADER_SIZE..key_end]).map_err(|_| Corruption::InvalidFormat)?;
+    let value = Vec::from(&entry[key_end..key_end + header.value_size as usize]);
+
+    let mut record = Record::new_with_timestamp(key.to_string(), value, header.timestamp);
+    record.value_type = header.value_type;
+    record.flags = header.flags;
+    record.expire_at = match header.expire_at {
+        NO_EXPIRY => None,
+        expire_at => Some(expire_at),
+    };
+    Ok(record)
+}
+
+impl DiskTable {
+    pub async fn new_from_memtable(
+        name: Rc<String>,
+        path: PathBuf,
+        timestamp: u64,
+        records: &[Record],
+        keyspace: u16,
+        options: &TableOptions,
+    ) -> Result<(DiskTable, Vec<RecordMetadata>), DataStoreError> {
+        let mut offsets = Vec::with_capacity(records.len());
+        let cipher = options.encryption.as_ref().map(|encryption| encryption.current());
+        let mut builder = TableBuilder::new(timestamp, options.compression, keyspace, cipher.clone());
         let mut count = 0;
         let mut references = 0;
+        let mut block_ordinals = vec![];
 
-        buf.extend((memtable.len() as u16).to_le_bytes());
-        buf.extend(crate::time::now().to_le_bytes());
-        memtable.values().iter().for_each(|r| {
+        records.iter().for_each(|r| {
+            let EntryPosition { block, entry } = builder.add(&encode_entry(r));
+            if block as usize == block_ordinals.len() {
+                block_ordinals.push(count);
+            }
             offsets.push(RecordMetadata {
                 data_ptr: super::RecordPtr::DiskTable(DiskPointer {
                     disktable: name.clone(),
-                    offset: buf.len() as u32,
+                    block,
+                    entry,
                 }),
                 key_size: r.key.string.len() as u16,
                 value_size: r.value.len() as u32,
                 timestamp: r.timestamp,
                 hash: r.key.hash,
+                slot: key_slot(r.key.string.as_bytes()),
+                value_type: r.value_type,
+                access: AccessStats::new(),
+                expire_at: r.expire_at.unwrap_or(NO_EXPIRY),
             });
-            buf.extend((r.key.string.len() as u16).to_le_bytes());
-            buf.extend((r.value.len() as u32).to_le_bytes());
-            buf.extend(r.timestamp.to_le_bytes());
-            buf.extend(r.key.string.as_bytes());
-            buf.extend(r.value.clone());
             count += 1;
             references += 1;
         });
-        let (res, _) = file.write_at(buf, 0).await;
-        res.unwrap();
-        memtable.len();
+        let (buf, blocks) = builder.finish();
+        // Written under a temporary name then renamed, a crash never leaves a
+        // torn table under its final name
+        let tmp_path = path.with_extension("tmp");
+        if let Err(e) = write_table_file(&tmp_path, buf, options.direct_io).await {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e.into());
+        }
+        std::fs::rename(&tmp_path, &path)?;
+        sync_directory(path.parent().unwrap())?;
 
-        let file = File::open(path.clone()).await.unwrap();
+        let file = TableFile::open(&path, options.direct_io).await?;
+        let mmap = map_table(&path, options.mmap)?;
 
-        (
+        Ok((
             DiskTable {
                 name,
                 path,
                 timestamp,
-                fd: file,
+                file,
+                mmap,
+                blocks,
+                block_ordinals,
+                compression: options.compression,
+                version: FORMAT_VERSION,
+                keyspace,
+                cipher,
+                stale_key: false,
                 count: Cell::new(count),
                 references: Cell::new(references),
                 status: Cell::new(DisktableStatus::Active),
+                suspect: Cell::new(false),
+                evicted: RefCell::new(None),
             },
             offsets,
-        )
+        ))
     }
 
-    /// Initialize a disktable from an already existing table
-    pub async fn new_from_disk(name: Rc<String>, path: PathBuf) -> DiskTable {
+    /// Initialize a disktable from an already existing table, its codec is
+    /// the one of its footer whatever `options` says, so is its key
+    pub async fn new_from_disk(name: Rc<String>, path: PathBuf, options: &TableOptions) -> Result<DiskTable, DataStoreError> {
         // Open the file and read its disktable metadata
-        let fd = File::open(path.clone()).await.unwrap();
-        // TODO find a way to use an array instead
-        let buf = vec![0u8; 10];
-        let (res, buf) = fd.read_at(buf, 0).await;
-        res.unwrap();
-        let timestamp = u64::from_le_bytes(buf[2..10].try_into().unwrap());
+        let file = TableFile::open(&path, options.direct_io).await?;
+        let buf = file.read_exact_at(TABLE_HEADER_SIZE, 0).await?;
+        let (footer, index) = read_index_block(&file, &path).await?;
+        let cipher = encryption::cipher_of(options.encryption.as_ref(), footer.key_id)?;
+        let timestamp = u64::from_le_bytes(buf[4..12].try_into().unwrap());
+        let index = block::open(index, cipher.as_ref(), &block::block_aad(footer.version, timestamp, footer.index_offset))?;
         crate::time::sync(timestamp);
 
-        DiskTable {
+        Ok(DiskTable {
+            mmap: map_table(&path, options.mmap)?,
             name,
             path,
             timestamp,
-            fd,
-            count: Cell::new(u16::from_le_bytes(buf[0..2].try_into().unwrap())),
+            file,
+            blocks: block::parse_block_handles(&index),
+            block_ordinals: block_ordinals(&index),
+            compression: footer.compression,
+            version: footer.version,
+            keyspace: footer.keyspace,
+            cipher,
+            stale_key: footer.key_id != options.key_id(),
+            count: Cell::new(u32::from_le_bytes(buf[0..4].try_into().unwrap())),
             references: Cell::new(0),
             status: Cell::new(DisktableStatus::Active),
-        }
+            suspect: Cell::new(false),
+            evicted: RefCell::new(None),
+        })
     }
 
-    pub async fn read_all_metadata(&self) -> Vec<RecordMetadata> {
-        let mut header_buffer = vec![0u8; 10];
-        let mut record_metadata_buffer = vec![0u8; 14];
-        let mut res;
-
-        let mut stream_cursor = 0;
-        (res, header_buffer) = self.fd.read_exact_at(header_buffer, stream_cursor).await;
-        res.unwrap();
-        let count = u16::from_le_bytes(header_buffer[0..2].try_into().unwrap());
+    /// Index block of the table, decrypted
+    async fn read_index(&self) -> Result<Vec<u8>, DataStoreError> {
+        let (footer, index) = read_index_block(&self.file, &self.path).await?;
+        Ok(block::open(index, self.cipher.as_ref(), &self.aad(footer.index_offset))?)
+    }
 
-        let mut meta = Vec::with_capacity(count as usize);
+    /// Associated data of the block at `offset`, see `block::block_aad`
+    fn aad(&self, offset: u64) -> Vec<u8> {
+        block::block_aad(self.version, self.timestamp, offset)
+    }
 
-        let mut cursor: usize = header_buffer.len();
-        stream_cursor += header_buffer.len() as u64;
+    /// Mark the table as suspect if `res` is an error
+    fn check<T>(&self, res: Result<T, DataStoreError>) -> Result<T, DataStoreError> {
+        if res.is_err() {
+            self.suspect.set(true);
+        }
+        res
+    }
 
-        for _ in 0..count {
-            println!("Cursor: {}", stream_cursor);
-            (res, record_metadata_buffer) = self.fd.read_exact_at(record_metadata_buffer, stream_cursor).await;
-            res.unwrap();
-            let key_size = u16::from_le_bytes(record_metadata_buffer[0..2].try_into().expect("incorrect length"));
-            let value_size = u32::from_le_bytes(record_metadata_buffer[2..6].try_into().expect("incorrect length"));
-            let timestamp = u64::from_le_bytes(record_metadata_buffer[6..14].try_into().expect("incorrect length"));
-            let mut key = vec![0u8; key_size as usize];
-            stream_cursor += record_metadata_buffer.len() as u64;
+    /// Read the key and metadata of every record from the index block only
+    pub async fn read_all_metadata(&self) -> Result<Vec<(Key, RecordMetadata)>, DataStoreError> {
+        let res = self.read_index_metadata().await;
+        self.check(res)
+    }
 
-            (res, key) = self.fd.read_exact_at(key, stream_cursor).await;
-            res.unwrap();
-            stream_cursor += key_size as u64 + value_size as u64;
+    async fn read_index_metadata(&self) -> Result<Vec<(Key, RecordMetadata)>, DataStoreError> {
+        let meta = self.index_metadata().await?;
+        self.references.set(self.references.get() + meta.len() as u32);
+        Ok(meta)
+    }
 
-            meta.push(RecordMetadata {
-                data_ptr: super::RecordPtr::DiskTable(DiskPointer {
-                    disktable: self.name.clone(),
-                    offset: cursor as u32,
-                }),
-                key_size,
-                value_size,
-                hash: hash_sha1_bytes(&key),
-                timestamp,
-            });
-            self.references.set(self.references.get() + 1);
-            cursor += meta.last().unwrap().size_of();
-            assert_eq!(cursor as u64, stream_cursor);
-        }
-        meta
-    }
-
-    pub async fn read_all_data(&self) -> Vec<(Record, RecordMetadata)> {
-        let mut header_buffer = vec![0u8; 10];
-        let mut record_metadata_buffer = vec![0u8; 14];
-        let mut res;
-
-        let mut stream_cursor = 0;
-        (res, header_buffer) = self.fd.read_exact_at(header_buffer, stream_cursor).await;
-        res.unwrap();
-
-        let count = u16::from_le_bytes(header_buffer[0..2].try_into().unwrap());
-        stream_cursor += header_buffer.len() as u64;
-
-        let mut meta = Vec::with_capacity(count as usize);
-        for _ in 0..count {
-            println!("Cursor head: {}", stream_cursor);
-            let offset = stream_cursor as u32;
-            (res, record_metadata_buffer) = self.fd.read_exact_at(record_metadata_buffer, stream_cursor).await;
-            res.unwrap();
-            let key_size = u16::from_le_bytes(record_metadata_buffer[0..2].try_into().expect("incorrect length"));
-            let value_size = u32::from_le_bytes(record_metadata_buffer[2..6].try_into().expect("incorrect length"));
-            let timestamp = u64::from_le_bytes(record_metadata_buffer[6..14].try_into().expect("incorrect length"));
-            let mut key_bytes = vec![0u8; key_size as usize];
-            println!("read meta: k:{:?} v:{} t:{}", key_size, value_size, timestamp);
-            println!("Cursor key: {} (reading {})", stream_cursor, key_size);
-            stream_cursor += record_metadata_buffer.len() as u64;
-
-            (res, key_bytes) = self.fd.read_exact_at(key_bytes, stream_cursor).await;
-            res.unwrap();
-            stream_cursor += key_size as u64;
-
-            println!("Cursor val: {} (reading {})", stream_cursor, value_size);
-
-            let mut value = vec![0u8; value_size as usize];
-            (res, value) = self.fd.read_exact_at(value, stream_cursor).await;
-            res.unwrap();
-            stream_cursor += value_size as u64;
-
-            println!("Cursor end: {}", stream_cursor);
-
-            let key = Key::new(std::str::from_utf8(&key_bytes).unwrap().to_string());
-            println!("read key: {:?}", key.string);
-            let hash = key.hash;
-            meta.push((
-                Record { timestamp, key, value },
-                RecordMetadata {
+    /// Key and metadata of every record, without referencing them
+    async fn index_metadata(&self) -> Result<Vec<(Key, RecordMetadata)>, DataStoreError> {
+        let index = self.read_index().await?;
+        let meta = block::parse_index_entries(&index)
+            .into_iter()
+            .map(|IndexEntry { position, header, key }| {
+                let RecordHeader {
+                    key_size,
+                    value_size,
+                    timestamp,
+                    value_type,
+                    expire_at,
+                    ..
+                } = RecordHeader::parse(header)?;
+                let slot = key_slot(key);
+                let key = Key::new(String::from_utf8(key.to_vec()).map_err(|_| Corruption::InvalidFormat)?);
+                let meta = RecordMetadata {
                     data_ptr: super::RecordPtr::DiskTable(DiskPointer {
                         disktable: self.name.clone(),
-                        offset,
+                        block: position.block,
+                        entry: position.entry,
                     }),
                     key_size,
                     value_size,
-                    hash,
+                    hash: key.hash,
+                    slot,
                     timestamp,
+                    value_type,
+                    access: AccessStats::new(),
+                    expire_at,
+                };
+                Ok((key, meta))
+            })
+            .collect::<Result<Vec<(Key, RecordMetadata)>, Corruption>>()?;
+        if meta.len() != self.count.get() as usize {
+            return Err(Corruption::InvalidFormat.into());
+        }
+        Ok(meta)
+    }
+
+    fn ordinal(&self, ptr: &DiskPointer) -> usize {
+        self.block_ordinals[ptr.block as usize] as usize + ptr.entry as usize
+    }
+
+    /// Remember that the index entry of the record at `ptr` was evicted, the
+    /// reference it held is kept
+    pub fn evict(&self, ptr: &DiskPointer, hash: &HashedKey) {
+        let count = self.count.get() as usize;
+        let mut evicted = self.evicted.borrow_mut();
+        let evicted = evicted.get_or_insert_with(|| Evicted {
+            filter: BloomFilter::new(count),
+            records: vec![0; count.div_ceil(64)],
+            count: 0,
+        });
+        let ordinal = self.ordinal(ptr);
+        evicted.records[ordinal / 64] |= 1 << (ordinal % 64);
+        evicted.filter.insert(hash);
+        evicted.count += 1;
+    }
+
+    /// Forget that the entry of the record at `ptr` was evicted, false if it
+    /// wasn't. Its reference is now held by the caller
+    pub fn take_evicted(&self, ptr: &DiskPointer) -> bool {
+        let ordinal = self.ordinal(ptr);
+        let mut evicted = self.evicted.borrow_mut();
+        let Some(e) = evicted.as_mut().filter(|e| e.records[ordinal / 64] & (1 << (ordinal % 64)) != 0) else {
+            return false;
+        };
+        e.records[ordinal / 64] &= !(1 << (ordinal % 64));
+        e.count -= 1;
+        // The filter can't remove keys, it is dropped once empty
+        if e.count == 0 {
+            *evicted = None;
+        }
+        true
+    }
+
+    /// Number of records whose entry is evicted
+    pub fn evicted(&self) -> usize {
+        self.evicted.borrow().as_ref().map_or(0, |e| e.count as usize)
+    }
+
+    /// Take the evicted records of the key `hash` (see `take_evicted`), with
+    /// their key. The index block is only read when the filter may hold the key
+    pub async fn find_evicted(&self, hash: &HashedKey) -> Result<Vec<(Key, RecordMetadata)>, DataStoreError> {
+        if !self.evicted.borrow().as_ref().is_some_and(|e| e.filter.may_contain(hash)) {
+            return Ok(vec![]);
+        }
+        let res = self.index_metadata().await;
+        let mut found = vec![];
+        for (key, meta) in self.check(res)? {
+            if let super::RecordPtr::DiskTable(ptr) = &meta.data_ptr {
+                if meta.hash == *hash && self.take_evicted(ptr) {
+                    found.push((key, meta));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Read `size` bytes at `offset`, from the mapping if there is one
+    async fn read_at(&self, offset: u64, size: usize) -> Result<Vec<u8>, DataStoreError> {
+        match &self.mmap {
+            Some(mmap) => Ok(mmap.get(offset, size).ok_or(Corruption::ShortRead)?.to_vec()),
+            None => self.file.read_exact_at(size, offset).await,
+        }
+    }
+
+    async fn read_block(&self, block: u32) -> Result<Block, DataStoreError> {
+        let handle = self.blocks[block as usize];
+        let buf = self.read_at(handle.offset, handle.size as usize).await?;
+        Ok(Block::decode(buf, self.compression, self.cipher.as_ref(), &self.aad(handle.offset))?)
+    }
+
+    /// Bytes of the data blocks, read by `read_all_data`
+    pub fn data_size(&self) -> usize {
+        self.blocks.iter().map(|handle| handle.size as usize).sum()
+    }
+
+    /// Read every record of the table (reclaim), the consecutive blocks are
+    /// read `READAHEAD_SIZE` bytes at a time
+    pub async fn read_all_data(&self) -> Result<Vec<(Record, RecordMetadata)>, DataStoreError> {
+        let data = self.read_all_data_unreferenced().await?;
+        // Only referenced once the whole table is read
+        self.references.set(self.references.get() + data.len() as u32);
+        Ok(data)
+    }
+
+    /// Like `read_all_data` without referencing the records, for a caller
+    /// that moves the references already held on them (direct compaction)
+    pub async fn read_all_data_unreferenced(&self) -> Result<Vec<(Record, RecordMetadata)>, DataStoreError> {
+        let res = self.read_blocks().await;
+        self.check(res)
+    }
+
+    async fn read_blocks(&self) -> Result<Vec<(Record, RecordMetadata)>, DataStoreError> {
+        let mut data = Vec::with_capacity(self.count.get() as usize);
+        for group in block::group_blocks(&self.blocks, READAHEAD_SIZE) {
+            let start = self.blocks[group.start].offset;
+            let last = self.blocks[group.end - 1];
+            let chunk = self.read_at(start, (last.offset + last.size as u64 - start) as usize).await?;
+            for block_number in group {
+                let handle = self.blocks[block_number];
+                let block_start = (handle.offset - start) as usize;
+                let buf = chunk[block_start..block_start + handle.size as usize].to_vec();
+                data.extend(self.decode_block_records(
+                    block_number as u32,
+                    &Block::decode(buf, self.compression, self.cipher.as_ref(), &self.aad(handle.offset))?,
+                )?);
+            }
+        }
+        Ok(data)
+    }
+
+    /// Check the whole file (header, footer, index block, checksums and entry
+    /// lengths of the data blocks, entries against the index block) and that
+    /// the records `referenced` by the datastore are there and hold its
+    /// references. The table is suspect if a problem is found
+    pub async fn verify(&self, referenced: &[RecordMetadata]) -> Vec<Problem> {
+        let problems = self.verify_file(referenced).await;
+        if !problems.is_empty() {
+            self.suspect.set(true);
+        }
+        problems
+    }
+
+    async fn verify_file(&self, referenced: &[RecordMetadata]) -> Vec<Problem> {
+        let table = &self.name;
+        let unreadable = |error| vec![Problem::Unreadable { table: table.clone(), error }];
+        let header = match self.file.read_exact_at(TABLE_HEADER_SIZE, 0).await {
+            Ok(header) => header,
+            Err(e) => return unreadable(e),
+        };
+        let index = match self.read_index().await {
+            Ok(index) => index,
+            Err(e) => return unreadable(e),
+        };
+        let mut problems = vec![];
+        let handles = block::parse_block_handles(&index);
+        let entries = block::parse_index_entries(&index);
+        let count = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        if count as usize != entries.len() {
+            problems.push(Problem::Count {
+                table: table.clone(),
+                header: count,
+                index: entries.len() as u32,
+            });
+        }
+
+        let mut indexed: Vec<Vec<&IndexEntry>> = handles.iter().map(|_| vec![]).collect();
+        for entry in &entries {
+            match indexed.get_mut(entry.position.block as usize) {
+                Some(block) => block.push(entry),
+                None => problems.push(Problem::Entry {
+                    table: table.clone(),
+                    block: entry.position.block,
+                    entry: entry.position.entry,
+                }),
+            }
+        }
+        for (number, handle) in handles.iter().enumerate() {
+            let block_problem = |error| Problem::Block {
+                table: table.clone(),
+                block: number as u32,
+                error,
+            };
+            let block = match self.read_at(handle.offset, handle.size as usize).await {
+                Ok(buf) => match Block::decode(buf, self.compression, self.cipher.as_ref(), &self.aad(handle.offset)) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        problems.push(block_problem(e.into()));
+                        continue;
+                    }
                 },
-            ));
-            self.references.set(self.references.get() + 1);
+                Err(e) => {
+                    problems.push(block_problem(e));
+                    continue;
+                }
+            };
+            let data = match block.checked_entries() {
+                Ok(data) => data,
+                Err(e) => {
+                    problems.push(block_problem(e.into()));
+                    continue;
+                }
+            };
+            let indexed = &indexed[number];
+            for n in 0..data.len().max(indexed.len()) {
+                // The index block has the header and the key of each entry
+                let matches = match (data.get(n), indexed.get(n)) {
+                    (Some(entry), Some(i)) => i.position.entry as usize == n && entry.starts_with(&[i.header, i.key].concat()),
+                    _ => false,
+                };
+                if !matches {
+                    problems.push(Problem::Entry {
+                        table: table.clone(),
+                        block: number as u32,
+                        entry: n as u16,
+                    });
+                }
+            }
         }
-        meta
+
+        let positions: HashMap<(u32, u16), &IndexEntry> = entries.iter().map(|e| ((e.position.block, e.position.entry), e)).collect();
+        for meta in referenced {
+            let (block, entry) = match &meta.data_ptr {
+                super::RecordPtr::DiskTable(ptr) => (ptr.block, ptr.entry),
+                super::RecordPtr::Compacting(ptr) => (ptr.d_block, ptr.d_entry),
+                super::RecordPtr::MemTable(_) => continue,
+            };
+            let found = positions.get(&(block, entry)).is_some_and(|i| {
+                let same_key = std::str::from_utf8(i.key).is_ok_and(|key| Key::new(key.to_string()).hash == meta.hash);
+                same_key && RecordHeader::parse(i.header).is_ok_and(|header| header.timestamp == meta.timestamp)
+            });
+            if !found {
+                problems.push(Problem::Dangling {
+                    hash: meta.hash,
+                    table: table.clone(),
+                    block,
+                    entry,
+                });
+            }
+        }
+        // Evicted entries keep their reference, see `evict`
+        let expected = referenced.len() + self.evicted();
+        if self.status.get() == DisktableStatus::Active && self.references.get() as usize != expected {
+            problems.push(Problem::References {
+                table: table.clone(),
+                expected,
+                found: self.references.get() as usize,
+            });
+        }
+        problems
+    }
+
+    /// Records of a block with their metadata
+    fn decode_block_records(&self, block_number: u32, block: &Block) -> Result<Vec<(Record, RecordMetadata)>, DataStoreError> {
+        block
+            .entries()
+            .into_iter()
+            .enumerate()
+            .map(|(entry_number, entry)| {
+                let record = decode_entry(entry)?;
+                let meta = RecordMetadata {
+                    data_ptr: super::RecordPtr::DiskTable(DiskPointer {
+                        disktable: self.name.clone(),
+                        block: block_number,
+                        entry: entry_number as u16,
+                    }),
+                    key_size: record.key.string.len() as u16,
+                    value_size: record.value.len() as u32,
+                    hash: record.key.hash,
+                    slot: key_slot(record.key.string.as_bytes()),
+                    timestamp: record.timestamp,
+                    value_type: record.value_type,
+                    access: AccessStats::new(),
+                    expire_at: record.expire_at.unwrap_or(NO_EXPIRY),
+                };
+                Ok((record, meta))
+            })
+            .collect()
+    }
+
+    fn incr_reference(&self) {
+        self.references.set(self.references.get() + 1);
     }
 
     fn decr_reference(&self) {
@@ -231,15 +743,23 @@ impl DiskTable {
         self.status.set(DisktableStatus::PendingReclaimFlush)
     }
 
-    async fn get(&self, meta: &RecordMetadata, offset: u32) -> Record {
-        let value_buff = vec![0; meta.size_of()];
-        let (res, value_buff) = self.fd.read_exact_at(value_buff, offset as u64).await;
-        res.unwrap();
-        let timestamp = u64::from_le_bytes(value_buff[6..14].try_into().expect("incorrect length"));
-        let key = std::str::from_utf8(&value_buff[14..14 + meta.key_size as usize]).unwrap();
-        let value = Vec::from(&value_buff[14 + meta.key_size as usize..14 + meta.key_size as usize + meta.value_size as usize]);
+    /// Record at `ptr` in its block
+    fn get(&self, block: &Block, ptr: &DiskPointer) -> Result<Record, DataStoreError> {
+        self.check(decode_entry(block.entry(ptr.entry)).map_err(DataStoreError::from))
+    }
 
-        Record::new_with_timestamp(key.to_string(), value, timestamp)
+    /// Only `range` of the value of the record at `ptr` in its block
+    fn get_value_range(&self, block: &Block, meta: &RecordMetadata, ptr: &DiskPointer, range: Range<usize>) -> Vec<u8> {
+        let value_start = RECORD_HEADER_SIZE + meta.key_size as usize;
+        block.entry(ptr.entry)[value_start + range.start..value_start + range.end].to_vec()
+    }
+
+    pub fn name(&self) -> &Rc<String> {
+        &self.name
+    }
+
+    pub fn keyspace(&self) -> u16 {
+        self.keyspace
     }
 
     pub fn get_stats(&self) -> DiskTableStats {
@@ -248,6 +768,10 @@ impl DiskTable {
             references: self.references.get() as usize,
             count: self.count.get() as usize,
             status: self.status.get(),
+            version: self.version,
+            suspect: self.suspect.get(),
+            keyspace: self.keyspace,
+            stale_key: self.stale_key,
         }
     }
 
@@ -256,21 +780,58 @@ impl DiskTable {
     }
 }
 
+/// File listing the live tables of a directory, one name per line. It is
+/// rewritten atomically when a table is created or deleted: files not listed
+/// (partially written or not deleted after a crash) are never loaded
+pub const MANIFEST: &str = "MANIFEST";
+
+/// Subdirectory where `Manager::init` moves the files that don't belong to
+/// the directory, for inspection
+pub const QUARANTINE_DIRECTORY: &str = "quarantine";
+
+/// Files of a datastore directory besides the tables and the WAL segments
+const KNOWN_FILES: &[&str] = &[MANIFEST, super::upgrade::VERSION_FILE, super::checkpoint::CHECKPOINT_FILE];
+
+/// Write the manifest of `directory` listing the tables `names`
+pub fn write_manifest<'a>(directory: &Path, names: impl Iterator<Item = &'a str>) -> std::io::Result<()> {
+    let mut names: Vec<&str> = names.collect();
+    names.sort();
+    let mut manifest = String::new();
+    for name in names {
+        manifest.push_str(name);
+        manifest.push('\n');
+    }
+    super::upgrade::write_atomically(&directory.join(MANIFEST), manifest.as_bytes())
+}
+
 pub struct Manager {
     directory: PathBuf,
     tables: RefCell<HashMap<Rc<String>, Rc<DiskTable>>>,
     oldest_table: Cell<u64>,
+    /// Timestamps held by `hold_gc_horizon`, by id
+    held_horizons: RefCell<HashMap<u64, u64>>,
+    next_horizon_id: Cell<u64>,
+    options: TableOptions,
+    cache: BlockCache,
 }
 
 #[derive(Debug)]
 pub struct ManagerStats {
     pub table_stats: Vec<(Rc<String>, DiskTableStats)>,
+    /// Bytes of blocks in the cache
+    pub block_cache_bytes: usize,
+    pub block_cache_hits: usize,
+    pub block_cache_misses: usize,
 }
 
 impl Manager {
-    pub fn new(directory: PathBuf) -> Manager {
+    pub fn new(directory: PathBuf, options: TableOptions) -> Manager {
         Manager {
+            cache: BlockCache::new(options.block_cache_bytes),
+            options,
             oldest_table: Cell::from(crate::time::now()),
+            held_horizons: RefCell::new(HashMap::new()),
+            next_horizon_id: Cell::new(0),
             directory,
             tables: RefCell::from(HashMap::new()),
         }
@@ -281,20 +842,112 @@ impl Manager {
             .set(self.tables.borrow().values().map(|t| t.timestamp).min().unwrap_or_else(crate::time::now))
     }
 
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn write_manifest(&self) -> std::io::Result<()> {
+        write_manifest(&self.directory, self.tables.borrow().keys().map(|name| name.as_str()))
+    }
+
+    /// Names of the tables listed in the manifest, None if there is no
+    /// manifest yet (new directory)
+    fn read_manifest(&self) -> Option<Vec<String>> {
+        match std::fs::read_to_string(self.directory.join(MANIFEST)) {
+            Ok(manifest) => Some(manifest.lines().map(String::from).collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => panic!("Can't read the manifest of {:?}: {}", self.directory, e),
+        }
+    }
+
+    /// Move a file to the quarantine subdirectory, it is not loaded anymore
+    fn quarantine(&self, path: &Path, reason: &str) {
+        let quarantine = self.directory.join(QUARANTINE_DIRECTORY);
+        std::fs::create_dir_all(&quarantine).unwrap();
+        println!("Warning: moving {:?} to {:?}: {}", path, quarantine, reason);
+        std::fs::rename(path, quarantine.join(path.file_name().unwrap())).unwrap();
+    }
+
+    /// Quarantine the files that don't belong to the directory: tables not
+    /// listed in the manifest (written or deleted during a crash), empty
+    /// tables, temporary files of interrupted writes and unknown files
+    fn quarantine_unexpected_files(&self) {
+        let listed = self.read_manifest();
+        for entry in std::fs::read_dir(&self.directory).unwrap() {
+            let path = entry.unwrap().path();
+            // Keyspaces and quarantine
+            if path.is_dir() {
+                continue;
+            }
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let reason = match path.extension().and_then(|e| e.to_str()) {
+                _ if KNOWN_FILES.contains(&name.as_str()) => continue,
+                Some(super::wal::SEGMENT_EXTENSION) => continue,
+                Some("data") if std::fs::metadata(&path).unwrap().len() == 0 => "empty disktable",
+                Some("data") => match &listed {
+                    Some(listed) if !listed.contains(&name) => "disktable not listed in the manifest",
+                    _ => continue,
+                },
+                Some("tmp") => "partially written file",
+                _ => "unknown file",
+            };
+            self.quarantine(&path, reason);
+        }
+    }
+
+    /// Quarantine the unexpected files of the directory (see
+    /// `quarantine_unexpected_files`) then load the tables of the manifest
     pub async fn init(&self) {
-        let paths = std::fs::read_dir(&self.directory).unwrap();
-        for result in paths {
-            let file = result.unwrap();
-            let name = Rc::new(file.file_name().into_string().unwrap());
-            let dt = Rc::from(DiskTable::new_from_disk(name.clone(), file.path()).await);
-            self.tables.borrow_mut().insert(name, dt);
+        self.quarantine_unexpected_files();
+        self.load_tables().await;
+    }
+
+    /// Load the tables listed in the manifest not loaded yet, none if there
+    /// is no manifest yet (new directory). Tables that can't be opened are
+    /// quarantined, and the missing ones are unlisted
+    async fn load_tables(&self) {
+        let mut unlisted = false;
+        for name in self.read_manifest().unwrap_or_default() {
+            let name = Rc::new(name);
+            if self.tables.borrow().contains_key(&name) {
+                continue;
+            }
+            let path = self.directory.join(name.as_str());
+            if !path.exists() {
+                println!("Skipping missing disktable {}", name);
+                unlisted = true;
+                continue;
+            }
+            match DiskTable::new_from_disk(name.clone(), path.clone(), &self.options).await {
+                Ok(dt) => {
+                    self.tables.borrow_mut().insert(name, Rc::from(dt));
+                }
+                // Its records are not served
+                Err(e) => {
+                    self.quarantine(&path, &format!("corrupted disktable ({:?})", e));
+                    unlisted = true;
+                }
+            }
+        }
+        if unlisted {
+            self.write_manifest().unwrap();
         }
 
         self.refresh_oldest_table();
     }
 
-    pub async fn truncate(&mut self) {
-        for (_, table) in self.tables.borrow_mut().drain() {
+    /// Forget the tables of `keyspace` and reopen them from disk, their
+    /// references have to be rebuilt from the metadata
+    pub async fn reload(&self, keyspace: u16) {
+        self.take_tables(keyspace);
+        self.load_tables().await;
+    }
+
+    /// Remove the tables of `keyspace`
+    pub async fn truncate(&self, keyspace: u16) {
+        let tables = self.take_tables(keyspace);
+        self.write_manifest().unwrap();
+        for table in tables {
             // write() is used here because the table is going to be destroyed
             // ensure only one ref is in use (ours)
             assert_eq!(Rc::strong_count(&table), 1);
@@ -303,32 +956,154 @@ impl Manager {
         }
     }
 
-    pub async fn get(&self, meta: &RecordMetadata) -> Record {
+    /// Forget the tables of `keyspace` and return the paths of their files,
+    /// for the caller to remove them. Files of tables still being read are
+    /// closed once the reads are done
+    pub fn detach(&self, keyspace: u16) -> Vec<PathBuf> {
+        let paths = self.take_tables(keyspace).iter().map(|table| table.path.clone()).collect();
+        self.write_manifest().unwrap();
+        paths
+    }
+
+    fn take_tables(&self, keyspace: u16) -> Vec<Rc<DiskTable>> {
+        let mut tables = self.tables.borrow_mut();
+        let names: Vec<Rc<String>> = tables.iter().filter(|(_, t)| t.keyspace == keyspace).map(|(n, _)| n.clone()).collect();
+        for name in &names {
+            self.cache.remove_table(name);
+        }
+        names.iter().map(|name| tables.remove(name).unwrap()).collect()
+    }
+
+    /// Block `block` of `disk`, from the cache if it's there
+    async fn read_block(&self, disk: &DiskTable, block: u32) -> Result<Rc<Block>, DataStoreError> {
+        if let Some(cached) = self.cache.get(&disk.name, block) {
+            return Ok(cached);
+        }
+        let res = disk.read_block(block).await;
+        let read = Rc::new(disk.check(res)?);
+        // The table may have been removed while reading
+        if self.tables.borrow().contains_key(&disk.name) {
+            self.cache.insert(disk.name.clone(), block, read.clone());
+        }
+        Ok(read)
+    }
+
+    pub async fn get(&self, meta: &RecordMetadata) -> Result<Record, DataStoreError> {
         match &meta.data_ptr {
             super::RecordPtr::DiskTable(ptr) => {
                 let disk = self.tables.borrow().get(&ptr.disktable).unwrap().clone();
-                disk.get(meta, ptr.offset).await
+                let block = self.read_block(&disk, ptr.block).await?;
+                disk.get(&block, ptr)
             }
             _ => panic!("Trying to query disk with a non disk pointer"),
         }
     }
 
-    pub async fn flush_memtable(&self, memtable: &MemTable) -> Vec<RecordMetadata> {
+    /// Like `get` for several records: each block is read once and the blocks
+    /// are read concurrently. Results are in the order of `metas`
+    pub async fn get_many(&self, metas: &[&RecordMetadata]) -> Vec<Result<Record, DataStoreError>> {
+        let mut blocks: HashMap<(Rc<String>, u32), Vec<usize>> = HashMap::new();
+        for (position, meta) in metas.iter().enumerate() {
+            let super::RecordPtr::DiskTable(ptr) = &meta.data_ptr else {
+                panic!("Trying to query disk with a non disk pointer")
+            };
+            blocks.entry((ptr.disktable.clone(), ptr.block)).or_default().push(position);
+        }
+        let reads = join_all(blocks.into_iter().map(|((table, block), positions)| async move {
+            let disk = self.get_table(&table).unwrap();
+            let read = self.read_block(&disk, block).await;
+            (disk, read, positions)
+        }))
+        .await;
+
+        let mut results: Vec<Option<Result<Record, DataStoreError>>> = metas.iter().map(|_| None).collect();
+        for (disk, read, positions) in reads {
+            for position in positions {
+                let super::RecordPtr::DiskTable(ptr) = &metas[position].data_ptr else {
+                    unreachable!()
+                };
+                results[position] = Some(read.as_ref().map_err(|e| *e).and_then(|block| disk.get(block, ptr)));
+            }
+        }
+        results.into_iter().map(|r| r.unwrap()).collect()
+    }
+
+    pub async fn get_value_range(&self, meta: &RecordMetadata, range: Range<usize>) -> Result<Vec<u8>, DataStoreError> {
+        match &meta.data_ptr {
+            super::RecordPtr::DiskTable(ptr) => {
+                let disk = self.tables.borrow().get(&ptr.disktable).unwrap().clone();
+                let block = self.read_block(&disk, ptr.block).await?;
+                Ok(disk.get_value_range(&block, meta, ptr, range))
+            }
+            _ => panic!("Trying to query disk with a non disk pointer"),
+        }
+    }
+
+    /// Write the records of `memtable` accepted by `keep` (given their offset)
+    /// to a new table, none if there are none. Return their metadata, on error
+    /// nothing is left on disk
+    pub async fn flush_memtable<F: Fn(u32, &Record) -> bool>(
+        &self,
+        memtable: &MemTable,
+        keyspace: u16,
+        keep: F,
+    ) -> Result<Vec<RecordMetadata>, DataStoreError> {
+        let records: Vec<Record> = memtable
+            .values()
+            .into_iter()
+            .enumerate()
+            .filter(|(offset, record)| keep(*offset as u32, record))
+            .map(|(_, record)| record)
+            .collect();
+        if records.is_empty() {
+            return Ok(vec![]);
+        }
+        println!("Flushing {}/{} records of memtable {}", records.len(), memtable.len(), memtable.id);
+        self.write_records(&records, keyspace).await
+    }
+
+    /// Write `records` to a new table, none if there are none. Return their
+    /// metadata, on error nothing is left on disk
+    pub async fn write_records(&self, records: &[Record], keyspace: u16) -> Result<Vec<RecordMetadata>, DataStoreError> {
+        if records.is_empty() {
+            return Ok(vec![]);
+        }
         let now = crate::time::now();
-        let name = format!("{}-v1.data", now);
-        println!("Flushing to: {}, {}, {}", name, memtable.len(), memtable.id);
+        let name = format!("{}-v{}.data", now, FORMAT_VERSION);
+        println!("Writing to: {}, {} records", name, records.len());
         let mut file_path = self.directory.clone();
         file_path.push(&name);
-        let (dt, offsets) = DiskTable::new_from_memtable(Rc::from(name), file_path, now, memtable).await;
-        self.tables.borrow_mut().insert(dt.name.clone(), Rc::from(dt));
+        let (dt, offsets) = DiskTable::new_from_memtable(Rc::from(name), file_path, now, records, keyspace, &self.options).await?;
+        let dt = Rc::from(dt);
+        self.tables.borrow_mut().insert(dt.name.clone(), dt.clone());
+        // Only listed once fully written
+        if let Err(e) = self.write_manifest() {
+            self.tables.borrow_mut().remove(&dt.name);
+            let _ = std::fs::remove_file(&dt.path);
+            return Err(e.into());
+        }
         self.refresh_oldest_table();
-        offsets
+        Ok(offsets)
+    }
+
+    pub fn add_reference_to_storage(&self, table: &Rc<String>) {
+        self.tables.borrow().get(table).unwrap().incr_reference()
     }
 
     pub fn remove_reference_from_storage(&self, table: &Rc<String>) {
         self.tables.borrow_mut().get_mut(table).unwrap().decr_reference()
     }
 
+    /// Files of the tables still referenced by the index
+    pub fn live_table_paths(&self) -> Vec<PathBuf> {
+        self.tables
+            .borrow()
+            .values()
+            .filter(|t| !t.is_marked_for_deletion())
+            .map(|t| t.path.clone())
+            .collect()
+    }
+
     pub fn get_disktables_marked_for_deletion(&self) -> Vec<Rc<String>> {
         self.tables
             .borrow()
@@ -341,56 +1116,107 @@ impl Manager {
 
     pub fn delete_disktables_marked_for_deletion(&self) {
         let table_marked_deletion = self.get_disktables_marked_for_deletion();
-        let mut tables_mut = self.tables.borrow_mut();
-        table_marked_deletion.iter().for_each(|t| {
-            let table = tables_mut.get(t).unwrap();
+        if table_marked_deletion.is_empty() {
+            return;
+        }
+        let tables: Vec<Rc<DiskTable>> = table_marked_deletion
+            .iter()
+            .map(|t| {
+                self.cache.remove_table(t);
+                self.tables.borrow_mut().remove(t).unwrap()
+            })
+            .collect();
+        // Unlisted before being removed
+        self.write_manifest().unwrap();
+        self.refresh_oldest_table();
+        for table in tables {
             std::fs::remove_file(&table.path).unwrap();
-            tables_mut.remove(t);
-        });
+        }
     }
 
-    pub fn references(&self) -> usize {
+    pub fn references(&self, keyspace: u16) -> usize {
         self.tables
             .borrow()
             .values()
-            .filter(|d| d.status.get() == DisktableStatus::Active)
+            .filter(|d| d.status.get() == DisktableStatus::Active && d.keyspace == keyspace)
             .fold(0, |size, t| size + t.get_stats().references)
     }
 
-    pub fn len(&self) -> usize {
-        self.tables.borrow().values().fold(0, |size, t| size + t.get_stats().count)
+    /// Number of records of `keyspace` whose index entry is evicted
+    pub fn evicted(&self, keyspace: u16) -> usize {
+        self.tables
+            .borrow()
+            .values()
+            .filter(|t| t.keyspace == keyspace)
+            .fold(0, |size, t| size + t.evicted())
+    }
+
+    /// Bytes of the data blocks of the tables of `keyspace` and the share of
+    /// them still referenced, estimated from the usage ratio of each table
+    pub fn data_size(&self, keyspace: u16) -> (usize, usize) {
+        self.tables
+            .borrow()
+            .values()
+            .filter(|t| t.keyspace == keyspace && !t.is_marked_for_deletion())
+            .fold((0, 0), |(total, live), t| {
+                let size = t.data_size();
+                (total + size, live + (size as f64 * t.get_stats().usage_ratio as f64) as usize)
+            })
+    }
+
+    pub fn len(&self, keyspace: u16) -> usize {
+        self.tables
+            .borrow()
+            .values()
+            .filter(|t| t.keyspace == keyspace)
+            .fold(0, |size, t| size + t.get_stats().count)
     }
 
     pub fn get_stats(&self) -> ManagerStats {
         ManagerStats {
             table_stats: self.tables.borrow().iter().map(|(n, t)| (n.clone(), t.get_stats())).collect(),
+            block_cache_bytes: self.cache.size(),
+            block_cache_hits: self.cache.hits(),
+            block_cache_misses: self.cache.misses(),
         }
     }
 
-    pub fn list_tables(&self) -> Vec<Rc<String>> {
-        self.tables.borrow().keys().cloned().collect()
+    pub fn list_tables(&self, keyspace: u16) -> Vec<Rc<String>> {
+        self.tables
+            .borrow()
+            .iter()
+            .filter(|(_, t)| t.keyspace == keyspace)
+            .map(|(n, _)| n.clone())
+            .collect()
     }
 
     pub fn get_table(&self, name: &Rc<String>) -> Option<Rc<DiskTable>> {
         self.tables.borrow().get(name).cloned()
     }
 
-    pub fn get_tables(&self) -> Vec<Rc<DiskTable>> {
-        self.tables.borrow().values().cloned().collect()
+    pub fn get_tables(&self, keyspace: u16) -> Vec<Rc<DiskTable>> {
+        self.tables.borrow().values().filter(|t| t.keyspace == keyspace).cloned().collect()
     }
 
-    pub fn get_oldest_table(&self) -> u64 {
-        self.oldest_table.get()
+    /// Tombstones older than this can be purged: no table holds a version
+    /// they delete (tables only hold versions that were live when flushed)
+    /// and no reader holding an older horizon needs them
+    pub fn gc_horizon(&self) -> u64 {
+        let held = self.held_horizons.borrow().values().min().copied();
+        held.map_or(self.oldest_table.get(), |held| held.min(self.oldest_table.get()))
     }
 
-    pub fn get_best_table_to_reclaim(&self) -> Option<Rc<String>> {
-        // TODO: Make ratio configurable
-        let target_ratio = 0.7;
-        self.tables
-            .borrow()
-            .iter()
-            .filter(|(_n, t)| t.status.get() == DisktableStatus::Active)
-            .find(|(_n, t)| t.get_stats().usage_ratio < target_ratio)
-            .map(|(n, _)| n.clone())
+    /// Keep the tombstones written after `timestamp` until the returned id is
+    /// released, for a reader that must see the deletions since then (e.g. a
+    /// snapshot or a replica catching up)
+    pub fn hold_gc_horizon(&self, timestamp: u64) -> u64 {
+        let id = self.next_horizon_id.get();
+        self.next_horizon_id.set(id + 1);
+        self.held_horizons.borrow_mut().insert(id, timestamp);
+        id
+    }
+
+    pub fn release_gc_horizon(&self, id: u64) {
+        self.held_horizons.borrow_mut().remove(&id);
     }
 }
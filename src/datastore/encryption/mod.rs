@@ -0,0 +1,270 @@
+//! Encryption at rest of the disktable blocks and of the WAL with AES-GCM
+//!
+//! |            sealed buffer             |
+//! |nonce(12 bytes)|ciphertext|tag(16 bytes)|
+//!
+//! Every buffer is sealed with a random nonce. Files hold the id of their key
+//! (footer of the disktables, name of the WAL segments) so the key can be
+//! rotated: new files are written with the current key and the previous keys
+//! are only needed to read the files written before, until compaction
+//! rewrites them.
+//!
+//! Buffers are sealed with associated data naming their place (table and
+//! offset of a block, segment and number of a WAL frame), a sealed buffer
+//! moved elsewhere doesn't authenticate
+
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+
+use crypto::{
+    aead::{AeadDecryptor, AeadEncryptor},
+    aes::KeySize,
+    aes_gcm::AesGcm,
+};
+
+use super::disktable::block::Corruption;
+
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+/// Bytes added to a buffer by `Cipher::seal`
+pub const SEAL_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+/// Key id of the files that are not encrypted
+pub const NO_KEY: u32 = 0;
+/// Variable read by `Encryption::from_env`: `<id>:<hex key>[,<id>:<hex key>...]`,
+/// the first key encrypts the new files
+pub const KEYS_VARIABLE: &str = "LSM_ENCRYPTION_KEYS";
+
+/// Error returned when a key can't be used
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyError {
+    /// The provider doesn't know the key
+    UnknownKey(u32),
+    /// The key is not 16, 24 or 32 bytes
+    InvalidKey(u32),
+    /// A file is encrypted with the key but no encryption is configured
+    NotConfigured(u32),
+    /// `KEYS_VARIABLE` is not `<id>:<hex key>[,<id>:<hex key>...]`
+    InvalidVariable,
+    /// `NO_KEY` can't encrypt the new files
+    ReservedId,
+}
+
+/// Source of the keys by id, e.g. a client of a KMS. Keys are 16, 24 or 32
+/// bytes (AES-128, AES-192 or AES-256)
+pub trait KeyProvider {
+    /// Key `id`, None if it is unknown
+    fn key(&self, id: u32) -> Option<Vec<u8>>;
+}
+
+/// Keys held in memory, from the config or the environment
+pub struct StaticKeys {
+    keys: HashMap<u32, Vec<u8>>,
+}
+
+impl StaticKeys {
+    pub fn new(keys: Vec<(u32, Vec<u8>)>) -> StaticKeys {
+        StaticKeys {
+            keys: keys.into_iter().collect(),
+        }
+    }
+}
+
+impl KeyProvider for StaticKeys {
+    fn key(&self, id: u32) -> Option<Vec<u8>> {
+        self.keys.get(&id).cloned()
+    }
+}
+
+/// AES-GCM with one key, seals and opens the buffers of the files written
+/// with it
+#[derive(Clone)]
+pub struct Cipher {
+    key_id: u32,
+    key: Rc<[u8]>,
+}
+
+impl Cipher {
+    fn new(key_id: u32, key: Vec<u8>) -> Result<Cipher, KeyError> {
+        match key.len() {
+            16 | 24 | 32 => Ok(Cipher { key_id, key: key.into() }),
+            _ => Err(KeyError::InvalidKey(key_id)),
+        }
+    }
+
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    fn aes_gcm(&self, nonce: &[u8], aad: &[u8]) -> AesGcm<'static> {
+        let key_size = match self.key.len() {
+            16 => KeySize::KeySize128,
+            24 => KeySize::KeySize192,
+            _ => KeySize::KeySize256,
+        };
+        AesGcm::new(key_size, &self.key, nonce, aad)
+    }
+
+    /// Encrypt `buf` with a new nonce, bound to `aad`
+    pub fn seal(&self, buf: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut sealed = vec![0u8; buf.len() + SEAL_OVERHEAD];
+        random_bytes(&mut sealed[..NONCE_SIZE]);
+        let (nonce, rest) = sealed.split_at_mut(NONCE_SIZE);
+        let (ciphertext, tag) = rest.split_at_mut(buf.len());
+        self.aes_gcm(nonce, aad).encrypt(buf, ciphertext, tag);
+        sealed
+    }
+
+    /// Decrypt a buffer written by `seal`, an error if it was written with
+    /// another key or `aad` or modified since
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, Corruption> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err(Corruption::ShortRead);
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_SIZE);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+        let mut buf = vec![0u8; ciphertext.len()];
+        match self.aes_gcm(nonce, aad).decrypt(ciphertext, &mut buf, tag) {
+            true => Ok(buf),
+            false => Err(Corruption::AuthenticationFailed),
+        }
+    }
+}
+
+/// Fill `buf` from the kernel random source
+pub fn random_bytes(buf: &mut [u8]) {
+    let read = unsafe { libc::getrandom(buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+    assert_eq!(read, buf.len() as isize, "getrandom failed: {}", std::io::Error::last_os_error());
+}
+
+/// Keys of a datastore: the current one encrypts the new files, the files
+/// are read with the key of their id
+#[derive(Clone)]
+pub struct Encryption {
+    key_id: u32,
+    provider: Rc<dyn KeyProvider>,
+    /// Keys already fetched from the provider
+    ciphers: Rc<RefCell<HashMap<u32, Cipher>>>,
+}
+
+impl fmt::Debug for Encryption {
+    // The keys are not printed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption").field("key_id", &self.key_id).finish()
+    }
+}
+
+impl Encryption {
+    /// Encrypt the new files with the key `key_id` of `provider`, an error if
+    /// it doesn't know it
+    pub fn new(key_id: u32, provider: Rc<dyn KeyProvider>) -> Result<Encryption, KeyError> {
+        if key_id == NO_KEY {
+            return Err(KeyError::ReservedId);
+        }
+        let encryption = Encryption {
+            key_id,
+            provider,
+            ciphers: Rc::new(RefCell::new(HashMap::new())),
+        };
+        encryption.cipher(key_id)?;
+        Ok(encryption)
+    }
+
+    /// Encryption with the keys of `KEYS_VARIABLE`, None if it is not set
+    pub fn from_env() -> Result<Option<Encryption>, KeyError> {
+        let Ok(keys) = std::env::var(KEYS_VARIABLE) else {
+            return Ok(None);
+        };
+        let keys = parse_keys(&keys).ok_or(KeyError::InvalidVariable)?;
+        let current = keys[0].0;
+        Encryption::new(current, Rc::new(StaticKeys::new(keys))).map(Some)
+    }
+
+    /// Id of the key of the new files
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// Cipher of the new files, fetched by `new`
+    pub fn current(&self) -> Cipher {
+        self.ciphers.borrow()[&self.key_id].clone()
+    }
+
+    /// Cipher of the files written with the key `key_id`, a file can't be
+    /// read without its key
+    pub fn cipher(&self, key_id: u32) -> Result<Cipher, KeyError> {
+        if let Some(cipher) = self.ciphers.borrow().get(&key_id) {
+            return Ok(cipher.clone());
+        }
+        let key = self.provider.key(key_id).ok_or(KeyError::UnknownKey(key_id))?;
+        let cipher = Cipher::new(key_id, key)?;
+        self.ciphers.borrow_mut().insert(key_id, cipher.clone());
+        Ok(cipher)
+    }
+}
+
+/// Cipher of the files written with `key_id` (`NO_KEY` if not encrypted)
+pub fn cipher_of(encryption: Option<&Encryption>, key_id: u32) -> Result<Option<Cipher>, KeyError> {
+    match (encryption, key_id) {
+        (_, NO_KEY) => Ok(None),
+        (Some(encryption), key_id) => encryption.cipher(key_id).map(Some),
+        (None, key_id) => Err(KeyError::NotConfigured(key_id)),
+    }
+}
+
+/// Parse the keys of `KEYS_VARIABLE`, None if one is malformed
+fn parse_keys(keys: &str) -> Option<Vec<(u32, Vec<u8>)>> {
+    keys.split(',')
+        .map(|key| key.trim().split_once(':').and_then(|(id, hex)| Some((id.parse().ok()?, parse_hex(hex)?))))
+        .collect()
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let keys = Rc::new(StaticKeys::new(vec![(1, vec![1; 16]), (2, vec![2; 32]), (3, vec![3; 10])]));
+        let encryption = Encryption::new(2, keys.clone()).unwrap();
+        let cipher = encryption.current();
+        assert_eq!(cipher.key_id(), 2);
+        let sealed = cipher.seal(b"value", b"block 1");
+        assert_eq!(sealed.len(), 5 + SEAL_OVERHEAD);
+        // A new nonce every time
+        assert_ne!(cipher.seal(b"value", b"block 1"), sealed);
+        assert_eq!(cipher.open(&sealed, b"block 1"), Ok(b"value".to_vec()));
+
+        // Another key, another place or a modified buffer
+        assert_eq!(
+            encryption.cipher(1).unwrap().open(&sealed, b"block 1"),
+            Err(Corruption::AuthenticationFailed)
+        );
+        assert_eq!(cipher.open(&sealed, b"block 2"), Err(Corruption::AuthenticationFailed));
+        let mut modified = sealed.clone();
+        modified[NONCE_SIZE] ^= 1;
+        assert_eq!(cipher.open(&modified, b"block 1"), Err(Corruption::AuthenticationFailed));
+        assert_eq!(cipher.open(&sealed[..10], b"block 1"), Err(Corruption::ShortRead));
+
+        // Keys that can't be used
+        assert_eq!(encryption.cipher(4).err(), Some(KeyError::UnknownKey(4)));
+        assert_eq!(encryption.cipher(3).err(), Some(KeyError::InvalidKey(3)));
+        assert_eq!(Encryption::new(4, keys.clone()).err(), Some(KeyError::UnknownKey(4)));
+        assert_eq!(Encryption::new(NO_KEY, keys).err(), Some(KeyError::ReservedId));
+        assert_eq!(cipher_of(None, 2).err(), Some(KeyError::NotConfigured(2)));
+
+        assert!(cipher_of(Some(&encryption), NO_KEY).unwrap().is_none());
+        assert_eq!(parse_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert_eq!(parse_hex("0g"), None);
+        assert_eq!(parse_keys("1:00ff, 2:10"), Some(vec![(1, vec![0, 255]), (2, vec![16])]));
+        assert_eq!(parse_keys("1:00ff,2"), None);
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::ErrorKind;
+
+use super::disktable::block::Corruption;
+use super::encryption::KeyError;
+
+/// Error of a datastore operation, returned instead of panicking so the
+/// callers can reply with an error and keep serving the other keys
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataStoreError {
+    /// A read or a write failed (other than a file too short)
+    Io(ErrorKind),
+    /// A table doesn't hold what its index says
+    Corruption(Corruption),
+    /// A file is encrypted with a key that can't be used
+    Key(KeyError),
+    /// No space left, on disk or for a new memtable
+    Full,
+    /// A file of the datastore is missing
+    NotFound,
+    /// The memtables are over their memory budget and the flushes didn't
+    /// catch up in time, the write can be retried later
+    Busy,
+}
+
+impl From<Corruption> for DataStoreError {
+    fn from(e: Corruption) -> DataStoreError {
+        DataStoreError::Corruption(e)
+    }
+}
+
+impl From<KeyError> for DataStoreError {
+    fn from(e: KeyError) -> DataStoreError {
+        DataStoreError::Key(e)
+    }
+}
+
+impl From<std::io::Error> for DataStoreError {
+    fn from(e: std::io::Error) -> DataStoreError {
+        match e.kind() {
+            ErrorKind::NotFound => DataStoreError::NotFound,
+            // ENOSPC and EDQUOT, `ErrorKind::StorageFull` is not stable yet
+            _ if matches!(e.raw_os_error(), Some(28) | Some(122)) => DataStoreError::Full,
+            kind => DataStoreError::Io(kind),
+        }
+    }
+}
+
+impl DataStoreError {
+    /// Error code of the redis replies
+    pub fn code(&self) -> &'static str {
+        match self {
+            DataStoreError::Busy => "BUSY",
+            _ => "ERR",
+        }
+    }
+
+    /// Message returned to the clients
+    pub fn message(&self) -> &'static str {
+        match self {
+            DataStoreError::Io(_) => "the data could not be read from or written to disk",
+            DataStoreError::Corruption(_) => "the value is stored in a corrupted disktable",
+            DataStoreError::Key(_) => "the encryption key of a file can't be used",
+            DataStoreError::Full => "no space left to store the data",
+            DataStoreError::NotFound => "a file of the datastore is missing",
+            DataStoreError::Busy => "too many writes waiting to be flushed, retry later",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_error_conversion() {
+        let full = std::io::Error::from_raw_os_error(28);
+        assert_eq!(DataStoreError::from(full), DataStoreError::Full);
+        let missing = std::io::Error::from(ErrorKind::NotFound);
+        assert_eq!(DataStoreError::from(missing), DataStoreError::NotFound);
+        let denied = std::io::Error::from(ErrorKind::PermissionDenied);
+        assert_eq!(DataStoreError::from(denied), DataStoreError::Io(ErrorKind::PermissionDenied));
+    }
+}
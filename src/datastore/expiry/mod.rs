@@ -0,0 +1,72 @@
+use std::cell::Cell;
+
+use super::access::next_random;
+
+/// Value of `RecordMetadata::expire_at` for records without expiration
+pub const NO_EXPIRY: u64 = 0;
+
+/// Remaining time to live of a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ttl {
+    Missing,
+    Persistent,
+    /// Nanoseconds before the key expires
+    Expiring(u64),
+}
+
+/// Push back an expiration date by a random amount, up to `ratio` of the ttl,
+/// so keys written together (e.g. when warming a cache) don't all expire in
+/// the same instant. Timestamps are the ones given by `crate::time::now`.
+pub fn apply_jitter(now: u64, expire_at: u64, ratio: f32) -> u64 {
+    if ratio <= 0.0 || expire_at <= now {
+        return expire_at;
+    }
+    let max_jitter = ((expire_at - now) as f64 * ratio as f64) as u64;
+    expire_at + (next_random() * max_jitter as f64) as u64
+}
+
+/// Bound the number of expired records removed per sweep so a mass expiration
+/// is spread over several ticks instead of causing a latency spike.
+/// Records over the budget stay until a later sweep: they are already hidden
+/// from reads, so delaying their removal only costs a bit of space.
+pub struct ExpiryBudget {
+    per_tick: usize,
+    remaining: Cell<usize>,
+}
+
+impl ExpiryBudget {
+    /// `per_tick` of 0 disables the limit
+    pub fn new(per_tick: usize) -> ExpiryBudget {
+        ExpiryBudget {
+            per_tick,
+            remaining: Cell::new(per_tick),
+        }
+    }
+
+    /// Called at the start of every sweep
+    pub fn refill(&self) {
+        self.remaining.set(self.per_tick);
+    }
+
+    /// Number of records that can still be removed during this sweep
+    pub fn remaining(&self) -> usize {
+        match self.per_tick {
+            0 => usize::MAX,
+            _ => self.remaining.get(),
+        }
+    }
+
+    /// Return true if one more record can be removed during this sweep
+    pub fn try_take(&self) -> bool {
+        if self.per_tick == 0 {
+            return true;
+        }
+        match self.remaining.get() {
+            0 => false,
+            n => {
+                self.remaining.set(n - 1);
+                true
+            }
+        }
+    }
+}
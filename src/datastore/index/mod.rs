@@ -1,16 +1,53 @@
 use std::{
     cell::RefCell,
+    cmp::Reverse,
     collections::{
         hash_map::Entry::{Occupied, Vacant},
-        HashMap,
+        BTreeMap, HashMap, HashSet,
+    },
+    ops::{
+        Bound::{Excluded, Included, Unbounded},
+        RangeBounds,
     },
 };
 
-use super::{HashedKey, RecordMetadata};
+use super::{HashedKey, Key, RecordMetadata};
+
+/// Number of bits of the key hash used as scan position
+pub const SCAN_POSITION_BITS: u32 = 48;
+
+fn scan_position(hash: &HashedKey) -> u64 {
+    hash[..(SCAN_POSITION_BITS / 8) as usize].iter().fold(0, |acc, b| (acc << 8) | *b as u64)
+}
+
+/// Number of bits of the scan position selecting the bucket of an entry
+const BUCKET_BITS: u32 = 4;
+
+/// Number of buckets of the entries
+const BUCKETS: usize = 1 << BUCKET_BITS;
+
+fn bucket_index(position: u64) -> usize {
+    (position >> (SCAN_POSITION_BITS - BUCKET_BITS)) as usize
+}
+
+type Bucket = RefCell<HashMap<HashedKey, RecordMetadata>>;
 
 #[derive(Debug)]
 pub struct Index {
-    kvs: RefCell<HashMap<HashedKey, RecordMetadata>>,
+    /// Entries split by scan position, each bucket borrowed on its own so a
+    /// long borrow (e.g. listing the coldest entries) only holds part of the
+    /// index and a bucket resizes without moving the others. Each bucket holds
+    /// a contiguous range of positions so scans only sort the buckets they visit
+    kvs: Vec<Bucket>,
+    /// Number of entries that are not tombstones, per slot
+    slot_counts: RefCell<HashMap<u16, usize>>,
+    /// Keys of the entries in order, for range reads. A key is removed with
+    /// its entry or when the entry becomes a tombstone
+    keys: RefCell<BTreeMap<String, HashedKey>>,
+    /// Hashes of the entries removed by `evict`, split like `kvs`. Their keys
+    /// are still listed by `range`, `prefix` and `scan` until they are loaded
+    /// back from the disktables
+    evicted: Vec<RefCell<HashSet<HashedKey>>>,
 }
 
 impl Default for Index {
@@ -22,43 +59,479 @@ impl Default for Index {
 impl Index {
     pub fn new() -> Index {
         Index {
-            kvs: RefCell::from(HashMap::new()),
+            kvs: (0..BUCKETS).map(|_| RefCell::from(HashMap::new())).collect(),
+            slot_counts: RefCell::from(HashMap::new()),
+            keys: RefCell::from(BTreeMap::new()),
+            evicted: (0..BUCKETS).map(|_| RefCell::from(HashSet::new())).collect(),
+        }
+    }
+
+    fn bucket(&self, hash: &HashedKey) -> &Bucket {
+        &self.kvs[bucket_index(scan_position(hash))]
+    }
+
+    fn evicted_bucket(&self, hash: &HashedKey) -> &RefCell<HashSet<HashedKey>> {
+        &self.evicted[bucket_index(scan_position(hash))]
+    }
+
+    /// Keep the slot counts in sync when `old` is replaced by `new`
+    fn count(&self, old: Option<&RecordMetadata>, new: Option<&RecordMetadata>) {
+        let mut slot_counts = self.slot_counts.borrow_mut();
+        if let Some(old) = old.filter(|meta| !meta.is_tombstone()) {
+            let count = slot_counts.get_mut(&old.slot).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                slot_counts.remove(&old.slot);
+            }
+        }
+        if let Some(new) = new.filter(|meta| !meta.is_tombstone()) {
+            *slot_counts.entry(new.slot).or_default() += 1;
         }
     }
 
     /// Update the index with new metadata
     /// If there was already a record in the index with older metadata (timestamp)
     /// return it and apply the new one.
-    pub fn update(&self, meta: RecordMetadata) -> Option<RecordMetadata> {
-        match self.kvs.borrow_mut().entry(meta.hash) {
+    /// Access stats are kept from the previous entry as they belong to the key
+    pub fn update(&self, mut meta: RecordMetadata) -> Option<RecordMetadata> {
+        match self.bucket(&meta.hash).borrow_mut().entry(meta.hash) {
             Occupied(mut entry) => {
                 let old = entry.get();
                 match meta.timestamp.cmp(&old.timestamp) {
                     // If the new record is older, return it as older
                     std::cmp::Ordering::Less => Some(meta),
-                    _ => Some(entry.insert(meta)),
+                    // Same version moved to another table, the index may have
+                    // expired it meanwhile (e.g. UNLINK)
+                    std::cmp::Ordering::Equal => {
+                        meta.access = old.access;
+                        if let Some(expire_at) = old.expire_at() {
+                            meta.expire_at = meta.expire_at().map_or(expire_at, |e| e.min(expire_at));
+                        }
+                        self.count(Some(old), Some(&meta));
+                        Some(entry.insert(meta))
+                    }
+                    _ => {
+                        meta.access = old.access;
+                        self.count(Some(old), Some(&meta));
+                        Some(entry.insert(meta))
+                    }
                 }
             }
             Vacant(vacant) => {
+                self.evicted_bucket(&meta.hash).borrow_mut().remove(&meta.hash);
+                self.count(None, Some(&meta));
                 vacant.insert(meta);
                 None
             }
         }
     }
 
+    /// Add a key to the ordered keys, its entry is set by `update`
+    pub fn add_key(&self, key: &Key) {
+        let mut keys = self.keys.borrow_mut();
+        if !keys.contains_key(&key.string) {
+            keys.insert(key.string.clone(), key.hash);
+        }
+    }
+
+    /// Remove a key from the ordered keys, e.g. when it is deleted
+    pub fn remove_key(&self, key: &Key) {
+        let mut keys = self.keys.borrow_mut();
+        if keys.get(&key.string) == Some(&key.hash) {
+            keys.remove(&key.string);
+        }
+    }
+
+    /// `update` the entry of `key`, which is listed unless the resulting
+    /// entry is a tombstone
+    pub fn update_key(&self, key: &Key, meta: RecordMetadata) -> Option<RecordMetadata> {
+        let replaced = self.update(meta);
+        match self.get(key.hash) {
+            Some(meta) if !meta.is_tombstone() => self.add_key(key),
+            _ => self.remove_key(key),
+        }
+        replaced
+    }
+
+    /// Keep the keys that are neither deleted nor expired at `now`, forget
+    /// the ones without an entry anymore. Evicted keys are kept, they can
+    /// only be evicted while live and are loaded back before being rewritten
+    fn live_keys<'a>(&self, keys: impl Iterator<Item = (&'a String, &'a HashedKey)>, now: u64) -> (Vec<Key>, Vec<String>) {
+        let mut live = vec![];
+        let mut removed = vec![];
+        for (key, hash) in keys {
+            match self.bucket(hash).borrow().get(hash) {
+                Some(meta) if !meta.is_tombstone() && !meta.is_expired(now) => live.push(Key {
+                    string: key.clone(),
+                    hash: *hash,
+                }),
+                Some(_) => (),
+                None if self.is_evicted(hash) => live.push(Key {
+                    string: key.clone(),
+                    hash: *hash,
+                }),
+                None => removed.push(key.clone()),
+            }
+        }
+        (live, removed)
+    }
+
+    fn forget_keys(&self, removed: Vec<String>) {
+        let mut keys = self.keys.borrow_mut();
+        for key in removed {
+            keys.remove(&key);
+        }
+    }
+
+    /// Return the keys in `range`, in order, that are neither deleted nor
+    /// expired at `now`
+    pub fn range<R: RangeBounds<String>>(&self, range: R, now: u64) -> Vec<Key> {
+        let (live, removed) = self.live_keys(self.keys.borrow().range(range), now);
+        self.forget_keys(removed);
+        live
+    }
+
+    /// Return the keys starting with `prefix`, in order, that are neither
+    /// deleted nor expired at `now`
+    pub fn prefix(&self, prefix: &str, now: u64) -> Vec<Key> {
+        let keys = self.keys.borrow();
+        let matching = keys.range(prefix.to_string()..).take_while(|(key, _)| key.starts_with(prefix));
+        let (live, removed) = self.live_keys(matching, now);
+        drop(keys);
+        self.forget_keys(removed);
+        live
+    }
+
+    /// Remove the entries of the keys in `start..end` (`start..` without end)
+    /// written before `timestamp`, return them
+    pub fn remove_range(&self, start: &str, end: Option<&str>, timestamp: u64) -> Vec<RecordMetadata> {
+        let end = end.map_or(Unbounded, Excluded);
+        let mut keys = self.keys.borrow_mut();
+        let mut forgotten = vec![];
+        let mut removed = vec![];
+        for (key, hash) in keys.range::<str, _>((Included(start), end)) {
+            let mut bucket = self.bucket(hash).borrow_mut();
+            match bucket.get(hash) {
+                Some(meta) if meta.timestamp >= timestamp => continue,
+                Some(_) => removed.extend(bucket.remove(hash)),
+                None => (),
+            }
+            self.evicted_bucket(hash).borrow_mut().remove(hash);
+            forgotten.push(key.clone());
+        }
+        for key in forgotten {
+            keys.remove(&key);
+        }
+        for meta in &removed {
+            self.count(Some(meta), None);
+        }
+        removed
+    }
+
     pub fn delete(&self, meta: &RecordMetadata) {
-        self.kvs.borrow_mut().remove(&meta.hash);
+        let removed = self.bucket(&meta.hash).borrow_mut().remove(&meta.hash);
+        self.count(removed.as_ref(), None);
+    }
+
+    /// Remove the entry to save memory, its key is still listed until it is
+    /// loaded back or `forget_evicted` is called
+    pub fn evict(&self, meta: &RecordMetadata) {
+        self.delete(meta);
+        self.evicted_bucket(&meta.hash).borrow_mut().insert(meta.hash);
+    }
+
+    /// True if the entry of the key was evicted and not loaded back since
+    pub fn is_evicted(&self, hash: &HashedKey) -> bool {
+        self.evicted_bucket(hash).borrow().contains(hash)
+    }
+
+    /// Stop listing an evicted key, e.g. when its version on disk was deleted
+    pub fn forget_evicted(&self, hash: &HashedKey) {
+        self.evicted_bucket(hash).borrow_mut().remove(hash);
+    }
+
+    /// Number of keys of `slot`, expired keys included until they are removed
+    pub fn count_in_slot(&self, slot: u16) -> usize {
+        self.slot_counts.borrow().get(&slot).cloned().unwrap_or(0)
     }
 
     pub fn get(&self, hash: HashedKey) -> Option<RecordMetadata> {
-        self.kvs.borrow().get(&hash).cloned()
+        self.bucket(&hash).borrow().get(&hash).cloned()
+    }
+
+    /// Get the metadata and record an access on it
+    pub fn get_and_touch(&self, hash: HashedKey) -> Option<RecordMetadata> {
+        self.bucket(&hash).borrow_mut().get_mut(&hash).map(|meta| {
+            meta.access.touch();
+            meta.clone()
+        })
+    }
+
+    /// Change the expiration of the current version of a key
+    pub fn set_expire_at(&self, hash: HashedKey, expire_at: u64) {
+        if let Some(meta) = self.bucket(&hash).borrow_mut().get_mut(&hash) {
+            meta.expire_at = expire_at;
+        }
+    }
+
+    pub fn touch(&self, hash: HashedKey) {
+        if let Some(meta) = self.bucket(&hash).borrow_mut().get_mut(&hash) {
+            meta.access.touch();
+        }
+    }
+
+    /// Return the hashes of up to `count` entries accepted by `filter`, the
+    /// least frequently accessed ones (then the least recently accessed)
+    pub fn coldest<F: Fn(&RecordMetadata) -> bool>(&self, count: usize, filter: F) -> Vec<HashedKey> {
+        let mut entries: Vec<(u8, Reverse<u32>, HashedKey)> = vec![];
+        for bucket in &self.kvs {
+            entries.extend(
+                bucket
+                    .borrow()
+                    .values()
+                    .filter(|meta| filter(meta))
+                    .map(|meta| (meta.access.frequency(), Reverse(meta.access.idle_time()), meta.hash)),
+            );
+        }
+        if entries.len() > count {
+            entries.select_nth_unstable(count);
+            entries.truncate(count);
+        }
+        entries.into_iter().map(|(_, _, hash)| hash).collect()
+    }
+
+    /// Return up to `limit` records expired at `now`
+    pub fn expired(&self, now: u64, limit: usize) -> Vec<RecordMetadata> {
+        let mut expired = vec![];
+        for bucket in &self.kvs {
+            let remaining = limit - expired.len();
+            expired.extend(bucket.borrow().values().filter(|meta| meta.is_expired(now)).take(remaining).cloned());
+        }
+        expired
+    }
+
+    /// Return the hashes of up to `limit` keys of `slot` that are neither
+    /// deleted nor expired at `now`
+    pub fn live_hashes_in_slot(&self, slot: u16, now: u64, limit: usize) -> Vec<HashedKey> {
+        let mut hashes = vec![];
+        for bucket in &self.kvs {
+            let remaining = limit - hashes.len();
+            hashes.extend(
+                bucket
+                    .borrow()
+                    .values()
+                    .filter(|meta| meta.slot == slot && !meta.is_tombstone() && !meta.is_expired(now))
+                    .take(remaining)
+                    .map(|meta| meta.hash),
+            );
+        }
+        hashes
+    }
+
+    /// Return the hashes of about `count` entries from `position` (ordered by
+    /// position) and the position to continue from, None once the end is reached.
+    /// The hashes of evicted entries are returned too.
+    /// Positions only depend on the key hash so keys present during the whole
+    /// iteration are returned exactly once, whatever is written meanwhile.
+    /// Buckets are visited in position order from the one holding `position`
+    /// and only until more than `count` entries are found
+    pub fn scan(&self, position: u64, count: usize) -> (Vec<HashedKey>, Option<u64>) {
+        let mut entries: Vec<(u64, HashedKey)> = vec![];
+        for (bucket, evicted) in self.kvs.iter().zip(&self.evicted).skip(bucket_index(position)) {
+            let start = entries.len();
+            entries.extend(
+                bucket
+                    .borrow()
+                    .keys()
+                    .chain(evicted.borrow().iter())
+                    .map(|hash| (scan_position(hash), *hash))
+                    .filter(|(p, _)| *p >= position),
+            );
+            entries[start..].sort_unstable();
+            if entries.len() > count {
+                break;
+            }
+        }
+
+        // Entries sharing a position can't be split between two calls
+        let mut end = count.max(1).min(entries.len());
+        while end > 0 && end < entries.len() && entries[end].0 == entries[end - 1].0 {
+            end += 1;
+        }
+        let next = entries.get(end).map(|(p, _)| *p);
+        entries.truncate(end);
+        (entries.into_iter().map(|(_, hash)| hash).collect(), next)
+    }
+
+    /// Every entry of the index, in no particular order
+    pub fn all(&self) -> Vec<RecordMetadata> {
+        self.kvs
+            .iter()
+            .flat_map(|bucket| bucket.borrow().values().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Keys and entries of the index, in key order
+    pub fn entries(&self) -> Vec<(Key, RecordMetadata)> {
+        self.keys
+            .borrow()
+            .iter()
+            .filter_map(|(string, hash)| {
+                let key = Key {
+                    string: string.clone(),
+                    hash: *hash,
+                };
+                Some((key, self.get(*hash)?))
+            })
+            .collect()
     }
 
     pub fn truncate(&self) {
-        self.kvs.borrow_mut().clear();
+        for bucket in &self.kvs {
+            bucket.borrow_mut().clear();
+        }
+        self.slot_counts.borrow_mut().clear();
+        self.keys.borrow_mut().clear();
+        for evicted in &self.evicted {
+            evicted.borrow_mut().clear();
+        }
+    }
+
+    /// Number of entries that are tombstones
+    pub fn tombstones(&self) -> usize {
+        self.len() - self.live_len()
+    }
+
+    /// Number of entries that are not tombstones
+    pub fn live_len(&self) -> usize {
+        self.slot_counts.borrow().values().sum()
     }
 
     pub fn len(&self) -> usize {
-        self.kvs.borrow().len()
+        self.kvs.iter().map(|bucket| bucket.borrow().len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::{access::AccessStats, MemtablePointer, RecordPtr};
+    use crate::record::ValueType;
+
+    fn meta(key: &Key, expire_at: u64) -> RecordMetadata {
+        RecordMetadata {
+            key_size: key.string.len() as u16,
+            value_size: 2,
+            timestamp: 1,
+            hash: key.hash,
+            slot: 0,
+            data_ptr: RecordPtr::MemTable(MemtablePointer { memtable: 0, offset: 0 }),
+            value_type: ValueType::String,
+            access: AccessStats::new(),
+            expire_at,
+        }
+    }
+
+    #[test]
+    fn test_index_buckets() {
+        let index = Index::new();
+        let keys: Vec<Key> = (0..100).map(|i| Key::new(format!("key{}", i))).collect();
+        for (i, key) in keys.iter().enumerate() {
+            index.add_key(key);
+            index.update(meta(key, if i % 2 == 0 { 10 } else { 0 }));
+        }
+        assert_eq!(index.len(), 100);
+        assert!(index.kvs.iter().filter(|bucket| !bucket.borrow().is_empty()).count() > 1);
+        assert_eq!(index.expired(20, 30).len(), 30);
+        assert_eq!(index.expired(20, 100).len(), 50);
+
+        let (hashes, next) = index.scan(0, 1000);
+        assert_eq!((hashes.len(), next), (100, None));
+        index.delete(&meta(&keys[0], 0));
+        assert!(index.get(keys[0].hash).is_none());
+        assert_eq!(index.range(.., 0).len(), 99);
+        index.truncate();
+        assert_eq!(index.len(), 0);
+    }
+
+    #[test]
+    fn test_index_keys_of_tombstones() {
+        let index = Index::new();
+        let keys: Vec<Key> = (0..3).map(|i| Key::new(format!("key{}", i))).collect();
+        for key in &keys {
+            index.update_key(key, meta(key, 0));
+        }
+        let mut tombstone = meta(&keys[0], 0);
+        (tombstone.value_size, tombstone.timestamp) = (0, 2);
+        index.update_key(&keys[0], tombstone);
+        index.delete(&meta(&keys[1], 0));
+        index.remove_key(&keys[1]);
+        assert_eq!(index.keys.borrow().len(), 1);
+
+        // Listed again when written again
+        let mut rewritten = meta(&keys[0], 0);
+        rewritten.timestamp = 3;
+        index.update_key(&keys[0], rewritten);
+        assert_eq!(index.range(.., 0).len(), 2);
+    }
+
+    #[test]
+    fn test_index_evicted_keys() {
+        let index = Index::new();
+        let keys: Vec<Key> = (0..3).map(|i| Key::new(format!("key{}", i))).collect();
+        for key in &keys {
+            index.add_key(key);
+            index.update(meta(key, 0));
+        }
+        index.evict(&meta(&keys[1], 0));
+        index.delete(&meta(&keys[2], 0));
+        assert_eq!(index.len(), 1);
+        // Still listed, unlike the deleted key
+        assert_eq!(index.range(.., 0).len(), 2);
+        assert_eq!(index.scan(0, 10).0.len(), 2);
+
+        // Loaded back
+        index.update(meta(&keys[1], 0));
+        assert!(!index.is_evicted(&keys[1].hash));
+        index.evict(&meta(&keys[1], 0));
+        index.forget_evicted(&keys[1].hash);
+        assert_eq!(index.prefix("key", 0).len(), 1);
+    }
+
+    #[test]
+    fn test_index_scan_with_writes() {
+        let index = Index::new();
+        let stable: Vec<Key> = (0..200).map(|i| Key::new(format!("stable{}", i))).collect();
+        for key in &stable {
+            index.update(meta(key, 0));
+        }
+
+        let mut seen = vec![];
+        let mut position = 0;
+        let mut written = 0;
+        loop {
+            let (hashes, next) = index.scan(position, 7);
+            seen.extend(hashes);
+            // Keys come and go between the calls
+            for _ in 0..5 {
+                let key = Key::new(format!("transient{}", written));
+                index.update(meta(&key, 0));
+                if written % 2 == 0 {
+                    index.delete(&meta(&key, 0));
+                }
+                written += 1;
+            }
+            match next {
+                Some(next) => position = next,
+                None => break,
+            }
+        }
+        for key in &stable {
+            assert_eq!(seen.iter().filter(|hash| **hash == key.hash).count(), 1);
+        }
+        let mut unique = seen.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), seen.len());
     }
 }
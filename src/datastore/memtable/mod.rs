@@ -1,16 +1,25 @@
 use std::{
     borrow::BorrowMut,
     cell::{Cell, RefCell},
+    collections::BTreeMap,
+    ops::{Range, RangeBounds},
     rc::Rc,
 };
 
-use crate::record::Record;
+use crate::record::{Record, ValueType};
 
-use super::MemtablePointer;
+use super::{error::DataStoreError, MemtablePointer};
 
+/// Records are stored in the order they are written, their offsets are the
+/// pointers of the index. The keys are also kept ordered, pointing to the
+/// latest record of each key, so a key can be found again and the memtable
+/// read as a sorted run
 pub struct MemTable {
     pub id: u16,
     buffer: RefCell<Vec<Record>>,
+    /// Offset of the latest record of each key (range tombstones are not
+    /// keys and not listed)
+    keys: RefCell<BTreeMap<String, u32>>,
     stats: RefCell<Stats>,
     status: Cell<MemtableStatus>,
 }
@@ -34,6 +43,7 @@ impl MemTable {
         MemTable {
             id,
             buffer: RefCell::from(Vec::with_capacity(usize::pow(2, 16))),
+            keys: RefCell::from(BTreeMap::new()),
             stats: RefCell::from(Stats { references: 0, bytes: 0 }),
             status: Cell::from(MemtableStatus::Open),
         }
@@ -43,27 +53,59 @@ impl MemTable {
         let mut mutable_stats = self.stats.borrow_mut();
         let mut mutable_buffer = self.buffer.borrow_mut();
         let old_record = &mutable_buffer[ptr.offset as usize];
-        mutable_stats.bytes += record.size_of() - old_record.size_of();
+        // Add before subtracting as the new record can be smaller
+        mutable_stats.bytes = mutable_stats.bytes + record.size_of() - old_record.size_of();
+        // Now the latest record of the key, a later one was unreferenced
+        if record.value_type != ValueType::RangeTombstone {
+            self.keys.borrow_mut().insert(record.key.string.clone(), ptr.offset);
+        }
         mutable_buffer[ptr.offset as usize] = record;
 
         mutable_stats.references += 1;
     }
 
-    pub fn append(&self, record: Record) -> u16 {
+    pub fn append(&self, record: Record) -> u32 {
         let size = record.size_of();
         let mut mutable_stats = self.stats.borrow_mut();
         let mut mutable_buffer = self.buffer.borrow_mut();
         let offset = mutable_buffer.len();
+        if record.value_type != ValueType::RangeTombstone {
+            self.keys.borrow_mut().insert(record.key.string.clone(), offset as u32);
+        }
         mutable_buffer.push(record);
         mutable_stats.references += 1;
         mutable_stats.bytes += size;
-        offset as u16
+        offset as u32
     }
 
     pub fn get(&self, ptr: &MemtablePointer) -> Record {
         self.buffer.borrow()[ptr.offset as usize].clone()
     }
 
+    /// Offset of the latest record of `key`
+    pub fn find(&self, key: &str) -> Option<u32> {
+        self.keys.borrow().get(key).copied()
+    }
+
+    /// Latest record of each key in `range` with its offset, ordered by key
+    pub fn range<R: RangeBounds<String>>(&self, range: R) -> Vec<(u32, Record)> {
+        let buffer = self.buffer.borrow();
+        self.keys
+            .borrow()
+            .range(range)
+            .map(|(_, offset)| (*offset, buffer[*offset as usize].clone()))
+            .collect()
+    }
+
+    pub fn get_value_range(&self, ptr: &MemtablePointer, range: Range<usize>) -> Vec<u8> {
+        self.buffer.borrow()[ptr.offset as usize].value[range].to_vec()
+    }
+
+    pub fn has_value(&self, ptr: &MemtablePointer, value: &[u8], flags: u32) -> bool {
+        let record = &self.buffer.borrow()[ptr.offset as usize];
+        record.value == value && record.flags == flags
+    }
+
     pub fn len(&self) -> usize {
         self.buffer.borrow().len()
     }
@@ -92,6 +134,11 @@ impl MemTable {
         self.status.set(MemtableStatus::Flushing)
     }
 
+    /// Back to waiting for a flush, after a failed one
+    pub fn mark_flushable(&self) {
+        self.status.set(MemtableStatus::Flushable)
+    }
+
     pub fn references(&self) -> usize {
         self.stats.borrow().references
     }
@@ -111,6 +158,7 @@ impl MemTable {
     pub fn truncate(&self) {
         let mut mutable_stats = self.stats.borrow_mut();
         self.buffer.borrow_mut().clear();
+        self.keys.borrow_mut().clear();
 
         mutable_stats.bytes = 0;
         mutable_stats.references = 0;
@@ -120,52 +168,88 @@ impl MemTable {
 
 pub struct Manager {
     tables: RefCell<MemtableList>,
-    memtable_max_size_bytes: usize,
+    memtable_max_size_bytes: Cell<usize>,
+    /// Bytes all the memtables not yet truncated can hold before writes are
+    /// stalled (0 means unlimited)
+    memory_budget_bytes: Cell<usize>,
     cur_memtable: Cell<u16>,
 }
 
 impl Manager {
-    pub fn new(memtable_max_size_bytes: usize) -> Manager {
+    pub fn new(memtable_max_size_bytes: usize, memory_budget_bytes: usize) -> Manager {
         let mut tables = MemtableList::new();
-        let id = tables.get_next_free();
+        let id = tables.get_next_free().unwrap();
 
         Manager {
             tables: RefCell::from(tables),
             cur_memtable: Cell::from(id),
-            memtable_max_size_bytes,
+            memtable_max_size_bytes: Cell::new(memtable_max_size_bytes),
+            memory_budget_bytes: Cell::new(memory_budget_bytes),
         }
     }
 
+    /// Applies to the next memtables, the current one is not closed earlier
+    pub fn set_max_size_bytes(&self, memtable_max_size_bytes: usize) {
+        self.memtable_max_size_bytes.set(memtable_max_size_bytes);
+    }
+
+    pub fn set_memory_budget_bytes(&self, memory_budget_bytes: usize) {
+        self.memory_budget_bytes.set(memory_budget_bytes);
+    }
+
+    /// Bytes of records held by the memtables, including the ones being flushed
+    pub fn byte_size(&self) -> usize {
+        self.tables
+            .borrow()
+            .iter()
+            .filter(|e| e.next_free.is_none())
+            .fold(0, |total, entry| total + entry.table.get_byte_size())
+    }
+
+    /// Whether the memtables hold more than the memory budget, writes should
+    /// wait for flushes
+    pub fn is_over_budget(&self) -> bool {
+        let budget = self.memory_budget_bytes.get();
+        budget != 0 && self.byte_size() > budget
+    }
+
+    /// Whether a record of the memtable `id` can still be replaced in place
+    pub fn is_unflushed(&self, id: u16) -> bool {
+        self.tables.borrow().get(id).is_unflushed()
+    }
+
     /// Try to replace the record in the memtable if the memtable is not
     /// already closed
-    pub fn try_emplace(&self, ptr: MemtablePointer, record: Record) -> MemtablePointer {
+    pub fn try_emplace(&self, ptr: MemtablePointer, record: Record) -> Result<MemtablePointer, DataStoreError> {
         {
             let tables = self.tables.borrow();
             let memtable = tables.get(ptr.memtable);
             if memtable.is_unflushed() {
                 memtable.emplace(&ptr, record);
-                return ptr;
+                return Ok(ptr);
             }
         }
         self.append(record)
     }
 
-    pub fn append(&self, record: Record) -> MemtablePointer {
+    /// Full if the current memtable is full and all the others are still
+    /// waiting to be flushed
+    pub fn append(&self, record: Record) -> Result<MemtablePointer, DataStoreError> {
         let mut tables = self.tables.borrow_mut();
         let mut memtable = tables.get(self.cur_memtable.get());
-        if (memtable.get_byte_size() + record.size_of() > self.memtable_max_size_bytes) || (memtable.len() >= (u16::MAX as usize - 1)) {
+        if (memtable.get_byte_size() + record.size_of() > self.memtable_max_size_bytes.get()) || (memtable.len() >= (u32::MAX as usize - 1)) {
             println!("Marking as flushable: {}, {}", memtable.get_byte_size(), memtable.id);
-            memtable.status.set(MemtableStatus::Flushable);
-            let id = tables.get_next_free();
+            let id = tables.get_next_free().ok_or(DataStoreError::Full)?;
+            tables.get(self.cur_memtable.get()).status.set(MemtableStatus::Flushable);
             self.cur_memtable.set(id);
             memtable = tables.get(id);
         }
         let offset = memtable.borrow_mut().append(record);
 
-        MemtablePointer {
+        Ok(MemtablePointer {
             memtable: self.cur_memtable.get(),
             offset,
-        }
+        })
     }
 
     pub fn get(&self, ptr: &MemtablePointer) -> Record {
@@ -173,6 +257,48 @@ impl Manager {
         tables.get(ptr.memtable).get(ptr).clone()
     }
 
+    /// Latest record of `key` in the current memtable, the one new records
+    /// are appended to
+    pub fn find_in_current(&self, key: &str) -> Option<MemtablePointer> {
+        let memtable = self.cur_memtable.get();
+        self.tables
+            .borrow()
+            .get(memtable)
+            .find(key)
+            .map(|offset| MemtablePointer { memtable, offset })
+    }
+
+    /// Newest record of each key in `range` over all the memtables (the ones
+    /// being flushed included), ordered by key. They may no longer be
+    /// referenced (e.g. deleted by a range tombstone), the caller checks them
+    /// against the index
+    pub fn range<R: RangeBounds<String> + Clone>(&self, range: R) -> Vec<(MemtablePointer, Record)> {
+        let mut newest: BTreeMap<String, (MemtablePointer, Record)> = BTreeMap::new();
+        for entry in self.tables.borrow().iter().filter(|e| e.next_free.is_none()) {
+            for (offset, record) in entry.table.range(range.clone()) {
+                if newest.get(&record.key.string).is_some_and(|(_, r)| r.timestamp >= record.timestamp) {
+                    continue;
+                }
+                let ptr = MemtablePointer {
+                    memtable: entry.table.id,
+                    offset,
+                };
+                newest.insert(record.key.string.clone(), (ptr, record));
+            }
+        }
+        newest.into_values().collect()
+    }
+
+    /// Copy only part of the value of a record
+    pub fn get_value_range(&self, ptr: &MemtablePointer, range: Range<usize>) -> Vec<u8> {
+        self.tables.borrow().get(ptr.memtable).get_value_range(ptr, range)
+    }
+
+    /// Compare the value and the flags of a record without copying it
+    pub fn has_value(&self, ptr: &MemtablePointer, value: &[u8], flags: u32) -> bool {
+        self.tables.borrow().get(ptr.memtable).has_value(ptr, value, flags)
+    }
+
     pub fn remove_reference_from_memtable(&self, ptr: &MemtablePointer) {
         let tables = self.tables.borrow();
         tables.get(ptr.memtable).decr_references(1)
@@ -181,7 +307,7 @@ impl Manager {
     pub fn truncate(&self) {
         let mut tables = self.tables.borrow_mut();
         tables.truncate();
-        self.cur_memtable.set(tables.get_next_free());
+        self.cur_memtable.set(tables.get_next_free().unwrap());
     }
 
     pub fn truncate_memtable(&self, id: u16) {
@@ -189,11 +315,13 @@ impl Manager {
         self.tables.borrow_mut().delete(id)
     }
 
-    pub fn mark_memtable_flushing(&self, id: u16) {
+    pub fn mark_memtable_flushing(&self, id: u16) -> Result<(), DataStoreError> {
         if self.cur_memtable.get() == id {
-            self.cur_memtable.set(self.tables.borrow_mut().get_next_free());
+            let next = self.tables.borrow_mut().get_next_free().ok_or(DataStoreError::Full)?;
+            self.cur_memtable.set(next);
         }
         self.tables.borrow().get(id).mark_flushing();
+        Ok(())
     }
 
     pub fn len(&self) -> usize {
@@ -222,6 +350,15 @@ impl Manager {
             .collect()
     }
 
+    /// Return true if a flush is in progress
+    pub fn has_flushing_memtables(&self) -> bool {
+        self.tables
+            .borrow()
+            .iter()
+            .filter(|e| e.next_free.is_none())
+            .any(|e| !e.table.is_unflushed())
+    }
+
     pub fn get_all_flushable_memtables(&self) -> Vec<Rc<MemTable>> {
         self.tables
             .borrow_mut()
@@ -258,18 +395,19 @@ impl MemtableList {
         &self.list[offset as usize].table
     }
 
-    pub fn get_next_free(&mut self) -> u16 {
+    /// None if every memtable is in use
+    pub fn get_next_free(&mut self) -> Option<u16> {
         if self.last_free >= self.list.len() {
             let offset = self.list.len();
             if self.list.len() > u16::MAX as usize {
-                panic!("Too much data: {} (max={})", self.list.len(), u16::MAX)
+                return None;
             }
             self.list.push(Entry {
                 table: Rc::from(MemTable::new(offset as u16)),
                 next_free: None,
             });
             self.last_free = self.list.len();
-            return offset as u16;
+            return Some(offset as u16);
         }
 
         let offset = match &self.list[self.last_free].next_free {
@@ -281,7 +419,7 @@ impl MemtableList {
             None => panic!("offset: {} should be free but contains data", self.last_free),
         };
         self.list[offset].next_free = None;
-        offset as u16
+        Some(offset as u16)
     }
 
     pub fn delete(&mut self, offset: u16) {
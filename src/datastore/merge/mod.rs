@@ -0,0 +1,58 @@
+use crate::record::Key;
+
+/// Combine the operands written by `DataStore::merge` with the value they
+/// apply to. Called when a key with pending operands is read or flushed, so
+/// it must be deterministic
+pub trait MergeOperator {
+    /// New value of `key`, `existing` is None if the key was missing, deleted
+    /// or expired. `operands` are in write order
+    fn merge(&self, key: &Key, existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8>;
+}
+
+/// Append the operands to the value
+pub struct AppendOperator;
+
+impl MergeOperator for AppendOperator {
+    fn merge(&self, _key: &Key, existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+        let mut value = existing.unwrap_or_default().to_vec();
+        for operand in operands {
+            value.extend_from_slice(operand);
+        }
+        value
+    }
+}
+
+/// Value of a delta record: each operand prefixed by its size (u32)
+pub fn encode_operands(operands: &[Vec<u8>]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(operands.iter().map(|o| 4 + o.len()).sum());
+    for operand in operands {
+        buf.extend_from_slice(&(operand.len() as u32).to_le_bytes());
+        buf.extend_from_slice(operand);
+    }
+    buf
+}
+
+pub fn decode_operands(mut buf: &[u8]) -> Vec<Vec<u8>> {
+    let mut operands = vec![];
+    while buf.len() >= 4 {
+        let size = u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
+        operands.push(buf[4..4 + size].to_vec());
+        buf = &buf[4 + size..];
+    }
+    operands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operands_encoding() {
+        let operands = vec![b"foo".to_vec(), vec![], b"bar".to_vec()];
+        assert_eq!(decode_operands(&encode_operands(&operands)), operands);
+
+        let key = Key::new("key".to_string());
+        assert_eq!(AppendOperator.merge(&key, Some(b">"), &operands), b">foobar");
+        assert_eq!(AppendOperator.merge(&key, None, &operands), b"foobar");
+    }
+}
@@ -0,0 +1,69 @@
+use std::time::Instant;
+
+/// Counters of the operations of a datastore since it was opened
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Counters {
+    /// Gets of a key that exists
+    pub read_hits: u64,
+    /// Gets of a missing, deleted or expired key
+    pub read_misses: u64,
+    pub writes: u64,
+    pub deletes: u64,
+    /// Writes delayed because the memtables were over their memory budget
+    pub stalled_writes: u64,
+    /// Stalled writes rejected as busy
+    pub rejected_writes: u64,
+    /// Memtables written to a disktable
+    pub flushes: u64,
+    pub bytes_flushed: u64,
+    pub flush_micros: u64,
+    /// Disktables reclaimed
+    pub compactions: u64,
+    pub compaction_micros: u64,
+}
+
+/// Microseconds elapsed since `start`
+pub fn micros_since(start: Instant) -> u64 {
+    start.elapsed().as_micros() as u64
+}
+
+/// Ratio of hits, 0 without any access
+pub fn hit_ratio(hits: u64, misses: u64) -> f64 {
+    match hits + misses {
+        0 => 0.0,
+        total => hits as f64 / total as f64,
+    }
+}
+
+/// Sum metrics listed in the same order (e.g. of several shards)
+pub fn sum<I: IntoIterator<Item = Vec<(&'static str, u64)>>>(metrics: I) -> Vec<(&'static str, u64)> {
+    let mut total: Vec<(&'static str, u64)> = vec![];
+    for metrics in metrics {
+        if total.is_empty() {
+            total = metrics;
+            continue;
+        }
+        for ((_, sum), (_, value)) in total.iter_mut().zip(metrics) {
+            *sum += value;
+        }
+    }
+    total
+}
+
+/// Value of the metric `name`, 0 if missing
+pub fn get(metrics: &[(&'static str, u64)], name: &str) -> u64 {
+    metrics.iter().find(|(n, _)| *n == name).map_or(0, |(_, value)| *value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sum_metrics() {
+        let total = sum([vec![("hits", 1), ("misses", 2)], vec![("hits", 3), ("misses", 0)]]);
+        assert_eq!(total, vec![("hits", 4), ("misses", 2)]);
+        assert_eq!(hit_ratio(get(&total, "hits"), get(&total, "misses")), 4.0 / 6.0);
+        assert_eq!(hit_ratio(0, 0), 0.0);
+    }
+}
@@ -1,12 +1,48 @@
-use std::{fs, path::PathBuf, rc::Rc};
-
-use crate::record::{HashedKey, Key, Record};
-
-use self::{disktable::ManagerStats, memtable::MemTable};
-
+use futures::{stream, Stream, StreamExt};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fs,
+    ops::{Range, RangeBounds, RangeInclusive},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::record::{key_slot, HashedKey, Key, Record, ValueType, RECORD_HEADER_SIZE};
+
+use self::{
+    access::AccessStats,
+    compaction::{CompactionFilter, CompactionPicker, FilterDecision, UsageRatioPicker},
+    disktable::{block::Compression, DisktableStatus, ManagerStats, TableOptions},
+    encryption::Encryption,
+    error::DataStoreError,
+    expiry::{ExpiryBudget, Ttl, NO_EXPIRY},
+    memtable::MemTable,
+    merge::MergeOperator,
+    metrics::Counters,
+    range_tombstone::{RangeTombstone, RangeTombstones},
+    throttle::IoThrottle,
+};
+
+pub mod access;
+pub mod bloom;
+pub mod checkpoint;
+pub mod compaction;
+pub mod direct_io;
 pub mod disktable;
+pub mod encryption;
+pub mod error;
+pub mod expiry;
 pub mod index;
 pub mod memtable;
+pub mod merge;
+pub mod metrics;
+pub mod range_tombstone;
+pub mod throttle;
+pub mod upgrade;
+pub mod verify;
+pub mod wal;
 
 #[derive(Debug, Clone)]
 pub struct RecordMetadata {
@@ -14,18 +50,43 @@ pub struct RecordMetadata {
     value_size: u32,
     timestamp: u64,
     hash: HashedKey,
+    /// Cluster slot of the key
+    slot: u16,
     data_ptr: RecordPtr,
+    value_type: ValueType,
+    access: AccessStats,
+    /// Expiration date of the record, `NO_EXPIRY` if it never expires
+    expire_at: u64,
 }
 
 impl RecordMetadata {
     /// Return the size in number of bytes of the record
     pub fn size_of(&self) -> usize {
-        self.key_size as usize + self.value_size as usize + 14
+        self.key_size as usize + self.value_size as usize + RECORD_HEADER_SIZE
     }
 
     pub fn is_tombstone(&self) -> bool {
         self.value_size == 0
     }
+
+    pub fn expire_at(&self) -> Option<u64> {
+        match self.expire_at {
+            NO_EXPIRY => None,
+            expire_at => Some(expire_at),
+        }
+    }
+
+    /// Type as seen by readers, deltas are read as their merged value
+    pub fn visible_type(&self) -> ValueType {
+        match self.value_type {
+            ValueType::Merge => ValueType::String,
+            value_type => value_type,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expire_at != NO_EXPIRY && self.expire_at <= now
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -35,19 +96,34 @@ pub enum RecordPtr {
     MemTable(MemtablePointer),
 }
 
+impl RecordPtr {
+    /// Whether the record is at `ptr` in a memtable (alone or while compacting)
+    pub fn is_at(&self, ptr: &MemtablePointer) -> bool {
+        match self {
+            RecordPtr::MemTable(p) => p == ptr,
+            RecordPtr::Compacting(p) => p.to_memtable_pointer() == *ptr,
+            RecordPtr::DiskTable(_) => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct DiskPointer {
     disktable: Rc<String>,
-    offset: u32,
+    /// Number of the data block of the record in the table
+    block: u32,
+    /// Number of the record in the block
+    entry: u16,
 }
 
 // Not using composition here to have small structure
 #[derive(Debug, Clone, PartialEq)]
 pub struct HybridPointer {
     disktable: Rc<String>,
-    d_offset: u32,
+    d_block: u32,
+    d_entry: u16,
     memtable: u16,
-    m_offset: u16,
+    m_offset: u32,
 }
 
 impl HybridPointer {
@@ -62,14 +138,63 @@ impl HybridPointer {
 #[derive(Debug, Clone, PartialEq)]
 pub struct MemtablePointer {
     memtable: u16,
-    offset: u16,
+    offset: u32,
 }
 
+/// Keyspace of the records of a datastore created with `new`, the other ones
+/// are opened with `open_keyspace`
+pub const DEFAULT_KEYSPACE: u16 = 0;
+
 pub struct DataStore {
+    /// Keyspaces share the disktable manager, each table holds the records
+    /// of one keyspace
+    keyspace: u16,
     index: index::Index,
     memtable_manager: memtable::Manager,
-    table_manager: disktable::Manager,
+    table_manager: Rc<disktable::Manager>,
+    wal: wal::Wal,
     config: Config,
+    /// Chooses the disktables to compact, `UsageRatioPicker` unless replaced
+    compaction_picker: Box<dyn CompactionPicker>,
+    /// Collapses the deltas written by `merge`, None until one is registered
+    merge_operator: Option<Box<dyn MergeOperator>>,
+    /// Applied in order to the records copied forward by reclaims
+    compaction_filters: Vec<Box<dyn CompactionFilter>>,
+    /// Version each delta in the index applies to, kept referenced until
+    /// the delta is collapsed or replaced
+    merge_bases: RefCell<HashMap<HashedKey, RecordMetadata>>,
+    /// Written by `delete_range`, kept while older records may be on disk
+    range_tombstones: RangeTombstones,
+    /// Number of sets skipped because the value was unchanged
+    skipped_writes: Cell<usize>,
+    /// Number of reads that failed because of a corrupted disktable
+    corrupted_reads: Cell<usize>,
+    expiry_budget: ExpiryBudget,
+    /// Shared by flushes and reclaims of every keyspace
+    io_throttle: Rc<IoThrottle>,
+    /// Time of the last index checkpoint
+    last_checkpoint: Cell<u64>,
+    counters: Cell<Counters>,
+}
+
+/// File listing the files of a backup, see `DataStore::backup_to`
+const BACKUP_MANIFEST: &str = "BACKUP_MANIFEST";
+
+/// Hard-link `from` to `to`, copy it if they are not on the same filesystem
+fn link_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Create `directory` if needed, an error if it holds files
+fn create_empty_dir(directory: &Path) -> Result<(), DataStoreError> {
+    fs::create_dir_all(directory)?;
+    match fs::read_dir(directory)?.next() {
+        Some(_) => Err(DataStoreError::Io(std::io::ErrorKind::AlreadyExists)),
+        None => Ok(()),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -81,7 +206,7 @@ pub struct Tombstone {
 
 impl Tombstone {
     pub fn size_of(&self) -> usize {
-        2 + 4 + 8 + self.key.len()
+        RECORD_HEADER_SIZE + self.key.len()
     }
 }
 
@@ -93,6 +218,62 @@ pub struct Config {
     /// Ratio of in-use data in a disktable, going underneath will compact
     /// the table
     pub disktable_target_usage_ratio: f32,
+    /// Number of disktables of the keyspace under which none is compacted
+    /// for its usage ratio
+    pub compaction_min_tables: usize,
+    /// Minimum time between two compactions, in milliseconds
+    pub compaction_cooldown_ms: u64,
+    /// Merge the tables picked for compaction directly into a new disktable
+    /// (see `DataStore::compact_disktables`) instead of copying their records
+    /// to the memtables
+    pub direct_compaction: bool,
+    /// Maximum random delay added to the relative ttls of the writes (EX/PX,
+    /// EXPIRE, memcached exptime), as a ratio of the ttl (0 disables it)
+    pub ttl_jitter_ratio: f32,
+    /// Maximum number of expired records removed per expiry sweep, the rest
+    /// is left for the next sweeps (0 means unlimited)
+    pub expiry_max_deletions_per_tick: usize,
+    /// Skip a set when the key already has the same value in memory
+    pub deduplicate_identical_sets: bool,
+    /// Codec of the data blocks of the new disktables, tables already on
+    /// disk keep theirs
+    pub block_compression: Compression,
+    /// Bytes/sec read and written by flushes and reclaims (0 means unlimited)
+    pub background_io_bytes_per_sec: u64,
+    /// Memory-map the disktables and read the records from the mappings
+    /// instead of issuing a read per get. Faster when the tables fit in the
+    /// page cache
+    pub disktable_mmap_reads: bool,
+    /// Open the disktables and the WAL with O_DIRECT so the records are not
+    /// cached twice (page cache and memtables). The filesystem must support it
+    pub direct_io: bool,
+    /// Sync the WAL segment after each append, so the acknowledged writes
+    /// survive a power loss and not only a crash of the process
+    pub wal_sync: bool,
+    /// Number of index entries kept in memory, the coldest ones are evicted
+    /// by `DataStore::evict_cold_entries` (0 means unlimited). Evicted keys
+    /// are found again from the disktables when loaded, they are still
+    /// listed (KEYS, SCAN) but not counted (DBSIZE) until then
+    pub index_max_entries: usize,
+    /// Seconds between two index checkpoints written by
+    /// `DataStore::maybe_checkpoint_index` (0 disables them)
+    pub index_checkpoint_interval_secs: u64,
+    /// Bytes of disktable blocks cached in memory, so the reads of hot
+    /// flushed records don't hit the disk (0 disables the cache)
+    pub block_cache_bytes: usize,
+    /// Number of memtables written at once by `DataStore::force_flush` and
+    /// `DataStore::flush_all_flushable_memtables` (at least 1)
+    pub max_concurrent_flushes: usize,
+    /// Bytes all the memtables of the datastore can hold (including the ones
+    /// being flushed) before writes are stalled, see
+    /// `DataStore::wait_for_memtable_budget` (0 means unlimited)
+    pub memtable_memory_budget_bytes: usize,
+    /// Longest delay of a stalled write before it is rejected as busy, in milliseconds
+    pub write_stall_timeout_ms: u64,
+    /// Encrypt the new disktables and WAL segments with the current key of
+    /// the keyring (None writes them in clear). The index checkpoint holds
+    /// the keys in clear, it is not written while encryption is enabled
+    pub encryption: Option<Encryption>,
 }
 
 impl Default for Config {
@@ -100,6 +281,71 @@ impl Default for Config {
         Self {
             memtable_max_size_bytes: 4 * 1024 * 1024, // Should be much higher for a real db
             disktable_target_usage_ratio: 0.7,
+            compaction_min_tables: 0,
+            compaction_cooldown_ms: 0,
+            direct_compaction: false,
+            ttl_jitter_ratio: 0.0,
+            expiry_max_deletions_per_tick: 1000,
+            deduplicate_identical_sets: false,
+            block_compression: Compression::None,
+            background_io_bytes_per_sec: 0,
+            disktable_mmap_reads: false,
+            direct_io: false,
+            wal_sync: false,
+            index_max_entries: 0,
+            index_checkpoint_interval_secs: 0,
+            block_cache_bytes: 0,
+            max_concurrent_flushes: 4,
+            memtable_memory_budget_bytes: 0,
+            write_stall_timeout_ms: 1000,
+            encryption: None,
+        }
+    }
+}
+
+/// Where the current version of a record is stored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    MemTable,
+    DiskTable,
+    /// Being written to a disktable
+    Compacting,
+}
+
+impl Location {
+    /// Name as returned by DEBUG OBJECT
+    pub fn name(&self) -> &'static str {
+        match self {
+            Location::MemTable => "memtable",
+            Location::DiskTable => "disktable",
+            Location::Compacting => "compacting",
+        }
+    }
+}
+
+/// Introspection information of a key (e.g. for OBJECT)
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub value_type: ValueType,
+    pub value_size: usize,
+    pub location: Location,
+    pub access: AccessStats,
+}
+
+/// Estimated number of keys and bytes on disk of some slots
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SizeEstimate {
+    pub keys: usize,
+    pub bytes: usize,
+}
+
+impl std::ops::Add for SizeEstimate {
+    type Output = SizeEstimate;
+
+    fn add(self, other: SizeEstimate) -> SizeEstimate {
+        SizeEstimate {
+            keys: self.keys + other.keys,
+            bytes: self.bytes + other.bytes,
         }
     }
 }
@@ -111,6 +357,8 @@ pub struct Stats {
     index_len: usize,
     /// Number of records in the memtable
     memtable_refs: usize,
+    /// Bytes held by the memtables, see `Config::memtable_memory_budget_bytes`
+    memtable_bytes: usize,
     /// Number of records in the disktables
     disktable_refs: usize,
     /// Stats from the disktable manager
@@ -118,12 +366,83 @@ pub struct Stats {
     /// Total number of records inside the table
     /// Should be >= index_refs
     all_records: usize,
+    /// Number of sets skipped because the value was unchanged
+    skipped_writes: usize,
+    /// Number of disktable reads that failed (corruption or I/O error)
+    corrupted_reads: usize,
+    /// Number of versions kept as the base of a delta, not in the index
+    merge_bases: usize,
+    /// Number of range tombstones, not in the index
+    range_tombstones: usize,
+    /// Number of records whose index entry is evicted
+    evicted: usize,
+    /// Number of deleted keys whose tombstone is in the index
+    tombstones: usize,
+    counters: Counters,
 }
 
 impl Stats {
+    /// Number of keys in the index
+    pub fn index_len(&self) -> usize {
+        self.index_len
+    }
+
+    /// Number of disktable reads that failed (corruption or I/O error)
+    pub fn corrupted_reads(&self) -> usize {
+        self.corrupted_reads
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Usage ratio and number of records of each disktable
+    pub fn disktables(&self) -> impl Iterator<Item = (&Rc<String>, f32, usize)> {
+        self.disktable_manager_stats
+            .table_stats
+            .iter()
+            .map(|(name, stats)| (name, stats.usage_ratio, stats.count))
+    }
+
+    /// Gauges and counters as name/value pairs, listed in the same order for
+    /// every datastore so they can be summed (see `metrics::sum`)
+    pub fn metrics(&self) -> Vec<(&'static str, u64)> {
+        let manager = &self.disktable_manager_stats;
+        vec![
+            ("keys", (self.index_len - self.tombstones + self.evicted) as u64),
+            ("tombstones", self.tombstones as u64),
+            ("evicted_keys", self.evicted as u64),
+            ("merge_bases", self.merge_bases as u64),
+            ("range_tombstones", self.range_tombstones as u64),
+            ("memtable_records", self.memtable_refs as u64),
+            ("memtable_bytes", self.memtable_bytes as u64),
+            ("disktables", manager.table_stats.len() as u64),
+            ("disktable_records", self.disktable_refs as u64),
+            ("read_hits", self.counters.read_hits),
+            ("read_misses", self.counters.read_misses),
+            ("writes", self.counters.writes),
+            ("deletes", self.counters.deletes),
+            ("stalled_writes", self.counters.stalled_writes),
+            ("rejected_writes", self.counters.rejected_writes),
+            ("skipped_writes", self.skipped_writes as u64),
+            ("corrupted_reads", self.corrupted_reads as u64),
+            ("flushes", self.counters.flushes),
+            ("bytes_flushed", self.counters.bytes_flushed),
+            ("flush_time_us", self.counters.flush_micros),
+            ("compactions", self.counters.compactions),
+            ("compaction_time_us", self.counters.compaction_micros),
+            ("block_cache_bytes", manager.block_cache_bytes as u64),
+            ("block_cache_hits", manager.block_cache_hits as u64),
+            ("block_cache_misses", manager.block_cache_misses as u64),
+        ]
+    }
+
     pub fn assert_not_corrupted(&self) {
         // println!("Stats: {:?}", self);
-        assert_eq!(self.index_len, self.memtable_refs + self.disktable_refs);
+        assert_eq!(
+            self.index_len + self.merge_bases + self.range_tombstones + self.evicted,
+            self.memtable_refs + self.disktable_refs
+        );
         assert!(self.all_records >= self.index_len);
     }
 }
@@ -134,51 +453,465 @@ impl DataStore {
     }
 
     pub async fn new_with_config(directory: PathBuf, config: Config) -> DataStore {
-        fs::create_dir_all(directory.clone()).unwrap();
+        DataStore::new_with_directories(directory.clone(), directory, config).await
+    }
+
+    /// Datastore keeping its disktables (with their manifest) in
+    /// `tables_directory` and its WAL (with the index checkpoint and the WAL of
+    /// the other keyspaces) in `wal_directory`, which may be the same
+    pub async fn new_with_directories(tables_directory: PathBuf, wal_directory: PathBuf, config: Config) -> DataStore {
+        fs::create_dir_all(&tables_directory).unwrap();
+        fs::create_dir_all(&wal_directory).unwrap();
+        let table_manager = disktable::Manager::new(
+            tables_directory,
+            TableOptions {
+                compression: config.block_compression,
+                mmap: config.disktable_mmap_reads,
+                direct_io: config.direct_io,
+                block_cache_bytes: config.block_cache_bytes,
+                encryption: config.encryption.clone(),
+            },
+        );
+        let io_throttle = IoThrottle::new(config.background_io_bytes_per_sec);
+        DataStore::new_keyspace(DEFAULT_KEYSPACE, wal_directory, Rc::new(table_manager), Rc::new(io_throttle), config)
+    }
+
+    /// Open another keyspace of the directory: a datastore with its own index,
+    /// memtables and WAL (in the `keyspace-<n>` subdirectory of the WAL) whose disktables
+    /// are managed with the ones of this one. The same key can be in several
+    /// keyspaces. The default keyspace has to be initialized first, the flushes,
+    /// reclaims and expiry sweeps of the new one are run by its owner
+    pub async fn open_keyspace(&self, keyspace: u16) -> DataStore {
+        assert_ne!(keyspace, DEFAULT_KEYSPACE, "the default keyspace is opened with new");
+        let wal_directory = self.wal.directory().join(format!("keyspace-{}", keyspace));
+        fs::create_dir_all(&wal_directory).unwrap();
+        let datastore = DataStore::new_keyspace(
+            keyspace,
+            wal_directory,
+            self.table_manager.clone(),
+            self.io_throttle.clone(),
+            self.config.clone(),
+        );
+        datastore.replay_wal();
+        datastore.rebuild_index_from_disk().await;
+        datastore
+    }
+
+    fn new_keyspace(
+        keyspace: u16,
+        wal_directory: PathBuf,
+        table_manager: Rc<disktable::Manager>,
+        io_throttle: Rc<IoThrottle>,
+        config: Config,
+    ) -> DataStore {
         DataStore {
+            keyspace,
             index: index::Index::new(),
-            memtable_manager: memtable::Manager::new(config.memtable_max_size_bytes),
-            table_manager: disktable::Manager::new(directory),
+            memtable_manager: memtable::Manager::new(config.memtable_max_size_bytes, config.memtable_memory_budget_bytes),
+            wal: wal::Wal::new(wal_directory, config.direct_io, config.wal_sync, config.encryption.clone()),
+            table_manager,
+            expiry_budget: ExpiryBudget::new(config.expiry_max_deletions_per_tick),
+            io_throttle,
+            compaction_picker: Box::new(UsageRatioPicker::new(
+                config.disktable_target_usage_ratio,
+                config.compaction_min_tables,
+                Duration::from_millis(config.compaction_cooldown_ms),
+            )),
+            merge_operator: None,
+            compaction_filters: vec![],
+            merge_bases: RefCell::new(HashMap::new()),
+            range_tombstones: RangeTombstones::default(),
             config,
+            skipped_writes: Cell::new(0),
+            corrupted_reads: Cell::new(0),
+            last_checkpoint: Cell::new(crate::time::now()),
+            counters: Cell::new(Counters::default()),
         }
     }
 
+    pub fn set_memtable_max_size_bytes(&self, memtable_max_size_bytes: usize) {
+        self.memtable_manager.set_max_size_bytes(memtable_max_size_bytes);
+    }
+
+    pub fn set_memtable_memory_budget_bytes(&self, memory_budget_bytes: usize) {
+        self.memtable_manager.set_memory_budget_bytes(memory_budget_bytes);
+    }
+
+    pub fn set_disktable_target_usage_ratio(&self, ratio: f32) {
+        self.compaction_picker.set_target_usage_ratio(ratio);
+    }
+
+    pub fn set_background_io_bytes_per_sec(&self, bytes_per_sec: u64) {
+        self.io_throttle.set_bytes_per_sec(bytes_per_sec);
+    }
+
+    /// Replace the compaction policy
+    pub fn set_compaction_picker(&mut self, picker: Box<dyn CompactionPicker>) {
+        self.compaction_picker = picker;
+    }
+
+    /// Register the function collapsing the operands written by `merge`
+    pub fn set_merge_operator(&mut self, operator: Box<dyn MergeOperator>) {
+        self.merge_operator = Some(operator);
+    }
+
+    /// Register a filter called for the records copied forward by reclaims,
+    /// after the ones already registered
+    pub fn add_compaction_filter(&mut self, filter: Box<dyn CompactionFilter>) {
+        self.compaction_filters.push(filter);
+    }
+
+    /// Keep the tombstones written after `timestamp` until released with
+    /// `release_gc_horizon` (see `disktable::Manager::hold_gc_horizon`)
+    pub fn hold_gc_horizon(&self, timestamp: u64) -> u64 {
+        self.table_manager.hold_gc_horizon(timestamp)
+    }
+
+    pub fn release_gc_horizon(&self, id: u64) {
+        self.table_manager.release_gc_horizon(id)
+    }
+
     pub async fn init(&mut self) {
+        upgrade::upgrade(self.table_manager.directory());
         self.table_manager.init().await;
+        self.replay_wal();
+    }
+
+    /// Write again the records logged but not flushed before the last stop
+    fn replay_wal(&self) {
+        let (records, segments) = self.wal.read_previous_segments().expect("can't read the write-ahead log");
+        if !records.is_empty() {
+            println!("Replaying {} records from the write-ahead log", records.len());
+        }
+        for record in records {
+            let replayed = match record.value_type {
+                ValueType::RangeTombstone => self.write_range_tombstone(record).map(|_| ()),
+                _ => self.set_raw(record),
+            };
+            replayed.expect("can't log again a record of the write-ahead log");
+        }
+        // Only removed once logged again in the new segments
+        for segment in segments {
+            fs::remove_file(segment).unwrap();
+        }
+    }
+
+    /// Write a consistent copy of the data to the empty directory `backup`,
+    /// to be given to `restore_from`. Memtables are flushed first so the
+    /// disktables hold every record, they are hard-linked when possible as
+    /// they are never modified. The tables of every keyspace are copied, the
+    /// memtables of the other keyspaces have to be flushed by their owner.
+    /// On error the backup is incomplete and can't be restored
+    pub async fn backup_to(&self, backup: &Path) -> Result<(), DataStoreError> {
+        create_empty_dir(backup)?;
+        self.force_flush().await?;
+        // Memtables already being flushed by the flush manager are skipped by force_flush
+        while self.is_flushing() {
+            monoio::time::sleep(Duration::from_millis(10)).await
+        }
+
+        // No await from here: the set of tables can't change while linking them
+        let tables = self.table_manager.live_table_paths();
+        let mut manifest = String::new();
+        for file in tables.iter().chain([&self.table_manager.directory().join(upgrade::VERSION_FILE)]) {
+            let name = file.file_name().unwrap().to_str().unwrap();
+            link_or_copy(file, &backup.join(name))?;
+            manifest.push_str(name);
+            manifest.push('\n');
+        }
+        // Tables marked for deletion are not part of the backup
+        disktable::write_manifest(backup, tables.iter().map(|path| path.file_name().unwrap().to_str().unwrap()))?;
+        manifest.push_str(disktable::MANIFEST);
+        manifest.push('\n');
+        // Written last, a backup without manifest is incomplete
+        upgrade::write_atomically(&backup.join(BACKUP_MANIFEST), manifest.as_bytes())?;
+        Ok(())
+    }
+
+    /// Create a datastore in the empty directory `directory` from a backup
+    /// written by `backup_to`, `NotFound` if the backup is incomplete
+    pub async fn restore_from(backup: &Path, directory: PathBuf, config: Config) -> Result<DataStore, DataStoreError> {
+        let manifest = fs::read_to_string(backup.join(BACKUP_MANIFEST))?;
+        create_empty_dir(&directory)?;
+        for name in manifest.lines() {
+            link_or_copy(&backup.join(name), &directory.join(name))?;
+        }
+
+        let mut datastore = DataStore::new_with_config(directory, config).await;
+        datastore.init().await;
+        datastore.rebuild_index_from_disk().await;
+        Ok(datastore)
+    }
+
+    pub async fn truncate(&self) {
+        self.index.truncate();
+        self.merge_bases.borrow_mut().clear();
+        self.range_tombstones.truncate();
+        self.memtable_manager.truncate();
+        self.wal.truncate();
+        self.remove_checkpoint();
+        self.table_manager.truncate(self.keyspace).await;
     }
 
-    pub async fn truncate(&mut self) {
+    /// Like `truncate` but return the disktable files instead of removing
+    /// them, so they can be deleted in the background
+    pub fn truncate_detached(&self) -> Vec<PathBuf> {
         self.index.truncate();
+        self.merge_bases.borrow_mut().clear();
+        self.range_tombstones.truncate();
         self.memtable_manager.truncate();
-        self.table_manager.truncate().await;
+        self.wal.truncate();
+        self.remove_checkpoint();
+        self.table_manager.detach(self.keyspace)
+    }
+
+    pub fn set(&self, record: Record) -> Result<(), DataStoreError> {
+        if self.config.deduplicate_identical_sets && self.is_identical_to_current(&record) {
+            self.skipped_writes.set(self.skipped_writes.get() + 1);
+            self.index.touch(record.key.hash);
+            return Ok(());
+        }
+        self.count(|c| c.writes += 1);
+        self.set_raw(record)
+    }
+
+    /// Add the ttl jitter (`Config::ttl_jitter_ratio`) to an expiration date
+    /// computed at `now` from a relative ttl. Carried over or absolute
+    /// expirations are written as they are
+    pub fn jitter_expiration(&self, now: u64, expire_at: u64) -> u64 {
+        expiry::apply_jitter(now, expire_at, self.config.ttl_jitter_ratio)
+    }
+
+    /// Version of a key, changed by every write (None if the key doesn't
+    /// exist, as expiring or unlinking a key doesn't write a new version)
+    pub async fn version(&self, key: &Key) -> Option<u64> {
+        self.load(key).await;
+        self.current_version(key)
+    }
+
+    /// `version` of a key already loaded
+    fn current_version(&self, key: &Key) -> Option<u64> {
+        match self.index.get(key.hash) {
+            Some(meta) if !meta.is_tombstone() && !meta.is_expired(crate::time::now()) => Some(meta.timestamp),
+            _ => None,
+        }
     }
 
-    pub fn set(&self, record: Record) {
-        self.set_raw(record);
+    /// Write the record only if the key is still at `version`, used by
+    /// read-modify-write operations as reads can yield
+    pub fn set_if_version(&self, record: Record, version: Option<u64>) -> Result<bool, DataStoreError> {
+        if self.current_version(&record.key) != version {
+            return Ok(false);
+        }
+        self.set(record)?;
+        Ok(true)
+    }
+
+    /// Delete the key only if it is still at `version`
+    pub fn delete_if_version(&self, key: &Key, version: Option<u64>) -> Result<bool, DataStoreError> {
+        if self.current_version(key) != version {
+            return Ok(false);
+        }
+        self.delete(key)?;
+        Ok(true)
+    }
+
+    /// Check if the current version of the key has the same value and flags.
+    /// Only versions still in memory are compared to keep the write path free of I/O
+    fn is_identical_to_current(&self, record: &Record) -> bool {
+        let meta = match self.index.get(record.key.hash) {
+            Some(meta) => meta,
+            None => return false,
+        };
+        if meta.is_tombstone()
+            || meta.value_size as usize != record.value.len()
+            || meta.value_type != record.value_type
+            || meta.expire_at() != record.expire_at
+        {
+            return false;
+        }
+        match &meta.data_ptr {
+            RecordPtr::MemTable(ptr) => self.memtable_manager.has_value(ptr, &record.value, record.flags),
+            RecordPtr::Compacting(ptr) => self.memtable_manager.has_value(&ptr.to_memtable_pointer(), &record.value, record.flags),
+            RecordPtr::DiskTable(_) => false,
+        }
     }
 
-    pub fn delete(&self, key: &Key) {
+    /// Delete a key, return false if it didn't exist
+    pub fn delete(&self, key: &Key) -> Result<bool, DataStoreError> {
         let timestamp = crate::time::now();
+        let existed = match self.index.get(key.hash) {
+            Some(meta) if !meta.is_tombstone() => !meta.is_expired(timestamp),
+            // Nothing to delete, evicted keys are loaded by the callers first
+            _ => return Ok(false),
+        };
+        self.count(|c| c.deletes += 1);
+        self.write_tombstone(key, timestamp)?;
+        Ok(existed)
+    }
+
+    fn write_tombstone(&self, key: &Key, timestamp: u64) -> Result<(), DataStoreError> {
         self.set_raw(Record {
             key: key.clone(),
             value: vec![],
             timestamp,
-        });
+            value_type: ValueType::String,
+            expire_at: None,
+            flags: 0,
+        })
+    }
+
+    /// Delete a key by expiring it in the index only, the tombstone is written
+    /// and the storage released by the expiry sweeps. Return false if the key
+    /// didn't exist
+    pub async fn unlink(&self, key: &Key) -> bool {
+        self.load(key).await;
+        let now = crate::time::now();
+        match self.index.get(key.hash) {
+            Some(meta) if !meta.is_tombstone() && !meta.is_expired(now) => {
+                self.index.set_expire_at(key.hash, now);
+                self.count(|c| c.deletes += 1);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Delete the keys in `range` with a single range tombstone instead of one
+    /// tombstone per key, their versions on disk are dropped by reclaims.
+    /// Return the number of deleted keys whose entry was in the index
+    pub fn delete_range(&self, range: Range<String>) -> Result<usize, DataStoreError> {
+        self.delete_keys_from(range.start, Some(range.end))
+    }
+
+    /// Delete the keys starting with `prefix`, like `delete_range`
+    pub fn delete_prefix(&self, prefix: &str) -> Result<usize, DataStoreError> {
+        self.delete_keys_from(prefix.to_string(), range_tombstone::prefix_end(prefix))
+    }
+
+    fn delete_keys_from(&self, start: String, end: Option<String>) -> Result<usize, DataStoreError> {
+        let deleted = self.write_range_tombstone(Record {
+            value: range_tombstone::encode_end(end.as_deref()),
+            key: Key::new(start),
+            timestamp: crate::time::now(),
+            value_type: ValueType::RangeTombstone,
+            expire_at: None,
+            flags: 0,
+        })?;
+        self.count(|c| c.deletes += deleted as u64);
+        Ok(deleted)
+    }
+
+    /// Log a range tombstone and remove the entries it covers from the index.
+    /// Always appended, its key is the start of the range and not a key of
+    /// the index
+    fn write_range_tombstone(&self, r: Record) -> Result<usize, DataStoreError> {
+        let entry = wal::encode(&r);
+        let ptr = self.append_logged(r.clone(), &entry)?;
+        let meta = RecordMetadata {
+            data_ptr: RecordPtr::MemTable(ptr),
+            key_size: r.key.string.len() as u16,
+            value_size: r.value.len() as u32,
+            timestamp: r.timestamp,
+            hash: r.key.hash,
+            slot: key_slot(r.key.string.as_bytes()),
+            value_type: r.value_type,
+            access: AccessStats::new(),
+            expire_at: NO_EXPIRY,
+        };
+        let tombstone = RangeTombstone::new(&r, meta);
+        let deleted = self.remove_covered(&tombstone);
+        self.range_tombstones.insert(tombstone);
+        Ok(deleted)
+    }
+
+    /// Remove the index entries deleted by a range tombstone, return the
+    /// number of keys that existed
+    fn remove_covered(&self, tombstone: &RangeTombstone) -> usize {
+        let now = crate::time::now();
+        let removed = self
+            .index
+            .remove_range(&tombstone.start, tombstone.end.as_deref(), tombstone.meta.timestamp);
+        let existed = removed.iter().filter(|meta| !meta.is_tombstone() && !meta.is_expired(now)).count();
+        for meta in removed {
+            self.release_replaced(meta);
+        }
+        existed
+    }
+
+    /// Write `operand` as a delta collapsed with the current value by the
+    /// merge operator when the key is read or flushed, so the value is not
+    /// read now. Consecutive merges add their operands to the same delta
+    pub async fn merge(&self, key: &Key, operand: Vec<u8>) -> Result<(), DataStoreError> {
+        assert!(self.merge_operator.is_some(), "no merge operator registered");
+        self.load(key).await;
+        loop {
+            let current = self.get_live_meta(key);
+            let mut operands = match &current {
+                Some(meta) if meta.value_type == ValueType::Merge => {
+                    let operands = merge::decode_operands(&self.read_stored(meta).await?.value);
+                    // Merged again while reading the previous operands
+                    if self.index.get(key.hash).map(|m| m.timestamp) != Some(meta.timestamp) {
+                        continue;
+                    }
+                    operands
+                }
+                _ => vec![],
+            };
+            operands.push(operand);
+            self.count(|c| c.writes += 1);
+            self.set_raw(Record {
+                key: key.clone(),
+                value: merge::encode_operands(&operands),
+                timestamp: crate::time::now(),
+                value_type: ValueType::Merge,
+                expire_at: current.and_then(|meta| meta.expire_at()),
+                flags: 0,
+            })?;
+            return Ok(());
+        }
     }
 
-    fn set_raw(&self, r: Record) {
+    /// Nothing is changed if the record can't be logged or stored
+    fn set_raw(&self, r: Record) -> Result<(), DataStoreError> {
+        // Listed before the write, tombstones are unlisted once written
+        let tombstone_key = r.value.is_empty().then(|| r.key.clone());
+        self.index.add_key(&r.key);
         let hash = r.key.hash;
+        let slot = key_slot(r.key.string.as_bytes());
         let key_size = r.key.string.len() as u16;
         let value_size = r.value.len() as u32;
         let timestamp = r.timestamp;
+        let expire_at = r.expire_at.unwrap_or(NO_EXPIRY);
+        let value_type = r.value_type;
+        let entry = wal::encode(&r);
 
-        let ptr = match self.index.get(hash) {
+        let in_place = match self.index.get(hash) {
             Some(m) => match m.data_ptr {
-                RecordPtr::DiskTable(_) => self.memtable_manager.append(r),
-                RecordPtr::Compacting(_) => self.memtable_manager.append(r),
-                RecordPtr::MemTable(ptr) => self.memtable_manager.try_emplace(ptr, r),
+                RecordPtr::DiskTable(_) => None,
+                // The value a delta applies to is kept
+                _ if value_type == ValueType::Merge && m.value_type != ValueType::Merge => None,
+                // The copy of a reclaimed record too, releasing the replaced
+                // version releases both copies
+                RecordPtr::Compacting(ptr) => Some(ptr.to_memtable_pointer()).filter(|ptr| self.memtable_manager.is_unflushed(ptr.memtable)),
+                RecordPtr::MemTable(ptr) => Some(ptr).filter(|ptr| self.memtable_manager.is_unflushed(ptr.memtable)),
             },
-            None => self.memtable_manager.append(r),
+            None => None,
+        }
+        // Or a version of the key nothing refers to anymore (e.g. deleted by a
+        // range tombstone), its space is reused
+        .or_else(|| {
+            self.memtable_manager
+                .find_in_current(&r.key.string)
+                .filter(|ptr| !self.is_referenced_by_key(ptr, hash))
+        });
+        let ptr = match in_place {
+            // Logged first, the current version is only replaced once logged
+            Some(ptr) => {
+                self.wal.append(ptr.memtable, &entry)?;
+                self.memtable_manager.try_emplace(ptr, r)?
+            }
+            None => self.append_logged(r, &entry)?,
         };
 
         let meta = RecordMetadata {
@@ -187,322 +920,2978 @@ impl DataStore {
             value_size,
             timestamp,
             hash,
+            slot,
+            value_type,
+            access: AccessStats::new(),
+            expire_at,
         };
 
         if let Some(old_meta) = self.index.update(meta) {
-            self.remove_reference_from_storage(&old_meta);
+            self.release_replaced(old_meta);
+        }
+        if let Some(key) = tombstone_key {
+            self.index.remove_key(&key);
         }
+        self.index.touch(hash);
+        Ok(())
     }
 
-    pub async fn get(&self, key: &Key) -> Option<Record> {
-        let meta = match self.index.get(key.hash) {
-            Some(meta) => meta,
-            None => return None,
+    /// Append a record to the current memtable and log it. If it can't be
+    /// logged the record is left unreferenced, the flush skips it
+    fn append_logged(&self, r: Record, entry: &[u8]) -> Result<MemtablePointer, DataStoreError> {
+        let ptr = self.memtable_manager.append(r)?;
+        if let Err(e) = self.wal.append(ptr.memtable, entry) {
+            self.memtable_manager.remove_reference_from_memtable(&ptr);
+            return Err(e.into());
+        }
+        Ok(ptr)
+    }
+
+    /// Evict the coldest entries of the index over `Config::index_max_entries`.
+    /// Only the entries of records in a disktable without expiration are
+    /// evicted, the record keeps its reference until `load` finds it again.
+    /// Return the number of evicted entries
+    pub fn evict_cold_entries(&self) -> usize {
+        let max = self.config.index_max_entries;
+        if max == 0 || self.index.len() <= max {
+            return 0;
+        }
+        let evictable = |meta: &RecordMetadata| {
+            matches!(meta.data_ptr, RecordPtr::DiskTable(_))
+                && !meta.is_tombstone()
+                && meta.expire_at().is_none()
+                && meta.value_type != ValueType::Merge
         };
-        if meta.is_tombstone() {
-            return None;
+        let hashes = self.index.coldest(self.index.len() - max, evictable);
+        for hash in &hashes {
+            let meta = self.index.get(*hash).unwrap();
+            let RecordPtr::DiskTable(ptr) = &meta.data_ptr else { unreachable!() };
+            self.table_manager.get_table(&ptr.disktable).unwrap().evict(ptr, hash);
+            self.index.evict(&meta);
         }
-        match meta.data_ptr {
-            RecordPtr::DiskTable(_) => Some(self.table_manager.get(&meta).await),
-            RecordPtr::MemTable(ptr) => Some(self.memtable_manager.get(&ptr)),
-            RecordPtr::Compacting(ptr) => Some(self.memtable_manager.get(&ptr.to_memtable_pointer())),
+        hashes.len()
+    }
+
+    /// Load back the index entry of a key evicted by `evict_cold_entries`, a
+    /// no-op if it is in memory or doesn't exist. Reads load the keys they
+    /// need, the callers of the write methods (e.g. `set`, `delete`) load
+    /// the key first when the index is bounded
+    pub async fn load(&self, key: &Key) {
+        if self.config.index_max_entries == 0 || self.index.get(key.hash).is_some() {
+            return;
         }
+        self.load_evicted(&key.hash).await;
     }
 
-    pub async fn rebuild_index_from_disk(&mut self) {
-        let mut meta_to_update: Vec<RecordMetadata> = Vec::new();
-        for t in self.table_manager.get_tables().into_iter() {
-            let meta = t.read_all_metadata().await;
-            let updates: Vec<RecordMetadata> = meta.into_iter().filter_map(|m| self.index.update(m)).collect();
-            meta_to_update.extend(updates);
+    /// Load back the evicted entries of the key `hash` from the disktables
+    async fn load_evicted(&self, hash: &HashedKey) {
+        let mut failed = false;
+        for t in self.table_manager.get_tables(self.keyspace) {
+            let found = match t.find_evicted(hash).await {
+                Ok(found) => found,
+                Err(e) => {
+                    self.report_corruption(hash, e);
+                    failed = true;
+                    continue;
+                }
+            };
+            // Several versions only if the key was written without being loaded
+            for (key, meta) in found {
+                if self.range_tombstones.covers(&key.string, meta.timestamp) {
+                    self.remove_reference_from_storage(&meta);
+                    continue;
+                }
+                if let Some(replaced) = self.index.update_key(&key, meta) {
+                    self.release_replaced(replaced);
+                }
+            }
         }
-        for meta in meta_to_update {
-            self.remove_reference_from_storage(&meta);
+        // Deleted by a range tombstone meanwhile, kept listed if a table
+        // couldn't be read
+        if !failed {
+            self.index.forget_evicted(hash);
         }
     }
 
-    pub async fn clean_unused_disktables(&self) {
-        self.table_manager.delete_disktables_marked_for_deletion();
+    fn count<F: FnOnce(&mut Counters)>(&self, update: F) {
+        let mut counters = self.counters.get();
+        update(&mut counters);
+        self.counters.set(counters);
     }
 
-    pub async fn force_flush(&self) {
-        for memtable in self.memtable_manager.get_all_unflushed_memtables() {
-            self.flush_memtable(&memtable).await
+    fn count_read(&self, meta: Option<&RecordMetadata>) {
+        match meta {
+            Some(_) => self.count(|c| c.read_hits += 1),
+            None => self.count(|c| c.read_misses += 1),
         }
     }
 
-    pub async fn flush_all_flushable_memtables(&self) {
-        for memtable in self.memtable_manager.get_all_flushable_memtables() {
-            self.flush_memtable(&memtable).await
+    /// Current record of a key, None if it is missing, deleted or expired
+    pub async fn get(&self, key: &Key) -> Result<Option<Record>, DataStoreError> {
+        self.load(key).await;
+        let meta = self.get_live_meta(key);
+        self.count_read(meta.as_ref());
+        match meta {
+            Some(meta) => Ok(Some(self.read(&meta).await?)),
+            None => Ok(None),
         }
     }
 
-    pub async fn flush_memtable(&self, memtable: &MemTable) {
-        if memtable.is_empty() {
-            return;
+    /// Like `try_get` for several keys, results are in the order of `keys`.
+    /// The records in disktables are read concurrently, each data block once
+    pub async fn get_many(&self, keys: &[Key]) -> Vec<Result<Option<Record>, DataStoreError>> {
+        for key in keys {
+            self.load(key).await;
         }
-        self.memtable_manager.mark_memtable_flushing(memtable.id);
-
-        let offsets = self.table_manager.flush_memtable(memtable).await;
-        let meta_to_update: Vec<RecordMetadata> = offsets
-            .into_iter()
-            // Update the index
-            .filter_map(|m| self.index.update(m))
+        let metas: Vec<Option<RecordMetadata>> = keys
+            .iter()
+            .map(|key| {
+                let meta = self.get_live_meta(key);
+                self.count_read(meta.as_ref());
+                meta
+            })
             .collect();
-        for old_meta in meta_to_update {
-            self.remove_reference_from_storage(&old_meta);
+        let on_disk: Vec<&RecordMetadata> = metas
+            .iter()
+            .flatten()
+            .filter(|meta| matches!(meta.data_ptr, RecordPtr::DiskTable(_)))
+            .collect();
+        let mut from_disk = self.table_manager.get_many(&on_disk).await.into_iter();
+
+        let mut results = Vec::with_capacity(keys.len());
+        for meta in &metas {
+            let result = match meta {
+                None => Ok(None),
+                Some(meta) if matches!(meta.data_ptr, RecordPtr::DiskTable(_)) => match from_disk.next().unwrap() {
+                    Ok(record) => self.complete(meta, record).await.map(Some),
+                    Err(e) => {
+                        self.report_corruption(meta.hash, e);
+                        Err(e)
+                    }
+                },
+                Some(meta) => self.read(meta).await.map(Some),
+            };
+            results.push(result);
         }
-        assert!(memtable.references() == 0);
-        self.memtable_manager.truncate_memtable(memtable.id);
+        results
     }
 
-    fn remove_reference_from_storage(&self, meta: &RecordMetadata) {
-        match &meta.data_ptr {
-            RecordPtr::DiskTable(ptr) => self.table_manager.remove_reference_from_storage(&ptr.disktable),
-            RecordPtr::MemTable(ptr) => self.memtable_manager.remove_reference_from_memtable(ptr),
-            RecordPtr::Compacting(ptr) => {
-                self.table_manager.remove_reference_from_storage(&ptr.disktable);
-                self.memtable_manager.remove_reference_from_memtable(&ptr.to_memtable_pointer());
-            }
+    /// Read only `range` of the value (clamped to its size) without copying
+    /// the whole record
+    pub async fn get_value_range(&self, key: &Key, range: Range<usize>) -> Result<Option<Vec<u8>>, DataStoreError> {
+        self.load(key).await;
+        let meta = self.get_live_meta(key);
+        self.count_read(meta.as_ref());
+        let Some(meta) = meta else {
+            return Ok(None);
         };
+        if meta.value_type == ValueType::Merge {
+            // The size is only known once collapsed
+            let value = self.read(&meta).await?.value;
+            return Ok(Some(value[range.start.min(value.len())..range.end.min(value.len())].to_vec()));
+        }
+        let size = meta.value_size as usize;
+        let range = range.start.min(size)..range.end.min(size);
+        match &meta.data_ptr {
+            RecordPtr::DiskTable(_) => match self.table_manager.get_value_range(&meta, range).await {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => {
+                    self.report_corruption(key, e);
+                    Err(e)
+                }
+            },
+            RecordPtr::MemTable(ptr) => Ok(Some(self.memtable_manager.get_value_range(ptr, range))),
+            RecordPtr::Compacting(ptr) => Ok(Some(self.memtable_manager.get_value_range(&ptr.to_memtable_pointer(), range))),
+        }
     }
 
-    async fn reclaim_disktable(&self, n: &Rc<String>) {
-        let t = self.table_manager.get_table(n).unwrap();
-        // TODO datastore should not access tables directly
-        let mut to_remove = 0;
-        let meta_to_update: Vec<RecordMetadata> = t
-            .read_all_data()
-            .await
-            .into_iter()
-            .filter_map(|(record, mut meta)| {
-                if let Some(in_index_meta) = self.index.get(meta.hash) {
-                    // Skip record if one is newer in memory
-                    if meta.timestamp.lt(&in_index_meta.timestamp) {
-                        to_remove += 1;
-                        return Some(meta);
-                    }
-                }
-                if meta.is_tombstone() && meta.timestamp < self.table_manager.get_oldest_table() {
-                    self.index.delete(&meta);
-                    return None;
-                }
-                let memtable_ptr = self.memtable_manager.append(record);
-                if let RecordPtr::DiskTable(ptr) = meta.data_ptr {
-                    meta.data_ptr = RecordPtr::Compacting(HybridPointer {
-                        disktable: ptr.disktable,
-                        d_offset: ptr.offset,
-                        memtable: memtable_ptr.memtable,
-                        m_offset: memtable_ptr.offset,
-                    })
-                }
-                self.index.update(meta)
-            })
-            .collect();
+    fn report_corruption(&self, key: impl std::fmt::Debug, e: DataStoreError) {
+        println!("Failed disktable read for {:?}: {:?}", key, e);
+        self.corrupted_reads.set(self.corrupted_reads.get() + 1);
+    }
 
-        for meta in meta_to_update {
-            self.remove_reference_from_storage(&meta);
+    /// Metadata of a key counting it as an access, None if it is deleted or expired
+    fn get_live_meta(&self, key: &Key) -> Option<RecordMetadata> {
+        let meta = self.index.get_and_touch(key.hash)?;
+        if meta.is_tombstone() {
+            return None;
         }
-        t.set_as_pending_flush();
+        if meta.is_expired(crate::time::now()) {
+            // Expired keys are deleted lazily when accessed, or by the sweeps
+            // if the tombstone can't be written now
+            let _ = self.delete(key);
+            return None;
+        }
+        Some(meta)
     }
 
-    pub async fn maybe_run_one_reclaim(&self) {
-        if let Some(n) = self.table_manager.get_best_table_to_reclaim() {
-            println!("Reclaiming {}", n);
-            self.reclaim_disktable(&n).await;
+    async fn read(&self, meta: &RecordMetadata) -> Result<Record, DataStoreError> {
+        let record = self.read_stored(meta).await?;
+        self.complete(meta, record).await
+    }
+
+    /// Collapse a delta read from the storage and apply the expiration of its
+    /// index entry
+    async fn complete(&self, meta: &RecordMetadata, mut record: Record) -> Result<Record, DataStoreError> {
+        if record.value_type == ValueType::Merge {
+            record = self.collapse(record).await?;
         }
+        // The index is the reference, UNLINK expires keys without writing them
+        record.expire_at = meta.expire_at();
+        Ok(record)
     }
 
-    pub async fn reclaim_all_disktables(&mut self) {
-        for n in self.table_manager.list_tables() {
-            self.reclaim_disktable(&n).await
+    /// Read the record as stored, deltas are not collapsed
+    async fn read_stored(&self, meta: &RecordMetadata) -> Result<Record, DataStoreError> {
+        Ok(match &meta.data_ptr {
+            RecordPtr::DiskTable(_) => self
+                .table_manager
+                .get(meta)
+                .await
+                .inspect_err(|e| self.report_corruption(meta.hash, *e))?,
+            RecordPtr::MemTable(ptr) => self.memtable_manager.get(ptr),
+            RecordPtr::Compacting(ptr) => self.memtable_manager.get(&ptr.to_memtable_pointer()),
+        })
+    }
+
+    /// Apply the operands of a delta to the value of its base
+    async fn collapse(&self, delta: Record) -> Result<Record, DataStoreError> {
+        let operator = self.merge_operator.as_ref().expect("no merge operator registered");
+        let base = self.merge_bases.borrow().get(&delta.key.hash).cloned();
+        let existing = match base {
+            Some(base) if !base.is_tombstone() && !base.is_expired(crate::time::now()) => Some(self.read_stored(&base).await?.value),
+            _ => None,
+        };
+        let value = operator.merge(&delta.key, existing.as_deref(), &merge::decode_operands(&delta.value));
+        Ok(Record {
+            value,
+            value_type: ValueType::String,
+            ..delta
+        })
+    }
+
+    /// Set the expiration date of a key, None makes it persistent.
+    /// Return false if the key doesn't exist or, when removing the
+    /// expiration, if it had none
+    pub async fn expire(&self, key: &Key, expire_at: Option<u64>) -> Result<bool, DataStoreError> {
+        let mut record = match self.get(key).await? {
+            Some(record) => record,
+            None => return Ok(false),
+        };
+        if expire_at.is_none() && record.expire_at.is_none() {
+            return Ok(false);
+        }
+        let now = crate::time::now();
+        if expire_at.is_some_and(|expire_at| expire_at <= now) {
+            self.delete(key)?;
+            return Ok(true);
         }
+        // Records are immutable: write a new version with the new expiration
+        record.timestamp = now;
+        record.expire_at = expire_at;
+        self.set(record)?;
+        Ok(true)
     }
 
-    /// Return number of active records from memtable/index
-    pub fn get_stats(&self) -> Stats {
-        Stats {
-            index_len: self.index.len(),
-            memtable_refs: self.memtable_manager.references(),
-            disktable_refs: self.table_manager.references(),
-            disktable_manager_stats: self.table_manager.get_stats(),
-            all_records: self.memtable_manager.len() + self.table_manager.len(),
+    pub async fn ttl(&self, key: &Key) -> Ttl {
+        self.load(key).await;
+        let meta = match self.index.get(key.hash) {
+            Some(meta) if !meta.is_tombstone() => meta,
+            _ => return Ttl::Missing,
+        };
+        let now = crate::time::now();
+        match meta.expire_at() {
+            None => Ttl::Persistent,
+            Some(_) if meta.is_expired(now) => Ttl::Missing,
+            Some(expire_at) => Ttl::Expiring(expire_at - now),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    // Note this useful idiom: importing names from outer (for mod tests) scope.
-    use super::*;
+    /// Delete expired keys still in the index, at most the expiry budget per call.
+    /// Return the number of deleted keys
+    pub async fn sweep_expired(&self) -> usize {
+        self.expiry_budget.refill();
+        let expired = self.index.expired(crate::time::now(), self.expiry_budget.remaining());
+        let mut deleted = 0;
+        for expired_meta in expired {
+            if !self.expiry_budget.try_take() {
+                break;
+            }
+            // Pointers may have moved (flush, reclaim) during the previous reads
+            let meta = match self.index.get(expired_meta.hash) {
+                Some(meta) if meta.timestamp == expired_meta.timestamp => meta,
+                _ => continue,
+            };
+            // The key is needed to write the tombstone
+            let Ok(record) = self.read(&meta).await else {
+                continue;
+            };
+            // The key may have been rewritten while reading it
+            if self.index.get(meta.hash).is_some_and(|current| current.timestamp == meta.timestamp) {
+                // Retried by the next sweeps
+                if self.delete(&record.key).is_err() {
+                    break;
+                }
+                deleted += 1;
+            }
+        }
+        deleted
+    }
 
-    fn assert_value_eq(r: &Record, expected: &str) {
-        assert_eq!(std::str::from_utf8(&r.value).unwrap(), expected);
+    /// Return the records of the keys in `range`, ordered by key. The index
+    /// points to the latest version of each key, in a memtable or a disktable.
+    /// Stop at the first record that can't be read
+    pub async fn range<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<Record>, DataStoreError> {
+        let mut records = vec![];
+        for key in self.index.range(range, crate::time::now()) {
+            if let Some(record) = self.read_live(&key).await? {
+                records.push(record);
+            }
+        }
+        Ok(records)
     }
 
-    #[test]
-    fn test_datastore_for_consistency() {
-        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+    /// Return the current records of the keys in `range` that are still in the
+    /// memtables, ordered by key: the in-memory half of a range scan, read
+    /// without any I/O. The keys whose current version is on disk, or is a
+    /// delta (its base may be on disk), are skipped
+    pub fn memtable_range<R: RangeBounds<String> + Clone>(&self, range: R) -> Vec<Record> {
+        let now = crate::time::now();
+        self.memtable_manager
+            .range(range)
+            .into_iter()
+            .filter_map(|(ptr, mut record)| {
+                let meta = self.index.get(record.key.hash)?;
+                if !meta.data_ptr.is_at(&ptr) || meta.is_tombstone() || meta.is_expired(now) || meta.value_type == ValueType::Merge {
+                    return None;
+                }
+                record.expire_at = meta.expire_at();
+                Some(record)
+            })
+            .collect()
+    }
+
+    /// Iterate over the records of the keys starting with `prefix`, ordered
+    /// by key. Keys are listed first, records are read as the stream is polled,
+    /// the ones that can't be read are returned as errors
+    pub fn scan_prefix<'a>(&'a self, prefix: &str) -> impl Stream<Item = Result<Record, DataStoreError>> + 'a {
+        stream::iter(self.index.prefix(prefix, crate::time::now())).filter_map(move |key| async move { self.read_live(&key).await.transpose() })
+    }
+
+    /// Iterate over every record ordered by key, read lazily like `scan_prefix`.
+    /// The index points to the newest version of each key (by timestamp) in a
+    /// memtable or a disktable, older versions are never read
+    pub fn iter(&self) -> impl Stream<Item = Result<(Key, Record), DataStoreError>> + '_ {
+        self.scan_prefix("").map(|record| record.map(|record| (record.key.clone(), record)))
+    }
+
+    /// Read the current version of a key without counting it as an access,
+    /// None if it was deleted or expired since it was listed
+    async fn read_live(&self, key: &Key) -> Result<Option<Record>, DataStoreError> {
+        self.load(key).await;
+        // Fetch the metadata after each read as pointers may have moved meanwhile
+        let meta = match self.index.get(key.hash) {
+            Some(meta) if !meta.is_tombstone() && !meta.is_expired(crate::time::now()) => meta,
+            _ => return Ok(None),
+        };
+        Ok(Some(self.read(&meta).await?))
+    }
+
+    /// Return the keys of about `count` index entries from `position` and the
+    /// position to continue from (see `Index::scan`)
+    pub async fn scan(&self, position: u64, count: usize) -> (Vec<Key>, Option<u64>) {
+        let (hashes, next) = self.index.scan(position, count);
+        let mut keys = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            if self.index.is_evicted(&hash) {
+                self.load_evicted(&hash).await;
+            }
+            // Fetch the metadata after each read as pointers may have moved meanwhile
+            let meta = match self.index.get(hash) {
+                Some(meta) if !meta.is_tombstone() && !meta.is_expired(crate::time::now()) => meta,
+                _ => continue,
+            };
+            if let Ok(record) = self.read(&meta).await {
+                keys.push(record.key);
+            }
+        }
+        (keys, next)
+    }
+
+    /// Return the keys starting with `prefix` accepted by `filter`, from the
+    /// ordered keys of the index
+    pub fn keys<F: Fn(&Key) -> bool>(&self, prefix: &str, filter: F) -> Vec<Key> {
+        let mut keys = self.index.prefix(prefix, crate::time::now());
+        keys.retain(filter);
+        keys
+    }
+
+    /// Return up to `count` keys of `slot`, the index doesn't hold the keys so
+    /// it costs one read per key
+    pub async fn keys_in_slot(&self, slot: u16, count: usize) -> Vec<Key> {
+        let mut keys = Vec::with_capacity(count);
+        for hash in self.index.live_hashes_in_slot(slot, crate::time::now(), count) {
+            if let Some(meta) = self.index.get(hash).filter(|meta| !meta.is_tombstone()) {
+                if let Ok(record) = self.read(&meta).await {
+                    keys.push(record.key);
+                }
+            }
+        }
+        keys
+    }
+
+    /// Load the index from the disktables: from the checkpoint if there is
+    /// one, the tables it doesn't cover (written since) are scanned
+    pub async fn rebuild_index_from_disk(&self) {
+        let (covered, mut meta_to_update) = self.load_checkpoint();
+        let mut scanned = vec![];
+        for t in self.table_manager.get_tables(self.keyspace).into_iter() {
+            if covered.contains(t.name()) {
+                continue;
+            }
+            match t.read_all_metadata().await {
+                Ok(meta) => scanned.extend(meta),
+                Err(e) => println!("Skipping corrupted disktable {}: {:?}", t.name(), e),
+            };
+        }
+        // Range tombstones first, they may delete records of any table
+        let (range_tombstones, scanned): (Vec<_>, Vec<_>) = scanned.into_iter().partition(|(_, m)| m.value_type == ValueType::RangeTombstone);
+        for (key, m) in range_tombstones {
+            // Also replayed from the WAL if it was not truncated after the flush
+            if self.range_tombstones.contains(&m) {
+                self.remove_reference_from_storage(&m);
+                continue;
+            }
+            match self.table_manager.get(&m).await {
+                Ok(record) => self.range_tombstones.insert(RangeTombstone::new(&record, m)),
+                Err(e) => {
+                    self.report_corruption(key, e);
+                    self.remove_reference_from_storage(&m);
+                }
+            }
+        }
+        for (key, m) in scanned {
+            if self.range_tombstones.covers(&key.string, m.timestamp) {
+                self.remove_reference_from_storage(&m);
+                continue;
+            }
+            meta_to_update.extend(self.index.update_key(&key, m));
+        }
+        for meta in meta_to_update {
+            self.release_replaced(meta);
+        }
+        // Entries of the checkpoint and the WAL may be older than a tombstone
+        for tombstone in self.range_tombstones.all() {
+            self.remove_covered(&tombstone);
+        }
+    }
+
+    fn checkpoint_path(&self) -> PathBuf {
+        self.wal.directory().join(checkpoint::CHECKPOINT_FILE)
+    }
+
+    /// Write the index entries of the records in disktables to the checkpoint
+    /// file, so `rebuild_index_from_disk` only scans the tables written since.
+    /// Tables being reclaimed, with evicted entries or range tombstones are
+    /// left to the scan
+    pub fn checkpoint_index(&self) {
+        if self.config.encryption.is_some() {
+            println!("Not checkpointing the index, the keys would be written in clear");
+            return;
+        }
+        let with_range_tombstones = self.range_tombstones.tables();
+        let tables: Vec<Rc<String>> = self
+            .table_manager
+            .get_tables(self.keyspace)
+            .into_iter()
+            .filter(|t| t.get_stats().status == DisktableStatus::Active && t.evicted() == 0 && !with_range_tombstones.contains(t.name()))
+            .map(|t| t.name().clone())
+            .collect();
+        let covered: HashSet<&Rc<String>> = tables.iter().collect();
+        let is_covered = |meta: &RecordMetadata| matches!(&meta.data_ptr, RecordPtr::DiskTable(ptr) if covered.contains(&ptr.disktable));
+        let merge_bases = self.merge_bases.borrow();
+        let mut entries = vec![];
+        for (key, meta) in self.index.entries() {
+            // A base is referenced as long as its delta, which is in a memtable
+            if let Some(base) = merge_bases.get(&key.hash).filter(|base| is_covered(base)) {
+                entries.push((key.clone(), base.clone()));
+            }
+            if is_covered(&meta) {
+                entries.push((key, meta));
+            }
+        }
+        println!("Checkpointing {} index entries of {} disktables", entries.len(), tables.len());
+        let buf = checkpoint::encode(&checkpoint::Checkpoint { tables, entries });
+        upgrade::write_atomically(&self.checkpoint_path(), &buf).unwrap();
+        self.last_checkpoint.set(crate::time::now());
+    }
+
+    /// Write a checkpoint if `Config::index_checkpoint_interval_secs` elapsed
+    /// since the last one, never with `Config::encryption`
+    pub fn maybe_checkpoint_index(&self) {
+        let interval = self.config.index_checkpoint_interval_secs;
+        if interval > 0
+            && self.config.encryption.is_none()
+            && crate::time::now() - self.last_checkpoint.get() >= Duration::from_secs(interval).as_nanos() as u64
+        {
+            self.checkpoint_index();
+        }
+    }
+
+    /// Add the entries of the checkpoint to the index, return the tables they
+    /// cover and the entries replaced
+    fn load_checkpoint(&self) -> (HashSet<Rc<String>>, Vec<RecordMetadata>) {
+        let mut covered = HashSet::new();
+        let mut replaced = vec![];
+        let Some(checkpoint) = checkpoint::read(&self.checkpoint_path()) else {
+            return (covered, replaced);
+        };
+        for table in checkpoint.tables {
+            // Reclaimed since, its live records were copied to newer tables
+            if self.table_manager.get_table(&table).is_some() {
+                covered.insert(table);
+            }
+        }
+        for (key, meta) in checkpoint.entries {
+            let RecordPtr::DiskTable(ptr) = &meta.data_ptr else { unreachable!() };
+            let Some(table) = covered.get(&ptr.disktable) else {
+                continue;
+            };
+            self.table_manager.add_reference_to_storage(table);
+            replaced.extend(self.index.update_key(&key, meta));
+        }
+        println!("Loaded the index checkpoint of {} disktables", covered.len());
+        (covered, replaced)
+    }
+
+    fn remove_checkpoint(&self) {
+        match fs::remove_file(self.checkpoint_path()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => panic!("can't remove the index checkpoint: {}", e),
+            _ => (),
+        }
+    }
+
+    /// Flush the memtables and rebuild the index from the disktables, like a
+    /// restart would
+    pub async fn reload(&self) {
+        self.force_flush().await.expect("can't flush the memtables before reloading");
+        self.index.truncate();
+        self.merge_bases.borrow_mut().clear();
+        self.range_tombstones.truncate();
+        self.memtable_manager.truncate();
+        self.wal.truncate();
+        self.table_manager.reload(self.keyspace).await;
+        self.rebuild_index_from_disk().await;
+    }
+
+    pub async fn clean_unused_disktables(&self) {
+        self.table_manager.delete_disktables_marked_for_deletion();
+    }
+
+    pub async fn force_flush(&self) -> Result<(), DataStoreError> {
+        self.flush_memtables(self.memtable_manager.get_all_unflushed_memtables()).await
+    }
+
+    pub fn is_flushing(&self) -> bool {
+        self.memtable_manager.has_flushing_memtables()
+    }
+
+    /// Delay a write while the memtables are over `Config::memtable_memory_budget_bytes`,
+    /// flushing them meanwhile. Busy if they are still over it after
+    /// `Config::write_stall_timeout_ms`
+    pub async fn wait_for_memtable_budget(&self) -> Result<(), DataStoreError> {
+        if !self.memtable_manager.is_over_budget() {
+            return Ok(());
+        }
+        self.count(|c| c.stalled_writes += 1);
+        let start = Instant::now();
+        while self.memtable_manager.is_over_budget() {
+            if start.elapsed() >= Duration::from_millis(self.config.write_stall_timeout_ms) {
+                self.count(|c| c.rejected_writes += 1);
+                return Err(DataStoreError::Busy);
+            }
+            // Otherwise wait for the running flushes to free their memtables
+            if !self.is_flushing() {
+                self.force_flush().await?;
+            }
+            monoio::time::sleep(Duration::from_millis(10)).await
+        }
+        Ok(())
+    }
+
+    pub async fn flush_all_flushable_memtables(&self) -> Result<(), DataStoreError> {
+        self.flush_memtables(self.memtable_manager.get_all_flushable_memtables()).await
+    }
+
+    /// Flush `Config::max_concurrent_flushes` memtables at a time, their
+    /// tables are written concurrently. Return the first error, the other
+    /// memtables are still flushed
+    async fn flush_memtables(&self, memtables: Vec<Rc<MemTable>>) -> Result<(), DataStoreError> {
+        let results: Vec<Result<(), DataStoreError>> = stream::iter(memtables)
+            .map(|memtable| async move {
+                // Another flush may have started to flush it in the meantime
+                match memtable.is_unflushed() {
+                    true => self.flush_memtable(&memtable).await,
+                    false => Ok(()),
+                }
+            })
+            .buffer_unordered(self.config.max_concurrent_flushes.max(1))
+            .collect()
+            .await;
+        results.into_iter().collect()
+    }
+
+    /// Write a memtable to a new disktable. On error the memtable is left as
+    /// it was, to be flushed again later
+    pub async fn flush_memtable(&self, memtable: &MemTable) -> Result<(), DataStoreError> {
+        if memtable.is_empty() {
+            return Ok(());
+        }
+        self.memtable_manager.mark_memtable_flushing(memtable.id)?;
+        self.io_throttle.acquire(memtable.get_byte_size()).await;
+        let start = Instant::now();
+        let bases = self.collapse_merges(memtable).await;
+
+        // Replaced or deleted versions are not written, so a table never holds
+        // a version deleted before it was created (see `gc_horizon`)
+        let flushed = self
+            .table_manager
+            .flush_memtable(memtable, self.keyspace, |offset, record| {
+                self.is_referenced(
+                    &MemtablePointer {
+                        memtable: memtable.id,
+                        offset,
+                    },
+                    record,
+                )
+            })
+            .await;
+        let offsets = match flushed {
+            Ok(offsets) => offsets,
+            Err(e) => {
+                // The collapsed values are only in memory, their bases are
+                // kept until they are on disk
+                for base in bases {
+                    self.set_merge_base(base);
+                }
+                memtable.mark_flushable();
+                return Err(e);
+            }
+        };
+        let meta_to_update: Vec<RecordMetadata> = offsets
+            .into_iter()
+            // Update the index
+            .filter_map(|m| match m.value_type {
+                ValueType::RangeTombstone => {
+                    self.remove_reference_from_storage(&self.range_tombstones.update(m));
+                    None
+                }
+                // Deleted by a range tombstone since written
+                _ if self.index.get(m.hash).is_none() => Some(m),
+                _ => self.index.update(m),
+            })
+            .collect();
+        for old_meta in meta_to_update {
+            self.release_replaced(old_meta);
+        }
+        // Only once the merged values are on disk
+        for base in bases {
+            self.remove_reference_from_storage(&base);
+        }
+        assert!(memtable.references() == 0);
+        self.count(|c| {
+            c.flushes += 1;
+            c.bytes_flushed += memtable.get_byte_size() as u64;
+            c.flush_micros += metrics::micros_since(start);
+        });
+        self.memtable_manager.truncate_memtable(memtable.id);
+        self.wal.truncate_segment(memtable.id);
+        Ok(())
+    }
+
+    /// Whether the record at `ptr` is the current version of its key, the base
+    /// of its delta or a range tombstone
+    fn is_referenced(&self, ptr: &MemtablePointer, record: &Record) -> bool {
+        match record.value_type {
+            ValueType::RangeTombstone => self.range_tombstones.any(|t| t.meta.data_ptr.is_at(ptr)),
+            _ => self.is_referenced_by_key(ptr, record.key.hash),
+        }
+    }
+
+    /// Whether the record at `ptr` is the current version of the key `hash`
+    /// or the base of its delta
+    fn is_referenced_by_key(&self, ptr: &MemtablePointer, hash: HashedKey) -> bool {
+        self.index.get(hash).is_some_and(|meta| meta.data_ptr.is_at(ptr))
+            || self.merge_bases.borrow().get(&hash).is_some_and(|base| base.data_ptr.is_at(ptr))
+    }
+
+    /// Replace the current deltas of a memtable about to be flushed by their
+    /// merged value. Return their bases, to release once it is flushed
+    async fn collapse_merges(&self, memtable: &MemTable) -> Vec<RecordMetadata> {
+        let mut bases = vec![];
+        // A base in another memtable may be flushed at the same time, it must
+        // stay a base until then. The delta is flushed as is
+        let base_elsewhere = |hash: &HashedKey| {
+            self.merge_bases.borrow().get(hash).is_some_and(|base| match &base.data_ptr {
+                RecordPtr::MemTable(ptr) => ptr.memtable != memtable.id,
+                RecordPtr::Compacting(ptr) => ptr.memtable != memtable.id,
+                RecordPtr::DiskTable(_) => false,
+            })
+        };
+        for (offset, record) in memtable.values().into_iter().enumerate() {
+            let ptr = RecordPtr::MemTable(MemtablePointer {
+                memtable: memtable.id,
+                offset: offset as u32,
+            });
+            let is_current = || self.index.get(record.key.hash).filter(|meta| meta.data_ptr == ptr);
+            if record.value_type != ValueType::Merge {
+                // Collapsed by a failed flush of the memtable, its base was kept
+                if self.merge_bases.borrow().contains_key(&record.key.hash) && is_current().is_some() && !base_elsewhere(&record.key.hash) {
+                    bases.extend(self.merge_bases.borrow_mut().remove(&record.key.hash));
+                }
+                continue;
+            }
+            if is_current().is_none() {
+                continue;
+            }
+            // Flushed as a delta if the base can't be read
+            let Ok(collapsed) = self.collapse(record.clone()).await else {
+                continue;
+            };
+            // The key may have been written while reading the base
+            let Some(meta) = is_current() else {
+                continue;
+            };
+            if base_elsewhere(&meta.hash) {
+                continue;
+            }
+            let RecordPtr::MemTable(memtable_ptr) = &meta.data_ptr else {
+                unreachable!()
+            };
+            let collapsed_meta = RecordMetadata {
+                value_size: collapsed.value.len() as u32,
+                value_type: collapsed.value_type,
+                ..meta.clone()
+            };
+            memtable.emplace(memtable_ptr, collapsed);
+            self.remove_reference_from_storage(&self.index.update(collapsed_meta).unwrap());
+            bases.extend(self.merge_bases.borrow_mut().remove(&meta.hash));
+        }
+        bases
+    }
+
+    /// Keep `meta` as the base of the current delta of its key
+    fn set_merge_base(&self, meta: RecordMetadata) {
+        let released = match self.merge_bases.borrow_mut().entry(meta.hash) {
+            // The same version moved to another table, or a newer one
+            Entry::Occupied(mut entry) if entry.get().timestamp <= meta.timestamp => Some(entry.insert(meta)),
+            Entry::Occupied(_) => Some(meta),
+            Entry::Vacant(entry) => {
+                entry.insert(meta);
+                None
+            }
+        };
+        if let Some(released) = released {
+            self.remove_reference_from_storage(&released);
+        }
+    }
+
+    /// Release a version replaced in the index, or rejected as older than
+    /// the indexed one. A value older than the current delta of its key is
+    /// kept as its base instead, and the base is released once the key is no
+    /// longer a delta
+    fn release_replaced(&self, meta: RecordMetadata) {
+        let delta = self.index.get(meta.hash).filter(|current| current.value_type == ValueType::Merge);
+        if delta
+            .as_ref()
+            .is_some_and(|delta| meta.value_type != ValueType::Merge && meta.timestamp < delta.timestamp)
+        {
+            return self.set_merge_base(meta);
+        }
+        if delta.is_none() {
+            let base = self.merge_bases.borrow_mut().remove(&meta.hash);
+            if let Some(base) = base {
+                self.remove_reference_from_storage(&base);
+            }
+        }
+        self.remove_reference_from_storage(&meta);
+    }
+
+    fn is_merge_base(&self, meta: &RecordMetadata) -> bool {
+        self.merge_bases
+            .borrow()
+            .get(&meta.hash)
+            .is_some_and(|base| base.timestamp == meta.timestamp)
+    }
+
+    fn remove_reference_from_storage(&self, meta: &RecordMetadata) {
+        match &meta.data_ptr {
+            RecordPtr::DiskTable(ptr) => self.table_manager.remove_reference_from_storage(&ptr.disktable),
+            RecordPtr::MemTable(ptr) => self.memtable_manager.remove_reference_from_memtable(ptr),
+            RecordPtr::Compacting(ptr) => {
+                self.table_manager.remove_reference_from_storage(&ptr.disktable);
+                self.memtable_manager.remove_reference_from_memtable(&ptr.to_memtable_pointer());
+            }
+        };
+    }
+
+    async fn reclaim_disktable(&self, n: &Rc<String>) {
+        let t = self.table_manager.get_table(n).unwrap();
+        // TODO datastore should not access tables directly
+        let mut to_remove = 0;
+        self.io_throttle.acquire(t.data_size()).await;
+        let start = Instant::now();
+        let now = crate::time::now();
+        let data = match t.read_all_data().await {
+            Ok(data) => data,
+            Err(e) => {
+                // Kept as is: its readable records are still served, the
+                // table is suspect and not picked again
+                println!("Can't reclaim corrupted disktable {}: {:?}", n, e);
+                return;
+            }
+        };
+        // Marked first, releasing its last record marks it for deletion
+        t.set_as_pending_flush();
+        let (range_tombstones, data): (Vec<_>, Vec<_>) = data.into_iter().partition(|(_, meta)| meta.value_type == ValueType::RangeTombstone);
+        for (record, meta) in range_tombstones {
+            // Only needed while older tables may hold the records it deletes
+            if meta.timestamp < self.table_manager.gc_horizon() {
+                self.remove_reference_from_storage(&self.range_tombstones.remove(&meta));
+                self.remove_reference_from_storage(&meta);
+            } else {
+                let copied = self.copy_to_memtable(record, meta);
+                self.remove_reference_from_storage(&self.range_tombstones.update(copied));
+            }
+        }
+        let meta_to_update: Vec<RecordMetadata> = data
+            .into_iter()
+            .filter_map(|(mut record, mut meta)| {
+                let mut evicted = matches!(&meta.data_ptr, RecordPtr::DiskTable(ptr) if t.take_evicted(ptr));
+                let in_index = self.index.get(meta.hash);
+                // Written again without being loaded, or deleted by a range
+                // tombstone: the evicted entry is released
+                if evicted && (in_index.is_some() || self.range_tombstones.covers(&record.key.string, meta.timestamp)) {
+                    self.remove_reference_from_storage(&meta);
+                    evicted = false;
+                }
+                // Copied forward like the current version while a delta applies to it
+                let is_base = self.is_merge_base(&meta);
+                if in_index.is_none() && !evicted && !is_base {
+                    // Neither current nor evicted: a version of a deleted key
+                    to_remove += 1;
+                    return Some(meta);
+                }
+                if let Some(in_index_meta) = in_index {
+                    // Skip record if one is newer in memory
+                    if meta.timestamp.lt(&in_index_meta.timestamp) && !is_base {
+                        to_remove += 1;
+                        return Some(meta);
+                    }
+                    // Drop expired records instead of copying them
+                    if in_index_meta.is_expired(now) {
+                        self.index.delete(&in_index_meta);
+                        self.index.remove_key(&record.key);
+                        self.release_replaced(in_index_meta);
+                        return Some(meta);
+                    }
+                    // The index may have expired the key since it was written
+                    if !is_base {
+                        meta.expire_at = in_index_meta.expire_at;
+                        record.expire_at = in_index_meta.expire_at();
+                    }
+                }
+                // Already swept from the index, don't copy it forward
+                if meta.is_expired(now) && !is_base {
+                    return Some(meta);
+                }
+                if !is_base && !meta.is_tombstone() && meta.value_type != ValueType::Merge {
+                    // Copied forward if the tombstone can't be written
+                    if !self.apply_compaction_filters(&mut record) && self.write_tombstone(&record.key, crate::time::now()).is_ok() {
+                        if evicted {
+                            self.remove_reference_from_storage(&meta);
+                        }
+                        to_remove += 1;
+                        return Some(meta);
+                    }
+                    meta.value_size = record.value.len() as u32;
+                }
+                if meta.is_tombstone() && !is_base && meta.timestamp < self.table_manager.gc_horizon() {
+                    // Both the reference of the index and of the read are released
+                    self.index.delete(&meta);
+                    self.remove_reference_from_storage(&meta);
+                    return Some(meta);
+                }
+                let original = meta.clone();
+                match self.index.update(self.copy_to_memtable(record, meta)) {
+                    // The reference of the evicted entry is now held by the index
+                    None if evicted => Some(original),
+                    replaced => replaced,
+                }
+            })
+            .collect();
+
+        for meta in meta_to_update {
+            self.release_replaced(meta);
+        }
+        self.count(|c| {
+            c.compactions += 1;
+            c.compaction_micros += metrics::micros_since(start);
+        });
+    }
+
+    /// Merge the disktables `names` into a new one without going through the
+    /// memtables: the records still referenced (current versions, merge bases,
+    /// range tombstones) are written to it and their references are moved to
+    /// it, the merged tables are deleted once released. Records are dropped
+    /// like by a reclaim (replaced, expired, purged tombstones, compaction
+    /// filters), evicted entries still current are loaded back
+    pub async fn compact_disktables(&self, names: &[Rc<String>]) -> Result<(), DataStoreError> {
+        let start = Instant::now();
+        let mut data = vec![];
+        for name in names {
+            let Some(table) = self.table_manager.get_table(name) else {
+                continue;
+            };
+            // Being reclaimed, or a read failed
+            let stats = table.get_stats();
+            if stats.status != DisktableStatus::Active || stats.suspect {
+                continue;
+            }
+            self.io_throttle.acquire(table.data_size()).await;
+            data.extend(table.read_all_data_unreferenced().await?);
+        }
+
+        // No await until the records to keep are written: nothing is released meanwhile
+        let now = crate::time::now();
+        let gc_horizon = self.table_manager.gc_horizon();
+        let mut records = vec![];
+        let mut metas = vec![];
+        for (mut record, meta) in data {
+            if meta.value_type == ValueType::RangeTombstone {
+                if !self.range_tombstones.any(|t| t.meta.data_ptr == meta.data_ptr) {
+                    continue;
+                }
+                // Only needed while older tables may hold the records it deletes
+                if meta.timestamp < gc_horizon {
+                    self.remove_reference_from_storage(&self.range_tombstones.remove(&meta));
+                    continue;
+                }
+                records.push(record);
+                metas.push(meta);
+                continue;
+            }
+            let evicted = match &meta.data_ptr {
+                RecordPtr::DiskTable(ptr) => self.table_manager.get_table(&ptr.disktable).is_some_and(|t| t.take_evicted(ptr)),
+                _ => false,
+            };
+            if evicted {
+                // Written again without being loaded, or deleted by a range tombstone
+                if self.index.get(meta.hash).is_some() || self.range_tombstones.covers(&record.key.string, meta.timestamp) {
+                    self.remove_reference_from_storage(&meta);
+                    continue;
+                }
+                // The index now holds the reference of the evicted entry
+                self.index.update_key(&record.key, meta.clone());
+            }
+            let is_base = self
+                .merge_bases
+                .borrow()
+                .get(&meta.hash)
+                .is_some_and(|base| base.data_ptr == meta.data_ptr);
+            let Some(in_index) = self.index.get(meta.hash).filter(|m| m.data_ptr == meta.data_ptr) else {
+                if is_base {
+                    records.push(record);
+                    metas.push(meta);
+                }
+                continue;
+            };
+            if in_index.is_expired(now) {
+                self.index.delete(&in_index);
+                self.index.remove_key(&record.key);
+                self.release_replaced(in_index);
+                continue;
+            }
+            if in_index.is_tombstone() && in_index.timestamp < gc_horizon {
+                self.index.delete(&in_index);
+                self.remove_reference_from_storage(&in_index);
+                continue;
+            }
+            // The index may have expired the key since it was written
+            record.expire_at = in_index.expire_at();
+            if !in_index.is_tombstone() && in_index.value_type != ValueType::Merge {
+                // Kept if the tombstone can't be written, it releases the entry
+                if !self.apply_compaction_filters(&mut record) && self.write_tombstone(&record.key, crate::time::now()).is_ok() {
+                    continue;
+                }
+            }
+            records.push(record);
+            metas.push(meta);
+        }
+
+        let moved = self.table_manager.write_records(&records, self.keyspace).await?;
+        for (old, new) in metas.into_iter().zip(moved) {
+            // Anything may have been written while the table was written, only
+            // the references still pointing to the merged tables are moved
+            let replaced = if old.value_type == ValueType::RangeTombstone {
+                match self.range_tombstones.any(|t| t.meta.data_ptr == old.data_ptr) {
+                    true => Some(self.range_tombstones.update(new.clone())),
+                    false => None,
+                }
+            } else if self.index.get(old.hash).is_some_and(|m| m.data_ptr == old.data_ptr) {
+                self.index.update(new.clone())
+            } else if self.merge_bases.borrow().get(&old.hash).is_some_and(|base| base.data_ptr == old.data_ptr) {
+                self.merge_bases.borrow_mut().insert(old.hash, new.clone())
+            } else {
+                None
+            };
+            match replaced {
+                Some(replaced) => self.remove_reference_from_storage(&replaced),
+                None => self.remove_reference_from_storage(&new),
+            }
+        }
+        self.count(|c| {
+            c.compactions += 1;
+            c.compaction_micros += metrics::micros_since(start);
+        });
+        Ok(())
+    }
+
+    /// Run the compaction filters on a record about to be copied forward,
+    /// return false if it is removed
+    fn apply_compaction_filters(&self, record: &mut Record) -> bool {
+        for filter in &self.compaction_filters {
+            match filter.filter(record) {
+                FilterDecision::Keep => (),
+                FilterDecision::Remove => return false,
+                FilterDecision::ChangeValue(value) => record.value = value,
+            }
+        }
+        true
+    }
+
+    /// Append a record of a reclaimed table to the memtable, return its
+    /// metadata pointing to both copies until it is flushed
+    fn copy_to_memtable(&self, record: Record, mut meta: RecordMetadata) -> RecordMetadata {
+        let memtable_ptr = self.memtable_manager.append(record).expect("no memtable left to copy a reclaimed record");
+        if let RecordPtr::DiskTable(ptr) = meta.data_ptr {
+            meta.data_ptr = RecordPtr::Compacting(HybridPointer {
+                disktable: ptr.disktable,
+                d_block: ptr.block,
+                d_entry: ptr.entry,
+                memtable: memtable_ptr.memtable,
+                m_offset: memtable_ptr.offset,
+            })
+        }
+        meta
+    }
+
+    /// Reclaim the tables chosen by the compaction picker, if any
+    pub async fn maybe_run_one_reclaim(&self) {
+        let mut tables = self.table_manager.get_stats().table_stats;
+        // The records of a table are copied to the memtables of its keyspace
+        tables.retain(|(_, stats)| stats.keyspace == self.keyspace);
+        let picked = self.compaction_picker.pick(&tables);
+        if self.config.direct_compaction && !picked.is_empty() {
+            println!("Compacting {:?}", picked);
+            if let Err(e) = self.compact_disktables(&picked).await {
+                println!("Can't compact the disktables: {:?}", e);
+            }
+            return;
+        }
+        for n in picked {
+            println!("Reclaiming {}", n);
+            self.reclaim_disktable(&n).await;
+        }
+    }
+
+    pub async fn reclaim_all_disktables(&mut self) {
+        for n in self.table_manager.list_tables(self.keyspace) {
+            self.reclaim_disktable(&n).await
+        }
+    }
+
+    /// Type of the value of a key, None if it doesn't exist
+    pub async fn value_type(&self, key: &Key) -> Option<ValueType> {
+        self.load(key).await;
+        match self.index.get(key.hash) {
+            Some(meta) if !meta.is_tombstone() && !meta.is_expired(crate::time::now()) => Some(meta.visible_type()),
+            _ => None,
+        }
+    }
+
+    /// Type and size of the value, read from the index only unless the key
+    /// is a delta: its size is only known once collapsed
+    pub async fn value_size(&self, key: &Key) -> Result<Option<(ValueType, usize)>, DataStoreError> {
+        self.load(key).await;
+        match self.index.get(key.hash) {
+            Some(meta) if meta.value_type == ValueType::Merge && !meta.is_expired(crate::time::now()) => {
+                let record = self.read(&meta).await?;
+                Ok(Some((record.value_type, record.value.len())))
+            }
+            Some(meta) if !meta.is_tombstone() && !meta.is_expired(crate::time::now()) => Ok(Some((meta.value_type, meta.value_size as usize))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Return the introspection information of a key without counting it as an access
+    pub async fn object_info(&self, key: &Key) -> Option<ObjectInfo> {
+        self.load(key).await;
+        let meta = self.index.get(key.hash)?;
+        if meta.is_tombstone() || meta.is_expired(crate::time::now()) {
+            return None;
+        }
+        let location = match meta.data_ptr {
+            RecordPtr::MemTable(_) => Location::MemTable,
+            RecordPtr::DiskTable(_) => Location::DiskTable,
+            RecordPtr::Compacting(_) => Location::Compacting,
+        };
+        Some(ObjectInfo {
+            value_type: meta.visible_type(),
+            value_size: meta.value_size as usize,
+            location,
+            access: meta.access,
+        })
+    }
+
+    /// Bytes of the disktables of the keyspace, including the versions replaced
+    /// or deleted since they were written
+    pub fn approximate_disk_size(&self) -> usize {
+        self.table_manager.data_size(self.keyspace).0
+    }
+
+    /// Number of keys, expired keys are counted until they are swept
+    pub fn approximate_key_count(&self) -> usize {
+        self.index.live_len() + self.table_manager.evicted(self.keyspace)
+    }
+
+    /// Keys of `slots` and their share of the live data of the disktables,
+    /// assuming records of similar sizes. Evicted keys are assumed to be
+    /// spread over the slots like the ones in the index
+    pub fn approximate_size_of_slots(&self, slots: RangeInclusive<u16>) -> SizeEstimate {
+        let indexed = self.index.live_len();
+        if indexed == 0 {
+            return SizeEstimate::default();
+        }
+        let ratio = slots.map(|slot| self.index.count_in_slot(slot)).sum::<usize>() as f64 / indexed as f64;
+        SizeEstimate {
+            keys: (ratio * self.approximate_key_count() as f64).round() as usize,
+            bytes: (ratio * self.table_manager.data_size(self.keyspace).1 as f64) as usize,
+        }
+    }
+
+    /// Number of keys of `slot` in the index (see `Index::count_in_slot`)
+    pub fn count_keys_in_slot(&self, slot: u16) -> usize {
+        self.index.count_in_slot(slot)
+    }
+
+    /// Return the access information of a key without counting it as an access
+    pub fn get_access_stats(&self, key: &Key) -> Option<AccessStats> {
+        self.index.get(key.hash).map(|meta| meta.access)
+    }
+
+    /// Check the disktables of this keyspace and the references to them (see
+    /// `verify::Problem`), tables with a problem are marked as suspect. Entries
+    /// moved while the tables are read (flush, reclaim) may be reported too,
+    /// it is meant for an idle datastore
+    pub async fn verify(&self) -> verify::Report {
+        // Records referenced by the index, the merge bases and the range tombstones
+        let mut referenced: HashMap<Rc<String>, Vec<RecordMetadata>> = HashMap::new();
+        let bases: Vec<RecordMetadata> = self.merge_bases.borrow().values().cloned().collect();
+        let tombstones = self.range_tombstones.all().into_iter().map(|t| t.meta);
+        for meta in self.index.all().into_iter().chain(bases).chain(tombstones) {
+            let table = match &meta.data_ptr {
+                RecordPtr::DiskTable(ptr) => ptr.disktable.clone(),
+                RecordPtr::Compacting(ptr) => ptr.disktable.clone(),
+                RecordPtr::MemTable(_) => continue,
+            };
+            referenced.entry(table).or_default().push(meta);
+        }
+
+        let mut report = verify::Report::default();
+        for table in self.table_manager.get_tables(self.keyspace) {
+            // About to be deleted, nothing points to them anymore
+            if table.is_marked_for_deletion() {
+                continue;
+            }
+            let metas = referenced.remove(table.name()).unwrap_or_default();
+            report.tables += 1;
+            report.records += table.get_stats().count;
+            report.problems.extend(table.verify(&metas).await);
+        }
+        for (table, metas) in referenced {
+            report.problems.extend(metas.into_iter().map(|meta| verify::Problem::MissingTable {
+                hash: meta.hash,
+                table: table.clone(),
+            }));
+        }
+        report
+    }
+
+    /// Return number of active records from memtable/index
+    pub fn get_stats(&self) -> Stats {
+        Stats {
+            index_len: self.index.len(),
+            memtable_refs: self.memtable_manager.references(),
+            memtable_bytes: self.memtable_manager.byte_size(),
+            disktable_refs: self.table_manager.references(self.keyspace),
+            disktable_manager_stats: self.table_manager.get_stats(),
+            all_records: self.memtable_manager.len() + self.table_manager.len(self.keyspace),
+            skipped_writes: self.skipped_writes.get(),
+            corrupted_reads: self.corrupted_reads.get(),
+            merge_bases: self.merge_bases.borrow().len(),
+            range_tombstones: self.range_tombstones.len(),
+            evicted: self.table_manager.evicted(self.keyspace),
+            tombstones: self.index.tombstones(),
+            counters: self.counters.get(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::datastore::disktable::{
+        block::{Corruption, Footer, LEGACY_VERSION, TABLE_HEADER_SIZE},
+        DiskTableStats,
+    };
+    use crate::datastore::encryption::StaticKeys;
+
+    fn assert_value_eq(r: &Record, expected: &str) {
+        assert_eq!(std::str::from_utf8(&r.value).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_datastore_for_consistency() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_for_consistency")).await;
+            storage.init().await;
+            storage.truncate().await;
+            let opt = storage.get(&Key::new("test".to_string())).await.unwrap();
+            assert!(opt.is_none());
+            storage.get_stats().assert_not_corrupted();
+
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            let opt = storage.get(&Key::new("test1".to_string())).await.unwrap();
+            assert_value_eq(&opt.unwrap(), "foo1");
+            storage.get_stats().assert_not_corrupted();
+
+            storage.set(Record::new("test2".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            let opt = storage.get(&Key::new("test2".to_string())).await.unwrap();
+            assert_value_eq(&opt.unwrap(), "foo2");
+            storage.get_stats().assert_not_corrupted();
+
+            storage.set(Record::new("test3".to_string(), Vec::from("foo99".as_bytes()))).unwrap();
+            let opt = storage.get(&Key::new("test3".to_string())).await.unwrap();
+            assert_value_eq(&opt.unwrap(), "foo99");
+            storage.get_stats().assert_not_corrupted();
+
+            storage.force_flush().await.unwrap();
+            storage.get_stats().assert_not_corrupted();
+
+            storage.set(Record::new("test1".to_string(), Vec::from("foo3".as_bytes()))).unwrap();
+
+            let opt = storage.get(&Key::new("test1".to_string())).await.unwrap();
+            assert_value_eq(&opt.unwrap(), "foo3");
+            storage.get_stats().assert_not_corrupted();
+
+            let opt = storage.get(&Key::new("test99999".to_string())).await.unwrap(); // unknown key
+            assert!(opt.is_none());
+            storage.get_stats().assert_not_corrupted();
+
+            assert!(storage.delete(&Key::new("test3".to_string())).unwrap());
+            let opt = storage.get(&Key::new("test3".to_string())).await.unwrap();
+            assert!(opt.is_none());
+            assert!(!storage.delete(&Key::new("test3".to_string())).unwrap());
+            assert!(!storage.delete(&Key::new("test99999".to_string())).unwrap());
+            storage.get_stats().assert_not_corrupted();
+            storage.force_flush().await.unwrap();
+            storage.get_stats().assert_not_corrupted();
+            println!("{:?}", storage.get_stats());
+
+            let opt = storage.get(&Key::new("test1".to_string())).await.unwrap();
+            assert_value_eq(&opt.unwrap(), "foo3");
+
+            let mut storage2 = DataStore::new(PathBuf::from(r"./data/test/test_datastore_for_consistency")).await;
+            storage2.init().await;
+            storage2.get_stats().assert_not_corrupted();
+
+            let opt = storage2.get(&Key::new("test1".to_string())).await.unwrap();
+            assert!(opt.is_none());
+            storage2.get_stats().assert_not_corrupted();
+
+            storage2.rebuild_index_from_disk().await;
+            storage2.get_stats().assert_not_corrupted();
+            assert_eq!(storage2.value_type(&Key::new("test1".to_string())).await, Some(ValueType::String));
+            assert_eq!(storage2.value_type(&Key::new("test3".to_string())).await, None);
+
+            let opt = storage2.get(&Key::new("test1".to_string())).await.unwrap();
+            assert_value_eq(&opt.unwrap(), "foo3");
+
+            let opt = storage2.get(&Key::new("test2".to_string())).await.unwrap();
+            assert_value_eq(&opt.unwrap(), "foo2");
+            storage2.get_stats().assert_not_corrupted();
+
+            // Should have been deleted
+            let opt = storage2.get(&Key::new("test3".to_string())).await.unwrap();
+            assert!(opt.is_none());
+            storage2.get_stats().assert_not_corrupted();
+
+            println!("{:?}", storage2.get_stats());
+            storage2.reclaim_all_disktables().await;
+            println!("{:?}", storage2.get_stats());
+            assert_eq!(storage2.table_manager.get_disktables_marked_for_deletion().len(), 0);
+            storage2.force_flush().await.unwrap();
+            assert_eq!(storage2.table_manager.get_disktables_marked_for_deletion().len(), 2);
+            storage2.table_manager.delete_disktables_marked_for_deletion();
+            storage2.get_stats().assert_not_corrupted();
+
+            let opt = storage.get(&Key::new("test1".to_string())).await.unwrap();
+            assert_value_eq(&opt.unwrap(), "foo3");
+
+            let opt = storage.get(&Key::new("test2".to_string())).await.unwrap();
+            assert_value_eq(&opt.unwrap(), "foo2");
+
+            let opt = storage2.get(&Key::new("test3".to_string())).await.unwrap();
+            assert!(opt.is_none());
+
+            println!("{:?}", storage.get_stats());
+        });
+    }
+
+    #[test]
+    fn test_datastore_for_flush_and_compactions() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_for_flush_and_compactions")).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.set(Record::new("test2".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            storage.set(Record::new("test3".to_string(), Vec::from("foo3".as_bytes()))).unwrap();
+            storage.set(Record::new("test4".to_string(), Vec::from("foo4".as_bytes()))).unwrap();
+            storage.set(Record::new("test5".to_string(), Vec::from("foo5".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+
+            storage.get_stats().assert_not_corrupted();
+
+            storage.reclaim_all_disktables().await;
+            storage.get_stats().assert_not_corrupted();
+
+            // Try to flush empty memtable: should not add a new disktable
+            storage.force_flush().await.unwrap();
+            storage.table_manager.delete_disktables_marked_for_deletion();
+            assert_eq!(storage.get_stats().disktable_manager_stats.table_stats.len(), 1);
+            storage.get_stats().assert_not_corrupted();
+
+            // Reclaiming with only one table doesn't do anything
+            storage.maybe_run_one_reclaim().await;
+            assert_eq!(storage.get_stats().disktable_manager_stats.table_stats.len(), 1);
+            storage.get_stats().assert_not_corrupted();
+
+            storage.set(Record::new("test6".to_string(), Vec::from("foo6".as_bytes()))).unwrap();
+            storage.set(Record::new("test7".to_string(), Vec::from("foo7".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            assert_eq!(storage.get_stats().disktable_manager_stats.table_stats.len(), 2);
+            storage.get_stats().assert_not_corrupted();
+
+            println!("{:?}", storage.get_stats());
+            // No reason to make a compaction
+            storage.maybe_run_one_reclaim().await;
+            assert_eq!(storage.get_stats().disktable_manager_stats.table_stats.len(), 2);
+            storage.set(Record::new("test3".to_string(), Vec::from("foo31".as_bytes()))).unwrap();
+            storage.set(Record::new("test4".to_string(), Vec::from("foo41".as_bytes()))).unwrap();
+
+            storage.maybe_run_one_reclaim().await;
+            storage.force_flush().await.unwrap();
+            storage.get_stats().assert_not_corrupted();
+            assert_eq!(storage.table_manager.get_disktables_marked_for_deletion().len(), 1);
+            storage.table_manager.delete_disktables_marked_for_deletion();
+            assert_eq!(storage.get_stats().disktable_manager_stats.table_stats.len(), 2);
+
+            // if we delete all data in a disktable, it should be ready for deletion
+            storage.delete(&Key::new("test6".to_string())).unwrap();
+            storage.delete(&Key::new("test7".to_string())).unwrap();
+            storage.force_flush().await.unwrap();
+            assert_eq!(storage.table_manager.get_disktables_marked_for_deletion().len(), 1);
+            storage.table_manager.delete_disktables_marked_for_deletion();
+            storage.get_stats().assert_not_corrupted();
+
+            storage.reclaim_all_disktables().await;
+            storage.get_stats().assert_not_corrupted();
+            storage.force_flush().await.unwrap();
+            storage.table_manager.delete_disktables_marked_for_deletion();
+            assert_eq!(storage.table_manager.get_disktables_marked_for_deletion().len(), 0);
+        });
+    }
+
+    #[test]
+    fn test_datastore_deduplicate_identical_sets() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let config = Config {
+                deduplicate_identical_sets: true,
+                ttl_jitter_ratio: 0.5,
+                ..Config::default()
+            };
+            let mut storage = DataStore::new_with_config(PathBuf::from(r"./data/test/test_datastore_deduplicate_identical_sets"), config).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            assert_eq!(storage.get_stats().skipped_writes, 1);
+
+            storage.set(Record::new("test1".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            assert_eq!(storage.get_stats().skipped_writes, 1);
+            assert_value_eq(&storage.get(&Key::new("test1".to_string())).await.unwrap().unwrap(), "foo2");
+            storage.get_stats().assert_not_corrupted();
+
+            // The expiration is written as it is, the jitter is only added to relative ttls
+            let mut record = Record::new("test2".to_string(), Vec::from("foo".as_bytes()));
+            record.expire_at = Some(record.timestamp + 10_000_000_000);
+            storage.set(record.clone()).unwrap();
+            storage.set(record.clone()).unwrap();
+            assert_eq!(storage.get_stats().skipped_writes, 2);
+            assert_eq!(storage.get(&record.key).await.unwrap().unwrap().expire_at, record.expire_at);
+
+            // Same value with new flags
+            record.flags = 3;
+            storage.set(record.clone()).unwrap();
+            assert_eq!(storage.get_stats().skipped_writes, 2);
+            assert_eq!(storage.get(&record.key).await.unwrap().unwrap().flags, 3);
+
+            // Versions on disk are not compared
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("test1".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            assert_eq!(storage.get_stats().skipped_writes, 2);
+            storage.get_stats().assert_not_corrupted();
+        });
+    }
+
+    #[test]
+    fn test_datastore_value_range() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_value_range")).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            let key = Key::new("test1".to_string());
+            storage
+                .set(Record::new("test1".to_string(), Vec::from("Hello World".as_bytes())))
+                .unwrap();
+            assert_eq!(storage.value_size(&key).await, Ok(Some((ValueType::String, 11))));
+            assert_eq!(storage.get_value_range(&key, 6..11).await.unwrap().unwrap(), b"World");
+
+            // Read from the disktable without reading the whole record
+            storage.force_flush().await.unwrap();
+            assert_eq!(storage.get_value_range(&key, 0..5).await.unwrap().unwrap(), b"Hello");
+            assert_eq!(storage.get_value_range(&key, 6..100).await.unwrap().unwrap(), b"World");
+            assert_eq!(storage.get_value_range(&Key::new("test2".to_string()), 0..5).await, Ok(None));
+            assert_eq!(storage.value_size(&Key::new("test2".to_string())).await, Ok(None));
+        });
+    }
+
+    #[test]
+    fn test_datastore_unlink() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_unlink")).await;
+            storage.init().await;
+            storage.truncate().await;
+            let key1 = Key::new("test1".to_string());
+            let key2 = Key::new("test2".to_string());
+
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("test2".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            assert!(storage.unlink(&key1).await);
+            assert!(storage.unlink(&key2).await);
+            assert!(!storage.unlink(&key2).await);
+            assert_eq!(storage.version(&key1).await, None);
+
+            // Flushing the record must not bring the key back
+            storage.force_flush().await.unwrap();
+            assert!(storage.get(&key2).await.unwrap().is_none());
+            assert_eq!(storage.value_type(&key1).await, None);
+            storage.get_stats().assert_not_corrupted();
+
+            assert_eq!(storage.sweep_expired().await, 1);
+            assert!(storage.index.get(key1.hash).unwrap().is_tombstone());
+            storage.get_stats().assert_not_corrupted();
+        });
+    }
+
+    #[test]
+    fn test_datastore_reload() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_reload")).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.set(Record::new("test2".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            storage
+                .set(Record {
+                    flags: 42,
+                    ..Record::new("test3".to_string(), Vec::from("foo4".as_bytes()))
+                })
+                .unwrap();
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("test1".to_string(), Vec::from("foo3".as_bytes()))).unwrap();
+            storage.delete(&Key::new("test2".to_string())).unwrap();
+
+            storage.reload().await;
+            storage.get_stats().assert_not_corrupted();
+            assert_eq!(storage.count_keys_in_slot(key_slot(b"test1")), 1);
+            assert_eq!(storage.count_keys_in_slot(key_slot(b"test2")), 0);
+            let keys = storage.keys_in_slot(key_slot(b"test1"), 10).await;
+            assert_eq!(keys.iter().map(|key| key.string.as_str()).collect::<Vec<_>>(), vec!["test1"]);
+            assert_eq!(
+                storage.object_info(&Key::new("test1".to_string())).await.unwrap().location,
+                Location::DiskTable
+            );
+            assert_value_eq(&storage.get(&Key::new("test1".to_string())).await.unwrap().unwrap(), "foo3");
+            assert!(storage.get(&Key::new("test2".to_string())).await.unwrap().is_none());
+            assert_eq!(storage.get(&Key::new("test3".to_string())).await.unwrap().unwrap().flags, 42);
+        });
+    }
+
+    #[test]
+    fn test_datastore_rewrite_old_format() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_rewrite_old_format")).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            // Same table with the footer of the last format without version
+            let path = storage.table_manager.live_table_paths().pop().unwrap();
+            let mut data = fs::read(&path).unwrap();
+            let footer = Footer::parse(&data).unwrap();
+            data.truncate(data.len() - footer.size());
+            Footer {
+                version: LEGACY_VERSION,
+                ..footer
+            }
+            .write(&mut data);
+            fs::write(&path, data).unwrap();
+            storage.reload().await;
+            let versions = |storage: &DataStore| -> Vec<u32> {
+                let stats = storage.get_stats().disktable_manager_stats.table_stats;
+                stats.iter().map(|(_, stats)| stats.version).collect()
+            };
+            assert_eq!(versions(&storage), vec![LEGACY_VERSION]);
+            assert_value_eq(&storage.get(&Key::new("test1".to_string())).await.unwrap().unwrap(), "foo1");
+
+            // Rewritten by the next compaction even though it is full
+            storage.maybe_run_one_reclaim().await;
+            storage.force_flush().await.unwrap();
+            storage.clean_unused_disktables().await;
+            assert_eq!(versions(&storage), vec![disktable::FORMAT_VERSION]);
+            assert_value_eq(&storage.get(&Key::new("test1".to_string())).await.unwrap().unwrap(), "foo1");
+            storage.get_stats().assert_not_corrupted();
+        });
+    }
+
+    #[test]
+    fn test_datastore_manifest() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let directory = PathBuf::from(r"./data/test/test_datastore_manifest");
+            let mut storage = DataStore::new(directory.clone()).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            let tables = storage.table_manager.live_table_paths();
+            let name = tables[0].file_name().unwrap().to_str().unwrap();
+            assert_eq!(fs::read_to_string(directory.join(disktable::MANIFEST)).unwrap(), format!("{}\n", name));
+            // Tables are written under a temporary name then renamed
+            assert!(!tables[0].with_extension("tmp").exists());
+
+            // A table left by a crash before being listed is not loaded
+            fs::copy(&tables[0], directory.join("1-v8.data")).unwrap();
+            storage.reload().await;
+            assert_eq!(storage.get_stats().disktable_manager_stats.table_stats.len(), 1);
+            storage.get_stats().assert_not_corrupted();
+            fs::remove_file(directory.join("1-v8.data")).unwrap();
+
+            storage.delete(&Key::new("test1".to_string())).unwrap();
+            storage.force_flush().await.unwrap();
+            storage.clean_unused_disktables().await;
+            assert!(!fs::read_to_string(directory.join(disktable::MANIFEST)).unwrap().contains(name));
+        });
+    }
+
+    #[test]
+    fn test_datastore_large_disktable() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let config = Config {
+                memtable_max_size_bytes: 64 * 1024 * 1024,
+                ..Config::default()
+            };
+            let mut storage = DataStore::new_with_config(PathBuf::from(r"./data/test/test_datastore_large_disktable"), config).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            // More records than a u16 can count, in a single table
+            for i in 0..70_000 {
+                storage.set(Record::new(format!("key{}", i), Vec::from("foo".as_bytes()))).unwrap();
+            }
+            storage.force_flush().await.unwrap();
+            storage.reload().await;
+            let stats = storage.get_stats();
+            assert_eq!(stats.disktable_manager_stats.table_stats.len(), 1);
+            assert_eq!(stats.disktable_manager_stats.table_stats[0].1.count, 70_000);
+            assert_eq!(stats.index_len(), 70_000);
+            assert_value_eq(&storage.get(&Key::new("key69999".to_string())).await.unwrap().unwrap(), "foo");
+            stats.assert_not_corrupted();
+        });
+    }
+
+    #[test]
+    fn test_datastore_block_compression() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let directory = PathBuf::from(r"./data/test/test_datastore_block_compression");
+            let config = Config {
+                block_compression: Compression::Zstd,
+                ..Config::default()
+            };
+            let mut storage = DataStore::new_with_config(directory.clone(), config).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            storage.set(Record::new("test1".to_string(), "foo1".repeat(1000).into_bytes())).unwrap();
+            storage.set(Record::new("test2".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            assert_eq!(
+                storage
+                    .get_value_range(&Key::new("test1".to_string()), 3996..4000)
+                    .await
+                    .unwrap()
+                    .unwrap(),
+                b"foo1"
+            );
+
+            // Tables keep the codec they were written with
+            let config = Config {
+                block_compression: Compression::Lz4,
+                ..Config::default()
+            };
+            let mut reopened = DataStore::new_with_config(directory, config).await;
+            reopened.init().await;
+            reopened.rebuild_index_from_disk().await;
+            reopened.get_stats().assert_not_corrupted();
+            assert_value_eq(
+                &reopened.get(&Key::new("test1".to_string())).await.unwrap().unwrap(),
+                &"foo1".repeat(1000),
+            );
+            assert_value_eq(&reopened.get(&Key::new("test2".to_string())).await.unwrap().unwrap(), "foo2");
+        });
+    }
+
+    #[test]
+    fn test_datastore_mmap_reads() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let directory = PathBuf::from(r"./data/test/test_datastore_mmap_reads");
+            let config = Config {
+                disktable_mmap_reads: true,
+                block_compression: Compression::Lz4,
+                ..Config::default()
+            };
+            let mut storage = DataStore::new_with_config(directory.clone(), config.clone()).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            storage.set(Record::new("test1".to_string(), "foo1".repeat(1000).into_bytes())).unwrap();
+            storage.set(Record::new("test2".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            assert_value_eq(&storage.get(&Key::new("test2".to_string())).await.unwrap().unwrap(), "foo2");
+
+            let mut reopened = DataStore::new_with_config(directory, config).await;
+            reopened.init().await;
+            reopened.rebuild_index_from_disk().await;
+            assert_value_eq(
+                &reopened.get(&Key::new("test1".to_string())).await.unwrap().unwrap(),
+                &"foo1".repeat(1000),
+            );
+            assert_eq!(
+                reopened.get_value_range(&Key::new("test2".to_string()), 0..3).await.unwrap().unwrap(),
+                b"foo"
+            );
+        });
+    }
+
+    #[test]
+    fn test_datastore_direct_io() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let directory = PathBuf::from(r"./data/test/test_datastore_direct_io");
+            let config = Config {
+                direct_io: true,
+                ..Config::default()
+            };
+            let mut storage = DataStore::new_with_config(directory.clone(), config.clone()).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            for i in 0..100 {
+                storage
+                    .set(Record::new(format!("test{}", i), format!("foo{}", i).repeat(100).into_bytes()))
+                    .unwrap();
+            }
+            storage.force_flush().await.unwrap();
+            // More than a page of log
+            for i in 0..20 {
+                storage
+                    .set(Record::new(format!("unflushed{}", i), format!("bar{}", i).repeat(100).into_bytes()))
+                    .unwrap();
+            }
+            assert_value_eq(
+                &storage.get(&Key::new("test42".to_string())).await.unwrap().unwrap(),
+                &"foo42".repeat(100),
+            );
+
+            // Tables and log segments are not padded to the alignment
+            let mut reopened = DataStore::new_with_config(directory, config).await;
+            reopened.init().await;
+            reopened.rebuild_index_from_disk().await;
+            reopened.get_stats().assert_not_corrupted();
+            assert_eq!(reopened.get_stats().index_len(), 120);
+            assert_value_eq(
+                &reopened.get(&Key::new("test99".to_string())).await.unwrap().unwrap(),
+                &"foo99".repeat(100),
+            );
+            assert_value_eq(
+                &reopened.get(&Key::new("unflushed19".to_string())).await.unwrap().unwrap(),
+                &"bar19".repeat(100),
+            );
+        });
+    }
+
+    #[test]
+    fn test_datastore_corrupted_read() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let directory = PathBuf::from(r"./data/test/test_datastore_corrupted_read");
+            let mut storage = DataStore::new(directory.clone()).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            let key = Key::new("test1".to_string());
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+
+            let table = fs::read_dir(&directory)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .find(|path| path.extension().is_some_and(|e| e == "data"))
+                .unwrap();
+            let mut data = fs::read(&table).unwrap();
+            // Last byte of the value, in the first data block
+            data[TABLE_HEADER_SIZE + RECORD_HEADER_SIZE + 8] ^= 1;
+            fs::write(&table, data).unwrap();
+
+            assert_eq!(
+                storage.get(&key).await.err(),
+                Some(DataStoreError::Corruption(Corruption::ChecksumMismatch))
+            );
+            assert!(storage.get(&key).await.is_err());
+            assert_eq!(
+                storage.get_value_range(&key, 0..4).await,
+                Err(DataStoreError::Corruption(Corruption::ChecksumMismatch))
+            );
+            assert_eq!(storage.get_stats().corrupted_reads(), 3);
+            // Not compacted anymore
+            let tables = storage.table_manager.get_tables(DEFAULT_KEYSPACE);
+            assert!(tables[0].get_stats().suspect);
+            let stats = vec![(
+                tables[0].name().clone(),
+                DiskTableStats {
+                    usage_ratio: 0.0,
+                    ..tables[0].get_stats()
+                },
+            )];
+            assert!(storage.compaction_picker.pick(&stats).is_empty());
+        });
+    }
+
+    #[test]
+    fn test_datastore_direct_compaction() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let config = Config {
+                direct_compaction: true,
+                index_max_entries: 80,
+                ..Config::default()
+            };
+            let directory = PathBuf::from(r"./data/test/test_datastore_direct_compaction");
+            let mut storage = DataStore::new_with_config(directory, config).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            for i in 0..100 {
+                storage.set(Record::new(format!("test{:03}", i), Vec::from("foo".as_bytes()))).unwrap();
+            }
+            storage.force_flush().await.unwrap();
+            for i in 0..50 {
+                storage.set(Record::new(format!("test{:03}", i), Vec::from("bar".as_bytes()))).unwrap();
+            }
+            storage.delete_range("test090".to_string().."test095".to_string()).unwrap();
+            storage.force_flush().await.unwrap();
+            storage.delete(&Key::new("test060".to_string())).unwrap();
+            storage.force_flush().await.unwrap();
+            // Some entries of the first table are only on disk
+            assert!(storage.evict_cold_entries() > 0);
+
+            let tables = storage.table_manager.list_tables(DEFAULT_KEYSPACE);
+            assert_eq!(tables.len(), 3);
+            storage.compact_disktables(&tables).await.unwrap();
+            storage.clean_unused_disktables().await;
+            assert_eq!(storage.table_manager.list_tables(DEFAULT_KEYSPACE).len(), 1);
+            // Nothing went through the memtables
+            assert!(storage.memtable_manager.get_all_unflushed_memtables().iter().all(|m| m.is_empty()));
+            storage.get_stats().assert_not_corrupted();
+            assert!(storage.verify().await.is_ok());
+
+            for reload in [false, true] {
+                if reload {
+                    storage.reload().await;
+                }
+                for i in 0..100 {
+                    let value = storage.get(&Key::new(format!("test{:03}", i))).await.unwrap();
+                    match i {
+                        0..=49 => assert_value_eq(&value.unwrap(), "bar"),
+                        60 | 90..=94 => assert!(value.is_none()),
+                        _ => assert_value_eq(&value.unwrap(), "foo"),
+                    }
+                }
+            }
+            assert_eq!(storage.get_stats().counters.compactions, 1);
+        });
+    }
+
+    #[test]
+    fn test_datastore_quarantine() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let directory = PathBuf::from(r"./data/test/test_datastore_quarantine");
+            let _ = fs::remove_dir_all(&directory);
+            let mut storage = DataStore::new(directory.clone()).await;
+            storage.init().await;
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("test2".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            let corrupted = storage.table_manager.list_tables(DEFAULT_KEYSPACE).into_iter().max().unwrap();
+            drop(storage);
+
+            fs::write(directory.join(corrupted.as_str()), [0u8; 3]).unwrap();
+            for name in ["1-v10.data", "2-v10.data.tmp", "notes.txt"] {
+                fs::write(directory.join(name), "junk").unwrap();
+            }
+            fs::write(directory.join("3-v10.data"), "").unwrap();
+
+            let mut storage = DataStore::new(directory.clone()).await;
+            storage.init().await;
+            storage.rebuild_index_from_disk().await;
+            let mut quarantined: Vec<String> = fs::read_dir(directory.join(disktable::QUARANTINE_DIRECTORY))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            quarantined.sort();
+            let mut expected = vec!["1-v10.data", "2-v10.data.tmp", "3-v10.data", "notes.txt", corrupted.as_str()];
+            expected.sort();
+            assert_eq!(quarantined, expected);
+            // No longer listed
+            assert!(!fs::read_to_string(directory.join(disktable::MANIFEST))
+                .unwrap()
+                .contains(corrupted.as_str()));
+
+            let opt = storage.get(&Key::new("test1".to_string())).await.unwrap();
+            assert_value_eq(&opt.unwrap(), "foo1");
+            assert!(storage.get(&Key::new("test2".to_string())).await.unwrap().is_none());
+            storage.get_stats().assert_not_corrupted();
+        });
+    }
+
+    #[test]
+    fn test_datastore_encryption() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let directory = PathBuf::from(r"./data/test/test_datastore_encryption");
+            let keys = Rc::new(StaticKeys::new(vec![(1, vec![1; 32]), (2, vec![2; 16])]));
+            let config = Config {
+                encryption: Some(Encryption::new(1, keys.clone()).unwrap()),
+                block_compression: Compression::Lz4,
+                ..Config::default()
+            };
+            let mut storage = DataStore::new_with_config(directory.clone(), config).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            storage
+                .set(Record::new("user:1".to_string(), "secret1".repeat(100).into_bytes()))
+                .unwrap();
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("user:2".to_string(), Vec::from("secret2".as_bytes()))).unwrap();
+            // Neither the disktable nor the WAL hold a key or a value in clear
+            for entry in fs::read_dir(&directory).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_file() {
+                    let data = fs::read(&path).unwrap();
+                    assert!(!data.windows(5).any(|w| w == b"user:" || w == b"secre"), "{:?}", path);
+                }
+            }
+            drop(storage);
+
+            // Rotated: the new files are written with key 2, key 1 still reads the old ones
+            let config = Config {
+                encryption: Some(Encryption::new(2, keys).unwrap()),
+                ..Config::default()
+            };
+            let mut reopened = DataStore::new_with_config(directory, config).await;
+            reopened.init().await;
+            reopened.rebuild_index_from_disk().await;
+            assert_value_eq(
+                &reopened.get(&Key::new("user:1".to_string())).await.unwrap().unwrap(),
+                &"secret1".repeat(100),
+            );
+            assert_value_eq(&reopened.get(&Key::new("user:2".to_string())).await.unwrap().unwrap(), "secret2");
+            let stale_keys = |storage: &DataStore| -> Vec<bool> {
+                let stats = storage.get_stats().disktable_manager_stats.table_stats;
+                stats.iter().map(|(_, stats)| stats.stale_key).collect()
+            };
+            assert_eq!(stale_keys(&reopened), vec![true]);
+
+            // The table of the previous key is rewritten by compaction
+            reopened.maybe_run_one_reclaim().await;
+            reopened.force_flush().await.unwrap();
+            reopened.clean_unused_disktables().await;
+            assert_eq!(stale_keys(&reopened), vec![false]);
+            reopened.get_stats().assert_not_corrupted();
+            assert!(reopened.verify().await.is_ok());
+            reopened.reload().await;
+            assert_value_eq(
+                &reopened.get(&Key::new("user:1".to_string())).await.unwrap().unwrap(),
+                &"secret1".repeat(100),
+            );
+        });
+    }
+
+    #[test]
+    fn test_datastore_verify() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let directory = PathBuf::from(r"./data/test/test_datastore_verify");
+            let mut storage = DataStore::new(directory.clone()).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            for i in 0..50 {
+                storage.set(Record::new(format!("test{}", i), Vec::from("foo".as_bytes()))).unwrap();
+            }
+            storage.force_flush().await.unwrap();
+            for i in 0..10 {
+                storage.set(Record::new(format!("test{}", i), Vec::from("bar".as_bytes()))).unwrap();
+            }
+            storage.delete(&Key::new("test10".to_string())).unwrap();
+            storage.force_flush().await.unwrap();
+
+            let report = storage.verify().await;
+            assert!(report.is_ok(), "{:?}", report);
+            assert_eq!((report.tables, report.records), (2, 61));
+
+            let table = storage
+                .table_manager
+                .get_tables(DEFAULT_KEYSPACE)
+                .into_iter()
+                .find(|t| t.get_stats().count == 50)
+                .unwrap();
+            let path = directory.join(table.name().as_str());
+            let mut data = fs::read(&path).unwrap();
+            data[TABLE_HEADER_SIZE + RECORD_HEADER_SIZE + 8] ^= 1;
+            fs::write(&path, data).unwrap();
+
+            let report = storage.verify().await;
+            assert_eq!(
+                report.problems,
+                vec![verify::Problem::Block {
+                    table: table.name().clone(),
+                    block: 0,
+                    error: DataStoreError::Corruption(Corruption::ChecksumMismatch),
+                }]
+            );
+            assert!(table.get_stats().suspect);
+            // Same from the files only
+            let report = verify::verify_directory(&directory).await;
+            assert_eq!(report.problems.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_datastore_keyspaces() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let directory = PathBuf::from(r"./data/test/test_datastore_keyspaces");
+            let mut storage = DataStore::new(directory.clone()).await;
+            storage.init().await;
+            storage.truncate().await;
+            let mut other = storage.open_keyspace(1).await;
+            other.truncate().await;
+            let key = Key::new("key".to_string());
+
+            storage.set(Record::new("key".to_string(), Vec::from("default".as_bytes()))).unwrap();
+            other.set(Record::new("key".to_string(), Vec::from("other".as_bytes()))).unwrap();
+            other.set(Record::new("only".to_string(), Vec::from("other".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            other.force_flush().await.unwrap();
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "default");
+            assert_value_eq(&other.get(&key).await.unwrap().unwrap(), "other");
+            assert!(storage.get(&Key::new("only".to_string())).await.unwrap().is_none());
+            assert_eq!(storage.get_stats().index_len(), 1);
+            storage.get_stats().assert_not_corrupted();
+            other.get_stats().assert_not_corrupted();
+
+            // Compacted into its own keyspace
+            other.reclaim_all_disktables().await;
+            other.force_flush().await.unwrap();
+            storage.clean_unused_disktables().await;
+            assert_value_eq(&other.get(&key).await.unwrap().unwrap(), "other");
+            storage.get_stats().assert_not_corrupted();
+            other.get_stats().assert_not_corrupted();
+            other.delete(&Key::new("only".to_string())).unwrap();
+
+            // The tables are sorted out by keyspace on restart, unflushed writes replayed
+            let mut restarted = DataStore::new(directory).await;
+            restarted.init().await;
+            restarted.rebuild_index_from_disk().await;
+            let restarted_other = restarted.open_keyspace(1).await;
+            restarted.get_stats().assert_not_corrupted();
+            restarted_other.get_stats().assert_not_corrupted();
+            assert_value_eq(&restarted.get(&key).await.unwrap().unwrap(), "default");
+            assert_value_eq(&restarted_other.get(&key).await.unwrap().unwrap(), "other");
+            assert!(restarted_other.get(&Key::new("only".to_string())).await.unwrap().is_none());
+
+            // Truncating a keyspace leaves the other ones
+            restarted_other.truncate().await;
+            assert!(restarted_other.get(&key).await.unwrap().is_none());
+            assert_value_eq(&restarted.get(&key).await.unwrap().unwrap(), "default");
+            restarted.get_stats().assert_not_corrupted();
+        });
+    }
+
+    #[test]
+    fn test_datastore_bounded_index() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let config = Config {
+                index_max_entries: 10,
+                ..Config::default()
+            };
+            let mut storage = DataStore::new_with_config(PathBuf::from(r"./data/test/test_datastore_bounded_index"), config).await;
+            storage.init().await;
+            storage.truncate().await;
+            let keys: Vec<Key> = (0..50).map(|i| Key::new(format!("k{}", i))).collect();
+
+            for i in 0..50 {
+                storage.set(Record::new(format!("k{}", i), Vec::from("v1".as_bytes()))).unwrap();
+            }
+            // Only the entries of flushed records can be evicted
+            assert_eq!(storage.evict_cold_entries(), 0);
+            storage.force_flush().await.unwrap();
+            assert_eq!(storage.evict_cold_entries(), 40);
+            assert_eq!(storage.get_stats().index_len(), 10);
+            storage.get_stats().assert_not_corrupted();
+
+            // Loaded back on read
+            for key in &keys {
+                assert_value_eq(&storage.get(key).await.unwrap().unwrap(), "v1");
+            }
+            assert_eq!(storage.get_stats().index_len(), 50);
+            assert!(storage.get(&Key::new("missing".to_string())).await.unwrap().is_none());
+            storage.get_stats().assert_not_corrupted();
+
+            // Evicted keys are still listed by range reads and scans
+            assert_eq!(storage.evict_cold_entries(), 40);
+            assert_eq!(storage.range(..).await.unwrap().len(), 50);
+            assert_eq!(storage.evict_cold_entries(), 40);
+            assert_eq!(storage.scan(0, 100).await.0.len(), 50);
+            assert_eq!(storage.get_stats().index_len(), 50);
+            storage.get_stats().assert_not_corrupted();
+
+            // Written again without being loaded, the evicted version is dropped by reclaim
+            storage.evict_cold_entries();
+            storage.set(Record::new("k0".to_string(), Vec::from("v2".as_bytes()))).unwrap();
+            storage.get_stats().assert_not_corrupted();
+            storage.reclaim_all_disktables().await;
+            storage.force_flush().await.unwrap();
+            storage.clean_unused_disktables().await;
+            storage.get_stats().assert_not_corrupted();
+            assert_eq!(storage.get_stats().index_len(), 50);
+            assert_value_eq(&storage.get(&keys[0]).await.unwrap().unwrap(), "v2");
+            assert_value_eq(&storage.get(&keys[49]).await.unwrap().unwrap(), "v1");
+        });
+    }
+
+    #[test]
+    fn test_datastore_metrics() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_metrics")).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            storage.set(Record::new("key1".to_string(), Vec::from("value".as_bytes()))).unwrap();
+            storage.set(Record::new("key2".to_string(), Vec::from("value".as_bytes()))).unwrap();
+            storage.delete(&Key::new("key2".to_string())).unwrap();
+            assert!(storage.get(&Key::new("key1".to_string())).await.unwrap().is_some());
+            assert!(storage.get(&Key::new("key2".to_string())).await.unwrap().is_none());
+            storage.force_flush().await.unwrap();
+            assert_eq!(metrics::get(&storage.get_stats().metrics(), "tombstones"), 1);
+            storage.reclaim_all_disktables().await;
+
+            let stats = storage.get_stats();
+            let metric = |name| metrics::get(&stats.metrics(), name);
+            assert_eq!(metric("keys"), 1);
+            assert_eq!((metric("read_hits"), metric("read_misses")), (1, 1));
+            assert_eq!((metric("writes"), metric("deletes")), (2, 1));
+            assert_eq!((metric("flushes"), metric("compactions")), (1, 1));
+            assert!(metric("bytes_flushed") > 0);
+            assert_eq!(stats.disktables().count(), 1);
+        });
+    }
+
+    #[test]
+    fn test_datastore_block_cache() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
 
         rt.block_on(async {
-            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_for_consistency")).await;
+            let config = Config {
+                block_cache_bytes: 1024 * 1024,
+                ..Config::default()
+            };
+            let mut storage = DataStore::new_with_config(PathBuf::from(r"./data/test/test_datastore_block_cache"), config).await;
             storage.init().await;
             storage.truncate().await;
-            let opt = storage.get(&Key::new("test".to_string())).await;
-            assert!(opt.is_none());
+            let key = Key::new("key".to_string());
+
+            storage.set(Record::new("key".to_string(), Vec::from("value".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "value");
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "value");
+            assert_eq!(storage.get_value_range(&key, 1..3).await.unwrap().unwrap(), b"al");
+            let stats = storage.get_stats().disktable_manager_stats;
+            assert_eq!((stats.block_cache_hits, stats.block_cache_misses), (2, 1));
+            assert!(stats.block_cache_bytes > 0);
+
+            // Blocks of removed tables are dropped
+            storage.reclaim_all_disktables().await;
+            storage.force_flush().await.unwrap();
+            storage.clean_unused_disktables().await;
+            assert_eq!(storage.get_stats().disktable_manager_stats.block_cache_bytes, 0);
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "value");
             storage.get_stats().assert_not_corrupted();
+        });
+    }
 
-            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes())));
-            let opt = storage.get(&Key::new("test1".to_string())).await;
-            assert_value_eq(&opt.unwrap(), "foo1");
+    #[test]
+    fn test_datastore_get_many() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let config = Config {
+                block_cache_bytes: 1024 * 1024,
+                ..Config::default()
+            };
+            let mut storage = DataStore::new_with_config(PathBuf::from(r"./data/test/test_datastore_get_many"), config).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            for i in 0..3 {
+                storage.set(Record::new(format!("disk{}", i), format!("v{}", i).into_bytes())).unwrap();
+            }
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("mem".to_string(), Vec::from("m".as_bytes()))).unwrap();
+
+            let keys: Vec<Key> = ["disk2", "missing", "mem", "disk0", "disk1"]
+                .iter()
+                .map(|k| Key::new(k.to_string()))
+                .collect();
+            let records = storage.get_many(&keys).await;
+            let values: Vec<Option<Vec<u8>>> = records.into_iter().map(|r| r.unwrap().map(|r| r.value)).collect();
+            assert_eq!(
+                values,
+                vec![
+                    Some(b"v2".to_vec()),
+                    None,
+                    Some(b"m".to_vec()),
+                    Some(b"v0".to_vec()),
+                    Some(b"v1".to_vec())
+                ]
+            );
+            // The three records on disk share a block, read once
+            let stats = storage.get_stats();
+            assert_eq!(
+                (
+                    stats.disktable_manager_stats.block_cache_hits,
+                    stats.disktable_manager_stats.block_cache_misses
+                ),
+                (0, 1)
+            );
+            assert_eq!((stats.counters.read_hits, stats.counters.read_misses), (4, 1));
+            stats.assert_not_corrupted();
+        });
+    }
+
+    #[test]
+    fn test_datastore_index_checkpoint() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let directory = PathBuf::from(r"./data/test/test_datastore_index_checkpoint");
+            let mut storage = DataStore::new(directory.clone()).await;
+            storage.init().await;
+            storage.truncate().await;
+            let key = |i: usize| Key::new(format!("k{}", i));
+
+            for i in 0..20 {
+                storage.set(Record::new(format!("k{}", i), Vec::from("v1".as_bytes()))).unwrap();
+            }
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("k0".to_string(), Vec::from("v2".as_bytes()))).unwrap();
+            storage.checkpoint_index();
+
+            // Written after the checkpoint: in a new table or only in the WAL
+            storage.set(Record::new("k1".to_string(), Vec::from("v2".as_bytes()))).unwrap();
+            storage.delete(&key(2)).unwrap();
+            storage.set(Record::new("k20".to_string(), Vec::from("v1".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("k3".to_string(), Vec::from("v2".as_bytes()))).unwrap();
+
+            let mut restarted = DataStore::new(directory.clone()).await;
+            restarted.init().await;
+            restarted.rebuild_index_from_disk().await;
+            restarted.get_stats().assert_not_corrupted();
+            for (i, expected) in [(0, "v2"), (1, "v2"), (3, "v2"), (4, "v1"), (20, "v1")] {
+                assert_value_eq(&restarted.get(&key(i)).await.unwrap().unwrap(), expected);
+            }
+            assert!(restarted.get(&key(2)).await.unwrap().is_none());
+            assert_eq!(restarted.keys("", |_| true).len(), 20);
+
+            // The covered tables reclaimed since are left to the newer ones
+            restarted.reclaim_all_disktables().await;
+            restarted.force_flush().await.unwrap();
+            restarted.clean_unused_disktables().await;
+            let mut restarted = DataStore::new(directory.clone()).await;
+            restarted.init().await;
+            restarted.rebuild_index_from_disk().await;
+            restarted.get_stats().assert_not_corrupted();
+            assert_value_eq(&restarted.get(&key(1)).await.unwrap().unwrap(), "v2");
+            assert_value_eq(&restarted.get(&key(4)).await.unwrap().unwrap(), "v1");
+            assert_eq!(restarted.keys("", |_| true).len(), 20);
+
+            // A corrupted checkpoint is ignored
+            let path = directory.join(checkpoint::CHECKPOINT_FILE);
+            restarted.checkpoint_index();
+            let mut buf = fs::read(&path).unwrap();
+            buf[0] ^= 1;
+            fs::write(&path, buf).unwrap();
+            let mut restarted = DataStore::new(directory).await;
+            restarted.init().await;
+            restarted.rebuild_index_from_disk().await;
+            restarted.get_stats().assert_not_corrupted();
+            assert_value_eq(&restarted.get(&key(3)).await.unwrap().unwrap(), "v2");
+            assert_eq!(restarted.keys("", |_| true).len(), 20);
+        });
+    }
+
+    #[test]
+    fn test_datastore_range() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_range")).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            for key in ["d", "a", "c", "b"] {
+                storage.set(Record::new(key.to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            }
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("b".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            storage.set(Record::new("bb".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.delete(&Key::new("c".to_string())).unwrap();
+
+            let records = storage.range("b".to_string().."d".to_string()).await.unwrap();
+            let keys: Vec<&str> = records.iter().map(|r| r.key.string.as_str()).collect();
+            assert_eq!(keys, vec!["b", "bb"]);
+            assert_value_eq(&records[0], "foo2");
+
+            // Ordered keys are rebuilt from the disktables
+            storage.reload().await;
+            let keys: Vec<String> = storage.range(..).await.unwrap().into_iter().map(|r| r.key.string).collect();
+            assert_eq!(keys, vec!["a", "b", "bb", "d"]);
+        });
+    }
+
+    #[test]
+    fn test_datastore_overwrite_in_place() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_overwrite_in_place")).await;
+            storage.init().await;
+            storage.truncate().await;
+            let bytes = |storage: &DataStore| storage.memtable_manager.byte_size();
+
+            for i in 0..10 {
+                storage
+                    .set(Record::new("hot".to_string(), Vec::from(format!("v{}", i).as_bytes())))
+                    .unwrap();
+            }
+            assert_eq!(storage.memtable_manager.len(), 1);
+            let size = bytes(&storage);
+            storage.set(Record::new("hot".to_string(), Vec::from("longer value".as_bytes()))).unwrap();
+            assert_eq!(bytes(&storage), size + "longer value".len() - "v9".len());
+
+            // The copy of a reclaimed record is replaced too
+            storage.force_flush().await.unwrap();
+            storage.reclaim_all_disktables().await;
+            assert_eq!(storage.memtable_manager.len(), 1);
+            storage.set(Record::new("hot".to_string(), Vec::from("v2".as_bytes()))).unwrap();
+            assert_eq!(storage.memtable_manager.len(), 1);
+            assert_value_eq(&storage.get(&Key::new("hot".to_string())).await.unwrap().unwrap(), "v2");
             storage.get_stats().assert_not_corrupted();
 
-            storage.set(Record::new("test2".to_string(), Vec::from("foo2".as_bytes())));
-            let opt = storage.get(&Key::new("test2".to_string())).await;
-            assert_value_eq(&opt.unwrap(), "foo2");
+            storage.force_flush().await.unwrap();
+            storage.clean_unused_disktables().await;
+            assert_eq!(storage.table_manager.list_tables(DEFAULT_KEYSPACE).len(), 1);
+            storage.reload().await;
+            assert_value_eq(&storage.get(&Key::new("hot".to_string())).await.unwrap().unwrap(), "v2");
             storage.get_stats().assert_not_corrupted();
+        });
+    }
 
-            storage.set(Record::new("test3".to_string(), Vec::from("foo99".as_bytes())));
-            let opt = storage.get(&Key::new("test3".to_string())).await;
-            assert_value_eq(&opt.unwrap(), "foo99");
+    #[test]
+    fn test_datastore_memtable_range() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_memtable_range")).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            for key in ["a", "b", "c"] {
+                storage.set(Record::new(key.to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            }
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("bb".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.set(Record::new("b".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            storage.delete(&Key::new("c".to_string())).unwrap();
+            // "a" is only on disk, "c" is deleted
+            let records = storage.memtable_range(..);
+            let keys: Vec<&str> = records.iter().map(|r| r.key.string.as_str()).collect();
+            assert_eq!(keys, vec!["b", "bb"]);
+            assert_value_eq(&records[0], "foo2");
+
+            // The version deleted by the range tombstone is overwritten
+            storage.set(Record::new("x".to_string(), Vec::from("v1".as_bytes()))).unwrap();
+            storage.delete_prefix("x").unwrap();
+            let len = storage.memtable_manager.len();
+            storage.set(Record::new("x".to_string(), Vec::from("v2".as_bytes()))).unwrap();
+            assert_eq!(storage.memtable_manager.len(), len);
+            assert_value_eq(&storage.get(&Key::new("x".to_string())).await.unwrap().unwrap(), "v2");
             storage.get_stats().assert_not_corrupted();
 
-            storage.force_flush().await;
+            storage.force_flush().await.unwrap();
+            storage.reload().await;
+            assert_value_eq(&storage.get(&Key::new("x".to_string())).await.unwrap().unwrap(), "v2");
             storage.get_stats().assert_not_corrupted();
+        });
+    }
+
+    #[test]
+    fn test_datastore_delete_range() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
 
-            storage.set(Record::new("test1".to_string(), Vec::from("foo3".as_bytes())));
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_delete_range")).await;
+            storage.init().await;
+            storage.truncate().await;
+            let keys = |storage: &DataStore| -> Vec<String> { storage.keys("", |_| true).into_iter().map(|k| k.string).collect() };
 
-            let opt = storage.get(&Key::new("test1".to_string())).await;
-            assert_value_eq(&opt.unwrap(), "foo3");
+            for key in ["a:1", "a:2", "b:1", "c:1"] {
+                storage.set(Record::new(key.to_string(), Vec::from("v1".as_bytes()))).unwrap();
+            }
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("a:3".to_string(), Vec::from("v1".as_bytes()))).unwrap();
+            assert_eq!(storage.delete_prefix("a:").unwrap(), 3);
+            assert_eq!(storage.delete_range("b".to_string().."c".to_string()).unwrap(), 1);
+            storage.set(Record::new("a:1".to_string(), Vec::from("v2".as_bytes()))).unwrap();
+            assert!(storage.get(&Key::new("a:2".to_string())).await.unwrap().is_none());
+            assert_eq!(keys(&storage), vec!["a:1", "c:1"]);
             storage.get_stats().assert_not_corrupted();
 
-            let opt = storage.get(&Key::new("test99999".to_string())).await; // unknown key
-            assert!(opt.is_none());
+            // The tombstones are found again on disk and in the WAL
+            storage.reload().await;
+            assert_eq!(keys(&storage), vec!["a:1", "c:1"]);
+            storage.delete_prefix("c").unwrap();
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_delete_range")).await;
+            storage.init().await;
+            storage.rebuild_index_from_disk().await;
+            assert_eq!(keys(&storage), vec!["a:1"]);
+            assert_value_eq(&storage.get(&Key::new("a:1".to_string())).await.unwrap().unwrap(), "v2");
+            assert_eq!(storage.get_stats().range_tombstones, 3);
             storage.get_stats().assert_not_corrupted();
 
-            storage.delete(&Key::new("test3".to_string()));
-            let opt = storage.get(&Key::new("test3".to_string())).await;
-            assert!(opt.is_none());
+            // Reclaims drop the deleted records, then the tombstones once no
+            // older table is left
+            for _ in 0..2 {
+                storage.reclaim_all_disktables().await;
+                storage.force_flush().await.unwrap();
+                storage.clean_unused_disktables().await;
+                storage.get_stats().assert_not_corrupted();
+            }
+            assert_eq!(storage.get_stats().range_tombstones, 0);
+            assert_eq!(storage.table_manager.len(DEFAULT_KEYSPACE), 1);
+            storage.reload().await;
+            assert_eq!(keys(&storage), vec!["a:1"]);
             storage.get_stats().assert_not_corrupted();
-            storage.force_flush().await;
+        });
+    }
+
+    #[test]
+    fn test_datastore_size_estimates() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_size_estimates")).await;
+            storage.init().await;
+            storage.truncate().await;
+            assert_eq!(storage.approximate_size_of_slots(0..=u16::MAX), SizeEstimate::default());
+
+            for i in 0..10 {
+                storage.set(Record::new(format!("key{}", i), vec![0; 100])).unwrap();
+            }
+            storage.force_flush().await.unwrap();
+            let disk_size = storage.approximate_disk_size();
+            assert!(disk_size > 1000);
+            assert_eq!(storage.approximate_key_count(), 10);
+            assert_eq!(
+                storage.approximate_size_of_slots(0..=u16::MAX),
+                SizeEstimate { keys: 10, bytes: disk_size }
+            );
+
+            let slot = key_slot(b"key0");
+            let estimate = storage.approximate_size_of_slots(slot..=slot);
+            assert_eq!(estimate.keys, 1);
+            assert_eq!(estimate.bytes, disk_size / 10);
+
+            // Deleted keys are still on disk until reclaimed
+            for i in 0..5 {
+                storage.delete(&Key::new(format!("key{}", i))).unwrap();
+            }
+            assert_eq!(storage.approximate_key_count(), 5);
+            assert_eq!(storage.approximate_disk_size(), disk_size);
+            assert_eq!(storage.approximate_size_of_slots(slot..=slot), SizeEstimate::default());
+        });
+    }
+
+    #[test]
+    fn test_datastore_tombstone_gc() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_tombstone_gc")).await;
+            storage.init().await;
+            storage.truncate().await;
+            let tombstones = |storage: &DataStore| storage.get_stats().tombstones;
+
+            // One record per memtable: the first one is deleted before its
+            // memtable is flushed, it is not written
+            storage.set_memtable_max_size_bytes(40);
+            storage.set(Record::new("deleted".to_string(), Vec::from("v1".as_bytes()))).unwrap();
+            storage.set(Record::new("other".to_string(), Vec::from("v1".as_bytes()))).unwrap();
+            storage.delete_prefix("deleted").unwrap();
+            storage.set(Record::new("key".to_string(), Vec::from("v1".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            assert_eq!(storage.table_manager.list_tables(DEFAULT_KEYSPACE).len(), 3);
             storage.get_stats().assert_not_corrupted();
-            println!("{:?}", storage.get_stats());
 
-            let opt = storage.get(&Key::new("test1".to_string())).await;
-            assert_value_eq(&opt.unwrap(), "foo3");
+            storage.delete(&Key::new("key".to_string())).unwrap();
+            storage.force_flush().await.unwrap();
+            let horizon = storage.hold_gc_horizon(0);
+            for _ in 0..2 {
+                storage.reclaim_all_disktables().await;
+                storage.force_flush().await.unwrap();
+                storage.clean_unused_disktables().await;
+            }
+            // Kept for the holder of the horizon
+            assert_eq!(tombstones(&storage), 1);
+            storage.release_gc_horizon(horizon);
+            storage.reclaim_all_disktables().await;
+            storage.force_flush().await.unwrap();
+            storage.clean_unused_disktables().await;
+            assert_eq!(tombstones(&storage), 0);
+            storage.get_stats().assert_not_corrupted();
 
-            let mut storage2 = DataStore::new(PathBuf::from(r"./data/test/test_datastore_for_consistency")).await;
-            storage2.init().await;
-            storage2.get_stats().assert_not_corrupted();
+            storage.reload().await;
+            assert!(storage.get(&Key::new("key".to_string())).await.unwrap().is_none());
+            assert_value_eq(&storage.get(&Key::new("other".to_string())).await.unwrap().unwrap(), "v1");
+        });
+    }
 
-            let opt = storage2.get(&Key::new("test1".to_string())).await;
-            assert!(opt.is_none());
-            storage2.get_stats().assert_not_corrupted();
+    #[test]
+    fn test_datastore_flush_error() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
 
-            storage2.rebuild_index_from_disk().await;
-            storage2.get_stats().assert_not_corrupted();
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_flush_error")).await;
+            storage.init().await;
+            storage.truncate().await;
+            let key = Key::new("key".to_string());
+            storage.set(Record::new("key".to_string(), Vec::from("v1".as_bytes()))).unwrap();
+
+            // The manifest can't be replaced, the new table is not kept
+            let manifest = storage.table_manager.directory().join(disktable::MANIFEST);
+            let _ = fs::remove_file(&manifest);
+            fs::create_dir(&manifest).unwrap();
+            assert!(storage.force_flush().await.is_err());
+            assert!(storage.table_manager.list_tables(DEFAULT_KEYSPACE).is_empty());
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "v1");
+            storage.get_stats().assert_not_corrupted();
 
-            let opt = storage2.get(&Key::new("test1".to_string())).await;
-            assert_value_eq(&opt.unwrap(), "foo3");
+            fs::remove_dir(&manifest).unwrap();
+            storage.flush_all_flushable_memtables().await.unwrap();
+            assert_eq!(storage.table_manager.list_tables(DEFAULT_KEYSPACE).len(), 1);
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "v1");
+            storage.get_stats().assert_not_corrupted();
+        });
+    }
 
-            let opt = storage2.get(&Key::new("test2".to_string())).await;
-            assert_value_eq(&opt.unwrap(), "foo2");
-            storage2.get_stats().assert_not_corrupted();
+    #[test]
+    fn test_datastore_iter() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
 
-            // Should have been deleted
-            let opt = storage2.get(&Key::new("test3".to_string())).await;
-            assert!(opt.is_none());
-            storage2.get_stats().assert_not_corrupted();
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_iter")).await;
+            storage.init().await;
+            storage.truncate().await;
 
-            println!("{:?}", storage2.get_stats());
-            storage2.reclaim_all_disktables().await;
-            println!("{:?}", storage2.get_stats());
-            assert_eq!(storage2.table_manager.get_disktables_marked_for_deletion().len(), 0);
-            storage2.force_flush().await;
-            assert_eq!(storage2.table_manager.get_disktables_marked_for_deletion().len(), 2);
-            storage2.table_manager.delete_disktables_marked_for_deletion();
-            storage2.get_stats().assert_not_corrupted();
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.set(Record::new("test2".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.set(Record::new("test3".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("test2".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("test1".to_string(), Vec::from("foo3".as_bytes()))).unwrap();
+            storage.delete(&Key::new("test3".to_string())).unwrap();
+
+            let records: Vec<(String, String)> = storage
+                .iter()
+                .map(|entry| entry.unwrap())
+                .map(|(key, record)| (key.string, String::from_utf8(record.value).unwrap()))
+                .collect()
+                .await;
+            assert_eq!(
+                records,
+                vec![("test1".to_string(), "foo3".to_string()), ("test2".to_string(), "foo2".to_string())]
+            );
+        });
+    }
 
-            let opt = storage.get(&Key::new("test1".to_string())).await;
-            assert_value_eq(&opt.unwrap(), "foo3");
+    #[test]
+    fn test_datastore_backup_and_restore() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
 
-            let opt = storage.get(&Key::new("test2".to_string())).await;
-            assert_value_eq(&opt.unwrap(), "foo2");
+        rt.block_on(async {
+            let backup = PathBuf::from(r"./data/test/test_datastore_backup_and_restore/backup");
+            let restored = PathBuf::from(r"./data/test/test_datastore_backup_and_restore/restored");
+            let _ = fs::remove_dir_all(&backup);
+            let _ = fs::remove_dir_all(&restored);
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_backup_and_restore/data")).await;
+            storage.init().await;
+            storage.truncate().await;
 
-            let opt = storage2.get(&Key::new("test3".to_string())).await;
-            assert!(opt.is_none());
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.set(Record::new("test2".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("test1".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            storage.backup_to(&backup).await.unwrap();
+            // The backup directory must be empty
+            assert_eq!(
+                storage.backup_to(&backup).await,
+                Err(DataStoreError::Io(std::io::ErrorKind::AlreadyExists))
+            );
+            // Not part of the backup
+            storage.set(Record::new("test3".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.delete(&Key::new("test2".to_string())).unwrap();
+            storage.force_flush().await.unwrap();
+
+            let missing = backup.with_file_name("missing");
+            assert!(matches!(
+                DataStore::restore_from(&missing, restored.clone(), Config::default()).await,
+                Err(DataStoreError::NotFound)
+            ));
+            let restored = DataStore::restore_from(&backup, restored, Config::default()).await.unwrap();
+            restored.get_stats().assert_not_corrupted();
+            assert_eq!(restored.get_stats().disktable_manager_stats.table_stats.len(), 2);
+            assert_value_eq(&restored.get(&Key::new("test1".to_string())).await.unwrap().unwrap(), "foo2");
+            assert_value_eq(&restored.get(&Key::new("test2".to_string())).await.unwrap().unwrap(), "foo1");
+            assert!(restored.get(&Key::new("test3".to_string())).await.unwrap().is_none());
+        });
+    }
 
-            println!("{:?}", storage.get_stats());
+    #[test]
+    fn test_datastore_wal_replay() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let directory = PathBuf::from(r"./data/test/test_datastore_wal_replay");
+            let mut storage = DataStore::new(directory.clone()).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            storage.set(Record::new("test2".to_string(), Vec::from("foo2".as_bytes()))).unwrap();
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("test1".to_string(), Vec::from("foo3".as_bytes()))).unwrap();
+            storage.delete(&Key::new("test2".to_string())).unwrap();
+
+            // Like a crash: the memtables are lost but not the log
+            let mut restarted = DataStore::new(directory).await;
+            restarted.init().await;
+            restarted.rebuild_index_from_disk().await;
+            restarted.get_stats().assert_not_corrupted();
+            assert_value_eq(&restarted.get(&Key::new("test1".to_string())).await.unwrap().unwrap(), "foo3");
+            assert!(restarted.get(&Key::new("test2".to_string())).await.unwrap().is_none());
         });
     }
 
+    /// Add the operands to the value, all parsed as integers
+    struct CounterOperator;
+
+    impl MergeOperator for CounterOperator {
+        fn merge(&self, _key: &Key, existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+            let parse = |v: &[u8]| std::str::from_utf8(v).unwrap().parse::<i64>().unwrap();
+            let sum = existing.map_or(0, parse) + operands.iter().map(|o| parse(o)).sum::<i64>();
+            sum.to_string().into_bytes()
+        }
+    }
+
     #[test]
-    fn test_datastore_for_flush_and_compactions() {
+    fn test_datastore_merge() {
         let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
 
         rt.block_on(async {
-            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_for_flush_and_compactions")).await;
+            let directory = PathBuf::from(r"./data/test/test_datastore_merge");
+            let mut storage = DataStore::new(directory.clone()).await;
+            storage.set_merge_operator(Box::new(CounterOperator));
             storage.init().await;
             storage.truncate().await;
+            let key = Key::new("counter".to_string());
 
-            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes())));
-            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes())));
-            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes())));
-            storage.set(Record::new("test2".to_string(), Vec::from("foo2".as_bytes())));
-            storage.set(Record::new("test3".to_string(), Vec::from("foo3".as_bytes())));
-            storage.set(Record::new("test4".to_string(), Vec::from("foo4".as_bytes())));
-            storage.set(Record::new("test5".to_string(), Vec::from("foo5".as_bytes())));
-            storage.force_flush().await;
+            storage.set(Record::new("counter".to_string(), Vec::from("10".as_bytes()))).unwrap();
+            storage.merge(&key, b"1".to_vec()).await.unwrap();
+            storage.merge(&key, b"2".to_vec()).await.unwrap();
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "13");
+            assert_eq!(storage.value_size(&key).await, Ok(Some((ValueType::String, 2))));
+            storage.get_stats().assert_not_corrupted();
 
+            // Collapsed when flushed
+            storage.force_flush().await.unwrap();
+            assert!(storage.merge_bases.borrow().is_empty());
+            assert_eq!(storage.index.get(key.hash).unwrap().value_type, ValueType::String);
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "13");
             storage.get_stats().assert_not_corrupted();
 
+            // The base on disk is copied forward by reclaims
+            storage.merge(&key, b"5".to_vec()).await.unwrap();
             storage.reclaim_all_disktables().await;
             storage.get_stats().assert_not_corrupted();
-
-            // Try to flush empty memtable: should not add a new disktable
-            storage.force_flush().await;
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "18");
+            storage.force_flush().await.unwrap();
             storage.table_manager.delete_disktables_marked_for_deletion();
-            assert_eq!(storage.get_stats().disktable_manager_stats.table_stats.len(), 1);
+            storage.get_stats().assert_not_corrupted();
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "18");
+
+            // Missing and deleted keys start from nothing
+            storage.merge(&Key::new("new".to_string()), b"3".to_vec()).await.unwrap();
+            assert_value_eq(&storage.get(&Key::new("new".to_string())).await.unwrap().unwrap(), "3");
+            storage.set(Record::new("deleted".to_string(), Vec::from("7".as_bytes()))).unwrap();
+            storage.delete(&Key::new("deleted".to_string())).unwrap();
+            storage.merge(&Key::new("deleted".to_string()), b"1".to_vec()).await.unwrap();
+            assert_value_eq(&storage.get(&Key::new("deleted".to_string())).await.unwrap().unwrap(), "1");
             storage.get_stats().assert_not_corrupted();
 
-            // Reclaiming with only one table doesn't do anything
-            storage.maybe_run_one_reclaim().await;
-            assert_eq!(storage.get_stats().disktable_manager_stats.table_stats.len(), 1);
+            // Like a crash: the delta is replayed from the log, its base found on disk
+            storage.merge(&key, b"2".to_vec()).await.unwrap();
+            let mut restarted = DataStore::new(directory).await;
+            restarted.set_merge_operator(Box::new(CounterOperator));
+            restarted.init().await;
+            restarted.rebuild_index_from_disk().await;
+            restarted.get_stats().assert_not_corrupted();
+            assert_value_eq(&restarted.get(&key).await.unwrap().unwrap(), "20");
+            assert_value_eq(&restarted.get(&Key::new("deleted".to_string())).await.unwrap().unwrap(), "1");
+        });
+    }
+
+    #[test]
+    fn test_datastore_concurrent_flushes() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let config = Config {
+                max_concurrent_flushes: 3,
+                ..Config::default()
+            };
+            let mut storage = DataStore::new_with_config(PathBuf::from(r"./data/test/test_datastore_concurrent_flushes"), config).await;
+            storage.set_merge_operator(Box::new(CounterOperator));
+            storage.init().await;
+            storage.truncate().await;
+            let key = Key::new("counter".to_string());
+
+            // One record per memtable, the deltas and their base are flushed
+            // at the same time
+            storage.set_memtable_max_size_bytes(40);
+            storage.set(Record::new("counter".to_string(), Vec::from("10".as_bytes()))).unwrap();
+            storage.merge(&key, b"1".to_vec()).await.unwrap();
+            storage.merge(&key, b"2".to_vec()).await.unwrap();
+            for i in 0..3 {
+                storage.set(Record::new(format!("k{}", i), Vec::from("v1".as_bytes()))).unwrap();
+            }
+            storage.force_flush().await.unwrap();
+            assert!(storage.memtable_manager.get_all_unflushed_memtables().iter().all(|m| m.is_empty()));
             storage.get_stats().assert_not_corrupted();
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "13");
+            for i in 0..3 {
+                assert_value_eq(&storage.get(&Key::new(format!("k{}", i))).await.unwrap().unwrap(), "v1");
+            }
 
-            storage.set(Record::new("test6".to_string(), Vec::from("foo6".as_bytes())));
-            storage.set(Record::new("test7".to_string(), Vec::from("foo7".as_bytes())));
-            storage.force_flush().await;
-            assert_eq!(storage.get_stats().disktable_manager_stats.table_stats.len(), 2);
+            storage.reload().await;
+            assert_value_eq(&storage.get(&key).await.unwrap().unwrap(), "13");
+            storage.get_stats().assert_not_corrupted();
+        });
+    }
+
+    #[test]
+    fn test_datastore_memtable_budget() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let config = Config {
+                memtable_memory_budget_bytes: 200,
+                write_stall_timeout_ms: 0,
+                ..Config::default()
+            };
+            let mut storage = DataStore::new_with_config(PathBuf::from(r"./data/test/test_datastore_memtable_budget"), config).await;
+            storage.init().await;
+            storage.truncate().await;
+            let metric = |storage: &DataStore, name| metrics::get(&storage.get_stats().metrics(), name);
+
+            storage.set(Record::new("key".to_string(), Vec::from("v1".as_bytes()))).unwrap();
+            storage.wait_for_memtable_budget().await.unwrap();
+            assert_eq!(metric(&storage, "stalled_writes"), 0);
+            for i in 0..10 {
+                storage.set(Record::new(format!("k{}", i), Vec::from("v1".as_bytes()))).unwrap();
+            }
+            assert!(metric(&storage, "memtable_bytes") > 200);
+            // Not flushed in time
+            assert_eq!(storage.wait_for_memtable_budget().await, Err(DataStoreError::Busy));
+            assert_eq!((metric(&storage, "stalled_writes"), metric(&storage, "rejected_writes")), (1, 1));
+
+            storage.force_flush().await.unwrap();
+            assert_eq!(metric(&storage, "memtable_bytes"), 0);
+            storage.wait_for_memtable_budget().await.unwrap();
+            assert_value_eq(&storage.get(&Key::new("k9".to_string())).await.unwrap().unwrap(), "v1");
             storage.get_stats().assert_not_corrupted();
+        });
+    }
 
-            println!("{:?}", storage.get_stats());
-            // No reason to make a compaction
-            storage.maybe_run_one_reclaim().await;
-            assert_eq!(storage.get_stats().disktable_manager_stats.table_stats.len(), 2);
-            storage.set(Record::new("test3".to_string(), Vec::from("foo31".as_bytes())));
-            storage.set(Record::new("test4".to_string(), Vec::from("foo41".as_bytes())));
+    /// Remove the keys starting with "drop", upper-case the other values
+    struct UpperCaseFilter;
 
-            storage.maybe_run_one_reclaim().await;
-            storage.force_flush().await;
+    impl CompactionFilter for UpperCaseFilter {
+        fn filter(&self, record: &Record) -> FilterDecision {
+            match record.key.string.starts_with("drop") {
+                true => FilterDecision::Remove,
+                false => FilterDecision::ChangeValue(record.value.to_ascii_uppercase()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_datastore_compaction_filter() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_compaction_filter")).await;
+            storage.init().await;
+            storage.truncate().await;
+            storage.add_compaction_filter(Box::new(UpperCaseFilter));
+
+            storage.set(Record::new("key".to_string(), Vec::from("value".as_bytes()))).unwrap();
+            storage.set(Record::new("drop".to_string(), Vec::from("value".as_bytes()))).unwrap();
+            storage.set(Record::new("deleted".to_string(), Vec::from("value".as_bytes()))).unwrap();
+            storage.delete(&Key::new("deleted".to_string())).unwrap();
+            storage.force_flush().await.unwrap();
+            // Filters only run on reclaim
+            assert_value_eq(&storage.get(&Key::new("key".to_string())).await.unwrap().unwrap(), "value");
+
+            storage.reclaim_all_disktables().await;
+            storage.force_flush().await.unwrap();
+            storage.clean_unused_disktables().await;
+            // The purged tombstone doesn't keep the reclaimed table
+            assert_eq!(storage.table_manager.list_tables(DEFAULT_KEYSPACE).len(), 1);
+            assert_value_eq(&storage.get(&Key::new("key".to_string())).await.unwrap().unwrap(), "VALUE");
+            assert!(storage.get(&Key::new("drop".to_string())).await.unwrap().is_none());
             storage.get_stats().assert_not_corrupted();
-            assert_eq!(storage.table_manager.get_disktables_marked_for_deletion().len(), 1);
-            storage.table_manager.delete_disktables_marked_for_deletion();
-            assert_eq!(storage.get_stats().disktable_manager_stats.table_stats.len(), 2);
 
-            // if we delete all data in a disktable, it should be ready for deletion
-            storage.delete(&Key::new("test6".to_string()));
-            storage.delete(&Key::new("test7".to_string()));
-            storage.force_flush().await;
-            assert_eq!(storage.table_manager.get_disktables_marked_for_deletion().len(), 1);
-            storage.table_manager.delete_disktables_marked_for_deletion();
+            // The removed key is deleted on disk too
+            storage.reload().await;
+            assert!(storage.get(&Key::new("drop".to_string())).await.unwrap().is_none());
+            assert_value_eq(&storage.get(&Key::new("key".to_string())).await.unwrap().unwrap(), "VALUE");
+            storage.get_stats().assert_not_corrupted();
+        });
+    }
+
+    #[test]
+    fn test_datastore_expiration() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_expiration")).await;
+            storage.init().await;
+            storage.truncate().await;
+            let key1 = Key::new("test1".to_string());
+            let key2 = Key::new("test2".to_string());
+
+            storage.set(Record::new("test1".to_string(), Vec::from("foo1".as_bytes()))).unwrap();
+            assert_eq!(storage.ttl(&key1).await, Ttl::Persistent);
+            assert_eq!(storage.ttl(&key2).await, Ttl::Missing);
+            assert!(!storage.expire(&key2, Some(crate::time::now() + 1_000_000_000)).await.unwrap());
+            // Nothing to remove
+            assert!(!storage.expire(&key1, None).await.unwrap());
+
+            assert!(storage.expire(&key1, Some(crate::time::now() + 60_000_000_000)).await.unwrap());
+            assert!(matches!(storage.ttl(&key1).await, Ttl::Expiring(_)));
+            assert!(storage.expire(&key1, None).await.unwrap());
+            assert_eq!(storage.ttl(&key1).await, Ttl::Persistent);
+            assert_value_eq(&storage.get(&key1).await.unwrap().unwrap(), "foo1");
+
+            // An expiration in the past deletes the key
+            assert!(storage.expire(&key1, Some(0)).await.unwrap());
+            assert!(storage.get(&key1).await.unwrap().is_none());
+            storage.get_stats().assert_not_corrupted();
+
+            // Expired keys are hidden then removed by the sweeper, even once on disk
+            let mut record = Record::new("test2".to_string(), Vec::from("foo2".as_bytes()));
+            record.expire_at = Some(crate::time::now() + 20_000_000);
+            storage.set(record).unwrap();
+            storage.force_flush().await.unwrap();
+            assert_value_eq(&storage.get(&key2).await.unwrap().unwrap(), "foo2");
+            std::thread::sleep(std::time::Duration::from_millis(30));
+            assert_eq!(storage.ttl(&key2).await, Ttl::Missing);
+            assert_eq!(storage.sweep_expired().await, 1);
+            assert!(storage.get(&key2).await.unwrap().is_none());
             storage.get_stats().assert_not_corrupted();
 
+            // TTLs are on disk: they survive a reload and expired records
+            // are dropped by reclaim instead of being copied
+            let key3 = Key::new("test3".to_string());
+            let key4 = Key::new("test4".to_string());
+            let expire_at = crate::time::now() + 60_000_000_000;
+            let mut record = Record::new("test3".to_string(), Vec::from("foo3".as_bytes()));
+            record.expire_at = Some(expire_at);
+            storage.set(record).unwrap();
+            let mut record = Record::new("test4".to_string(), Vec::from("foo4".as_bytes()));
+            record.expire_at = Some(crate::time::now() + 20_000_000);
+            storage.set(record).unwrap();
+            storage.reload().await;
+            assert_eq!(storage.get(&key3).await.unwrap().unwrap().expire_at, Some(expire_at));
+            assert!(matches!(storage.ttl(&key4).await, Ttl::Expiring(_)));
+            std::thread::sleep(std::time::Duration::from_millis(30));
+            assert_eq!(storage.ttl(&key4).await, Ttl::Missing);
             storage.reclaim_all_disktables().await;
+            assert!(storage.index.get(key4.hash).is_none());
+            assert!(storage.get(&key3).await.unwrap().is_some());
             storage.get_stats().assert_not_corrupted();
-            storage.force_flush().await;
-            storage.table_manager.delete_disktables_marked_for_deletion();
-            assert_eq!(storage.table_manager.get_disktables_marked_for_deletion().len(), 0);
+        });
+    }
+
+    #[test]
+    fn test_datastore_scan() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let mut storage = DataStore::new(PathBuf::from(r"./data/test/test_datastore_scan")).await;
+            storage.init().await;
+            storage.truncate().await;
+
+            for i in 0..25 {
+                storage.set(Record::new(format!("test{}", i), Vec::from("foo".as_bytes()))).unwrap();
+            }
+            storage.delete(&Key::new("test0".to_string())).unwrap();
+            storage.force_flush().await.unwrap();
+            storage.set(Record::new("test1".to_string(), Vec::from("bar".as_bytes()))).unwrap();
+
+            let mut keys = vec![];
+            let mut position = 0;
+            loop {
+                let (batch, next) = storage.scan(position, 10).await;
+                keys.extend(batch.into_iter().map(|k| k.string));
+                match next {
+                    Some(next) => position = next,
+                    None => break,
+                }
+            }
+            keys.sort();
+            let mut expected: Vec<String> = (1..25).map(|i| format!("test{}", i)).collect();
+            expected.sort();
+            assert_eq!(keys, expected);
+
+            let keys = storage.keys("test2", |_| true);
+            assert_eq!(keys.len(), 6);
+            let keys: Vec<String> = storage.scan_prefix("test1").map(|r| r.unwrap().key.string).collect().await;
+            assert_eq!(
+                keys,
+                vec!["test1", "test10", "test11", "test12", "test13", "test14", "test15", "test16", "test17", "test18", "test19"]
+            );
         });
     }
 }
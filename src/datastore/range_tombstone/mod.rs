@@ -0,0 +1,157 @@
+use std::{cell::RefCell, collections::HashSet, rc::Rc};
+
+use super::{RecordMetadata, RecordPtr};
+use crate::record::Record;
+
+/// Deletion of the keys in `start..end` (`start..` without end) written
+/// before it. Stored as one record whose key is `start` and value is `end`,
+/// it is not part of the index
+#[derive(Debug, Clone)]
+pub struct RangeTombstone {
+    pub start: String,
+    pub end: Option<String>,
+    /// Location of the record
+    pub meta: RecordMetadata,
+}
+
+impl RangeTombstone {
+    pub fn new(record: &Record, meta: RecordMetadata) -> RangeTombstone {
+        RangeTombstone {
+            start: record.key.string.clone(),
+            end: decode_end(&record.value),
+            meta,
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        key >= self.start.as_str() && self.end.as_ref().map_or(true, |end| key < end.as_str())
+    }
+
+    /// Whether the version of `key` written at `timestamp` is deleted
+    pub fn covers(&self, key: &str, timestamp: u64) -> bool {
+        timestamp < self.meta.timestamp && self.contains(key)
+    }
+}
+
+/// Value of the record of a range tombstone, never empty so it is not taken
+/// for a point tombstone
+pub fn encode_end(end: Option<&str>) -> Vec<u8> {
+    match end {
+        Some(end) => [&[1], end.as_bytes()].concat(),
+        None => vec![0],
+    }
+}
+
+fn decode_end(value: &[u8]) -> Option<String> {
+    match value.first() {
+        Some(1) => Some(String::from_utf8_lossy(&value[1..]).into_owned()),
+        _ => None,
+    }
+}
+
+/// Smallest string greater than every string starting with `prefix`, None
+/// if there is none (e.g. empty prefix). Strings are ordered like their
+/// UTF-8 bytes, so like their chars
+pub fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// Range tombstones of a datastore, each holds a reference on the storage of
+/// its record
+#[derive(Debug, Default)]
+pub struct RangeTombstones {
+    tombstones: RefCell<Vec<RangeTombstone>>,
+}
+
+impl RangeTombstones {
+    pub fn insert(&self, tombstone: RangeTombstone) {
+        self.tombstones.borrow_mut().push(tombstone);
+    }
+
+    /// Whether the version of `key` written at `timestamp` is deleted by one
+    /// of the range tombstones
+    pub fn covers(&self, key: &str, timestamp: u64) -> bool {
+        self.tombstones.borrow().iter().any(|t| t.covers(key, timestamp))
+    }
+
+    /// Whether the record at `meta` is one of the range tombstones
+    pub fn contains(&self, meta: &RecordMetadata) -> bool {
+        self.position(meta).is_some()
+    }
+
+    fn position(&self, meta: &RecordMetadata) -> Option<usize> {
+        self.tombstones
+            .borrow()
+            .iter()
+            .position(|t| t.meta.hash == meta.hash && t.meta.timestamp == meta.timestamp)
+    }
+
+    /// Point the tombstone to its record moved to `meta` (flush, reclaim),
+    /// return its previous location
+    pub fn update(&self, meta: RecordMetadata) -> RecordMetadata {
+        let position = self.position(&meta).expect("unknown range tombstone");
+        std::mem::replace(&mut self.tombstones.borrow_mut()[position].meta, meta)
+    }
+
+    /// Forget the tombstone of the record at `meta`, return its location
+    pub fn remove(&self, meta: &RecordMetadata) -> RecordMetadata {
+        let position = self.position(meta).expect("unknown range tombstone");
+        self.tombstones.borrow_mut().remove(position).meta
+    }
+
+    pub fn any<F: Fn(&RangeTombstone) -> bool>(&self, f: F) -> bool {
+        self.tombstones.borrow().iter().any(f)
+    }
+
+    pub fn all(&self) -> Vec<RangeTombstone> {
+        self.tombstones.borrow().clone()
+    }
+
+    /// Disktables holding a range tombstone
+    pub fn tables(&self) -> HashSet<Rc<String>> {
+        self.tombstones
+            .borrow()
+            .iter()
+            .filter_map(|t| match &t.meta.data_ptr {
+                RecordPtr::DiskTable(ptr) => Some(ptr.disktable.clone()),
+                RecordPtr::Compacting(ptr) => Some(ptr.disktable.clone()),
+                RecordPtr::MemTable(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn truncate(&self) {
+        self.tombstones.borrow_mut().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.tombstones.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tombstones.borrow().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end("user:"), Some("user;".to_string()));
+        assert_eq!(prefix_end("a\u{10FFFF}"), Some("b".to_string()));
+        assert_eq!(prefix_end("\u{D7FF}"), Some("\u{E000}".to_string()));
+        assert_eq!(prefix_end(""), None);
+        assert_eq!(decode_end(&encode_end(Some("end"))), Some("end".to_string()));
+        assert_eq!(decode_end(&encode_end(None)), None);
+    }
+}
@@ -0,0 +1,79 @@
+use std::{cell::Cell, time::Duration};
+
+/// Token bucket capping the bytes/sec of the background I/O (flushes and
+/// reclaims) so they don't take the disk bandwidth needed by GET/SET.
+/// Callers take the bytes they are about to read or write and wait when the
+/// bucket is in debt. It holds at most one second of I/O.
+pub struct IoThrottle {
+    /// 0 disables the limit
+    bytes_per_sec: Cell<u64>,
+    /// Bytes available right away, negative when callers have to wait
+    tokens: Cell<f64>,
+    refilled_at: Cell<u64>,
+}
+
+impl IoThrottle {
+    pub fn new(bytes_per_sec: u64) -> IoThrottle {
+        IoThrottle {
+            bytes_per_sec: Cell::new(bytes_per_sec),
+            tokens: Cell::new(bytes_per_sec as f64),
+            refilled_at: Cell::new(crate::time::now()),
+        }
+    }
+
+    /// Change the limit, the bucket starts full. Setting the same limit again
+    /// doesn't refill it
+    pub fn set_bytes_per_sec(&self, bytes_per_sec: u64) {
+        if bytes_per_sec == self.bytes_per_sec.get() {
+            return;
+        }
+        self.bytes_per_sec.set(bytes_per_sec);
+        self.tokens.set(bytes_per_sec as f64);
+    }
+
+    /// Take `bytes` from the bucket at `now`, return how long to wait before
+    /// doing the I/O
+    fn take(&self, bytes: usize, now: u64) -> Duration {
+        let rate = self.bytes_per_sec.get() as f64;
+        if rate == 0.0 {
+            return Duration::ZERO;
+        }
+        let elapsed = now.saturating_sub(self.refilled_at.get()) as f64 / 1_000_000_000.0;
+        self.refilled_at.set(now.max(self.refilled_at.get()));
+        let tokens = (self.tokens.get() + elapsed * rate).min(rate) - bytes as f64;
+        self.tokens.set(tokens);
+        match tokens < 0.0 {
+            true => Duration::from_secs_f64(-tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Wait until `bytes` can be read or written
+    pub async fn acquire(&self, bytes: usize) {
+        let delay = self.take(bytes, crate::time::now());
+        if !delay.is_zero() {
+            monoio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_throttle() {
+        let throttle = IoThrottle::new(1024);
+        let now = throttle.refilled_at.get();
+        assert_eq!(throttle.take(768, now), Duration::ZERO);
+        // 256 bytes of debt
+        assert_eq!(throttle.take(512, now), Duration::from_millis(250));
+        // Refilled by 512 bytes, the next caller waits for the previous debt too
+        assert_eq!(throttle.take(512, now + 500_000_000), Duration::from_millis(250));
+        // Never more than one second of burst
+        assert_eq!(throttle.take(1024, now + 60_000_000_000), Duration::ZERO);
+
+        throttle.set_bytes_per_sec(0);
+        assert_eq!(throttle.take(1_000_000, now), Duration::ZERO);
+    }
+}
@@ -0,0 +1,508 @@
+use std::{fs, io::Write, path::Path};
+
+use crate::datastore::disktable::block::{append_checksum, parse_block_handles, verify_checksum, Compression, Corruption, Footer, TableBuilder};
+use crate::datastore::disktable::{encode_entry, sync_directory, write_manifest, FORMAT_VERSION};
+use crate::datastore::encryption::NO_KEY;
+use crate::datastore::expiry::NO_EXPIRY;
+use crate::record::{Record, ValueType};
+
+/// File storing the layout version of a data directory
+pub const VERSION_FILE: &str = "VERSION";
+/// Layout version written by this version of lsm-rs
+pub const CURRENT_VERSION: u32 = 13;
+
+/// A migration brings a data directory from version `from` to `from + 1`.
+/// It is run on the directory before any table is loaded.
+struct Migration {
+    from: u32,
+    description: &'static str,
+    run: fn(&Path),
+}
+
+/// Ordered list of migrations, new format changes should append to it
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "add the value type to disktable records",
+        run: add_value_type,
+    },
+    Migration {
+        from: 2,
+        description: "add the memcached flags to disktable records",
+        run: add_flags,
+    },
+    Migration {
+        from: 3,
+        description: "add the index block to disktables",
+        run: add_index_block,
+    },
+    Migration {
+        from: 4,
+        description: "split disktables in data blocks",
+        run: split_in_blocks,
+    },
+    Migration {
+        from: 5,
+        description: "add checksums to disktable blocks",
+        run: add_block_checksums,
+    },
+    Migration {
+        from: 6,
+        description: "add the expiration to disktable records",
+        run: add_expiration,
+    },
+    Migration {
+        from: 7,
+        description: "store the number of records of disktables as a u32",
+        run: widen_record_count,
+    },
+    Migration {
+        from: 8,
+        description: "list the disktables in a manifest",
+        run: add_manifest,
+    },
+    Migration {
+        from: 9,
+        description: "add the format version to disktable footers, v8 tables are rewritten by compaction",
+        // v8 tables are still read, older versions of lsm-rs can't read the new ones
+        run: |_| {},
+    },
+    Migration {
+        from: 10,
+        description: "add the keyspace to disktable footers",
+        // Older tables are read as the default keyspace
+        run: |_| {},
+    },
+    Migration {
+        from: 11,
+        description: "add the encryption key id to disktable footers",
+        // Older tables are read as not encrypted
+        run: |_| {},
+    },
+    Migration {
+        from: 12,
+        description: "bind the sealed disktable blocks to their table and offset",
+        // Older tables are opened without associated data until compaction rewrites them
+        run: |_| {},
+    },
+];
+
+/// Size of the table header of v1 to v7 tables (count u16, timestamp u64)
+const V1_TABLE_HEADER_SIZE: usize = 2 + 8;
+
+/// Write then rename so a crash never leaves a torn file. The file is synced
+/// before the rename and the directory after, so a power loss leaves either
+/// the old or the new content
+pub fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => sync_directory(parent),
+        _ => sync_directory(Path::new(".")),
+    }
+}
+
+/// Append `field` to the fixed size header (`header_size` bytes) of every
+/// record of the `<timestamp>-v<version>.data` tables. Each table is rewritten
+/// as `<timestamp>-v<version + 1>.data`, the old table being removed last so
+/// an interrupted migration can be run again.
+fn extend_record_headers(directory: &Path, version: u32, header_size: usize, field: &[u8]) {
+    let suffix = format!("-v{}.data", version);
+    for entry in fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let timestamp = match name.strip_suffix(suffix.as_str()) {
+            Some(timestamp) => timestamp,
+            None => continue,
+        };
+
+        let old = fs::read(&path).unwrap();
+        let count = u16::from_le_bytes(old[0..2].try_into().unwrap()) as usize;
+        let mut new = Vec::with_capacity(old.len() + count * field.len());
+        // The table header (count u16, timestamp u64) doesn't change
+        new.extend_from_slice(&old[..V1_TABLE_HEADER_SIZE]);
+        let mut cursor = V1_TABLE_HEADER_SIZE;
+        for _ in 0..count {
+            let key_size = u16::from_le_bytes(old[cursor..cursor + 2].try_into().unwrap()) as usize;
+            let value_size = u32::from_le_bytes(old[cursor + 2..cursor + 6].try_into().unwrap()) as usize;
+            new.extend_from_slice(&old[cursor..cursor + header_size]);
+            new.extend_from_slice(field);
+            new.extend_from_slice(&old[cursor + header_size..cursor + header_size + key_size + value_size]);
+            cursor += header_size + key_size + value_size;
+        }
+
+        write_atomically(&directory.join(format!("{}-v{}.data", timestamp, version + 1)), &new).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+/// v1 records have no value type byte after the timestamp, they were all strings
+fn add_value_type(directory: &Path) {
+    extend_record_headers(directory, 1, 14, &[ValueType::String as u8]);
+}
+
+/// v2 records have no memcached flags after the value type
+fn add_flags(directory: &Path) {
+    extend_record_headers(directory, 2, 15, &0u32.to_le_bytes());
+}
+
+/// Size of the record header of v3 to v6 tables
+const V3_RECORD_HEADER_SIZE: usize = 19;
+/// Size of the record header of v7 tables, the expiration follows the flags
+const V7_RECORD_HEADER_SIZE: usize = 27;
+
+/// Decode the records of the entries of a v3 to v7 table, written one after
+/// the other. `header_size` tells if they have an expiration (v7)
+fn decode_v3_entries(mut entries: &[u8], header_size: usize) -> Vec<Record> {
+    let mut records = vec![];
+    while !entries.is_empty() {
+        let key_size = u16::from_le_bytes(entries[0..2].try_into().unwrap()) as usize;
+        let value_size = u32::from_le_bytes(entries[2..6].try_into().unwrap()) as usize;
+        let key_end = header_size + key_size;
+        let key = String::from_utf8(entries[header_size..key_end].to_vec()).unwrap();
+        let timestamp = u64::from_le_bytes(entries[6..14].try_into().unwrap());
+        let mut record = Record::new_with_timestamp(key, entries[key_end..key_end + value_size].to_vec(), timestamp);
+        record.value_type = ValueType::from_u8(entries[14]);
+        record.flags = u32::from_le_bytes(entries[15..19].try_into().unwrap());
+        if header_size == V7_RECORD_HEADER_SIZE {
+            record.expire_at = match u64::from_le_bytes(entries[19..27].try_into().unwrap()) {
+                NO_EXPIRY => None,
+                expire_at => Some(expire_at),
+            };
+        }
+        records.push(record);
+        entries = &entries[key_end + value_size..];
+    }
+    records
+}
+
+/// Decode the records of a v6 or v7 table: checksummed data blocks and index
+/// block
+fn decode_v6_blocks(table: &[u8], header_size: usize) -> Result<DecodedTable, Corruption> {
+    let footer = Footer::parse(table)?;
+    let index = verify_checksum(table[footer.index_offset as usize..table.len() - footer.size()].to_vec())?;
+    let mut records = vec![];
+    for handle in parse_block_handles(&index) {
+        let block = table[handle.offset as usize..(handle.offset + handle.size as u64) as usize].to_vec();
+        let block = footer.compression.decompress(verify_checksum(block)?)?;
+        // The entries are followed by the restart points and their number
+        let num_restarts = u32::from_le_bytes(block[block.len() - 4..].try_into().unwrap()) as usize;
+        records.extend(decode_v3_entries(&block[..block.len() - 4 - num_restarts * 4], header_size));
+    }
+    Ok((footer.compression, records))
+}
+
+/// Codec and records of a table
+type DecodedTable = (Compression, Vec<Record>);
+
+/// Rewrite the `<timestamp>-v<version>.data` tables (v7 at most, see
+/// `V1_TABLE_HEADER_SIZE`) directly in the current format, `decode` returning the codec and the records of a table. The next
+/// migrations only look for their own version so they skip these tables.
+/// Corrupted tables can't be rewritten, they are renamed so they are not loaded.
+fn rewrite_tables(directory: &Path, version: u32, decode: fn(&[u8]) -> Result<DecodedTable, Corruption>) {
+    let suffix = format!("-v{}.data", version);
+    for entry in fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let timestamp = match name.strip_suffix(suffix.as_str()) {
+            Some(timestamp) => timestamp,
+            None => continue,
+        };
+
+        let old = fs::read(&path).unwrap();
+        let (compression, records) = match decode(&old) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                println!("Can't migrate corrupted disktable {}, renamed to .corrupted: {:?}", name, e);
+                fs::rename(&path, path.with_extension("corrupted")).unwrap();
+                continue;
+            }
+        };
+        let mut builder = TableBuilder::new(u64::from_le_bytes(old[2..10].try_into().unwrap()), compression, 0, None);
+        for record in records {
+            builder.add(&encode_entry(&record));
+        }
+        let (table, _) = builder.finish();
+        write_atomically(&directory.join(format!("{}-v{}.data", timestamp, FORMAT_VERSION)), &table).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+/// v3 tables end with the last record: append the index block (offset, header
+/// and key of each record) and the footer pointing to it
+fn add_index_block(directory: &Path) {
+    for entry in fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let timestamp = match name.strip_suffix("-v3.data") {
+            Some(timestamp) => timestamp,
+            None => continue,
+        };
+
+        let mut table = fs::read(&path).unwrap();
+        let count = u16::from_le_bytes(table[0..2].try_into().unwrap()) as usize;
+        let mut index = vec![];
+        let mut cursor = V1_TABLE_HEADER_SIZE;
+        for _ in 0..count {
+            let key_size = u16::from_le_bytes(table[cursor..cursor + 2].try_into().unwrap()) as usize;
+            let value_size = u32::from_le_bytes(table[cursor + 2..cursor + 6].try_into().unwrap()) as usize;
+            index.extend((cursor as u32).to_le_bytes());
+            index.extend_from_slice(&table[cursor..cursor + V3_RECORD_HEADER_SIZE + key_size]);
+            cursor += V3_RECORD_HEADER_SIZE + key_size + value_size;
+        }
+        let index_offset = table.len() as u64;
+        table.extend(index);
+        table.extend(index_offset.to_le_bytes());
+
+        write_atomically(&directory.join(format!("{}-v4.data", timestamp)), &table).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+/// v4 tables hold their records one after the other: rewrite them in data
+/// blocks, with the block based index block and footer
+fn split_in_blocks(directory: &Path) {
+    rewrite_tables(directory, 4, |table| {
+        // The index block is followed by its offset
+        let index_offset = u64::from_le_bytes(table[table.len() - 8..].try_into().unwrap()) as usize;
+        Ok((
+            Compression::None,
+            decode_v3_entries(&table[V1_TABLE_HEADER_SIZE..index_offset], V3_RECORD_HEADER_SIZE),
+        ))
+    });
+}
+
+/// v5 blocks have no checksum: append the CRC32C of every data block and of
+/// the index block
+fn add_block_checksums(directory: &Path) {
+    for entry in fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let timestamp = match name.strip_suffix("-v5.data") {
+            Some(timestamp) => timestamp,
+            None => continue,
+        };
+
+        let table = fs::read(&path).unwrap();
+        let footer = Footer::parse(&table).unwrap();
+        let mut index = table[footer.index_offset as usize..table.len() - footer.size()].to_vec();
+        let mut new = Vec::with_capacity(table.len());
+        new.extend_from_slice(&table[..V1_TABLE_HEADER_SIZE]);
+        for (i, handle) in parse_block_handles(&index.clone()).into_iter().enumerate() {
+            let mut block = table[handle.offset as usize..(handle.offset + handle.size as u64) as usize].to_vec();
+            append_checksum(&mut block);
+            // Handles (offset u64, size u32) follow the number of blocks
+            let position = 4 + i * 12;
+            index[position..position + 8].copy_from_slice(&(new.len() as u64).to_le_bytes());
+            index[position + 8..position + 12].copy_from_slice(&(block.len() as u32).to_le_bytes());
+            new.extend(block);
+        }
+        let index_offset = new.len() as u64;
+        append_checksum(&mut index);
+        new.extend(index);
+        Footer {
+            index_offset,
+            compression: footer.compression,
+            version: 6,
+            keyspace: 0,
+            key_id: NO_KEY,
+        }
+        .write(&mut new);
+        write_atomically(&directory.join(format!("{}-v6.data", timestamp)), &new).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+/// v6 records have no expiration, TTLs were only kept in the index
+fn add_expiration(directory: &Path) {
+    rewrite_tables(directory, 6, |table| decode_v6_blocks(table, V3_RECORD_HEADER_SIZE));
+}
+
+/// v7 tables store their number of records as a u16
+fn widen_record_count(directory: &Path) {
+    rewrite_tables(directory, 7, |table| decode_v6_blocks(table, V7_RECORD_HEADER_SIZE));
+}
+
+/// v8 tables were found by listing the directory: list them in the manifest
+fn add_manifest(directory: &Path) {
+    let names: Vec<String> = fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".data"))
+        .collect();
+    write_manifest(directory, names.iter().map(|name| name.as_str())).unwrap();
+}
+
+/// Return the version of the directory or None if it doesn't contain data yet.
+/// Directories written before the version file existed are detected using
+/// the version suffix of the disktables (`<timestamp>-v<version>.data`).
+pub fn detect_version(directory: &Path) -> Option<u32> {
+    let version_path = directory.join(VERSION_FILE);
+    if version_path.exists() {
+        let raw = fs::read_to_string(&version_path).unwrap();
+        return Some(raw.trim().parse().unwrap());
+    }
+
+    fs::read_dir(directory)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            let version = name.strip_suffix(".data")?.rsplit_once("-v")?.1;
+            version.parse::<u32>().ok()
+        })
+        .max()
+}
+
+fn write_version(directory: &Path, version: u32) {
+    write_atomically(&directory.join(VERSION_FILE), format!("{}\n", version).as_bytes()).unwrap();
+}
+
+/// Bring the data directory to the current version by running every missing
+/// migration in order. Progress is recorded after each step so an interrupted
+/// upgrade resumes where it stopped.
+pub fn upgrade(directory: &Path) {
+    let mut version = match detect_version(directory) {
+        Some(v) => v,
+        None => {
+            write_version(directory, CURRENT_VERSION);
+            return;
+        }
+    };
+
+    if version > CURRENT_VERSION {
+        panic!(
+            "{:?} was written by a newer version of lsm-rs (version: {}, supported: {})",
+            directory, version, CURRENT_VERSION
+        );
+    }
+
+    while version < CURRENT_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.from == version)
+            .unwrap_or_else(|| panic!("no migration from version {}", version));
+        println!("Migrating {:?} from v{}: {}", directory, version, migration.description);
+        (migration.run)(directory);
+        version += 1;
+        write_version(directory, version);
+    }
+
+    if !directory.join(VERSION_FILE).exists() {
+        write_version(directory, version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::datastore::DataStore;
+    use crate::record::Key;
+
+    /// Load the upgraded directory and check it holds "key" => "value"
+    fn assert_upgraded(directory: PathBuf) {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+        rt.block_on(async {
+            let mut storage = DataStore::new(directory).await;
+            storage.init().await;
+            storage.rebuild_index_from_disk().await;
+            let record = storage.get(&Key::new("key".to_string())).await.unwrap().unwrap();
+            assert_eq!(record.value, b"value");
+            assert_eq!(record.timestamp, 7);
+            assert_eq!((record.value_type, record.flags, record.expire_at), (ValueType::String, 0, None));
+        });
+    }
+
+    #[test]
+    fn test_upgrade_from_v1() {
+        let directory = PathBuf::from(r"./data/test/test_upgrade_from_v1");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        // One record "key" => "value" written with the v1 format
+        let mut table = vec![];
+        table.extend(1u16.to_le_bytes());
+        table.extend(42u64.to_le_bytes());
+        table.extend(3u16.to_le_bytes());
+        table.extend(5u32.to_le_bytes());
+        table.extend(7u64.to_le_bytes());
+        table.extend(b"keyvalue");
+        fs::write(directory.join("42-v1.data"), &table).unwrap();
+
+        assert_eq!(detect_version(&directory), Some(1));
+        upgrade(&directory);
+        assert_eq!(detect_version(&directory), Some(CURRENT_VERSION));
+        for version in 1..FORMAT_VERSION {
+            assert!(!directory.join(format!("42-v{}.data", version)).exists());
+        }
+        let upgraded = fs::read(directory.join(format!("42-v{}.data", FORMAT_VERSION))).unwrap();
+        // The count is now a u32
+        assert_eq!(&upgraded[0..4], &1u32.to_le_bytes());
+        assert_eq!(&upgraded[4..12], &table[2..10]);
+        assert_upgraded(directory);
+    }
+
+    #[test]
+    fn test_upgrade_without_version_file() {
+        let directory = PathBuf::from(r"./data/test/test_upgrade_without_version_file");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        // Detected from the suffix of the tables written in the current format
+        fs::write(directory.join(format!("42-v{}.data", FORMAT_VERSION)), b"").unwrap();
+        assert_eq!(detect_version(&directory), Some(FORMAT_VERSION));
+        upgrade(&directory);
+        assert_eq!(detect_version(&directory), Some(CURRENT_VERSION));
+    }
+
+    #[test]
+    fn test_upgrade_from_v5() {
+        let directory = PathBuf::from(r"./data/test/test_upgrade_from_v5");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+
+        // One record "key" => "value" in a single data block, without checksums
+        let mut header = vec![];
+        header.extend(3u16.to_le_bytes());
+        header.extend(5u32.to_le_bytes());
+        header.extend(7u64.to_le_bytes());
+        header.push(ValueType::String as u8);
+        header.extend(0u32.to_le_bytes());
+        let mut table = vec![];
+        table.extend(1u16.to_le_bytes());
+        table.extend(42u64.to_le_bytes());
+        let block_size = header.len() + 8 + 8;
+        table.extend(&header);
+        table.extend(b"keyvalue");
+        table.extend(0u32.to_le_bytes());
+        table.extend(1u32.to_le_bytes());
+        let index_offset = table.len() as u64;
+        table.extend(1u32.to_le_bytes());
+        table.extend((V1_TABLE_HEADER_SIZE as u64).to_le_bytes());
+        table.extend((block_size as u32).to_le_bytes());
+        table.extend(0u32.to_le_bytes());
+        table.extend(0u16.to_le_bytes());
+        table.extend(&header);
+        table.extend(b"key");
+        Footer {
+            index_offset,
+            compression: Compression::None,
+            version: 5,
+            keyspace: 0,
+            key_id: NO_KEY,
+        }
+        .write(&mut table);
+        fs::write(directory.join("42-v5.data"), &table).unwrap();
+        fs::write(directory.join(VERSION_FILE), "5\n").unwrap();
+
+        upgrade(&directory);
+        assert_eq!(detect_version(&directory), Some(CURRENT_VERSION));
+        assert!(!directory.join("42-v5.data").exists());
+        assert!(!directory.join("42-v6.data").exists());
+        assert_upgraded(directory);
+    }
+}
@@ -0,0 +1,66 @@
+use std::{path::Path, rc::Rc};
+
+use super::{encryption::Encryption, error::DataStoreError, Config, DataStore};
+use crate::record::HashedKey;
+
+/// Problem found by `DataStore::verify`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The header, the footer or the index block of a table can't be read
+    Unreadable { table: Rc<String>, error: DataStoreError },
+    /// The header of a table doesn't count the records of its index block
+    Count { table: Rc<String>, header: u32, index: u32 },
+    /// A data block can't be read, fails its checksum or can't be decoded
+    Block { table: Rc<String>, block: u32, error: DataStoreError },
+    /// An entry of a data block is missing or differs from its entry in the
+    /// index block
+    Entry { table: Rc<String>, block: u32, entry: u16 },
+    /// An index entry points to a table that is not loaded
+    MissingTable { hash: HashedKey, table: Rc<String> },
+    /// An index entry points to a record of the table that doesn't exist or
+    /// is another version
+    Dangling {
+        hash: HashedKey,
+        table: Rc<String>,
+        block: u32,
+        entry: u16,
+    },
+    /// The references held on a table don't match the entries pointing to it
+    References { table: Rc<String>, expected: usize, found: usize },
+}
+
+/// Result of `DataStore::verify`
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Tables checked
+    pub tables: usize,
+    /// Records of the tables checked
+    pub records: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Open the datastore of `directory` and check it, for a stopped node (the
+/// WAL is replayed like on a restart). Encrypted files are read with the keys
+/// of the environment (see `Encryption::from_env`)
+pub async fn verify_directory(directory: &Path) -> Report {
+    verify_directories(directory, directory).await
+}
+
+/// Like `verify_directory` for a datastore whose disktables and WAL are in
+/// different directories (see `DataStore::new_with_directories`)
+pub async fn verify_directories(tables_directory: &Path, wal_directory: &Path) -> Report {
+    let config = Config {
+        encryption: Encryption::from_env().expect("invalid encryption keys in the environment"),
+        ..Config::default()
+    };
+    let mut datastore = DataStore::new_with_directories(tables_directory.to_path_buf(), wal_directory.to_path_buf(), config).await;
+    datastore.init().await;
+    datastore.rebuild_index_from_disk().await;
+    datastore.verify().await
+}
@@ -0,0 +1,398 @@
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+use crate::record::{Record, ValueType};
+
+use super::direct_io::{self, AlignedBuf, ALIGNMENT};
+use super::disktable::block::{crc32c, CHECKSUM_SIZE};
+use super::encryption::{self, Cipher, Encryption, KeyError, NO_KEY};
+use super::expiry::NO_EXPIRY;
+
+/// Extension of the log segments
+pub const SEGMENT_EXTENSION: &str = "wal";
+/// Size of the fixed part of an entry
+const ENTRY_HEADER_SIZE: usize = 2 + 4 + 8 + 1 + 4 + 8;
+/// In the name of the segments in clear whose entries have a checksum, the
+/// ones written before don't
+const CHECKSUMS_MARKER: &str = "-crc";
+/// In the name of the encrypted segments whose frames are bound to the
+/// segment and their number, the ones written before aren't
+const AAD_MARKER: &str = "-aad";
+
+/// Write-ahead log of a datastore. Writes are appended to the segment of the
+/// memtable holding them and the segment is removed once the memtable is
+/// flushed, so the log only holds the records not on disk yet.
+///
+/// |                                      entry                                        |
+/// |keysize(u16le)|valsize(u32le)|timestamp(u64le)|type(u8)|flags(u32le)|expire_at(u64le)|key|value|
+///
+/// In clear, each entry is preceded by its checksum (`<time>-<memtable>-crc.wal`)
+///
+/// |        frame        |
+/// |crc32c(u32le)|entry|
+///
+/// With encryption, each entry is sealed (see `encryption`) in a frame bound
+/// to the name of the segment and the number of the frame, and the id of the
+/// key is in the name of the segment (`<time>-<memtable>-k<id>-aad.wal`)
+///
+/// |          frame          |
+/// |size(u32le)|sealed entry|
+///
+/// Reads stop at the first frame that is torn or doesn't match its checksum
+pub struct Wal {
+    directory: PathBuf,
+    /// Open segment of each memtable
+    segments: RefCell<HashMap<u16, Segment>>,
+    /// Write the segments with O_DIRECT
+    direct_io: bool,
+    /// Sync the segment after each append
+    sync: bool,
+    /// Keys of the segments, the new ones are encrypted with the current one
+    encryption: Option<Encryption>,
+}
+
+struct Segment {
+    path: PathBuf,
+    file: File,
+    /// Seals the entries, None to write them in clear
+    cipher: Option<Cipher>,
+    /// Number of frames written, the next one is sealed with it
+    frames: u64,
+    /// With O_DIRECT, bytes of the last page of the file, written again with
+    /// the next entries
+    tail: Vec<u8>,
+    /// Offset of `tail` in the file
+    tail_offset: u64,
+}
+
+impl Segment {
+    fn create(path: PathBuf, direct_io: bool, cipher: Option<Cipher>) -> std::io::Result<Segment> {
+        let file = match direct_io {
+            // Written at the offset of the tail, not appended
+            true => OpenOptions::new()
+                .create(true)
+                .write(true)
+                .custom_flags(direct_io::O_DIRECT)
+                .open(&path)?,
+            false => OpenOptions::new().create(true).append(true).open(&path)?,
+        };
+        Ok(Segment {
+            path,
+            file,
+            cipher,
+            frames: 0,
+            tail: vec![],
+            tail_offset: 0,
+        })
+    }
+
+    fn append(&mut self, entry: &[u8], direct_io: bool, sync: bool) -> std::io::Result<()> {
+        let entry = match &self.cipher {
+            Some(cipher) => {
+                let sealed = cipher.seal(entry, &frame_aad(&self.path, self.frames));
+                [&(sealed.len() as u32).to_le_bytes()[..], &sealed].concat()
+            }
+            None => [&crc32c(entry).to_le_bytes()[..], entry].concat(),
+        };
+        self.write(&entry, direct_io)?;
+        self.frames += 1;
+        match sync {
+            true => self.file.sync_data(),
+            false => Ok(()),
+        }
+    }
+
+    fn write(&mut self, entry: &[u8], direct_io: bool) -> std::io::Result<()> {
+        if !direct_io {
+            return self.file.write_all(entry);
+        }
+        // The last page is padded with zeros, truncated back after the write
+        let tail_len = self.tail.len();
+        self.tail.extend_from_slice(entry);
+        let written = self
+            .file
+            .write_all_at(&AlignedBuf::from_slice(&self.tail), self.tail_offset)
+            .and_then(|_| self.file.set_len(self.tail_offset + self.tail.len() as u64));
+        if let Err(e) = written {
+            self.tail.truncate(tail_len);
+            return Err(e);
+        }
+        let full_pages = self.tail.len() / ALIGNMENT * ALIGNMENT;
+        self.tail.drain(..full_pages);
+        self.tail_offset += full_pages as u64;
+        Ok(())
+    }
+}
+
+pub fn encode(record: &Record) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(ENTRY_HEADER_SIZE + record.key.string.len() + record.value.len());
+    buf.extend((record.key.string.len() as u16).to_le_bytes());
+    buf.extend((record.value.len() as u32).to_le_bytes());
+    buf.extend(record.timestamp.to_le_bytes());
+    buf.push(record.value_type as u8);
+    buf.extend(record.flags.to_le_bytes());
+    buf.extend(record.expire_at.unwrap_or(NO_EXPIRY).to_le_bytes());
+    buf.extend(record.key.string.as_bytes());
+    buf.extend(&record.value);
+    buf
+}
+
+/// Decode the entries of a segment, preceded by their checksum if
+/// `checksums` is set. A crash can leave the last entry torn (or followed by
+/// the zeros padding the last page with O_DIRECT), decoding stops at the
+/// first invalid entry
+fn decode(buf: &[u8], checksums: bool) -> Vec<Record> {
+    let checksum_size = if checksums { CHECKSUM_SIZE } else { 0 };
+    let mut records = vec![];
+    let mut cursor = 0;
+    while cursor + checksum_size + ENTRY_HEADER_SIZE <= buf.len() {
+        let entry_start = cursor + checksum_size;
+        let header = &buf[entry_start..entry_start + ENTRY_HEADER_SIZE];
+        let key_size = u16::from_le_bytes(header[0..2].try_into().unwrap()) as usize;
+        let value_size = u32::from_le_bytes(header[2..6].try_into().unwrap()) as usize;
+        let timestamp = u64::from_le_bytes(header[6..14].try_into().unwrap());
+        let key_start = entry_start + ENTRY_HEADER_SIZE;
+        let value_start = key_start + key_size;
+        if value_start + value_size > buf.len() || timestamp == 0 {
+            break;
+        }
+        if checksums && crc32c(&buf[entry_start..value_start + value_size]).to_le_bytes() != buf[cursor..entry_start] {
+            break;
+        }
+        let Ok(key) = std::str::from_utf8(&buf[key_start..value_start]) else {
+            break;
+        };
+        let mut record = Record::new_with_timestamp(key.to_string(), buf[value_start..value_start + value_size].to_vec(), timestamp);
+        record.value_type = ValueType::from_u8(header[14]);
+        record.flags = u32::from_le_bytes(header[15..19].try_into().unwrap());
+        record.expire_at = match u64::from_le_bytes(header[19..27].try_into().unwrap()) {
+            NO_EXPIRY => None,
+            expire_at => Some(expire_at),
+        };
+        records.push(record);
+        cursor = value_start + value_size;
+    }
+    records
+}
+
+/// Associated data of the frame `index` of the segment at `path`, empty for
+/// the segments written without `AAD_MARKER`
+fn frame_aad(path: &Path, index: u64) -> Vec<u8> {
+    let name = path.file_name().unwrap().to_string_lossy();
+    match name.contains(AAD_MARKER) {
+        true => [name.as_bytes(), &index.to_le_bytes()].concat(),
+        false => vec![],
+    }
+}
+
+/// Entries of the frames of the encrypted segment at `path`. A crash can
+/// leave the last frame torn, it is ignored
+fn open_frames(buf: &[u8], cipher: &Cipher, path: &Path) -> Vec<u8> {
+    let mut entries = vec![];
+    let mut cursor = 0;
+    let mut index = 0;
+    while cursor + 4 <= buf.len() {
+        let size = u32::from_le_bytes(buf[cursor..cursor + 4].try_into().unwrap()) as usize;
+        let frame = buf.get(cursor + 4..cursor + 4 + size);
+        let Some(Ok(entry)) = frame.map(|frame| cipher.open(frame, &frame_aad(path, index))) else {
+            break;
+        };
+        entries.extend(entry);
+        cursor += 4 + size;
+        index += 1;
+    }
+    entries
+}
+
+/// Id of the key of a segment from its name, `NO_KEY` if it is not encrypted
+fn segment_key_id(path: &Path) -> u32 {
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let stem = stem.strip_suffix(AAD_MARKER).unwrap_or(&stem);
+    stem.rsplit_once("-k").and_then(|(_, id)| id.parse().ok()).unwrap_or(NO_KEY)
+}
+
+impl Wal {
+    pub fn new(directory: PathBuf, direct_io: bool, sync: bool, encryption: Option<Encryption>) -> Wal {
+        Wal {
+            directory,
+            segments: RefCell::from(HashMap::new()),
+            direct_io,
+            sync,
+            encryption,
+        }
+    }
+
+    /// Segments found in the directory, open ones included
+    fn list_segments(&self) -> Vec<PathBuf> {
+        fs::read_dir(&self.directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == SEGMENT_EXTENSION))
+            .collect()
+    }
+
+    /// Directory of the segments
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Log an entry (see `encode`) written to `memtable`
+    pub fn append(&self, memtable: u16, entry: &[u8]) -> std::io::Result<()> {
+        let mut segments = self.segments.borrow_mut();
+        let segment = match segments.entry(memtable) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // Segments are never reopened, the name is unique
+                let cipher = self.encryption.as_ref().map(|encryption| encryption.current());
+                let name = match &cipher {
+                    Some(cipher) => format!(
+                        "{}-{}-k{}{}.{}",
+                        crate::time::now(),
+                        memtable,
+                        cipher.key_id(),
+                        AAD_MARKER,
+                        SEGMENT_EXTENSION
+                    ),
+                    None => format!("{}-{}{}.{}", crate::time::now(), memtable, CHECKSUMS_MARKER, SEGMENT_EXTENSION),
+                };
+                let segment = Segment::create(self.directory.join(name), self.direct_io, cipher)?;
+                if self.sync {
+                    fs::File::open(&self.directory)?.sync_all()?;
+                }
+                entry.insert(segment)
+            }
+        };
+        segment.append(entry, self.direct_io, self.sync)
+    }
+
+    /// Remove the segment of a memtable, once it is flushed
+    pub fn truncate_segment(&self, memtable: u16) {
+        if let Some(segment) = self.segments.borrow_mut().remove(&memtable) {
+            fs::remove_file(segment.path).unwrap();
+        }
+    }
+
+    /// Remove every segment
+    pub fn truncate(&self) {
+        self.segments.borrow_mut().clear();
+        for path in self.list_segments() {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    /// Records of a segment, decrypted with the key of its name
+    fn read_segment(&self, path: &Path) -> Result<Vec<Record>, KeyError> {
+        let cipher = encryption::cipher_of(self.encryption.as_ref(), segment_key_id(path))?;
+        let buf = fs::read(path).unwrap();
+        Ok(match cipher {
+            // The frames are authenticated
+            Some(cipher) => decode(&open_frames(&buf, &cipher, path), false),
+            None => decode(&buf, path.file_stem().unwrap().to_string_lossy().ends_with(CHECKSUMS_MARKER)),
+        })
+    }
+
+    /// Read the records of the segments left by a previous run, ordered by
+    /// timestamp. The segments are returned to be removed once the records
+    /// are logged again. An error if the key of a segment can't be used
+    pub fn read_previous_segments(&self) -> Result<(Vec<Record>, Vec<PathBuf>), KeyError> {
+        let paths: Vec<PathBuf> = self
+            .list_segments()
+            .into_iter()
+            .filter(|path| !self.segments.borrow().values().any(|segment| &segment.path == path))
+            .collect();
+        let mut records = vec![];
+        for path in &paths {
+            records.extend(self.read_segment(path)?);
+        }
+        records.sort_by_key(|record| record.timestamp);
+        Ok((records, paths))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::datastore::encryption::{StaticKeys, SEAL_OVERHEAD};
+
+    #[test]
+    fn test_wal_decode_torn_entry() {
+        let mut record = Record::new("key".to_string(), b"value".to_vec());
+        record.expire_at = Some(42);
+        record.flags = 7;
+        let mut buf = encode(&record);
+        buf.extend(&encode(&Record::new("other".to_string(), b"value".to_vec()))[..10]);
+
+        let records = decode(&buf, false);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].key.string, "key");
+        assert_eq!(records[0].value, b"value");
+        assert_eq!(records[0].timestamp, record.timestamp);
+        assert_eq!((records[0].expire_at, records[0].flags), (Some(42), 7));
+    }
+
+    #[test]
+    fn test_wal_checksums() {
+        let directory = PathBuf::from(r"./data/test/test_wal_checksums");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let wal = Wal::new(directory.clone(), false, true, None);
+        for key in ["key", "other", "last"] {
+            wal.append(0, &encode(&Record::new(key.to_string(), b"value".to_vec()))).unwrap();
+        }
+        let path = wal.list_segments().pop().unwrap();
+        let buf = fs::read(&path).unwrap();
+        assert_eq!(wal.read_segment(&path).unwrap().len(), 3);
+
+        // A corrupted entry and the ones after it are ignored
+        let entry_size = CHECKSUM_SIZE + ENTRY_HEADER_SIZE + 3 + 5;
+        let mut corrupted = buf.clone();
+        corrupted[entry_size + CHECKSUM_SIZE + ENTRY_HEADER_SIZE] ^= 1;
+        fs::write(&path, &corrupted).unwrap();
+        let records = wal.read_segment(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].key.string, "key");
+
+        // Zeros padding the last page after a crash are not entries
+        let mut padded = buf[..entry_size].to_vec();
+        padded.resize(ALIGNMENT, 0);
+        fs::write(&path, &padded).unwrap();
+        assert_eq!(wal.read_segment(&path).unwrap().len(), 1);
+        assert!(decode(&padded[entry_size..], false).is_empty());
+    }
+
+    #[test]
+    fn test_wal_encryption() {
+        let directory = PathBuf::from(r"./data/test/test_wal_encryption");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let encryption = Encryption::new(3, Rc::new(StaticKeys::new(vec![(3, vec![3; 16])]))).unwrap();
+        let wal = Wal::new(directory.clone(), false, false, Some(encryption.clone()));
+        wal.append(0, &encode(&Record::new("key".to_string(), b"value".to_vec()))).unwrap();
+        wal.append(0, &encode(&Record::new("other".to_string(), b"value".to_vec()))).unwrap();
+        let path = wal.list_segments().pop().unwrap();
+        assert_eq!(segment_key_id(&path), 3);
+        let buf = fs::read(&path).unwrap();
+        assert!(!buf.windows(5).any(|w| w == b"value"));
+
+        // Frames swapped or moved to another segment don't authenticate
+        let frame_size = 4 + SEAL_OVERHEAD + ENTRY_HEADER_SIZE + 3 + 5;
+        let swapped = [&buf[frame_size..], &buf[..frame_size]].concat();
+        assert!(open_frames(&swapped, &encryption.current(), &path).is_empty());
+        assert!(open_frames(&buf, &encryption.current(), &path.with_file_name("1-0-k3-aad.wal")).is_empty());
+        assert!(wal.read_segment(&path.with_file_name("1-0-k4-aad.wal")).is_err());
+
+        // Read by the next run, the torn end of the last frame is ignored
+        fs::write(&path, &buf[..buf.len() - 1]).unwrap();
+        let (records, _) = Wal::new(directory, false, false, Some(encryption)).read_previous_segments().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].key.string.as_str(), records[0].value.as_slice()), ("key", &b"value"[..]));
+    }
+}
@@ -1,5 +1,6 @@
 pub mod api;
 pub mod cluster;
+pub mod config;
 pub mod datastore;
 pub mod memcached;
 pub mod reactor;
@@ -1,11 +1,17 @@
-use lsm_rs::cluster::ClusterManagerBuilder;
+use lsm_rs::cluster::{self, ClusterManagerBuilder, MeshMessage};
+use lsm_rs::config::RuntimeConfig;
+use lsm_rs::datastore::encryption::{Encryption, KEYS_VARIABLE};
+use lsm_rs::datastore::verify;
 use lsm_rs::reactor::Reactor;
-use lsm_rs::topology::{ReactorMetadata, Topology};
+use lsm_rs::record::hasher::{self, KeyHashAlgorithm};
+use lsm_rs::storageproxy::layout::ShardLayout;
+use lsm_rs::topology::ReactorMetadata;
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::thread;
 use structopt::StructOpt;
-use uuid::Uuid;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "lsm-rs", about = "lsm-rs is a (mostly) Redis compatible database")]
@@ -21,46 +27,147 @@ struct Opt {
     /// Input file
     #[structopt(short = "d", long = "data-directory", parse(from_os_str), default_value = "./data/")]
     data_dir: std::path::PathBuf,
+
+    /// Addresses to listen on, can be repeated (e.g. IPv4 and IPv6)
+    #[structopt(short = "b", long = "bind", default_value = "127.0.0.1")]
+    bind_addrs: Vec<IpAddr>,
+
+    /// Address advertised to clients and other nodes (e.g. in CLUSTER SLOTS),
+    /// defaults to the first bind address
+    #[structopt(long = "advertise-ip")]
+    advertise_ip: Option<IpAddr>,
+
+    /// Hash of the keys in the index: blake2b keyed with a secret of the data
+    /// directory, xxh64 (faster, but clients can craft colliding keys) or sha1
+    /// as in previous versions. Changing it only discards the index checkpoints
+    #[structopt(long = "key-hash", default_value = "blake2b")]
+    key_hash: KeyHashAlgorithm,
+
+    /// Check the disktables of every shard of the data directory and exit,
+    /// with status 1 if a problem is found. The node must be stopped
+    #[structopt(long = "verify")]
+    verify: bool,
+}
+
+/// Check the shard directories (named by the first shard of their range) of
+/// `data_dir`, return the number of problems found
+fn verify_data_directory(data_dir: &Path) -> usize {
+    let mut shard_dirs: Vec<PathBuf> = std::fs::read_dir(data_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir() && path.file_name().unwrap().to_str().is_some_and(|name| name.parse::<u16>().is_ok()))
+        .collect();
+    shard_dirs.sort();
+    let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().enable_timer().build().unwrap();
+    let mut problems = 0;
+    for shard_dir in shard_dirs {
+        let layout = ShardLayout::new(shard_dir.clone());
+        // Shards not opened since they have subdirectories keep their files flat
+        let report = match layout.tables().exists() {
+            true => rt.block_on(verify::verify_directories(&layout.tables(), &layout.wal())),
+            false => rt.block_on(verify::verify_directory(&shard_dir)),
+        };
+        println!(
+            "{:?}: tables:{} records:{} problems:{}",
+            shard_dir,
+            report.tables,
+            report.records,
+            report.problems.len()
+        );
+        for problem in &report.problems {
+            println!("{:?}", problem);
+        }
+        problems += report.problems.len();
+    }
+    problems
 }
 
 fn main() {
     let opt = Opt::from_args();
+    // Before any key is built
+    hasher::set_algorithm(opt.key_hash);
+    hasher::set_secret(hasher::load_secret(&opt.data_dir));
+    // Each shard loads the keys again, a mistake is reported once here
+    if let Err(e) = Encryption::from_env() {
+        panic!("invalid encryption keys in {}: {:?}", KEYS_VARIABLE, e)
+    }
+
+    if opt.verify {
+        let problems = verify_data_directory(&opt.data_dir);
+        std::process::exit(if problems == 0 { 0 } else { 1 });
+    }
 
     // let cpus = CpuSet::online().unwrap();
     let mut shard_threads = vec![];
     let mut reactors = Vec::with_capacity(opt.reactors_total as usize);
     let mut reactor_metadatas = Vec::with_capacity(opt.reactors_total as usize);
     let mut port = 6379;
-    let mut mesh: HashMap<u8, async_channel::Sender<Topology>> = HashMap::new();
-    // TODO: persist this
-    let node_id = Uuid::new_v4();
+    let mut mesh: HashMap<u8, async_channel::Sender<MeshMessage>> = HashMap::new();
+    // Kept across restarts, a hard CLUSTER RESET generates a new one
+    let node_id = cluster::load_node_id(&opt.data_dir);
     println!("Start node with ID: {}", node_id);
 
     // Chan to send message to the cluster manager
     let (cluster_sender, cluster_receiver) = async_channel::unbounded();
 
+    let advertise_ip = match opt.advertise_ip {
+        Some(ip) => ip,
+        None => {
+            let ip = opt.bind_addrs[0];
+            if ip.is_unspecified() {
+                panic!("--advertise-ip is required when binding on {}", ip)
+            }
+            ip
+        }
+    };
+
+    // Changed by CONFIG SET on any reactor
+    let runtime_config = Arc::new(RuntimeConfig::new());
+
+    // Every reactor knows the mesh channels of the others (e.g. for pub/sub)
+    let mut mesh_receivers = Vec::with_capacity(opt.reactors_total as usize);
     for reactor_id in 0..opt.reactors_total {
+        let (mesh_sender, mesh_receiver) = async_channel::unbounded();
+        mesh.insert(reactor_id as u8, mesh_sender);
+        mesh_receivers.push(mesh_receiver);
+    }
+
+    for (reactor_id, mesh_receiver) in mesh_receivers.into_iter().enumerate() {
         let metadata = ReactorMetadata {
             node_id,
             id: reactor_id as u8,
-            ip: std::net::IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            ip: advertise_ip,
             port,
         };
         reactor_metadatas.push(metadata.clone());
 
         let data_dir = opt.data_dir.clone();
-        let (mesh_sender, mesh_receiver) = async_channel::unbounded();
-        reactors.push(Reactor::new(metadata, opt.shard_total, mesh_receiver, cluster_sender.clone(), data_dir));
-        mesh.insert(reactor_id as u8, mesh_sender);
+        reactors.push(Reactor::new(
+            metadata,
+            opt.bind_addrs.clone(),
+            opt.shard_total,
+            mesh_receiver,
+            mesh.clone(),
+            cluster_sender.clone(),
+            data_dir,
+        ));
         port += 1;
     }
 
-    let cm: ClusterManagerBuilder = ClusterManagerBuilder::new(reactor_metadatas.clone(), opt.shard_total, mesh, cluster_receiver, None);
+    let cm: ClusterManagerBuilder = ClusterManagerBuilder::new(
+        reactor_metadatas.clone(),
+        opt.shard_total,
+        mesh,
+        cluster_receiver,
+        None,
+        opt.data_dir.clone(),
+    );
     reactors[0].cluster_manager(cm);
 
     println!("{:?}", opt.data_dir);
 
     for mut reactor in reactors {
+        reactor.runtime_config(runtime_config.clone());
         let t = thread::spawn(move || {
             reactor.start();
         });
@@ -70,4 +177,5 @@ fn main() {
     for t in shard_threads {
         t.join().unwrap();
     }
+    println!("Node stopped");
 }
@@ -0,0 +1,231 @@
+use core::str;
+
+use monoio::io::{AsyncBufRead, AsyncWriteRentExt, BufReader};
+
+use crate::{api, record::Record};
+
+/// Keys longer than this are rejected, like memcached
+const KEY_MAX_LENGTH: usize = 250;
+/// Expiration times up to 30 days are relative, unix times otherwise
+const RELATIVE_EXPTIME_MAX: i64 = 60 * 60 * 24 * 30;
+/// Larger items are refused, like the default of memcached
+const ITEM_MAX_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Get(Vec<String>),
+    Set(Set),
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    /// incr and decr
+    Counter {
+        key: String,
+        delta: u64,
+        decrement: bool,
+        noreply: bool,
+    },
+    Stats,
+    /// Line to reply (ERROR or CLIENT_ERROR)
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Set {
+    pub key: String,
+    pub flags: u32,
+    pub exptime: i64,
+    pub data: Vec<u8>,
+    pub noreply: bool,
+}
+
+impl Set {
+    pub fn to_api_command(&self) -> api::Command {
+        let mut record = Record::new(self.key.clone(), self.data.clone());
+        record.expire_at = expire_at(self.exptime);
+        record.flags = self.flags;
+        api::Command::Data(api::DataCommand::Set(api::Set {
+            record,
+            options: api::SetOptions::default(),
+            relative_ttl: self.exptime > 0 && self.exptime <= RELATIVE_EXPTIME_MAX,
+        }))
+    }
+}
+
+/// Convert a memcached expiration time to a timestamp. Negative times expire
+/// the item right away
+fn expire_at(exptime: i64) -> Option<u64> {
+    let now = crate::time::now();
+    match exptime {
+        0 => None,
+        exptime if exptime < 0 => Some(now),
+        exptime if exptime <= RELATIVE_EXPTIME_MAX => Some(now.saturating_add(exptime as u64 * 1_000_000_000)),
+        exptime => Some(crate::time::from_unix_ms((exptime as u64).saturating_mul(1000))),
+    }
+}
+
+fn client_error(message: &str) -> Command {
+    Command::Error(format!("CLIENT_ERROR {}", message))
+}
+
+fn parse_key(key: &str) -> Option<String> {
+    match key.len() <= KEY_MAX_LENGTH && !key.bytes().any(|c| c.is_ascii_control()) {
+        true => Some(String::from(key)),
+        false => None,
+    }
+}
+
+/// Parse the command at the start of `buffer`, return it with the number of
+/// bytes it used or None if it is not fully received yet. The data block of a
+/// refused item is counted as used even if it is not received yet
+pub fn parse(buffer: &[u8]) -> Option<(Command, usize)> {
+    let line_end = buffer.iter().position(|c| *c == b'\n')?;
+    let line = buffer[..line_end].strip_suffix(b"\r").unwrap_or(&buffer[..line_end]);
+    let mut consumed = line_end + 1;
+    let Ok(line) = str::from_utf8(line) else {
+        return Some((client_error("bad command line format"), consumed));
+    };
+    let tokens: Vec<&str> = line.split(' ').filter(|t| !t.is_empty()).collect();
+    let noreply = tokens.last() == Some(&"noreply");
+
+    let command = match tokens.as_slice() {
+        ["get" | "gets", keys @ ..] if !keys.is_empty() => match keys.iter().map(|key| parse_key(key)).collect() {
+            Some(keys) => Command::Get(keys),
+            None => client_error("bad command line format"),
+        },
+        ["set", key, flags, exptime, bytes] | ["set", key, flags, exptime, bytes, "noreply"] => {
+            let (Some(key), Ok(flags), Ok(exptime), Ok(bytes)) = (parse_key(key), flags.parse(), exptime.parse(), bytes.parse::<usize>()) else {
+                return Some((client_error("bad command line format"), consumed));
+            };
+            if bytes > ITEM_MAX_SIZE {
+                let consumed = consumed.saturating_add(bytes).saturating_add(2);
+                return Some((Command::Error(String::from("SERVER_ERROR object too large for cache")), consumed));
+            }
+            // The data block follows the command line
+            let data = buffer.get(consumed..consumed + bytes + 2)?;
+            consumed += bytes + 2;
+            if !data.ends_with(b"\r\n") {
+                return Some((client_error("bad data chunk"), consumed));
+            }
+            Command::Set(Set {
+                key,
+                flags,
+                exptime,
+                data: data[..bytes].to_vec(),
+                noreply,
+            })
+        }
+        ["delete", key] | ["delete", key, "noreply"] => match parse_key(key) {
+            Some(key) => Command::Delete { key, noreply },
+            None => client_error("bad command line format"),
+        },
+        [name @ ("incr" | "decr"), key, delta] | [name @ ("incr" | "decr"), key, delta, "noreply"] => match (parse_key(key), delta.parse()) {
+            (Some(key), Ok(delta)) => Command::Counter {
+                key,
+                delta,
+                decrement: *name == "decr",
+                noreply,
+            },
+            (_, Err(_)) => client_error("invalid numeric delta argument"),
+            (None, _) => client_error("bad command line format"),
+        },
+        ["stats"] => Command::Stats,
+        _ => Command::Error(String::from("ERROR")),
+    };
+    Some((command, consumed))
+}
+
+/// Text protocol of memcached, one command per line
+pub struct MemcachedAsciiHandler {
+    pub stream: BufReader<monoio::net::TcpStream>,
+    /// Received bytes, a set can span several reads
+    buffer: Vec<u8>,
+    /// Start of the bytes of `buffer` not decoded yet
+    position: usize,
+    /// Bytes of a refused item still to discard
+    skip: usize,
+}
+
+impl MemcachedAsciiHandler {
+    pub fn new(stream: BufReader<monoio::net::TcpStream>) -> MemcachedAsciiHandler {
+        MemcachedAsciiHandler {
+            stream,
+            buffer: Vec::new(),
+            position: 0,
+            skip: 0,
+        }
+    }
+
+    pub async fn decode_command(&mut self) -> Result<Command, std::io::Error> {
+        loop {
+            if let Some((command, consumed)) = parse(&self.buffer[self.position..]) {
+                let available = self.buffer.len() - self.position;
+                self.skip = consumed.saturating_sub(available);
+                self.position += consumed.min(available);
+                return Ok(command);
+            }
+            let buffer = self.stream.fill_buf().await?;
+            if buffer.is_empty() {
+                // Connection closed by the client
+                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "connection closed"));
+            }
+            let read = buffer.len();
+            let skipped = read.min(self.skip);
+            self.skip -= skipped;
+            self.buffer.drain(..self.position);
+            self.position = 0;
+            self.buffer.extend_from_slice(&buffer[skipped..]);
+            self.stream.consume(read);
+        }
+    }
+
+    pub async fn write_resp(&mut self, buff: Vec<u8>) {
+        let (res, _) = self.stream.write_all(buff).await;
+        res.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ascii_commands() {
+        assert_eq!(
+            parse(b"get foo bar\r\n"),
+            Some((Command::Get(vec![String::from("foo"), String::from("bar")]), 13))
+        );
+        // The data block is not fully received
+        assert_eq!(parse(b"set foo 5 0 3\r\nba"), None);
+        let set = Command::Set(Set {
+            key: String::from("foo"),
+            flags: 5,
+            exptime: 0,
+            data: b"bar".to_vec(),
+            noreply: true,
+        });
+        assert_eq!(parse(b"set foo 5 0 3 noreply\r\nbar\r\nget foo\r\n"), Some((set, 28)));
+        assert_eq!(parse(b"set foo 0 0 2\r\nbar\r\n").unwrap().0, client_error("bad data chunk"));
+        assert_eq!(
+            parse(b"decr foo 3\n"),
+            Some((
+                Command::Counter {
+                    key: String::from("foo"),
+                    delta: 3,
+                    decrement: true,
+                    noreply: false
+                },
+                11
+            ))
+        );
+        assert_eq!(parse(b"incr foo -1\r\n").unwrap().0, client_error("invalid numeric delta argument"));
+        assert_eq!(parse(b"touch foo 10\r\n").unwrap().0, Command::Error(String::from("ERROR")));
+        assert_eq!(parse(b"stats\r\n"), Some((Command::Stats, 7)));
+
+        // Oversized items are refused before their data block is received
+        let too_large = Command::Error(String::from("SERVER_ERROR object too large for cache"));
+        assert_eq!(parse(b"set foo 0 0 1048577\r\n"), Some((too_large.clone(), 21 + 1048577 + 2)));
+        assert_eq!(parse(b"set foo 0 0 18446744073709551615\r\n"), Some((too_large, usize::MAX)));
+    }
+}
@@ -1,25 +1,92 @@
+pub mod ascii;
 pub mod server;
-use monoio::io::{AsyncReadRent, AsyncWriteRentExt, BufReader};
+use monoio::io::{AsyncReadRentExt, AsyncWriteRentExt, BufReader};
 
 use crate::{
     api::{self},
+    datastore::error::DataStoreError,
     record::{Key, Record},
 };
 
+/// Command of a request and the opaque value its reply echoes, so clients
+/// pipelining quiet commands can tell which request a reply belongs to
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub command: Command,
+    pub opaque: u32,
+}
+
 #[derive(Debug, Clone)]
 pub enum Command {
     Set(Set),
     Get(Get),
+    Concat(Concat),
+    Delete(Delete),
+    Flush(Flush),
+    Noop,
+    Version,
+    /// Close the connection, after replying unless quiet
+    Quit {
+        quiet: bool,
+    },
+    /// Invalid request, answered with the status
+    Error {
+        opcode: u8,
+        status: OpCode,
+    },
 }
 
 impl Command {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Command::Set(s) if s.quiet => SETQ,
+            Command::Set(_) => SET,
+            Command::Get(g) if g.with_key && g.quiet => GETKQ,
+            Command::Get(g) if g.with_key => GETK,
+            Command::Get(g) if g.quiet => GETQ,
+            Command::Get(_) => GET,
+            Command::Concat(c) if c.prepend => PREPEND,
+            Command::Concat(_) => APPEND,
+            Command::Delete(d) if d.quiet => DELETEQ,
+            Command::Delete(_) => DELETE,
+            Command::Flush(f) if f.quiet => FLUSHQ,
+            Command::Flush(_) => FLUSH,
+            Command::Noop => NOOP,
+            Command::Version => VERSION,
+            Command::Quit { quiet: true } => QUITQ,
+            Command::Quit { quiet: false } => QUIT,
+            Command::Error { opcode, .. } => *opcode,
+        }
+    }
+
+    /// Key to echo in the response (GetK and GetKQ)
+    pub fn echoed_key(&self) -> Option<String> {
+        match self {
+            Command::Get(g) if g.with_key => Some(g.key.clone()),
+            _ => None,
+        }
+    }
+
     pub fn to_api_command(self) -> api::Command {
         api::Command::Data(match self {
             Command::Set(s) => api::DataCommand::Set(api::Set {
-                record: Record::new(s.key, s.data),
+                record: Record {
+                    flags: s.flags,
+                    ..Record::new(s.key, s.data)
+                },
+                options: api::SetOptions::default(),
+                relative_ttl: false,
             }),
             Command::Get(g) => api::DataCommand::Get(api::Get { key: Key::new(g.key) }),
-            _ => todo!(),
+            Command::Concat(c) => api::DataCommand::Concat(api::Concat {
+                key: Key::new(c.key),
+                value: c.data,
+                prepend: c.prepend,
+            }),
+            Command::Delete(d) => api::DataCommand::Delete(api::Delete { key: Key::new(d.key) }),
+            Command::Flush(_) | Command::Noop | Command::Version | Command::Quit { .. } | Command::Error { .. } => {
+                unreachable!("answered by the server")
+            }
         })
     }
 }
@@ -28,35 +95,76 @@ impl Command {
 pub enum Response {
     Set(SetResp),
     Get(GetResp),
+    Status(StatusResp),
+    Version(VersionResp),
 }
 
 impl Response {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// `opaque` is the one of the request
+    pub fn to_bytes(&self, opaque: u32) -> Vec<u8> {
         match self {
-            Response::Set(s) => s.to_bytes(),
-            Response::Get(g) => g.to_bytes(),
+            Response::Set(s) => s.to_bytes(opaque),
+            Response::Get(g) => g.to_bytes(opaque),
+            Response::Status(s) => s.to_bytes(opaque),
+            Response::Version(v) => v.to_bytes(opaque),
         }
     }
 
-    pub fn from_api_response(response: api::Response) -> Response {
+    pub fn status(&self) -> OpCode {
+        match self {
+            Response::Set(s) => s.status,
+            Response::Get(g) => g.status,
+            Response::Status(s) => s.status,
+            Response::Version(_) => OpCode::NoError,
+        }
+    }
+
+    /// `opcode` is the one of the command the response is for
+    pub fn from_api_response(opcode: u8, response: api::Response) -> Response {
         match response {
             api::Response::Get(g) => {
-                let maybe_value = match g.record {
-                    Some(r) => Some(r.value),
-                    None => None,
+                let status = match g.record {
+                    Ok(Some(_)) => OpCode::NoError,
+                    Ok(None) => OpCode::KeyNotFound,
+                    // The disktable holding the record can't be read
+                    Err(_) => OpCode::InternalError,
                 };
+                let record = g.record.ok().flatten();
                 Response::Get(GetResp {
-                    flags: 0,
-                    opcode: OpCode::NoError,
+                    flags: record.as_ref().map_or(0, |r| r.flags),
+                    opcode,
+                    status,
                     cas: 0,
-                    value: maybe_value,
+                    key: None,
+                    value: record.map(|r| r.value),
                 })
             }
-            api::Response::Delete(_) => todo!(),
-            api::Response::Set(_s) => Response::Set(SetResp {
-                opcode: OpCode::NoError,
+            api::Response::Delete(d) => Response::Status(StatusResp {
+                opcode,
+                status: match d.deleted {
+                    Ok(true) => OpCode::NoError,
+                    Ok(false) => OpCode::KeyNotFound,
+                    Err(_) => OpCode::InternalError,
+                },
+            }),
+            api::Response::Set(s) => Response::Set(SetResp {
+                opcode,
+                status: match s.applied {
+                    Ok(_) => OpCode::NoError,
+                    Err(DataStoreError::Busy) => OpCode::Busy,
+                    Err(_) => OpCode::InternalError,
+                },
                 cas: 0,
             }),
+            api::Response::Concat(c) => Response::Status(StatusResp {
+                opcode,
+                status: match c.len {
+                    Ok(Some(_)) => OpCode::NoError,
+                    // Also returned by memcached for keys it can't append to
+                    Ok(None) | Err(api::ValueError::WrongType) => OpCode::ItemNotStored,
+                    Err(api::ValueError::Storage(_)) => OpCode::InternalError,
+                },
+            }),
             _ => todo!(),
         }
     }
@@ -92,6 +200,32 @@ impl Response {
 
 const GET: u8 = 0x0;
 const SET: u8 = 0x1;
+const DELETE: u8 = 0x04;
+const QUIT: u8 = 0x07;
+const FLUSH: u8 = 0x08;
+const GETQ: u8 = 0x09;
+const NOOP: u8 = 0x0A;
+const VERSION: u8 = 0x0B;
+const GETK: u8 = 0x0C;
+const GETKQ: u8 = 0x0D;
+const APPEND: u8 = 0x0E;
+const PREPEND: u8 = 0x0F;
+const SETQ: u8 = 0x11;
+const DELETEQ: u8 = 0x14;
+const QUITQ: u8 = 0x17;
+const FLUSHQ: u8 = 0x18;
+
+pub fn is_quiet(opcode: u8) -> bool {
+    matches!(opcode, GETQ | GETKQ | SETQ | DELETEQ | QUITQ | FLUSHQ)
+}
+
+/// Quiet commands don't reply on a miss (GetQ) or on success (others)
+pub fn is_silenced(opcode: u8, status: OpCode) -> bool {
+    match opcode {
+        GETQ | GETKQ => status == OpCode::KeyNotFound,
+        opcode => is_quiet(opcode) && status == OpCode::NoError,
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Set {
@@ -99,29 +233,104 @@ pub struct Set {
     pub flags: u32,
     pub exptime: u32,
     pub data: Vec<u8>,
+    pub quiet: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct Get {
     pub key: String,
+    pub quiet: bool,
+    /// GetK and GetKQ return the key with the value
+    pub with_key: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Delete {
+    pub key: String,
+    pub quiet: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Flush {
+    /// Seconds before the items are removed, 0 to remove them right away
+    pub delay: u32,
+    pub quiet: bool,
+}
+
+/// Append or Prepend
+#[derive(Debug, Clone)]
+pub struct Concat {
+    pub key: String,
+    pub data: Vec<u8>,
+    pub prepend: bool,
+}
+
+/// Response without body, only the status of the command
+#[derive(Debug, Clone)]
+pub struct StatusResp {
+    pub opcode: u8,
+    pub status: OpCode,
+}
+
+impl StatusResp {
+    pub fn to_bytes(&self, opaque: u32) -> Vec<u8> {
+        Header {
+            magic: 0x81,
+            opcode: self.opcode,
+            key_size: 0,
+            extra_size: 0,
+            status: self.status as u16,
+            body_length: 0,
+            opaque,
+            cas: 0,
+            data_type: 0,
+        }
+        .to_be_bytes()
+    }
+}
+
+/// Version of the server as the value
+#[derive(Debug, Clone)]
+pub struct VersionResp {
+    pub version: String,
+}
+
+impl VersionResp {
+    pub fn to_bytes(&self, opaque: u32) -> Vec<u8> {
+        let mut resp = Header {
+            magic: 0x81,
+            opcode: VERSION,
+            key_size: 0,
+            extra_size: 0,
+            status: 0,
+            body_length: self.version.len() as u32,
+            opaque,
+            cas: 0,
+            data_type: 0,
+        }
+        .to_be_bytes();
+        resp.extend(self.version.as_bytes());
+        resp
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct SetResp {
-    pub opcode: OpCode,
+    pub opcode: u8,
+    pub status: OpCode,
     pub cas: u64,
 }
 
 impl SetResp {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    pub fn to_bytes(&self, opaque: u32) -> Vec<u8> {
         let h = Header {
             magic: 0x81,
-            opcode: self.opcode as u8,
+            opcode: self.opcode,
             key_size: 0,
             extra_size: 0,
-            status: 0,
+            status: self.status as u16,
             body_length: 0,
-            opaque: 0,
+            opaque,
             cas: self.cas,
             data_type: 0,
         };
@@ -132,39 +341,46 @@ impl SetResp {
 #[derive(Debug, Clone)]
 pub struct GetResp {
     pub flags: u32,
-    pub opcode: OpCode,
+    pub opcode: u8,
+    pub status: OpCode,
     pub cas: u64,
+    pub key: Option<String>,
     pub value: Option<Vec<u8>>,
 }
 
 impl GetResp {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let value_size = match &self.value {
-            Some(v) => v.len(),
+    pub fn to_bytes(&self, opaque: u32) -> Vec<u8> {
+        // Misses have neither the flags nor a value
+        let extra_size = match &self.value {
+            Some(_) => 4,
             None => 0,
-        } as u32;
-        let body_size = 4 + value_size as usize;
-        let mut resp = Vec::with_capacity(body_size);
+        };
+        let key_size = self.key.as_ref().map_or(0, |k| k.len());
+        let body_size = extra_size + key_size + self.value.as_ref().map_or(0, |v| v.len());
+        let mut resp = Vec::with_capacity(24 + body_size);
         resp.extend(
             Header {
                 magic: 0x81,
-                opcode: 0x0,
-                key_size: 0,
-                extra_size: 0,
-                status: 0,
+                opcode: self.opcode,
+                key_size: key_size as u16,
+                extra_size: extra_size as u8,
+                status: self.status as u16,
                 body_length: body_size as u32,
-                opaque: 0,
+                opaque,
                 cas: self.cas,
                 data_type: 0,
             }
-            .to_be_bytes()
-            .to_vec(),
+            .to_be_bytes(),
         );
-        resp.extend(self.flags.to_be_bytes());
-        match &self.value {
-            Some(v) => resp.extend(v.clone()),
-            None => (),
-        };
+        if self.value.is_some() {
+            resp.extend(self.flags.to_be_bytes());
+        }
+        if let Some(k) = &self.key {
+            resp.extend(k.as_bytes());
+        }
+        if let Some(v) = &self.value {
+            resp.extend(v);
+        }
         resp
     }
 }
@@ -186,7 +402,7 @@ impl GetResp {
 // 0x0085 	Busy
 // 0x0086 	Temporary failure
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
     NoError = 0,
     KeyNotFound = 1,
@@ -198,12 +414,12 @@ pub enum OpCode {
     VBucketBelongsToAnotherServer = 7,
     AuthErr = 8,
     AuthContinue = 9,
-    UnknownCommand = 81,
-    OOM = 82,
-    NotSupported = 83,
-    InternalError = 84,
-    Busy = 85,
-    TemporaryFailure = 86,
+    UnknownCommand = 0x81,
+    OOM = 0x82,
+    NotSupported = 0x83,
+    InternalError = 0x84,
+    Busy = 0x85,
+    TemporaryFailure = 0x86,
 }
 
 pub enum GetResult {
@@ -225,10 +441,6 @@ struct Header {
 }
 
 impl Header {
-    fn get_data_length(&self) -> usize {
-        self.body_length as usize - self.key_size as usize - self.extra_size as usize
-    }
-
     fn to_be_bytes(&self) -> Vec<u8> {
         let mut bytes = vec![0u8; 24];
         bytes[0] = self.magic;
@@ -258,48 +470,6 @@ impl Header {
     }
 }
 
-// pub struct MemcachedAsciiHandler {
-//     reader: dyn Read
-// }
-
-// impl MemcachedAsciiHandler {
-
-//     // <command name> <key> <flags> <exptime> <bytes> [noreply]\r\n
-//     fn parse_set(&mut self) -> Option<Set> {
-//         let mut command = Vec::new();
-//         self.reader.read(&mut command).unwrap();
-
-//         let mut cmd_iter = command.iter();
-//         let key_end = cmd_iter.position(|c| *c == SPACE).unwrap();
-//         let key = String::from_utf8(command[1..key_end].to_vec()).unwrap();
-//         cmd_iter.next().unwrap();
-
-//         let flags_end = cmd_iter.position(|c| *c == SPACE).unwrap();
-//         let flags = u16::from_be_bytes(command[key_end+1..flags_end].try_into().unwrap());
-//         cmd_iter.next().unwrap();
-
-//         let exptime_end = cmd_iter.position(|c| *c == SPACE).unwrap();
-//         let exptime = u64::from_be_bytes(command[flags_end+1..exptime_end].try_into().unwrap());
-
-//         let data = command[exptime_end+1..command.len()-4].to_vec();
-//         Some(Set { key, flags, exptime, data })
-//     }
-// }
-
-// impl Iterator for MemcachedAsciiHandler {
-//     type Item = Command;
-
-//     fn next(&mut self) -> Option<Self::Item> {
-//         let mut command_buffer = [0u8; 3];
-//         self.reader.read(&mut command_buffer).unwrap();
-//         match command_buffer {
-//             SET => Some(Command::Set(self.parse_set().unwrap())),
-//             GET => todo!(),
-//             _ => todo!(),
-//         }
-//     }
-// }
-
 // Byte/     0       |       1       |       2       |       3       |
 // /              |               |               |               |
 // |0 1 2 3 4 5 6 7|0 1 2 3 4 5 6 7|0 1 2 3 4 5 6 7|0 1 2 3 4 5 6 7|
@@ -326,78 +496,112 @@ impl Header {
 // Extras length       Length in bytes of the command extras.
 // Data type           Reserved for future use (Sean is using this soon).
 
-pub struct MemcachedBinaryHandler {
-    pub stream: BufReader<monoio::net::TcpStream>,
-}
-
-impl MemcachedBinaryHandler {
-    async fn parse_set(&mut self, header: &Header) -> Option<Set> {
-        assert_eq!(header.extra_size, 8u8);
-        let buff = vec![0u8; header.extra_size as usize + header.key_size as usize + header.get_data_length()];
-
-        let (res, buff) = self.stream.read(buff).await;
-        res.unwrap();
-        let flags = u32::from_be_bytes(buff[0..4].try_into().unwrap());
-        let exptime = u32::from_be_bytes(buff[4..8].try_into().unwrap());
-
-        let key_end = header.extra_size as usize + header.key_size as usize;
-        let key = String::from_utf8(buff[8..key_end].to_owned()).unwrap();
-        let data = buff[key_end..].to_vec();
+/// Larger requests are refused by closing the connection
+const BODY_MAX_LENGTH: u32 = 32 * 1024 * 1024;
 
-        Some(Set { key, flags, exptime, data })
+/// Split the body of a request in its extras, key and value
+fn split_body<'a>(header: &Header, body: &'a [u8]) -> Result<(&'a [u8], String, &'a [u8]), OpCode> {
+    let key_start = header.extra_size as usize;
+    let key_end = key_start + header.key_size as usize;
+    if key_end > body.len() {
+        return Err(OpCode::InvalidArguments);
     }
+    let key = String::from_utf8(body[key_start..key_end].to_vec()).map_err(|_| OpCode::InvalidArguments)?;
+    Ok((&body[..key_start], key, &body[key_end..]))
+}
 
-    async fn parse_get(&mut self, header: &Header) -> Option<Get> {
-        assert_eq!(header.extra_size, 0u8);
+/// Parse a request, return the status to reply if it is invalid
+fn parse_command(header: &Header, body: &[u8]) -> Result<Command, OpCode> {
+    let (extras, key, value) = split_body(header, body)?;
+    let opcode = header.opcode;
+    let has_key = !key.is_empty();
+    let command = match (opcode, extras.len()) {
+        (SET | SETQ, 8) if has_key => Command::Set(Set {
+            key,
+            flags: u32::from_be_bytes(extras[0..4].try_into().unwrap()),
+            exptime: u32::from_be_bytes(extras[4..8].try_into().unwrap()),
+            data: value.to_vec(),
+            quiet: opcode == SETQ,
+        }),
+        (GET | GETQ | GETK | GETKQ, 0) if has_key && value.is_empty() => Command::Get(Get {
+            key,
+            quiet: matches!(opcode, GETQ | GETKQ),
+            with_key: matches!(opcode, GETK | GETKQ),
+        }),
+        (DELETE | DELETEQ, 0) if has_key && value.is_empty() => Command::Delete(Delete {
+            key,
+            quiet: opcode == DELETEQ,
+        }),
+        // The expiration extra is optional
+        (FLUSH | FLUSHQ, 0 | 4) => Command::Flush(Flush {
+            delay: extras.try_into().map_or(0, u32::from_be_bytes),
+            quiet: opcode == FLUSHQ,
+        }),
+        (APPEND | PREPEND, 0) if has_key => Command::Concat(Concat {
+            key,
+            data: value.to_vec(),
+            prepend: opcode == PREPEND,
+        }),
+        (NOOP, 0) => Command::Noop,
+        (VERSION, 0) => Command::Version,
+        (QUIT | QUITQ, 0) => Command::Quit { quiet: opcode == QUITQ },
+        (SET | SETQ | GET | GETQ | GETK | GETKQ | DELETE | DELETEQ | FLUSH | FLUSHQ | APPEND | PREPEND, _) => return Err(OpCode::InvalidArguments),
+        _ => return Err(OpCode::UnknownCommand),
+    };
+    Ok(command)
+}
 
-        let key_bytes = vec![0u8; header.key_size as usize];
-        let (res, key_bytes) = self.stream.read(key_bytes).await;
-        res.unwrap();
-        let key = String::from_utf8(key_bytes.to_owned()).unwrap();
+pub struct MemcachedBinaryHandler {
+    pub stream: BufReader<monoio::net::TcpStream>,
+    /// Replies of quiet commands, sent with the next reply
+    pending: Vec<u8>,
+}
 
-        Some(Get { key })
+impl MemcachedBinaryHandler {
+    pub fn new(stream: BufReader<monoio::net::TcpStream>) -> MemcachedBinaryHandler {
+        MemcachedBinaryHandler { stream, pending: Vec::new() }
     }
 
-    // pub async fn await_new_data(&mut self) -> Result<(), GlommioError<()>> {
-    //     // TODO: Make this a future
-    //     let mut buffer = [0u8; 24];
-    //     loop {
-    //         let res = self.stream.readable(false).await;
-    //         match res {
-    //             Ok(b) => {
-    //                 if b > 0 {
-    //                     return Ok(());
-    //                 }
-    //             }
-    //             Err(r) => return Err(r),
-    //         }
-    //         sleep(Duration::from_millis(1)).await;
-    //     }
-    // }
-
-    pub async fn decode_command(&mut self) -> Result<Command, std::io::Error> {
-        let mut header_buff = vec![0u8; 24];
-        let res: Result<usize, std::io::Error>;
-        loop {
-            (res, header_buff) = self.stream.read(header_buff).await;
-            if res? == 0 {
-                panic!("huho");
-                // sleep(Duration::from_micros(100)).await;
-                // continue;
-            }
-            break;
+    /// Read the next request. Invalid requests are returned as
+    /// `Command::Error`, only unrecoverable framing errors are Err
+    pub async fn decode_request(&mut self) -> Result<Request, std::io::Error> {
+        let (res, header_buff) = self.stream.read_exact(vec![0u8; 24]).await;
+        if let Err(err) = res {
+            return match err.kind() {
+                // Connection closed by the client
+                std::io::ErrorKind::UnexpectedEof => Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "connection closed")),
+                _ => Err(err),
+            };
         }
 
         let header = Header::from_be_bytes(header_buff);
-        match header.opcode {
-            SET => Ok(Command::Set(self.parse_set(&header).await.unwrap())),
-            GET => Ok(Command::Get(self.parse_get(&header).await.unwrap())),
-            _ => todo!(),
+        if header.magic != 0x80 || header.body_length > BODY_MAX_LENGTH {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid request header"));
         }
+        // The whole body is read even if it is invalid to stay on the next request
+        let (res, body) = self.stream.read_exact(vec![0u8; header.body_length as usize]).await;
+        res?;
+
+        let command = parse_command(&header, &body).unwrap_or_else(|status| Command::Error {
+            opcode: header.opcode,
+            status,
+        });
+        Ok(Request {
+            command,
+            opaque: header.opaque,
+        })
+    }
+
+    /// Keep the reply of a quiet command until the next one is written
+    pub fn buffer_resp(&mut self, buff: Vec<u8>) {
+        self.pending.extend(buff);
     }
 
+    /// Write the reply after the buffered ones
     pub async fn write_resp(&mut self, buff: Vec<u8>) {
-        let (res, _) = self.stream.write_all(buff).await;
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend(buff);
+        let (res, _) = self.stream.write_all(pending).await;
         res.unwrap();
     }
 }
@@ -417,3 +621,105 @@ impl MemcachedBinaryHandler {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_response_with_key() {
+        let resp = GetResp {
+            flags: 7,
+            opcode: GETK,
+            status: OpCode::NoError,
+            cas: 0,
+            key: Some(String::from("foo")),
+            value: Some(b"bar".to_vec()),
+        };
+        let bytes = resp.to_bytes(42);
+        let header = Header::from_be_bytes(bytes[..24].to_vec());
+        assert_eq!((header.opcode, header.key_size, header.extra_size, header.body_length), (GETK, 3, 4, 10));
+        assert_eq!(header.opaque, 42);
+        assert_eq!(&bytes[24..], b"\0\0\0\x07foobar");
+        assert!(is_silenced(GETKQ, OpCode::KeyNotFound));
+        assert!(!is_silenced(GETK, OpCode::KeyNotFound));
+    }
+
+    #[test]
+    fn test_pipelined_quiet_gets_echo_opaque() {
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+
+        rt.block_on(async {
+            let listener = monoio::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = monoio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut handler = MemcachedBinaryHandler::new(BufReader::new(stream));
+
+            let mut requests = vec![];
+            for (opaque, key) in [(7, "a"), (8, "b"), (9, "c")] {
+                let header = Header {
+                    magic: 0x80,
+                    opcode: GETQ,
+                    key_size: 1,
+                    extra_size: 0,
+                    data_type: 0,
+                    status: 0,
+                    body_length: 1,
+                    opaque,
+                    cas: 0,
+                };
+                requests.extend(header.to_be_bytes());
+                requests.extend(key.as_bytes());
+            }
+            let (res, _) = client.write_all(requests).await;
+            res.unwrap();
+
+            // "b" misses, its reply is silenced
+            for hit in [true, false, true] {
+                let request = handler.decode_request().await.unwrap();
+                let resp = GetResp {
+                    flags: 0,
+                    opcode: request.command.opcode(),
+                    status: if hit { OpCode::NoError } else { OpCode::KeyNotFound },
+                    cas: 0,
+                    key: None,
+                    value: hit.then(|| b"v".to_vec()),
+                };
+                if !is_silenced(GETQ, resp.status) {
+                    handler.buffer_resp(resp.to_bytes(request.opaque));
+                }
+            }
+            handler.write_resp(vec![]).await;
+
+            let (res, replies) = client.read_exact(vec![0u8; 2 * (24 + 4 + 1)]).await;
+            res.unwrap();
+            let opaques: Vec<u32> = replies
+                .chunks(24 + 4 + 1)
+                .map(|reply| Header::from_be_bytes(reply[..24].to_vec()).opaque)
+                .collect();
+            assert_eq!(opaques, vec![7, 9]);
+        });
+    }
+
+    #[test]
+    fn test_parse_invalid_commands() {
+        let header = |opcode, key_size, extra_size, body_length| Header {
+            magic: 0x80,
+            opcode,
+            key_size,
+            extra_size,
+            data_type: 0,
+            status: 0,
+            body_length,
+            opaque: 0,
+            cas: 0,
+        };
+        let delete = parse_command(&header(DELETEQ, 3, 0, 3), b"foo").unwrap();
+        assert_eq!(delete.opcode(), DELETEQ);
+        assert_eq!(parse_command(&header(0x42, 0, 0, 0), b"").unwrap_err(), OpCode::UnknownCommand);
+        // Missing extras
+        assert_eq!(parse_command(&header(SET, 3, 0, 6), b"foobar").unwrap_err(), OpCode::InvalidArguments);
+        // Key larger than the body
+        assert_eq!(parse_command(&header(GET, 5, 0, 3), b"foo").unwrap_err(), OpCode::InvalidArguments);
+    }
+}
@@ -1,28 +1,46 @@
-use std::rc::Rc;
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
+use futures::future::join_all;
 use monoio::{io::BufReader, net::TcpListener};
 
 use crate::{
-    memcached::{MemcachedBinaryHandler, Response},
+    api,
+    datastore::metrics,
+    memcached::{
+        self,
+        ascii::{self, MemcachedAsciiHandler},
+        Command, MemcachedBinaryHandler, OpCode, Response, StatusResp, VersionResp,
+    },
+    reactor::supervisor,
+    record::{Key, ValueType},
     storageproxy::StorageProxy,
 };
 
 pub struct MemcachedBinaryServer {
-    pub host_port: String,
+    pub addrs: Vec<SocketAddr>,
     pub storage_proxy: Rc<StorageProxy>,
 }
 
 impl MemcachedBinaryServer {
-    pub async fn listen(self) {
-        let listener = TcpListener::bind(self.host_port.clone()).unwrap();
+    /// Listen on every configured address
+    pub async fn listen(&self) {
+        let listeners: Vec<TcpListener> = self.addrs.iter().map(|addr| TcpListener::bind(addr).unwrap()).collect();
+        join_all(listeners.iter().map(|listener| self.accept(listener))).await;
+    }
 
+    async fn accept(&self, listener: &TcpListener) {
         println!("Listening on {}", listener.local_addr().unwrap());
         loop {
-            let (stream, _) = listener.accept().await.unwrap();
+            let (stream, addr) = listener.accept().await.unwrap();
             let storage_proxy = self.storage_proxy.clone();
             let reader = BufReader::new(stream);
-            monoio::spawn(async move {
-                let mut handler = MemcachedBinaryHandler { stream: reader };
+            monoio::spawn(supervisor::isolate(format!("memcached connection {}", addr), async move {
+                let mut handler = MemcachedBinaryHandler::new(reader);
                 // let compat = TcpStreamCompat::new(stream);
                 // let tokio_stream: TcpStream = compat.into();
                 // compat.poll_peek();
@@ -30,7 +48,100 @@ impl MemcachedBinaryServer {
                     // if handler.await_new_data().await.is_err() {
                     //     return;
                     // }
-                    let memcached_command = match handler.decode_command().await {
+                    let request = match handler.decode_request().await {
+                        Ok(request) => request,
+                        Err(err) => match err.kind() {
+                            std::io::ErrorKind::ConnectionReset => break,
+                            _ => {
+                                println!("Error on conn: {}", err);
+                                break;
+                            }
+                        },
+                    };
+                    let memcached_command = request.command;
+                    let opcode = memcached_command.opcode();
+                    let quit = matches!(memcached_command, Command::Quit { .. });
+                    let key = memcached_command.echoed_key();
+                    let response = match memcached_command {
+                        Command::Flush(flush) => {
+                            flush_all(&storage_proxy, flush.delay).await;
+                            Response::Status(StatusResp {
+                                opcode,
+                                status: OpCode::NoError,
+                            })
+                        }
+                        Command::Noop | Command::Quit { .. } => Response::Status(StatusResp {
+                            opcode,
+                            status: OpCode::NoError,
+                        }),
+                        Command::Error { opcode, status } => Response::Status(StatusResp { opcode, status }),
+                        Command::Version => Response::Version(VersionResp {
+                            version: String::from(env!("CARGO_PKG_VERSION")),
+                        }),
+                        command => {
+                            let resp = storage_proxy.dispatch(command.to_api_command()).await;
+                            let mut response = Response::from_api_response(opcode, resp);
+                            if let Response::Get(get) = &mut response {
+                                get.key = key;
+                            }
+                            response
+                        }
+                    };
+                    if !memcached::is_silenced(opcode, response.status()) {
+                        match memcached::is_quiet(opcode) {
+                            true => handler.buffer_resp(response.to_bytes(request.opaque)),
+                            false => handler.write_resp(response.to_bytes(request.opaque)).await,
+                        }
+                    }
+                    if quit {
+                        // Buffered replies are still sent before closing
+                        handler.write_resp(vec![]).await;
+                        break;
+                    }
+                }
+            }));
+        }
+    }
+}
+
+/// Remove the data of the reactor, after `delay` seconds if not 0
+async fn flush_all(storage_proxy: &Rc<StorageProxy>, delay: u32) {
+    if delay == 0 {
+        storage_proxy.flush_all(false).await;
+        return;
+    }
+    let storage_proxy = storage_proxy.clone();
+    monoio::spawn(supervisor::isolate(String::from("delayed memcached flush"), async move {
+        monoio::time::sleep(Duration::from_secs(delay as u64)).await;
+        storage_proxy.flush_all(false).await;
+    }));
+}
+
+/// Server of the text protocol, on its own port
+pub struct MemcachedAsciiServer {
+    pub addrs: Vec<SocketAddr>,
+    pub storage_proxy: Rc<StorageProxy>,
+    pub started: Instant,
+}
+
+impl MemcachedAsciiServer {
+    /// Listen on every configured address
+    pub async fn listen(&self) {
+        let listeners: Vec<TcpListener> = self.addrs.iter().map(|addr| TcpListener::bind(addr).unwrap()).collect();
+        join_all(listeners.iter().map(|listener| self.accept(listener))).await;
+    }
+
+    async fn accept(&self, listener: &TcpListener) {
+        println!("Listening on {}", listener.local_addr().unwrap());
+        loop {
+            let (stream, addr) = listener.accept().await.unwrap();
+            let storage_proxy = self.storage_proxy.clone();
+            let started = self.started;
+            let reader = BufReader::new(stream);
+            monoio::spawn(supervisor::isolate(format!("memcached ascii connection {}", addr), async move {
+                let mut handler = MemcachedAsciiHandler::new(reader);
+                loop {
+                    let command = match handler.decode_command().await {
                         Ok(c) => c,
                         Err(err) => match err.kind() {
                             std::io::ErrorKind::ConnectionReset => break,
@@ -40,10 +151,104 @@ impl MemcachedBinaryServer {
                             }
                         },
                     };
-                    let resp = storage_proxy.dispatch(memcached_command.to_api_command()).await;
-                    handler.write_resp(Response::from_api_response(resp).to_bytes()).await;
+                    let resp = handle_ascii_command(command, &storage_proxy, started).await;
+                    if !resp.is_empty() {
+                        handler.write_resp(resp).await;
+                    }
                 }
-            });
+            }));
+        }
+    }
+}
+
+/// Execute a text command, return the reply (empty with noreply)
+async fn handle_ascii_command(command: ascii::Command, storage_proxy: &StorageProxy, started: Instant) -> Vec<u8> {
+    let (reply, noreply) = match command {
+        ascii::Command::Get(keys) => {
+            let keys = keys.into_iter().map(Key::new).collect();
+            let mut reply = Vec::new();
+            for resp in storage_proxy.get_many(keys).await {
+                let record = match resp.record {
+                    Ok(record) => record,
+                    // Ends the reply, like memcached
+                    Err(e) => {
+                        reply.extend(format!("SERVER_ERROR {}\r\n", e.message()).into_bytes());
+                        return reply;
+                    }
+                };
+                // Only strings are visible to memcached clients
+                if let Some(record) = record.filter(|r| r.value_type == ValueType::String) {
+                    reply.extend(format!("VALUE {} {} {}\r\n", record.key.string, record.flags, record.value.len()).into_bytes());
+                    reply.extend(record.value);
+                    reply.extend(b"\r\n");
+                }
+            }
+            reply.extend(b"END\r\n");
+            return reply;
+        }
+        ascii::Command::Set(set) => {
+            let api::Response::Set(resp) = storage_proxy.dispatch(set.to_api_command()).await else {
+                panic!("Unexpected response")
+            };
+            let reply = match resp.applied {
+                Ok(_) => String::from("STORED"),
+                Err(e) => format!("SERVER_ERROR {}", e.message()),
+            };
+            (reply, set.noreply)
+        }
+        ascii::Command::Delete { key, noreply } => {
+            let delete = api::Command::Data(api::DataCommand::Delete(api::Delete { key: Key::new(key) }));
+            let api::Response::Delete(resp) = storage_proxy.dispatch(delete).await else {
+                panic!("Unexpected response")
+            };
+            let reply = match resp.deleted {
+                Ok(true) => String::from("DELETED"),
+                Ok(false) => String::from("NOT_FOUND"),
+                Err(e) => format!("SERVER_ERROR {}", e.message()),
+            };
+            (reply, noreply)
+        }
+        ascii::Command::Counter {
+            key,
+            delta,
+            decrement,
+            noreply,
+        } => {
+            let counter = api::Command::Data(api::DataCommand::Counter(api::Counter {
+                key: Key::new(key),
+                delta,
+                decrement,
+            }));
+            let api::Response::Counter(resp) = storage_proxy.dispatch(counter).await else {
+                panic!("Unexpected response")
+            };
+            let reply = match resp.value {
+                Ok(Some(value)) => value.to_string(),
+                Ok(None) => String::from("NOT_FOUND"),
+                Err(api::IncrError::Storage(e)) => format!("SERVER_ERROR {}", e.message()),
+                Err(_) => String::from("CLIENT_ERROR cannot increment or decrement non-numeric value"),
+            };
+            (reply, noreply)
+        }
+        ascii::Command::Stats => {
+            let stats = storage_proxy.local_stats();
+            let items: usize = stats.iter().map(|(_, stats)| stats.index_len()).sum();
+            let mut reply = String::new();
+            writeln!(reply, "STAT pid {}\r", std::process::id()).unwrap();
+            writeln!(reply, "STAT uptime {}\r", started.elapsed().as_secs()).unwrap();
+            writeln!(reply, "STAT time {}\r", crate::time::to_unix_ms(crate::time::now()) / 1000).unwrap();
+            writeln!(reply, "STAT version {}\r", env!("CARGO_PKG_VERSION")).unwrap();
+            writeln!(reply, "STAT curr_items {}\r", items).unwrap();
+            for (name, value) in metrics::sum(stats.iter().map(|(_, stats)| stats.metrics())) {
+                writeln!(reply, "STAT {} {}\r", name, value).unwrap();
+            }
+            reply.push_str("END\r\n");
+            return reply.into_bytes();
         }
+        ascii::Command::Error(error) => (error, false),
+    };
+    match noreply {
+        true => vec![],
+        false => format!("{}\r\n", reply).into_bytes(),
     }
 }
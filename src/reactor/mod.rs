@@ -1,51 +1,99 @@
-use std::{path::PathBuf, rc::Rc};
+pub mod supervisor;
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    rc::Rc,
+    sync::Arc,
+    time::Instant,
+};
+
+use std::pin::pin;
 
+use futures::future::select;
 use monoio::join;
 
 use crate::{
-    cluster::{ClusterManagerBuilder, ClusterMessage},
-    memcached::server::MemcachedBinaryServer,
-    redis::server::RESPServer,
+    cluster::{ClusterManagerBuilder, ClusterMessage, MeshMessage},
+    config::RuntimeConfig,
+    memcached::server::{MemcachedAsciiServer, MemcachedBinaryServer},
+    redis::{connection::ConnectionRegistry, pubsub::Broker, server::RESPServer},
     storageproxy::StorageProxy,
-    topology::{ReactorMetadata, Topology},
+    topology::ReactorMetadata,
 };
 
+/// Applies the messages sent by the cluster manager and the other reactors,
+/// returns when the reactor must stop
 pub struct TopologyUpdater {
-    receiver: async_channel::Receiver<Topology>,
+    receiver: async_channel::Receiver<MeshMessage>,
     storage_proxy: Rc<StorageProxy>,
+    broker: Rc<Broker>,
 }
 
 impl TopologyUpdater {
     pub async fn start(&self) {
         loop {
             println!("Waiting for new topology");
-            let topology = self.receiver.recv().await.unwrap();
-            println!("Received new topology");
-            self.storage_proxy.apply_new_topology(&topology).await;
+            match self.receiver.recv().await.unwrap() {
+                MeshMessage::Topology(topology) => {
+                    println!("Received new topology");
+                    self.storage_proxy.apply_new_topology(&topology).await;
+                }
+                MeshMessage::Reset(reset) => {
+                    println!("Received reset (wipe data: {})", reset.wipe_data);
+                    self.storage_proxy.reset(reset.metadata, reset.wipe_data).await;
+                }
+                MeshMessage::Publish(publish) => {
+                    let receivers = self.broker.deliver(&publish.channel, &publish.payload);
+                    // The publisher may have gone away
+                    let _ = publish.receivers.send(receivers).await;
+                }
+                MeshMessage::Shutdown(shutdown) => {
+                    println!("Received shutdown (save: {})", shutdown.save);
+                    if shutdown.save {
+                        // The unflushed records are replayed from the WAL
+                        if let Err(e) = self.storage_proxy.flush_memtables().await {
+                            println!("Can't flush the memtables before shutting down: {:?}", e);
+                        }
+                    }
+                    return;
+                }
+            }
         }
     }
 }
 
 pub struct Reactor {
     metadata: ReactorMetadata,
-    receiver: async_channel::Receiver<Topology>,
+    /// Addresses to listen on, the advertised one is in the metadata
+    bind_addrs: Vec<IpAddr>,
+    receiver: async_channel::Receiver<MeshMessage>,
+    /// Mesh channels of every reactor of the node, this one included
+    mesh: HashMap<u8, async_channel::Sender<MeshMessage>>,
     data_dir: PathBuf,
     cmb: Option<ClusterManagerBuilder>,
     shard_total: u16,
     cluster_sender: async_channel::Sender<ClusterMessage>,
+    runtime_config: Arc<RuntimeConfig>,
 }
 
 impl Reactor {
     pub fn new(
         reactor: ReactorMetadata,
+        bind_addrs: Vec<IpAddr>,
         shard_total: u16,
-        receiver: async_channel::Receiver<Topology>,
+        receiver: async_channel::Receiver<MeshMessage>,
+        mesh: HashMap<u8, async_channel::Sender<MeshMessage>>,
         cluster_sender: async_channel::Sender<ClusterMessage>,
         data_dir: PathBuf,
     ) -> Reactor {
         Reactor {
+            runtime_config: Arc::new(RuntimeConfig::new()),
             metadata: reactor,
+            bind_addrs,
             receiver,
+            mesh,
             data_dir,
             cluster_sender,
             cmb: None,
@@ -57,6 +105,15 @@ impl Reactor {
         self.cmb = Some(cmb);
     }
 
+    /// Share the runtime configuration with the other reactors of the node
+    pub fn runtime_config(&mut self, runtime_config: Arc<RuntimeConfig>) {
+        self.runtime_config = runtime_config;
+    }
+
+    fn socket_addrs(&self, port: u16) -> Vec<SocketAddr> {
+        self.bind_addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect()
+    }
+
     pub fn start(&mut self) {
         println!("Start reactor {}", self.metadata.id);
 
@@ -71,6 +128,8 @@ impl Reactor {
             .build()
             .unwrap();
 
+        supervisor::configure(supervisor::DEFAULT_MAX_CRASHES, supervisor::DEFAULT_CRASH_WINDOW);
+
         rt.block_on(async {
             let id = 0;
             println!("Starting executor {}", id);
@@ -78,7 +137,10 @@ impl Reactor {
             match &self.cmb {
                 Some(cmb) => {
                     let mut cm = cmb.build().await;
-                    monoio::spawn(async move { cm.start_master().await });
+                    monoio::spawn(supervisor::isolate(
+                        String::from("cluster manager"),
+                        async move { cm.start_master().await },
+                    ));
                 }
                 None => (),
             };
@@ -88,24 +150,55 @@ impl Reactor {
                 self.shard_total,
                 self.cluster_sender.clone(),
                 &self.data_dir,
+                self.runtime_config.clone(),
             ));
 
+            let peers = self
+                .mesh
+                .iter()
+                .filter(|(id, _)| **id != self.metadata.id)
+                .map(|(_, sender)| sender.clone())
+                .collect();
+            let broker = Rc::from(Broker::new(peers));
+
             let topology_updater = TopologyUpdater {
                 receiver: self.receiver.clone(),
                 storage_proxy: storage_proxy.clone(),
+                broker: broker.clone(),
             };
 
             let resp = RESPServer {
-                host_port: format!("127.0.0.1:{}", self.metadata.port),
+                addrs: self.socket_addrs(self.metadata.port),
                 storage_proxy: storage_proxy.clone(),
+                broker,
+                connections: Rc::from(ConnectionRegistry::new()),
             };
-            let memcached_port = 11211 + self.metadata.id as u64;
+            let memcached_port = 11211 + self.metadata.id as u16;
             let memcached = MemcachedBinaryServer {
-                host_port: format!("127.0.0.1:{}", memcached_port),
+                addrs: self.socket_addrs(memcached_port),
+                storage_proxy: storage_proxy.clone(),
+            };
+            let memcached_ascii_port = 12211 + self.metadata.id as u16;
+            let memcached_ascii = MemcachedAsciiServer {
+                addrs: self.socket_addrs(memcached_ascii_port),
                 storage_proxy: storage_proxy.clone(),
+                started: Instant::now(),
             };
 
-            join!(resp.listen(), memcached.listen(), topology_updater.start());
+            let reactor_id = self.metadata.id;
+            let listeners = pin!(async {
+                join!(
+                    supervisor::supervise(format!("resp listener (reactor {})", reactor_id), || resp.listen()),
+                    supervisor::supervise(format!("memcached listener (reactor {})", reactor_id), || memcached.listen()),
+                    supervisor::supervise(format!("memcached ascii listener (reactor {})", reactor_id), || memcached_ascii.listen())
+                )
+            });
+            let updater = pin!(supervisor::supervise(format!("topology updater (reactor {})", reactor_id), || {
+                topology_updater.start()
+            }));
+            // The listeners and the background tasks are dropped with the
+            // runtime once the updater handled a shutdown
+            select(listeners, updater).await;
             println!("Terminated");
         });
     }
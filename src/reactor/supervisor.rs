@@ -0,0 +1,124 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::VecDeque,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+
+/// Number of restarts of supervised tasks allowed within `DEFAULT_CRASH_WINDOW`
+/// before the whole node is considered unhealthy and stopped
+pub const DEFAULT_MAX_CRASHES: usize = 10;
+pub const DEFAULT_CRASH_WINDOW: Duration = Duration::from_secs(60);
+
+/// Number of tasks that panicked since the start of the node (all reactors)
+static TASK_PANICS: AtomicU64 = AtomicU64::new(0);
+
+struct CrashPolicy {
+    max_crashes: usize,
+    window: Duration,
+    crashes: VecDeque<Instant>,
+}
+
+// Each reactor runs on its own thread so the policy is per reactor
+thread_local! {
+    static POLICY: RefCell<CrashPolicy> = const {
+        RefCell::new(CrashPolicy {
+            max_crashes: DEFAULT_MAX_CRASHES,
+            window: DEFAULT_CRASH_WINDOW,
+            crashes: VecDeque::new(),
+        })
+    };
+}
+
+/// Set the crash policy of the reactor running on the current thread
+pub fn configure(max_crashes: usize, window: Duration) {
+    POLICY.with(|p| {
+        let mut policy = p.borrow_mut();
+        policy.max_crashes = max_crashes;
+        policy.window = window;
+    })
+}
+
+pub fn task_panics() -> u64 {
+    TASK_PANICS.load(Ordering::Relaxed)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic payload"
+    }
+}
+
+fn log_panic(name: &str, payload: &(dyn Any + Send)) {
+    TASK_PANICS.fetch_add(1, Ordering::Relaxed);
+    println!("[supervisor] task {} panicked: {}", name, panic_message(payload));
+}
+
+/// Record a crash of a supervised task and stop the node if there were too
+/// many of them recently
+fn record_crash(name: &str) {
+    POLICY.with(|p| {
+        let mut policy = p.borrow_mut();
+        let now = Instant::now();
+        let window = policy.window;
+        policy.crashes.push_back(now);
+        while policy.crashes.front().is_some_and(|t| now.duration_since(*t) > window) {
+            policy.crashes.pop_front();
+        }
+        if policy.crashes.len() > policy.max_crashes {
+            println!(
+                "[supervisor] {} crashes in less than {:?} (last one: {}), stopping the node",
+                policy.crashes.len(),
+                window,
+                name
+            );
+            std::process::exit(1);
+        }
+    })
+}
+
+/// Run a short-lived task (e.g. a connection) so a panic only ends this task.
+/// The panic is logged and counted but never restarts the task nor fails the
+/// node as it can be triggered by a client.
+pub async fn isolate<F: Future<Output = ()>>(name: String, fut: F) {
+    if let Err(payload) = AssertUnwindSafe(fut).catch_unwind().await {
+        log_panic(&name, payload.as_ref());
+    }
+}
+
+/// Run a long-lived task (listener, background loop) built by `factory` and
+/// start a new one each time it panics. Return when the task ends normally.
+pub async fn supervise<F, Fut>(name: String, factory: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    loop {
+        match AssertUnwindSafe(factory()).catch_unwind().await {
+            Ok(()) => return,
+            Err(payload) => {
+                log_panic(&name, payload.as_ref());
+                record_crash(&name);
+                println!("[supervisor] restarting task {}", name);
+            }
+        }
+    }
+}
+
+/// Spawn a supervised task on the current reactor
+pub fn spawn_supervised<F, Fut>(name: String, factory: F)
+where
+    F: Fn() -> Fut + 'static,
+    Fut: Future<Output = ()> + 'static,
+{
+    monoio::spawn(supervise(name, factory));
+}
@@ -0,0 +1,225 @@
+//! Hash of the keys, identifying them in the index. The algorithm (and the
+//! secret of the keyed one) is chosen once for the process, before the first
+//! key is hashed. Only the index checkpoint stores the hashes, it is
+//! discarded if written with another one (disktables and the WAL store the
+//! keys, hashed again when loaded)
+
+use std::{fs, path::Path, sync::OnceLock};
+
+use crypto::{blake2b::Blake2b, digest::Digest, sha1::Sha1};
+
+use super::HashedKey;
+use crate::datastore::{encryption::random_bytes, upgrade::write_atomically};
+
+pub trait KeyHasher: Send + Sync {
+    fn hash(&self, key: &[u8]) -> HashedKey;
+}
+
+/// SHA-1 digest, the hash of the previous versions
+pub struct Sha1Hasher;
+
+impl KeyHasher for Sha1Hasher {
+    fn hash(&self, key: &[u8]) -> HashedKey {
+        let mut hasher = Sha1::new();
+        let mut hashed_key: HashedKey = [0; 20];
+
+        hasher.input(key);
+        hasher.result(&mut hashed_key);
+
+        hashed_key
+    }
+}
+
+/// BLAKE2b keyed with the secret of the data directory (see `load_secret`),
+/// with the size of a SHA-1 digest. Clients can't craft colliding keys
+/// without the secret
+pub struct Blake2bHasher;
+
+impl KeyHasher for Blake2bHasher {
+    fn hash(&self, key: &[u8]) -> HashedKey {
+        let mut hashed_key: HashedKey = [0; 20];
+        Blake2b::blake2b(&mut hashed_key, key, secret());
+        hashed_key
+    }
+}
+
+/// xxHash64 of the key with 3 seeds, truncated to the size of a SHA-1
+/// digest. Much faster, but not collision resistant against crafted keys
+pub struct XxHasher;
+
+impl KeyHasher for XxHasher {
+    fn hash(&self, key: &[u8]) -> HashedKey {
+        let mut hashed_key: HashedKey = [0; 20];
+        for (seed, chunk) in hashed_key.chunks_mut(8).enumerate() {
+            chunk.copy_from_slice(&xxh64(key, seed as u64).to_le_bytes()[..chunk.len()]);
+        }
+        hashed_key
+    }
+}
+
+const P1: u64 = 11400714785074694791;
+const P2: u64 = 14029467366897019727;
+const P3: u64 = 1609587929392839161;
+const P4: u64 = 9650029242287828579;
+const P5: u64 = 2870177450012600261;
+
+fn read_u64(input: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(input[at..at + 8].try_into().unwrap())
+}
+
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(P2)).rotate_left(31).wrapping_mul(P1)
+}
+
+fn merge_round(acc: u64, value: u64) -> u64 {
+    (acc ^ round(0, value)).wrapping_mul(P1).wrapping_add(P4)
+}
+
+/// XXH64 as specified by https://github.com/Cyan4973/xxHash
+pub fn xxh64(input: &[u8], seed: u64) -> u64 {
+    let len = input.len();
+    let mut i = 0;
+    let mut h = if len >= 32 {
+        let mut v = [seed.wrapping_add(P1).wrapping_add(P2), seed.wrapping_add(P2), seed, seed.wrapping_sub(P1)];
+        while i + 32 <= len {
+            for (lane, acc) in v.iter_mut().enumerate() {
+                *acc = round(*acc, read_u64(input, i + lane * 8));
+            }
+            i += 32;
+        }
+        let h = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        v.iter().fold(h, |h, acc| merge_round(h, *acc))
+    } else {
+        seed.wrapping_add(P5)
+    };
+    h = h.wrapping_add(len as u64);
+    while i + 8 <= len {
+        h = (h ^ round(0, read_u64(input, i))).rotate_left(27).wrapping_mul(P1).wrapping_add(P4);
+        i += 8;
+    }
+    if i + 4 <= len {
+        let k = u32::from_le_bytes(input[i..i + 4].try_into().unwrap()) as u64;
+        h = (h ^ k.wrapping_mul(P1)).rotate_left(23).wrapping_mul(P2).wrapping_add(P3);
+        i += 4;
+    }
+    for byte in &input[i..] {
+        h = (h ^ (*byte as u64).wrapping_mul(P5)).rotate_left(11).wrapping_mul(P1);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(P2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(P3);
+    h ^ (h >> 32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyHashAlgorithm {
+    Sha1,
+    XxHash64,
+    Blake2b,
+}
+
+impl KeyHashAlgorithm {
+    pub fn hasher(&self) -> &'static dyn KeyHasher {
+        match self {
+            KeyHashAlgorithm::Sha1 => &Sha1Hasher,
+            KeyHashAlgorithm::XxHash64 => &XxHasher,
+            KeyHashAlgorithm::Blake2b => &Blake2bHasher,
+        }
+    }
+}
+
+/// Name given on the command line
+impl std::str::FromStr for KeyHashAlgorithm {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<KeyHashAlgorithm, String> {
+        match name {
+            "sha1" => Ok(KeyHashAlgorithm::Sha1),
+            "xxh64" => Ok(KeyHashAlgorithm::XxHash64),
+            "blake2b" => Ok(KeyHashAlgorithm::Blake2b),
+            _ => Err(format!("unknown key hash: {} (blake2b, sha1 or xxh64)", name)),
+        }
+    }
+}
+
+pub const DEFAULT_ALGORITHM: KeyHashAlgorithm = KeyHashAlgorithm::Blake2b;
+
+static ALGORITHM: OnceLock<KeyHashAlgorithm> = OnceLock::new();
+
+/// File of the data directory storing the secret of the keyed hash
+pub const SECRET_FILE: &str = "KEY_HASH_SECRET";
+pub const SECRET_SIZE: usize = 32;
+
+static SECRET: OnceLock<[u8; SECRET_SIZE]> = OnceLock::new();
+
+/// Read the secret of the keyed hash from the data directory, a new one is
+/// generated and saved on the first start. Losing it only discards the
+/// index checkpoints
+pub fn load_secret(data_dir: &Path) -> [u8; SECRET_SIZE] {
+    let path = data_dir.join(SECRET_FILE);
+    match fs::read(&path) {
+        Ok(secret) => secret
+            .try_into()
+            .unwrap_or_else(|_| panic!("{:?} is not a {} bytes secret", path, SECRET_SIZE)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let mut secret = [0; SECRET_SIZE];
+            random_bytes(&mut secret);
+            fs::create_dir_all(data_dir).unwrap();
+            write_atomically(&path, &secret).unwrap();
+            secret
+        }
+        Err(err) => panic!("Cannot read the key hash secret: {}", err),
+    }
+}
+
+/// Choose the secret of the keyed hash, false if a key was already hashed
+/// with another one
+pub fn set_secret(secret: [u8; SECRET_SIZE]) -> bool {
+    *SECRET.get_or_init(|| secret) == secret
+}
+
+/// Secret of the keyed hash, a random one for the process if none was set
+fn secret() -> &'static [u8] {
+    SECRET.get_or_init(|| {
+        let mut secret = [0; SECRET_SIZE];
+        random_bytes(&mut secret);
+        secret
+    })
+}
+
+/// Choose the hash of the keys, false if a key was already hashed with
+/// another one
+pub fn set_algorithm(algorithm: KeyHashAlgorithm) -> bool {
+    *ALGORITHM.get_or_init(|| algorithm) == algorithm
+}
+
+pub fn algorithm() -> KeyHashAlgorithm {
+    *ALGORITHM.get_or_init(|| DEFAULT_ALGORITHM)
+}
+
+pub fn hash_key(key: &[u8]) -> HashedKey {
+    algorithm().hasher().hash(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xxh64() {
+        assert_eq!(xxh64(b"", 0), 0xEF46DB3751D8E999);
+        assert_eq!(xxh64(b"a", 0), 0xD24EC4F1A98C6E5B);
+        assert_eq!(xxh64(b"abc", 0), 0x44BC2CF5AD770999);
+        assert_eq!(xxh64(b"Nobody inspects the spammish repetition", 0), 0xFBCEA83C8A378BF1);
+        assert_ne!(XxHasher.hash(b"key"), XxHasher.hash(b"kez"));
+        assert_ne!(Blake2bHasher.hash(b"key"), Blake2bHasher.hash(b"kez"));
+        assert_eq!(Blake2bHasher.hash(b"key"), Blake2bHasher.hash(b"key"));
+        assert_eq!("sha1".parse(), Ok(KeyHashAlgorithm::Sha1));
+        assert_eq!(Sha1Hasher.hash(b"abc")[..4], [0xA9, 0x99, 0x3E, 0x36]);
+    }
+}
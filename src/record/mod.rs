@@ -1,25 +1,68 @@
-use crypto::{digest::Digest, sha1::Sha1};
+pub mod hasher;
 
 pub type HashedKey = [u8; 20];
 
-pub fn hash_sha1(key: &str) -> HashedKey {
-    let mut hasher = Sha1::new();
-    let mut hashed_key: HashedKey = [0; 20];
+/// Cluster slot of a key (crc16 like redis)
+pub fn key_slot(key: &[u8]) -> u16 {
+    crc16_xmodem_fast::hash(key) % crate::topology::MAX_RANGE
+}
 
-    hasher.input_str(key);
-    hasher.result(&mut hashed_key);
+/// Size of the fixed part of a serialized record
+/// (key size u16, value size u32, timestamp u64, value type u8, flags u32,
+/// expire_at u64)
+pub const RECORD_HEADER_SIZE: usize = 2 + 4 + 8 + 1 + 4 + 8;
 
-    hashed_key
+/// Type of the value stored in a record, written as one byte with the record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ValueType {
+    String = 0,
+    Hash = 1,
+    List = 2,
+    Set = 3,
+    ZSet = 4,
+    Stream = 5,
+    /// Operands of `DataStore::merge` not collapsed with the value yet, read
+    /// as a string
+    Merge = 6,
+    /// Deletion of a range of keys, never visible to readers (see
+    /// `datastore::range_tombstone`)
+    RangeTombstone = 7,
 }
 
-pub fn hash_sha1_bytes(key: &[u8]) -> HashedKey {
-    let mut hasher = Sha1::new();
-    let mut hashed_key: HashedKey = [0; 20];
+impl ValueType {
+    pub fn from_u8(b: u8) -> ValueType {
+        ValueType::try_from_u8(b).unwrap_or_else(|| panic!("unknown value type: {}", b))
+    }
 
-    hasher.input(key);
-    hasher.result(&mut hashed_key);
+    /// None for an unknown byte, when it comes from a file that may be corrupted
+    pub fn try_from_u8(b: u8) -> Option<ValueType> {
+        match b {
+            0 => Some(ValueType::String),
+            1 => Some(ValueType::Hash),
+            2 => Some(ValueType::List),
+            3 => Some(ValueType::Set),
+            4 => Some(ValueType::ZSet),
+            5 => Some(ValueType::Stream),
+            6 => Some(ValueType::Merge),
+            7 => Some(ValueType::RangeTombstone),
+            _ => None,
+        }
+    }
 
-    hashed_key
+    /// Name as returned by the TYPE command
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValueType::String => "string",
+            ValueType::Hash => "hash",
+            ValueType::List => "list",
+            ValueType::Set => "set",
+            ValueType::ZSet => "zset",
+            ValueType::Stream => "stream",
+            ValueType::Merge => "string",
+            ValueType::RangeTombstone => "none",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -27,6 +70,12 @@ pub struct Record {
     pub key: Key,
     pub value: Vec<u8>,
     pub timestamp: u64,
+    pub value_type: ValueType,
+    /// Date (as given by `crate::time::now`) after which the record is
+    /// considered deleted
+    pub expire_at: Option<u64>,
+    /// Opaque flags of memcached clients, 0 when written by redis
+    pub flags: u32,
 }
 
 #[derive(Debug, Clone)]
@@ -37,7 +86,7 @@ pub struct Key {
 
 impl Key {
     pub fn new(key: String) -> Key {
-        let hash = hash_sha1(&key);
+        let hash = hasher::hash_key(key.as_bytes());
         Key { string: key, hash }
     }
 }
@@ -53,10 +102,13 @@ impl Record {
             key: Key::new(key),
             value,
             timestamp,
+            value_type: ValueType::String,
+            expire_at: None,
+            flags: 0,
         }
     }
 
     pub fn size_of(&self) -> usize {
-        2 + 4 + 8 + self.key.string.len() + self.value.len()
+        RECORD_HEADER_SIZE + self.key.string.len() + self.value.len()
     }
 }
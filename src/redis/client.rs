@@ -18,7 +18,7 @@ impl Client {
     pub async fn new(addr: String) -> Client {
         let stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
         Client {
-            handler: RESPHandler { stream },
+            handler: RESPHandler::new(stream),
         }
     }
 
@@ -1,98 +1,1753 @@
 use core::str;
+use std::time::Duration;
 
 use monoio::io::{AsyncBufRead, AsyncWriteRentExt, BufReader};
 
 use crate::{
-    api::{self, Join},
-    record::{Key, Record},
-    redis::resp::{parse, NonHashableValue},
-    topology::ReactorMetadata,
+    api::{self, Join, Reset},
+    record::{Key, Record, ValueType},
+    redis::{
+        connection::KillFilter,
+        resp::{parse, Error, NonHashableValue},
+        types::{
+            geo::{self, Shape},
+            hyperloglog::HyperLogLog,
+            stream::{NewId, StreamId},
+            zset::ScoreBound,
+        },
+    },
+    topology::{self, ReactorMetadata},
+};
+
+use super::{
+    resp::{HashableValue, Value},
+    serde::FromResp,
 };
 
-use super::{
-    resp::{HashableValue, Value},
-    serde::FromResp,
-};
+#[derive(Debug, Clone)]
+pub enum Command {
+    Hello(HelloCmd),
+    Client(ClientCmd),
+    Cluster(ClusterCmd),
+    Command(CommandCmd),
+    Set(SetCmd),
+    Get(GetCmd),
+    Expire(ExpireCmd),
+    Persist(PersistCmd),
+    Ttl(TtlCmd),
+    Incr(IncrCmd),
+    MGet(MGetCmd),
+    MSet(MSetCmd),
+    Del(DelCmd),
+    Scan(ScanCmd),
+    Keys(KeysCmd),
+    Type(TypeCmd),
+    HSet(HSetCmd),
+    HGet(HGetCmd),
+    HGetAll(HGetAllCmd),
+    HDel(HDelCmd),
+    Push(PushCmd),
+    Pop(PopCmd),
+    LRange(LRangeCmd),
+    SAdd(SAddCmd),
+    SRem(SRemCmd),
+    SMembers(SMembersCmd),
+    SIsMember(SIsMemberCmd),
+    ZAdd(ZAddCmd),
+    ZScore(ZScoreCmd),
+    ZRange(ZRangeCmd),
+    GetRange(GetRangeCmd),
+    SetRange(SetRangeCmd),
+    StrLen(StrLenCmd),
+    Flush(FlushCmd),
+    Shutdown(ShutdownCmd),
+    Save(SaveCmd),
+    Info(InfoCmd),
+    Rename(RenameCmd),
+    Copy(CopyCmd),
+    PubSub(PubSubCmd),
+    Object(ObjectCmd),
+    Debug(DebugCmd),
+    Config(ConfigCmd),
+    Dump(DumpCmd),
+    Restore(RestoreCmd),
+    PfAdd(PfAddCmd),
+    PfCount(PfCountCmd),
+    PfMerge(PfMergeCmd),
+    XAdd(XAddCmd),
+    XLen(XLenCmd),
+    XRange(XRangeCmd),
+    XRead(XReadCmd),
+    GeoAdd(GeoAddCmd),
+    GeoPos(GeoPosCmd),
+    GeoDist(GeoDistCmd),
+    GeoSearch(GeoSearchCmd),
+    /// Unknown command or wrong number of arguments, replied with an error
+    Invalid(String),
+}
+
+const SYNTAX_ERROR: &str = "syntax error";
+const NOT_AN_INTEGER: &str = "value is not an integer or out of range";
+const NOT_A_FLOAT: &str = "value is not a valid float";
+
+#[derive(Debug, Clone)]
+pub struct SetInfoCmd {
+    pub lib_name: Option<String>,
+    pub lib_type: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HelloCmd {
+    pub version: char,
+}
+
+const CMD_HELLO: &str = "HELLO";
+fn parse_hello_command(args: &[Value]) -> Command {
+    let version = match &args[1] {
+        Value::HashableValue(hashable_value) => match hashable_value {
+            HashableValue::Blob(vec) => vec,
+            _ => todo!(),
+        },
+        Value::NonHashableValue(_) => todo!(),
+        Value::Null => todo!(),
+    };
+    Command::Hello(HelloCmd { version: version[0] as char })
+}
+
+#[derive(Debug, Clone)]
+pub enum ClientCmd {
+    SetInfo(SetInfoCmd),
+    Id,
+    List,
+    SetName(String),
+    GetName,
+    Kill(KillCmd),
+    Tracking(TrackingCmd),
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackingCmd {
+    pub enabled: bool,
+    /// Do not send invalidations for the keys modified by the connection itself
+    pub no_loop: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct KillCmd {
+    pub filter: KillFilter,
+    /// `CLIENT KILL addr:port` form, replying OK or an error instead of a count
+    pub legacy: bool,
+}
+
+const CMD_CLIENT: &str = "CLIENT";
+fn parse_client_command(args: &[Value]) -> Command {
+    let sub_command = args[1].try_as_str().unwrap().to_uppercase();
+    match (sub_command.as_str(), args.len()) {
+        (CMD_SETINFO, 4) => Command::Client(ClientCmd::SetInfo(parse_setinfo_cmd(args))),
+        ("ID", _) => Command::Client(ClientCmd::Id),
+        ("LIST", _) => Command::Client(ClientCmd::List),
+        ("SETNAME", 3) => Command::Client(ClientCmd::SetName(String::from(args[2].try_as_str().unwrap()))),
+        ("GETNAME", _) => Command::Client(ClientCmd::GetName),
+        ("KILL", 3..) => parse_kill_cmd(args),
+        ("TRACKING", _) => parse_tracking_cmd(args),
+        (CMD_SETINFO | "SETNAME" | "KILL", _) => {
+            Command::Invalid(format!("wrong number of arguments for 'client|{}' command", sub_command.to_lowercase()))
+        }
+        (sub_command, _) => Command::Invalid(format!("unknown subcommand '{}'", sub_command)),
+    }
+}
+
+// CLIENT TRACKING ON|OFF [NOLOOP]
+fn parse_tracking_cmd(args: &[Value]) -> Command {
+    let enabled = match args.get(2).map(|arg| arg.try_as_str().unwrap().to_uppercase()).as_deref() {
+        Some("ON") => true,
+        Some("OFF") => false,
+        _ => return Command::Invalid(String::from(SYNTAX_ERROR)),
+    };
+    let mut no_loop = false;
+    for arg in &args[3..] {
+        match arg.try_as_str().unwrap().to_uppercase().as_str() {
+            "NOLOOP" => no_loop = true,
+            option => return Command::Invalid(format!("CLIENT TRACKING option {} is not supported", option)),
+        }
+    }
+
+    Command::Client(ClientCmd::Tracking(TrackingCmd { enabled, no_loop }))
+}
+
+// CLIENT KILL addr:port / CLIENT KILL [ID id] [ADDR addr:port]
+fn parse_kill_cmd(args: &[Value]) -> Command {
+    if args.len() == 3 {
+        let Ok(addr) = args[2].try_as_str().unwrap().parse() else {
+            return Command::Invalid(String::from("No such client"));
+        };
+        return Command::Client(ClientCmd::Kill(KillCmd {
+            filter: KillFilter {
+                addr: Some(addr),
+                ..KillFilter::default()
+            },
+            legacy: true,
+        }));
+    }
+    if args.len() % 2 != 0 {
+        return Command::Invalid(String::from(SYNTAX_ERROR));
+    }
+    let mut filter = KillFilter::default();
+    for pair in args[2..].chunks_exact(2) {
+        let value = pair[1].try_as_str().unwrap();
+        match pair[0].try_as_str().unwrap().to_uppercase().as_str() {
+            "ID" => match value.parse() {
+                Ok(id) => filter.id = Some(id),
+                Err(_) => return Command::Invalid(String::from("client-id should be greater than 0")),
+            },
+            "ADDR" => match value.parse() {
+                Ok(addr) => filter.addr = Some(addr),
+                Err(_) => return Command::Invalid(String::from("No such client")),
+            },
+            _ => return Command::Invalid(String::from(SYNTAX_ERROR)),
+        }
+    }
+    Command::Client(ClientCmd::Kill(KillCmd { filter, legacy: false }))
+}
+
+const CMD_SETINFO: &str = "SETINFO";
+fn parse_setinfo_cmd(args: &[Value]) -> SetInfoCmd {
+    let _ = args[2].try_as_str().unwrap();
+    let value = args[3].try_as_str().unwrap();
+
+    SetInfoCmd {
+        lib_name: Some(String::from(value)),
+        lib_type: None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SetCmd {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub options: api::SetOptions,
+    /// Time to live in milliseconds (EX/PX)
+    pub ttl_ms: Option<i64>,
+    /// The ttl is a unix time in milliseconds (EXAT/PXAT)
+    pub absolute_ttl: bool,
+}
+
+impl SetCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        let mut record = Record::new(self.key.clone(), self.value.clone());
+        record.expire_at = self.ttl_ms.map(|ttl_ms| match self.absolute_ttl {
+            true => crate::time::from_unix_ms(ttl_ms as u64),
+            false => record.timestamp.saturating_add_signed(ttl_ms.saturating_mul(1_000_000)),
+        });
+        api::Command::Data(api::DataCommand::Set(api::Set {
+            record,
+            options: self.options.clone(),
+            relative_ttl: self.ttl_ms.is_some() && !self.absolute_ttl,
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GetCmd {
+    pub key: String,
+}
+
+impl GetCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_SET: &str = "SET";
+/// Positive ttl of `command` in milliseconds, or the reply to an invalid one
+fn parse_ttl_ms(arg: &Value, unit_ms: i64, command: &str) -> Result<i64, Command> {
+    match arg.try_as_str().unwrap().parse::<i64>() {
+        Ok(ttl) if ttl > 0 => Ok(ttl.saturating_mul(unit_ms)),
+        Ok(_) => Err(Command::Invalid(format!("invalid expire time in '{}' command", command))),
+        Err(_) => Err(Command::Invalid(String::from(NOT_AN_INTEGER))),
+    }
+}
+
+// SET key value [NX | XX] [GET] [EX seconds | PX milliseconds |
+//   EXAT unix-time-seconds | PXAT unix-time-milliseconds | KEEPTTL]
+fn parse_set_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let value = args[2].try_as_str().unwrap();
+    let mut options = api::SetOptions::default();
+    let mut ttl_ms = None;
+    let mut absolute_ttl = false;
+    let mut i = 3;
+    while i < args.len() {
+        match args[i].try_as_str().unwrap().to_uppercase().as_str() {
+            "NX" => options.condition = api::SetCondition::IfMissing,
+            "XX" => options.condition = api::SetCondition::IfExists,
+            "GET" => options.get = true,
+            "KEEPTTL" => options.keep_ttl = true,
+            option @ ("EX" | "PX" | "EXAT" | "PXAT") => {
+                let Some(ttl) = args.get(i + 1) else {
+                    return Command::Invalid(String::from(SYNTAX_ERROR));
+                };
+                let unit_ms = if option.starts_with('E') { 1000 } else { 1 };
+                match parse_ttl_ms(ttl, unit_ms, "set") {
+                    Ok(ttl) => ttl_ms = Some(ttl),
+                    Err(invalid) => return invalid,
+                }
+                absolute_ttl = option.ends_with("AT");
+                i += 1;
+            }
+            _ => return Command::Invalid(String::from(SYNTAX_ERROR)),
+        }
+        i += 1;
+    }
+    // KEEPTTL can't be used with EX, PX, EXAT or PXAT
+    if options.keep_ttl && ttl_ms.is_some() {
+        return Command::Invalid(String::from(SYNTAX_ERROR));
+    }
+
+    Command::Set(SetCmd {
+        key: String::from(key),
+        value: Vec::from(value),
+        options,
+        ttl_ms,
+        absolute_ttl,
+    })
+}
+
+const CMD_SETEX: &str = "SETEX";
+const CMD_PSETEX: &str = "PSETEX";
+// SETEX key seconds value / PSETEX key milliseconds value
+fn parse_setex_command(args: &[Value], unit_ms: i64) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let command = if unit_ms == 1 { "psetex" } else { "setex" };
+    let ttl_ms = match parse_ttl_ms(&args[2], unit_ms, command) {
+        Ok(ttl_ms) => ttl_ms,
+        Err(invalid) => return invalid,
+    };
+    let value = args[3].try_as_str().unwrap();
+
+    Command::Set(SetCmd {
+        key: String::from(key),
+        value: Vec::from(value),
+        options: api::SetOptions::default(),
+        ttl_ms: Some(ttl_ms),
+        absolute_ttl: false,
+    })
+}
+
+const CMD_GET: &str = "GET";
+fn parse_get_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+
+    Command::Get(GetCmd { key: String::from(key) })
+}
+
+#[derive(Debug, Clone)]
+pub struct ExpireCmd {
+    pub key: String,
+    /// Time to live in milliseconds, negative values delete the key
+    pub ttl_ms: i64,
+    /// The ttl is a unix time in milliseconds (EXPIREAT/PEXPIREAT), times in
+    /// the past delete the key
+    pub absolute_ttl: bool,
+}
+
+impl ExpireCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        let expire_at = match self.absolute_ttl {
+            true => crate::time::from_unix_ms(self.ttl_ms.max(0) as u64),
+            false => crate::time::now().saturating_add_signed(self.ttl_ms.saturating_mul(1_000_000)),
+        };
+        api::Command::Data(api::DataCommand::Expire(api::Expire {
+            key: Key::new(self.key.clone()),
+            expire_at: Some(expire_at),
+            relative_ttl: !self.absolute_ttl,
+        }))
+    }
+}
+
+const CMD_EXPIRE: &str = "EXPIRE";
+const CMD_PEXPIRE: &str = "PEXPIRE";
+const CMD_EXPIREAT: &str = "EXPIREAT";
+const CMD_PEXPIREAT: &str = "PEXPIREAT";
+// EXPIRE key seconds / PEXPIRE key milliseconds
+// EXPIREAT key unix-time-seconds / PEXPIREAT key unix-time-milliseconds
+fn parse_expire_command(args: &[Value], unit_ms: i64, absolute_ttl: bool) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let Ok(ttl) = args[2].try_as_str().unwrap().parse::<i64>() else {
+        return Command::Invalid(String::from(NOT_AN_INTEGER));
+    };
+
+    Command::Expire(ExpireCmd {
+        key: String::from(key),
+        ttl_ms: ttl.saturating_mul(unit_ms),
+        absolute_ttl,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct PersistCmd {
+    pub key: String,
+}
+
+impl PersistCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Expire(api::Expire {
+            key: Key::new(self.key.clone()),
+            expire_at: None,
+            relative_ttl: false,
+        }))
+    }
+}
+
+const CMD_PERSIST: &str = "PERSIST";
+fn parse_persist_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+
+    Command::Persist(PersistCmd { key: String::from(key) })
+}
+
+#[derive(Debug, Clone)]
+pub struct TtlCmd {
+    pub key: String,
+    /// Reply in milliseconds (PTTL) instead of seconds
+    pub millis: bool,
+    /// Reply with the unix time of the expiration (EXPIRETIME) instead of the
+    /// remaining time
+    pub absolute: bool,
+}
+
+impl TtlCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Ttl(api::GetTtl {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_TTL: &str = "TTL";
+const CMD_PTTL: &str = "PTTL";
+const CMD_EXPIRETIME: &str = "EXPIRETIME";
+const CMD_PEXPIRETIME: &str = "PEXPIRETIME";
+fn parse_ttl_command(args: &[Value], millis: bool, absolute: bool) -> Command {
+    let key = args[1].try_as_str().unwrap();
+
+    Command::Ttl(TtlCmd {
+        key: String::from(key),
+        millis,
+        absolute,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct TypeCmd {
+    pub key: String,
+}
+
+impl TypeCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Type(api::GetType {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_TYPE: &str = "TYPE";
+fn parse_type_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+
+    Command::Type(TypeCmd { key: String::from(key) })
+}
+
+#[derive(Debug, Clone)]
+pub struct HSetCmd {
+    pub key: String,
+    pub pairs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl HSetCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::HSet(api::HSet {
+            key: Key::new(self.key.clone()),
+            pairs: self.pairs.clone(),
+        }))
+    }
+}
+
+const CMD_HSET: &str = "HSET";
+// HSET key field value [field value ...]
+fn parse_hset_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let pairs = args[2..]
+        .chunks_exact(2)
+        .map(|pair| (Vec::from(pair[0].try_as_str().unwrap()), Vec::from(pair[1].try_as_str().unwrap())))
+        .collect();
+
+    Command::HSet(HSetCmd {
+        key: String::from(key),
+        pairs,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct HGetCmd {
+    pub key: String,
+    pub field: Vec<u8>,
+}
+
+impl HGetCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_HGET: &str = "HGET";
+fn parse_hget_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let field = args[2].try_as_str().unwrap();
+
+    Command::HGet(HGetCmd {
+        key: String::from(key),
+        field: Vec::from(field),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct HGetAllCmd {
+    pub key: String,
+}
+
+impl HGetAllCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_HGETALL: &str = "HGETALL";
+fn parse_hgetall_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+
+    Command::HGetAll(HGetAllCmd { key: String::from(key) })
+}
+
+#[derive(Debug, Clone)]
+pub struct HDelCmd {
+    pub key: String,
+    pub fields: Vec<Vec<u8>>,
+}
+
+impl HDelCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::HDel(api::HDel {
+            key: Key::new(self.key.clone()),
+            fields: self.fields.clone(),
+        }))
+    }
+}
+
+const CMD_HDEL: &str = "HDEL";
+// HDEL key field [field ...]
+fn parse_hdel_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let fields = args[2..].iter().map(|arg| Vec::from(arg.try_as_str().unwrap())).collect();
+
+    Command::HDel(HDelCmd {
+        key: String::from(key),
+        fields,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct PushCmd {
+    pub key: String,
+    pub values: Vec<Vec<u8>>,
+    pub front: bool,
+}
+
+impl PushCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Push(api::Push {
+            key: Key::new(self.key.clone()),
+            values: self.values.clone(),
+            front: self.front,
+        }))
+    }
+}
+
+const CMD_LPUSH: &str = "LPUSH";
+const CMD_RPUSH: &str = "RPUSH";
+// LPUSH/RPUSH key element [element ...]
+fn parse_push_command(args: &[Value], front: bool) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let values = args[2..].iter().map(|arg| Vec::from(arg.try_as_str().unwrap())).collect();
+
+    Command::Push(PushCmd {
+        key: String::from(key),
+        values,
+        front,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct PopCmd {
+    pub key: String,
+    /// Without a count a single value is returned instead of an array
+    pub count: Option<usize>,
+    pub front: bool,
+}
+
+impl PopCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Pop(api::Pop {
+            key: Key::new(self.key.clone()),
+            count: self.count.unwrap_or(1),
+            front: self.front,
+        }))
+    }
+}
+
+const CMD_LPOP: &str = "LPOP";
+const CMD_RPOP: &str = "RPOP";
+// LPOP/RPOP key [count]
+fn parse_pop_command(args: &[Value], front: bool) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let count = args.get(2).map(|arg| arg.try_as_str().unwrap().parse().unwrap());
+
+    Command::Pop(PopCmd {
+        key: String::from(key),
+        count,
+        front,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct LRangeCmd {
+    pub key: String,
+    pub start: i64,
+    pub stop: i64,
+}
+
+impl LRangeCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_LRANGE: &str = "LRANGE";
+// LRANGE key start stop
+fn parse_lrange_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let start = args[2].try_as_str().unwrap().parse().unwrap();
+    let stop = args[3].try_as_str().unwrap().parse().unwrap();
+
+    Command::LRange(LRangeCmd {
+        key: String::from(key),
+        start,
+        stop,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct SAddCmd {
+    pub key: String,
+    pub members: Vec<Vec<u8>>,
+}
+
+impl SAddCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::SAdd(api::SAdd {
+            key: Key::new(self.key.clone()),
+            members: self.members.clone(),
+        }))
+    }
+}
+
+const CMD_SADD: &str = "SADD";
+// SADD key member [member ...]
+fn parse_sadd_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let members = args[2..].iter().map(|arg| Vec::from(arg.try_as_str().unwrap())).collect();
+
+    Command::SAdd(SAddCmd {
+        key: String::from(key),
+        members,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct SRemCmd {
+    pub key: String,
+    pub members: Vec<Vec<u8>>,
+}
+
+impl SRemCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::SRem(api::SRem {
+            key: Key::new(self.key.clone()),
+            members: self.members.clone(),
+        }))
+    }
+}
+
+const CMD_SREM: &str = "SREM";
+// SREM key member [member ...]
+fn parse_srem_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let members = args[2..].iter().map(|arg| Vec::from(arg.try_as_str().unwrap())).collect();
+
+    Command::SRem(SRemCmd {
+        key: String::from(key),
+        members,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct SMembersCmd {
+    pub key: String,
+}
+
+impl SMembersCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_SMEMBERS: &str = "SMEMBERS";
+fn parse_smembers_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+
+    Command::SMembers(SMembersCmd { key: String::from(key) })
+}
+
+#[derive(Debug, Clone)]
+pub struct SIsMemberCmd {
+    pub key: String,
+    pub member: Vec<u8>,
+}
+
+impl SIsMemberCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::SIsMember(api::SIsMember {
+            key: Key::new(self.key.clone()),
+            member: self.member.clone(),
+        }))
+    }
+}
+
+const CMD_SISMEMBER: &str = "SISMEMBER";
+fn parse_sismember_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let member = args[2].try_as_str().unwrap();
+
+    Command::SIsMember(SIsMemberCmd {
+        key: String::from(key),
+        member: Vec::from(member),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct ZAddCmd {
+    pub key: String,
+    pub members: Vec<(f64, Vec<u8>)>,
+}
+
+impl ZAddCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::ZAdd(api::ZAdd {
+            key: Key::new(self.key.clone()),
+            members: self.members.clone(),
+        }))
+    }
+}
+
+const CMD_ZADD: &str = "ZADD";
+// ZADD key score member [score member ...]
+fn parse_zadd_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    if args.len() % 2 != 0 {
+        return Command::Invalid(String::from(SYNTAX_ERROR));
+    }
+    let mut members = Vec::with_capacity(args.len() / 2 - 1);
+    for pair in args[2..].chunks(2) {
+        match pair[0].try_as_str().unwrap().parse::<f64>() {
+            Ok(score) if !score.is_nan() => members.push((score, Vec::from(pair[1].try_as_str().unwrap()))),
+            _ => return Command::Invalid(String::from(NOT_A_FLOAT)),
+        }
+    }
+
+    Command::ZAdd(ZAddCmd {
+        key: String::from(key),
+        members,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct ZScoreCmd {
+    pub key: String,
+    pub member: Vec<u8>,
+}
+
+impl ZScoreCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_ZSCORE: &str = "ZSCORE";
+fn parse_zscore_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let member = args[2].try_as_str().unwrap();
+
+    Command::ZScore(ZScoreCmd {
+        key: String::from(key),
+        member: Vec::from(member),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub enum ZRangeBy {
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+}
+
+#[derive(Debug, Clone)]
+pub struct ZRangeCmd {
+    pub key: String,
+    pub by: ZRangeBy,
+    pub with_scores: bool,
+}
+
+impl ZRangeCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_ZRANGE: &str = "ZRANGE";
+// ZRANGE key start stop [BYSCORE] [WITHSCORES]
+fn parse_zrange_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let start = args[2].try_as_str().unwrap();
+    let stop = args[3].try_as_str().unwrap();
+    let mut by_score = false;
+    let mut with_scores = false;
+    for arg in &args[4..] {
+        match arg.try_as_str().unwrap().to_uppercase().as_str() {
+            "BYSCORE" => by_score = true,
+            "WITHSCORES" => with_scores = true,
+            _ => return Command::Invalid(String::from(SYNTAX_ERROR)),
+        }
+    }
+    let by = match by_score {
+        true => match (ScoreBound::parse(start), ScoreBound::parse(stop)) {
+            (Some(start), Some(stop)) => ZRangeBy::Score(start, stop),
+            _ => return Command::Invalid(String::from("min or max is not a float")),
+        },
+        false => match (start.parse(), stop.parse()) {
+            (Ok(start), Ok(stop)) => ZRangeBy::Rank(start, stop),
+            _ => return Command::Invalid(String::from(NOT_AN_INTEGER)),
+        },
+    };
+
+    Command::ZRange(ZRangeCmd {
+        key: String::from(key),
+        by,
+        with_scores,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct GetRangeCmd {
+    pub key: String,
+    pub start: i64,
+    pub end: i64,
+}
+
+impl GetRangeCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::GetRange(api::GetRange {
+            key: Key::new(self.key.clone()),
+            start: self.start,
+            end: self.end,
+        }))
+    }
+}
+
+const CMD_GETRANGE: &str = "GETRANGE";
+// GETRANGE key start end
+fn parse_getrange_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let (Ok(start), Ok(end)) = (args[2].try_as_str().unwrap().parse(), args[3].try_as_str().unwrap().parse()) else {
+        return Command::Invalid(String::from(NOT_AN_INTEGER));
+    };
+
+    Command::GetRange(GetRangeCmd {
+        key: String::from(key),
+        start,
+        end,
+    })
+}
+
+/// Largest string SETRANGE can create, like redis
+const MAX_STRING_SIZE: usize = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct SetRangeCmd {
+    pub key: String,
+    pub offset: usize,
+    pub value: Vec<u8>,
+}
+
+impl SetRangeCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::SetRange(api::SetRange {
+            key: Key::new(self.key.clone()),
+            offset: self.offset,
+            value: self.value.clone(),
+        }))
+    }
+}
+
+const CMD_SETRANGE: &str = "SETRANGE";
+// SETRANGE key offset value
+fn parse_setrange_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let Ok(offset) = args[2].try_as_str().unwrap().parse::<usize>() else {
+        return Command::Invalid(String::from("offset is out of range"));
+    };
+    let value = args[3].try_as_str().unwrap();
+    if offset.saturating_add(value.len()) > MAX_STRING_SIZE {
+        return Command::Invalid(String::from("string exceeds maximum allowed size (proto-max-bulk-len)"));
+    }
+
+    Command::SetRange(SetRangeCmd {
+        key: String::from(key),
+        offset,
+        value: Vec::from(value),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct StrLenCmd {
+    pub key: String,
+}
+
+impl StrLenCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::StrLen(api::StrLen {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_STRLEN: &str = "STRLEN";
+fn parse_strlen_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+
+    Command::StrLen(StrLenCmd { key: String::from(key) })
+}
+
+#[derive(Debug, Clone)]
+pub struct IncrCmd {
+    pub key: String,
+    pub by: api::Number,
+}
+
+impl IncrCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Incr(api::Incr {
+            key: Key::new(self.key.clone()),
+            by: self.by,
+        }))
+    }
+}
+
+const CMD_INCR: &str = "INCR";
+const CMD_DECR: &str = "DECR";
+const CMD_INCRBY: &str = "INCRBY";
+const CMD_DECRBY: &str = "DECRBY";
+const CMD_INCRBYFLOAT: &str = "INCRBYFLOAT";
+// INCR/DECR key, INCRBY/DECRBY key increment, INCRBYFLOAT key increment
+fn parse_incr_command(args: &[Value], name: &str) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let by = match name {
+        CMD_INCR => api::Number::Integer(1),
+        CMD_DECR => api::Number::Integer(-1),
+        CMD_INCRBY => api::Number::Integer(args[2].try_as_str().unwrap().parse().unwrap()),
+        CMD_DECRBY => api::Number::Integer(args[2].try_as_str().unwrap().parse::<i64>().unwrap().checked_neg().unwrap()),
+        CMD_INCRBYFLOAT => api::Number::Float(args[2].try_as_str().unwrap().parse().unwrap()),
+        _ => unreachable!(),
+    };
+
+    Command::Incr(IncrCmd { key: String::from(key), by })
+}
+
+#[derive(Debug, Clone)]
+pub struct MGetCmd {
+    pub keys: Vec<String>,
+}
+
+impl MGetCmd {
+    pub fn to_keys(&self) -> Vec<Key> {
+        self.keys.iter().map(|key| Key::new(key.clone())).collect()
+    }
+}
+
+const CMD_MGET: &str = "MGET";
+// MGET key [key ...]
+fn parse_mget_command(args: &[Value]) -> Command {
+    let keys = args[1..].iter().map(|arg| String::from(arg.try_as_str().unwrap())).collect();
+
+    Command::MGet(MGetCmd { keys })
+}
+
+#[derive(Debug, Clone)]
+pub struct MSetCmd {
+    pub pairs: Vec<(String, Vec<u8>)>,
+}
+
+impl MSetCmd {
+    pub fn to_api_commands(&self) -> Vec<api::DataCommand> {
+        self.pairs
+            .iter()
+            .map(|(key, value)| {
+                api::DataCommand::Set(api::Set {
+                    record: Record::new(key.clone(), value.clone()),
+                    options: api::SetOptions::default(),
+                    relative_ttl: false,
+                })
+            })
+            .collect()
+    }
+}
+
+const CMD_MSET: &str = "MSET";
+// MSET key value [key value ...]
+fn parse_mset_command(args: &[Value]) -> Command {
+    let pairs = args[1..]
+        .chunks_exact(2)
+        .map(|pair| (String::from(pair[0].try_as_str().unwrap()), Vec::from(pair[1].try_as_str().unwrap())))
+        .collect();
+
+    Command::MSet(MSetCmd { pairs })
+}
+
+#[derive(Debug, Clone)]
+pub struct DelCmd {
+    pub keys: Vec<String>,
+    /// Leave the tombstones to the background tasks (UNLINK)
+    pub lazy: bool,
+}
+
+impl DelCmd {
+    pub fn to_api_commands(&self) -> Vec<api::DataCommand> {
+        self.keys
+            .iter()
+            .map(|key| match self.lazy {
+                true => api::DataCommand::Unlink(api::Unlink { key: Key::new(key.clone()) }),
+                false => api::DataCommand::Delete(api::Delete { key: Key::new(key.clone()) }),
+            })
+            .collect()
+    }
+}
+
+const CMD_DEL: &str = "DEL";
+const CMD_UNLINK: &str = "UNLINK";
+// DEL/UNLINK key [key ...]
+fn parse_del_command(args: &[Value], lazy: bool) -> Command {
+    let keys = args[1..].iter().map(|arg| String::from(arg.try_as_str().unwrap())).collect();
+
+    Command::Del(DelCmd { keys, lazy })
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanCmd {
+    pub cursor: u64,
+    pub pattern: Option<String>,
+    pub count: usize,
+}
+
+const CMD_SCAN: &str = "SCAN";
+const CMD_SCAN_MATCH: &str = "MATCH";
+const CMD_SCAN_COUNT: &str = "COUNT";
+// SCAN cursor [MATCH pattern] [COUNT count]
+fn parse_scan_command(args: &[Value]) -> Command {
+    let Ok(cursor) = args[1].try_as_str().unwrap().parse() else {
+        return Command::Invalid(String::from("invalid cursor"));
+    };
+    if args.len() % 2 != 0 {
+        return Command::Invalid(String::from(SYNTAX_ERROR));
+    }
+    let mut pattern = None;
+    let mut count = 10;
+    for option in args[2..].chunks_exact(2) {
+        let value = option[1].try_as_str().unwrap();
+        match option[0].try_as_str().unwrap().to_uppercase().as_str() {
+            CMD_SCAN_MATCH => pattern = Some(String::from(value)),
+            CMD_SCAN_COUNT => match value.parse::<usize>() {
+                Ok(value) => count = value.max(1),
+                Err(_) => return Command::Invalid(String::from(NOT_AN_INTEGER)),
+            },
+            _ => return Command::Invalid(String::from(SYNTAX_ERROR)),
+        }
+    }
+
+    Command::Scan(ScanCmd { cursor, pattern, count })
+}
+
+#[derive(Debug, Clone)]
+pub struct KeysCmd {
+    pub pattern: String,
+}
+
+const CMD_KEYS: &str = "KEYS";
+fn parse_keys_command(args: &[Value]) -> Command {
+    let pattern = args[1].try_as_str().unwrap();
+
+    Command::Keys(KeysCmd {
+        pattern: String::from(pattern),
+    })
+}
+
+/// Commands handled by the connection with the pub/sub broker
+#[derive(Debug, Clone)]
+pub enum PubSubCmd {
+    Subscribe(Vec<Vec<u8>>),
+    /// No channel unsubscribes from all of them
+    Unsubscribe(Vec<Vec<u8>>),
+    PSubscribe(Vec<Vec<u8>>),
+    /// No pattern unsubscribes from all of them
+    PUnsubscribe(Vec<Vec<u8>>),
+    Publish {
+        channel: Vec<u8>,
+        payload: Vec<u8>,
+    },
+}
+
+const CMD_SUBSCRIBE: &str = "SUBSCRIBE";
+const CMD_UNSUBSCRIBE: &str = "UNSUBSCRIBE";
+const CMD_PSUBSCRIBE: &str = "PSUBSCRIBE";
+const CMD_PUNSUBSCRIBE: &str = "PUNSUBSCRIBE";
+const CMD_PUBLISH: &str = "PUBLISH";
+fn parse_pubsub_command(args: &[Value], name: &str) -> Command {
+    let names = args[1..].iter().map(|arg| Vec::from(arg.try_as_str().unwrap())).collect();
+    Command::PubSub(match name {
+        CMD_SUBSCRIBE => PubSubCmd::Subscribe(names),
+        CMD_UNSUBSCRIBE => PubSubCmd::Unsubscribe(names),
+        CMD_PSUBSCRIBE => PubSubCmd::PSubscribe(names),
+        CMD_PUNSUBSCRIBE => PubSubCmd::PUnsubscribe(names),
+        // PUBLISH channel message
+        CMD_PUBLISH => PubSubCmd::Publish {
+            channel: Vec::from(args[1].try_as_str().unwrap()),
+            payload: Vec::from(args[2].try_as_str().unwrap()),
+        },
+        _ => unreachable!(),
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ObjectSubCmd {
+    Encoding,
+    RefCount,
+    IdleTime,
+    Freq,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectCmd {
+    pub sub_command: ObjectSubCmd,
+    pub key: String,
+}
+
+impl ObjectCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Object(api::Object {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_OBJECT: &str = "OBJECT";
+// OBJECT ENCODING|REFCOUNT|IDLETIME|FREQ key
+fn parse_object_command(args: &[Value]) -> Command {
+    let name = args[1].try_as_str().unwrap().to_uppercase();
+    let sub_command = match name.as_str() {
+        "ENCODING" => ObjectSubCmd::Encoding,
+        "REFCOUNT" => ObjectSubCmd::RefCount,
+        "IDLETIME" => ObjectSubCmd::IdleTime,
+        "FREQ" => ObjectSubCmd::Freq,
+        _ => return Command::Invalid(format!("unknown subcommand '{}'", name)),
+    };
+    if args.len() != 3 {
+        return Command::Invalid(format!("wrong number of arguments for 'object|{}' command", name.to_lowercase()));
+    }
+    let key = args[2].try_as_str().unwrap();
+
+    Command::Object(ObjectCmd {
+        sub_command,
+        key: String::from(key),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct RenameCmd {
+    pub key: String,
+    pub new_key: String,
+    /// Only rename if the new key doesn't exist (RENAMENX)
+    pub only_if_missing: bool,
+}
+
+const CMD_RENAME: &str = "RENAME";
+const CMD_RENAMENX: &str = "RENAMENX";
+// RENAME/RENAMENX key newkey
+fn parse_rename_command(args: &[Value], only_if_missing: bool) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let new_key = args[2].try_as_str().unwrap();
+
+    Command::Rename(RenameCmd {
+        key: String::from(key),
+        new_key: String::from(new_key),
+        only_if_missing,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct CopyCmd {
+    pub source: String,
+    pub destination: String,
+    /// Overwrite the destination if it exists (REPLACE)
+    pub replace: bool,
+}
+
+const CMD_COPY: &str = "COPY";
+// COPY source destination [REPLACE]
+fn parse_copy_command(args: &[Value]) -> Command {
+    let source = args[1].try_as_str().unwrap();
+    let destination = args[2].try_as_str().unwrap();
+    let replace = match args.get(3).map(|arg| arg.try_as_str().unwrap().to_uppercase()) {
+        None => false,
+        Some(option) if option == "REPLACE" => true,
+        Some(option) => todo!("COPY option {}", option),
+    };
+
+    Command::Copy(CopyCmd {
+        source: String::from(source),
+        destination: String::from(destination),
+        replace,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct FlushCmd {
+    /// Delete the files in the background (ASYNC)
+    pub background: bool,
+}
+
+// There is a single database, FLUSHDB and FLUSHALL are the same
+const CMD_FLUSHDB: &str = "FLUSHDB";
+const CMD_FLUSHALL: &str = "FLUSHALL";
+// FLUSHDB/FLUSHALL [ASYNC | SYNC]
+fn parse_flush_command(args: &[Value]) -> Command {
+    let background = match args.get(1).map(|arg| arg.try_as_str().unwrap().to_uppercase()) {
+        None => false,
+        Some(mode) if mode == "SYNC" && args.len() == 2 => false,
+        Some(mode) if mode == "ASYNC" && args.len() == 2 => true,
+        Some(_) => return Command::Invalid(String::from(SYNTAX_ERROR)),
+    };
+
+    Command::Flush(FlushCmd { background })
+}
+
+#[derive(Debug, Clone)]
+pub struct ShutdownCmd {
+    /// Flush the memtables before stopping (SAVE, the default)
+    pub save: bool,
+}
+
+impl ShutdownCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Cluster(api::ClusterCommand::Shutdown(api::Shutdown { save: self.save }))
+    }
+}
+
+const CMD_SHUTDOWN: &str = "SHUTDOWN";
+// SHUTDOWN [NOSAVE | SAVE]
+fn parse_shutdown_command(args: &[Value]) -> Command {
+    let save = match args.get(1).map(|arg| arg.try_as_str().unwrap().to_uppercase()) {
+        None => true,
+        Some(mode) if mode == "SAVE" => true,
+        Some(mode) if mode == "NOSAVE" => false,
+        Some(_) => return Command::Invalid(String::from(SYNTAX_ERROR)),
+    };
+
+    Command::Shutdown(ShutdownCmd { save })
+}
+
+/// Saving flushes the memtables of the reactor of the connection
+#[derive(Debug, Clone)]
+pub enum SaveCmd {
+    Save(),
+    BgSave(),
+    LastSave(),
+}
+
+const CMD_SAVE: &str = "SAVE";
+const CMD_BGSAVE: &str = "BGSAVE";
+const CMD_LASTSAVE: &str = "LASTSAVE";
+
+/// Metrics of the shards of the reactor of the connection
+#[derive(Debug, Clone)]
+pub struct InfoCmd {
+    /// Lowercase names of the sections to return, every section if empty
+    pub sections: Vec<String>,
+}
+
+const CMD_INFO: &str = "INFO";
+fn parse_info_command(args: &[Value]) -> Command {
+    let sections = args[1..].iter().map(|arg| arg.try_as_str().unwrap().to_lowercase()).collect();
+    Command::Info(InfoCmd { sections })
+}
+
+#[derive(Debug, Clone)]
+pub struct DumpCmd {
+    pub key: String,
+}
+
+impl DumpCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_DUMP: &str = "DUMP";
+// DUMP key
+fn parse_dump_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+
+    Command::Dump(DumpCmd { key: String::from(key) })
+}
+
+#[derive(Debug, Clone)]
+pub struct RestoreCmd {
+    pub key: String,
+    /// 0 for no ttl
+    pub ttl_ms: i64,
+    /// The ttl is a unix time in milliseconds (ABSTTL)
+    pub absolute_ttl: bool,
+    pub payload: Vec<u8>,
+    /// Overwrite the key if it exists (REPLACE)
+    pub replace: bool,
+}
+
+impl RestoreCmd {
+    /// Set command of the value decoded from the payload
+    pub fn to_api_command(&self, value_type: ValueType, value: Vec<u8>) -> api::Command {
+        let mut record = Record::new(self.key.clone(), value);
+        record.value_type = value_type;
+        record.expire_at = match self.ttl_ms {
+            0 => None,
+            ttl_ms if self.absolute_ttl => Some(crate::time::from_unix_ms(ttl_ms as u64)),
+            ttl_ms => Some(record.timestamp.saturating_add_signed(ttl_ms.saturating_mul(1_000_000))),
+        };
+        let condition = match self.replace {
+            true => api::SetCondition::Always,
+            false => api::SetCondition::IfMissing,
+        };
+        api::Command::Data(api::DataCommand::Set(api::Set {
+            record,
+            options: api::SetOptions {
+                condition,
+                ..api::SetOptions::default()
+            },
+            relative_ttl: false,
+        }))
+    }
+}
+
+const CMD_RESTORE: &str = "RESTORE";
+// RESTORE key ttl serialized-value [REPLACE] [ABSTTL]
+fn parse_restore_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let ttl_ms = match args[2].try_as_str().unwrap().parse::<i64>() {
+        Ok(ttl_ms) if ttl_ms >= 0 => ttl_ms,
+        Ok(_) => return Command::Invalid(String::from("Invalid TTL value, must be >= 0")),
+        Err(_) => return Command::Invalid(String::from(NOT_AN_INTEGER)),
+    };
+    let payload = args[3].try_as_bytes().unwrap();
+    let mut replace = false;
+    let mut absolute_ttl = false;
+    for arg in &args[4..] {
+        match arg.try_as_str().unwrap().to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            "ABSTTL" => absolute_ttl = true,
+            _ => return Command::Invalid(String::from(SYNTAX_ERROR)),
+        }
+    }
+
+    Command::Restore(RestoreCmd {
+        key: String::from(key),
+        ttl_ms,
+        absolute_ttl,
+        payload: payload.to_vec(),
+        replace,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct PfAddCmd {
+    pub key: String,
+    pub elements: Vec<Vec<u8>>,
+}
+
+impl PfAddCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        let mut hll = HyperLogLog::default();
+        for element in &self.elements {
+            hll.add(element);
+        }
+        api::Command::Data(api::DataCommand::PfMerge(api::PfMerge {
+            key: Key::new(self.key.clone()),
+            hll: hll.encode(),
+        }))
+    }
+}
+
+const CMD_PFADD: &str = "PFADD";
+// PFADD key [element ...]
+fn parse_pfadd_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let elements = args[2..].iter().map(|arg| Vec::from(arg.try_as_str().unwrap())).collect();
+
+    Command::PfAdd(PfAddCmd {
+        key: String::from(key),
+        elements,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct PfCountCmd {
+    pub keys: Vec<String>,
+}
+
+impl PfCountCmd {
+    pub fn to_api_commands(&self) -> Vec<api::DataCommand> {
+        self.keys
+            .iter()
+            .map(|key| api::DataCommand::Get(api::Get { key: Key::new(key.clone()) }))
+            .collect()
+    }
+}
+
+const CMD_PFCOUNT: &str = "PFCOUNT";
+// PFCOUNT key [key ...]
+fn parse_pfcount_command(args: &[Value]) -> Command {
+    let keys = args[1..].iter().map(|arg| String::from(arg.try_as_str().unwrap())).collect();
+
+    Command::PfCount(PfCountCmd { keys })
+}
+
+#[derive(Debug, Clone)]
+pub struct PfMergeCmd {
+    pub destination: String,
+    pub sources: Vec<String>,
+}
+
+impl PfMergeCmd {
+    /// Read the sources
+    pub fn to_api_commands(&self) -> Vec<api::DataCommand> {
+        self.sources
+            .iter()
+            .map(|key| api::DataCommand::Get(api::Get { key: Key::new(key.clone()) }))
+            .collect()
+    }
+
+    /// Merge the union of the sources into the destination
+    pub fn to_api_command(&self, union: &HyperLogLog) -> api::Command {
+        api::Command::Data(api::DataCommand::PfMerge(api::PfMerge {
+            key: Key::new(self.destination.clone()),
+            hll: union.encode(),
+        }))
+    }
+}
+
+const CMD_PFMERGE: &str = "PFMERGE";
+// PFMERGE destkey [sourcekey ...]
+fn parse_pfmerge_command(args: &[Value]) -> Command {
+    let destination = args[1].try_as_str().unwrap();
+    let sources = args[2..].iter().map(|arg| String::from(arg.try_as_str().unwrap())).collect();
+
+    Command::PfMerge(PfMergeCmd {
+        destination: String::from(destination),
+        sources,
+    })
+}
+
+const INVALID_STREAM_ID: &str = "Invalid stream ID specified as stream command argument";
+
+#[derive(Debug, Clone)]
+pub struct XAddCmd {
+    pub key: String,
+    pub id: NewId,
+    pub fields: Vec<Vec<u8>>,
+    pub max_len: Option<usize>,
+    pub create: bool,
+}
+
+impl XAddCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::XAdd(api::XAdd {
+            key: Key::new(self.key.clone()),
+            id: self.id,
+            fields: self.fields.clone(),
+            max_len: self.max_len,
+            create: self.create,
+        }))
+    }
+}
+
+const CMD_XADD: &str = "XADD";
+// XADD key [NOMKSTREAM] [MAXLEN [=|~] threshold] <* | id> field value [field value ...]
+fn parse_xadd_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let mut create = true;
+    let mut max_len = None;
+    let mut position = 2;
+    loop {
+        match args[position].try_as_str().unwrap().to_uppercase().as_str() {
+            "NOMKSTREAM" => create = false,
+            "MAXLEN" => {
+                if matches!(args[position + 1].try_as_str().unwrap(), "=" | "~") {
+                    position += 1;
+                }
+                position += 1;
+                max_len = Some(args[position].try_as_str().unwrap().parse().unwrap());
+            }
+            _ => break,
+        }
+        position += 1;
+    }
+
+    let id = match args[position].try_as_str().unwrap() {
+        "*" => NewId::Auto,
+        id => match id.strip_suffix("-*").map(str::parse) {
+            Some(Ok(ms)) => NewId::AutoSeq(ms),
+            Some(Err(_)) => return Command::Invalid(String::from(INVALID_STREAM_ID)),
+            None => match StreamId::parse(id, 0) {
+                Some(StreamId::MIN) => return Command::Invalid(String::from("The ID specified in XADD must be greater than 0-0")),
+                Some(id) => NewId::Explicit(id),
+                None => return Command::Invalid(String::from(INVALID_STREAM_ID)),
+            },
+        },
+    };
+    let fields: Vec<Vec<u8>> = args[position + 1..].iter().map(|arg| Vec::from(arg.try_as_str().unwrap())).collect();
+    if fields.is_empty() || fields.len() % 2 != 0 {
+        return Command::Invalid(String::from("wrong number of arguments for 'xadd' command"));
+    }
 
-#[derive(Debug, Clone)]
-pub enum Command {
-    Hello(HelloCmd),
-    Client(ClientCmd),
-    Cluster(ClusterCmd),
-    Command(),
-    Set(SetCmd),
-    Get(GetCmd),
+    Command::XAdd(XAddCmd {
+        key: String::from(key),
+        id,
+        fields,
+        max_len,
+        create,
+    })
 }
 
 #[derive(Debug, Clone)]
-pub struct SetInfoCmd {
-    pub lib_name: Option<String>,
-    pub lib_type: Option<String>,
+pub struct XLenCmd {
+    pub key: String,
+}
+
+impl XLenCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_XLEN: &str = "XLEN";
+// XLEN key
+fn parse_xlen_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+
+    Command::XLen(XLenCmd { key: String::from(key) })
 }
 
 #[derive(Debug, Clone)]
-pub struct HelloCmd {
-    pub version: char,
+pub struct XRangeCmd {
+    pub key: String,
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
 }
 
-const CMD_HELLO: &str = "HELLO";
-fn parse_hello_command(args: &[Value]) -> Command {
-    let version = match &args[1] {
-        Value::HashableValue(hashable_value) => match hashable_value {
-            HashableValue::Blob(vec) => vec,
-            _ => todo!(),
+impl XRangeCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_XRANGE: &str = "XRANGE";
+// XRANGE key start end [COUNT count]
+fn parse_xrange_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let start = StreamId::parse_range_start(args[2].try_as_str().unwrap());
+    let end = StreamId::parse_range_end(args[3].try_as_str().unwrap());
+    let (Some(start), Some(end)) = (start, end) else {
+        return Command::Invalid(String::from(INVALID_STREAM_ID));
+    };
+    let count = match args.get(4).map(|arg| arg.try_as_str().unwrap().to_uppercase()) {
+        Some(option) if option == "COUNT" && args.len() == 6 => match args[5].try_as_str().unwrap().parse() {
+            Ok(count) => count,
+            Err(_) => return Command::Invalid(String::from(NOT_AN_INTEGER)),
         },
-        Value::NonHashableValue(_) => todo!(),
-        Value::Null => todo!(),
+        Some(_) => return Command::Invalid(String::from(SYNTAX_ERROR)),
+        None => usize::MAX,
     };
-    Command::Hello(HelloCmd { version: version[0] as char })
+
+    Command::XRange(XRangeCmd {
+        key: String::from(key),
+        start,
+        end,
+        count,
+    })
 }
 
 #[derive(Debug, Clone)]
-pub enum ClientCmd {
-    SetInfo(SetInfoCmd),
+pub struct XReadCmd {
+    /// Keys with the id after which entries are read, None for `$` (only
+    /// new entries, so none without blocking)
+    pub streams: Vec<(String, Option<StreamId>)>,
+    pub count: usize,
 }
 
-const CMD_CLIENT: &str = "CLIENT";
-fn parse_client_command(args: &[Value]) -> Command {
-    let sub_command = args[1].try_as_str().unwrap();
-    match sub_command {
-        CMD_SETINFO => Command::Client(ClientCmd::SetInfo(parse_setinfo_cmd(args))),
-        _ => todo!(),
+impl XReadCmd {
+    pub fn to_api_commands(&self) -> Vec<api::DataCommand> {
+        self.streams
+            .iter()
+            .map(|(key, _)| api::DataCommand::Get(api::Get { key: Key::new(key.clone()) }))
+            .collect()
     }
 }
 
-const CMD_SETINFO: &str = "SETINFO";
-fn parse_setinfo_cmd(args: &[Value]) -> SetInfoCmd {
-    let _ = args[2].try_as_str().unwrap();
-    let value = args[3].try_as_str().unwrap();
+const CMD_XREAD: &str = "XREAD";
+// XREAD [COUNT count] STREAMS key [key ...] id [id ...]
+fn parse_xread_command(args: &[Value]) -> Command {
+    let mut count = usize::MAX;
+    let mut position = 1;
+    loop {
+        let Some(option) = args.get(position) else {
+            return Command::Invalid(String::from(SYNTAX_ERROR));
+        };
+        match option.try_as_str().unwrap().to_uppercase().as_str() {
+            "COUNT" => {
+                let Some(value) = args.get(position + 1) else {
+                    return Command::Invalid(String::from(SYNTAX_ERROR));
+                };
+                let Ok(value) = value.try_as_str().unwrap().parse() else {
+                    return Command::Invalid(String::from(NOT_AN_INTEGER));
+                };
+                count = value;
+                position += 2;
+            }
+            "STREAMS" => break,
+            "BLOCK" => return Command::Invalid(String::from("XREAD BLOCK is not supported")),
+            _ => return Command::Invalid(String::from(SYNTAX_ERROR)),
+        }
+    }
 
-    SetInfoCmd {
-        lib_name: Some(String::from(value)),
-        lib_type: None,
+    let streams = &args[position + 1..];
+    if streams.is_empty() || streams.len() % 2 != 0 {
+        return Command::Invalid(String::from(
+            "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.",
+        ));
+    }
+    let (keys, ids) = streams.split_at(streams.len() / 2);
+    let mut parsed = Vec::with_capacity(keys.len());
+    for (key, id) in keys.iter().zip(ids) {
+        let id = match id.try_as_str().unwrap() {
+            "$" => None,
+            id => match StreamId::parse(id, 0) {
+                Some(id) => Some(id),
+                None => return Command::Invalid(String::from(INVALID_STREAM_ID)),
+            },
+        };
+        parsed.push((String::from(key.try_as_str().unwrap()), id));
     }
+
+    Command::XRead(XReadCmd { streams: parsed, count })
 }
 
+const UNSUPPORTED_UNIT: &str = "unsupported unit provided. please use M, KM, FT, MI";
+
 #[derive(Debug, Clone)]
-pub struct SetCmd {
+pub struct GeoAddCmd {
     pub key: String,
-    pub value: Vec<u8>,
+    /// Geohash score and member pairs
+    pub members: Vec<(f64, Vec<u8>)>,
 }
 
-impl SetCmd {
+impl GeoAddCmd {
     pub fn to_api_command(&self) -> api::Command {
-        api::Command::Data(api::DataCommand::Set(api::Set {
-            record: Record::new(self.key.clone(), self.value.clone()),
+        api::Command::Data(api::DataCommand::ZAdd(api::ZAdd {
+            key: Key::new(self.key.clone()),
+            members: self.members.clone(),
         }))
     }
 }
 
+const CMD_GEOADD: &str = "GEOADD";
+// GEOADD key longitude latitude member [longitude latitude member ...]
+fn parse_geoadd_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    if (args.len() - 2) % 3 != 0 {
+        return Command::Invalid(String::from("wrong number of arguments for 'geoadd' command"));
+    }
+    let mut members = Vec::with_capacity((args.len() - 2) / 3);
+    for position in args[2..].chunks(3) {
+        let longitude = position[0].try_as_str().unwrap();
+        let latitude = position[1].try_as_str().unwrap();
+        let score = match (longitude.parse(), latitude.parse()) {
+            (Ok(longitude), Ok(latitude)) => geo::encode(longitude, latitude),
+            _ => None,
+        };
+        match score {
+            Some(score) => members.push((score, Vec::from(position[2].try_as_str().unwrap()))),
+            None => return Command::Invalid(format!("invalid longitude,latitude pair {},{}", longitude, latitude)),
+        }
+    }
+
+    Command::GeoAdd(GeoAddCmd {
+        key: String::from(key),
+        members,
+    })
+}
+
 #[derive(Debug, Clone)]
-pub struct GetCmd {
+pub struct GeoPosCmd {
     pub key: String,
+    pub members: Vec<Vec<u8>>,
 }
 
-impl GetCmd {
+impl GeoPosCmd {
     pub fn to_api_command(&self) -> api::Command {
         api::Command::Data(api::DataCommand::Get(api::Get {
             key: Key::new(self.key.clone()),
@@ -100,33 +1755,273 @@ impl GetCmd {
     }
 }
 
-const CMD_SET: &str = "SET";
-fn parse_set_command(args: &[Value]) -> Command {
+const CMD_GEOPOS: &str = "GEOPOS";
+// GEOPOS key [member ...]
+fn parse_geopos_command(args: &[Value]) -> Command {
     let key = args[1].try_as_str().unwrap();
-    let value = args[2].try_as_str().unwrap();
+    let members = args[2..].iter().map(|arg| Vec::from(arg.try_as_str().unwrap())).collect();
 
-    Command::Set(SetCmd {
+    Command::GeoPos(GeoPosCmd {
         key: String::from(key),
-        value: Vec::from(value),
+        members,
     })
 }
 
-const CMD_GET: &str = "GET";
-fn parse_get_command(args: &[Value]) -> Command {
+#[derive(Debug, Clone)]
+pub struct GeoDistCmd {
+    pub key: String,
+    pub members: (Vec<u8>, Vec<u8>),
+    /// Meters per unit of the reply
+    pub unit: f64,
+}
+
+impl GeoDistCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_GEODIST: &str = "GEODIST";
+// GEODIST key member1 member2 [M | KM | FT | MI]
+fn parse_geodist_command(args: &[Value]) -> Command {
     let key = args[1].try_as_str().unwrap();
+    let members = (Vec::from(args[2].try_as_str().unwrap()), Vec::from(args[3].try_as_str().unwrap()));
+    let unit = match args.get(4) {
+        Some(unit) => match geo::unit_in_meters(unit.try_as_str().unwrap()) {
+            Some(unit) => unit,
+            None => return Command::Invalid(String::from(UNSUPPORTED_UNIT)),
+        },
+        None => 1.0,
+    };
 
-    Command::Get(GetCmd { key: String::from(key) })
+    Command::GeoDist(GeoDistCmd {
+        key: String::from(key),
+        members,
+        unit,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub enum GeoOrigin {
+    Member(Vec<u8>),
+    /// Longitude and latitude
+    Position(f64, f64),
+}
+
+#[derive(Debug, Clone)]
+pub struct GeoSearchCmd {
+    pub key: String,
+    pub origin: GeoOrigin,
+    pub shape: Shape,
+    /// Meters per unit of the distances of the reply
+    pub unit: f64,
+    pub descending: bool,
+    pub count: Option<usize>,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
+}
+
+impl GeoSearchCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Get(api::Get {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_GEOSEARCH: &str = "GEOSEARCH";
+// GEOSEARCH key <FROMMEMBER member | FROMLONLAT longitude latitude>
+//   <BYRADIUS radius unit | BYBOX width height unit> [ASC | DESC]
+//   [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]
+fn parse_geosearch_command(args: &[Value]) -> Command {
+    let key = args[1].try_as_str().unwrap();
+    let arg = |position: usize| args[position].try_as_str().unwrap();
+    let float = |position: usize| arg(position).parse::<f64>().ok().filter(|value| !value.is_nan());
+    let mut origin = None;
+    let mut shape = None;
+    let mut unit = 1.0;
+    let mut descending = false;
+    let mut count = None;
+    let (mut with_coord, mut with_dist, mut with_hash) = (false, false, false);
+    let mut position = 2;
+    while position < args.len() {
+        let option = arg(position).to_uppercase();
+        let values = match option.as_str() {
+            "FROMMEMBER" | "COUNT" => 1,
+            "FROMLONLAT" | "BYRADIUS" => 2,
+            "BYBOX" => 3,
+            _ => 0,
+        };
+        if position + values >= args.len() {
+            return Command::Invalid(String::from(SYNTAX_ERROR));
+        }
+        match option.as_str() {
+            "FROMMEMBER" => origin = Some(GeoOrigin::Member(Vec::from(arg(position + 1)))),
+            "FROMLONLAT" => {
+                let (Some(longitude), Some(latitude)) = (float(position + 1), float(position + 2)) else {
+                    return Command::Invalid(String::from(NOT_A_FLOAT));
+                };
+                origin = Some(GeoOrigin::Position(longitude, latitude));
+            }
+            "BYRADIUS" => {
+                let Some(meters) = geo::unit_in_meters(arg(position + 2)) else {
+                    return Command::Invalid(String::from(UNSUPPORTED_UNIT));
+                };
+                let Some(radius) = float(position + 1) else {
+                    return Command::Invalid(String::from(NOT_A_FLOAT));
+                };
+                shape = Some(Shape::Radius(radius * meters));
+                unit = meters;
+            }
+            "BYBOX" => {
+                let Some(meters) = geo::unit_in_meters(arg(position + 3)) else {
+                    return Command::Invalid(String::from(UNSUPPORTED_UNIT));
+                };
+                let (Some(width), Some(height)) = (float(position + 1), float(position + 2)) else {
+                    return Command::Invalid(String::from(NOT_A_FLOAT));
+                };
+                shape = Some(Shape::Box {
+                    width: width * meters,
+                    height: height * meters,
+                });
+                unit = meters;
+            }
+            "ASC" => descending = false,
+            "DESC" => descending = true,
+            "COUNT" => match arg(position + 1).parse() {
+                Ok(value) if value > 0 => count = Some(value),
+                _ => return Command::Invalid(String::from("COUNT must be > 0")),
+            },
+            // Results are always sorted, so ANY just returns the closest ones
+            "ANY" => (),
+            "WITHCOORD" => with_coord = true,
+            "WITHDIST" => with_dist = true,
+            "WITHHASH" => with_hash = true,
+            _ => return Command::Invalid(String::from(SYNTAX_ERROR)),
+        }
+        position += values + 1;
+    }
+    let Some(origin) = origin else {
+        return Command::Invalid(String::from("exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH"));
+    };
+    let Some(shape) = shape else {
+        return Command::Invalid(String::from("exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH"));
+    };
+
+    Command::GeoSearch(GeoSearchCmd {
+        key: String::from(key),
+        origin,
+        shape,
+        unit,
+        descending,
+        count,
+        with_coord,
+        with_dist,
+        with_hash,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub enum DebugCmd {
+    /// Pause the connection
+    Sleep(Duration),
+    Object(DebugObjectCmd),
+    /// Statistics of the storage of the local shards
+    Jmap(),
+    /// Flush the memtables and rebuild the index of the local shards from disk
+    Reload(),
+    /// Check the disktables of the local shards and the index entries
+    /// pointing to them
+    Verify(),
+}
+
+#[derive(Debug, Clone)]
+pub struct DebugObjectCmd {
+    pub key: String,
+}
+
+impl DebugObjectCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Data(api::DataCommand::Object(api::Object {
+            key: Key::new(self.key.clone()),
+        }))
+    }
+}
+
+const CMD_DEBUG: &str = "DEBUG";
+// DEBUG SLEEP seconds | OBJECT key | JMAP | RELOAD | VERIFY
+fn parse_debug_command(args: &[Value]) -> Command {
+    let sub_command = args[1].try_as_str().unwrap().to_uppercase();
+    let debug_cmd = match (sub_command.as_str(), args.len()) {
+        ("SLEEP", 3) => {
+            // Negative, NaN, infinite or too large durations are rejected
+            let seconds = args[2].try_as_str().unwrap().parse::<f64>().map(Duration::try_from_secs_f64);
+            match seconds {
+                Ok(Ok(duration)) => DebugCmd::Sleep(duration),
+                _ => return Command::Invalid(String::from(NOT_A_FLOAT)),
+            }
+        }
+        ("OBJECT", 3) => DebugCmd::Object(DebugObjectCmd {
+            key: String::from(args[2].try_as_str().unwrap()),
+        }),
+        ("JMAP", _) => DebugCmd::Jmap(),
+        ("RELOAD", _) => DebugCmd::Reload(),
+        ("VERIFY", _) => DebugCmd::Verify(),
+        ("SLEEP" | "OBJECT", _) => return Command::Invalid(format!("wrong number of arguments for 'debug|{}' command", sub_command.to_lowercase())),
+        (sub_command, _) => return Command::Invalid(format!("unknown subcommand '{}'", sub_command)),
+    };
+
+    Command::Debug(debug_cmd)
+}
+
+#[derive(Debug, Clone)]
+pub enum ConfigCmd {
+    /// Parameters matching any of the glob patterns
+    Get(Vec<String>),
+    /// (name, value) pairs, applied all at once
+    Set(Vec<(String, String)>),
+}
+
+const CMD_CONFIG: &str = "CONFIG";
+// CONFIG GET pattern [pattern ...] | SET name value [name value ...]
+fn parse_config_command(args: &[Value]) -> Command {
+    let params: Vec<String> = args[2..].iter().map(|arg| String::from(arg.try_as_str().unwrap())).collect();
+    let config_cmd = match args[1].try_as_str().unwrap().to_uppercase().as_str() {
+        "GET" if !params.is_empty() => ConfigCmd::Get(params),
+        "SET" if !params.is_empty() && params.len() % 2 == 0 => {
+            ConfigCmd::Set(params.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect())
+        }
+        sub_command @ ("GET" | "SET") => {
+            return Command::Invalid(format!("wrong number of arguments for 'config|{}' command", sub_command.to_lowercase()))
+        }
+        sub_command => return Command::Invalid(format!("unknown subcommand '{}'", sub_command)),
+    };
+
+    Command::Config(config_cmd)
 }
 
 #[derive(Debug, Clone)]
 pub enum ClusterCmd {
     Slots(),
     Info(),
+    Nodes(),
+    MyId(),
+    KeySlot(String),
+    CountKeysInSlot(u16),
+    GetKeysInSlot(GetKeysInSlotCmd),
     Join(JoinCmd),
+    Reset(ResetCmd),
 }
 
 const CMD_CLUSTER_SLOT: &str = "SLOTS";
 const CMD_CLUSTER_INFO: &str = "INFO";
+const CMD_CLUSTER_NODES: &str = "NODES";
+const CMD_CLUSTER_MYID: &str = "MYID";
+const CMD_CLUSTER_KEYSLOT: &str = "KEYSLOT";
+const CMD_CLUSTER_COUNTKEYSINSLOT: &str = "COUNTKEYSINSLOT";
 
 const CMD_CLUSTER_JOIN: &str = "JOIN";
 #[derive(Debug, Clone)]
@@ -156,35 +2051,323 @@ fn parse_cluster_join_command(args: &[Value]) -> Command {
     Command::Cluster(ClusterCmd::Join(JoinCmd { reactors }))
 }
 
+const CMD_CLUSTER_RESET: &str = "RESET";
+const CMD_CLUSTER_RESET_HARD: &str = "HARD";
+const CMD_CLUSTER_RESET_SOFT: &str = "SOFT";
+#[derive(Debug, Clone)]
+pub struct ResetCmd {
+    hard: bool,
+}
+
+impl ResetCmd {
+    pub fn to_api_command(&self) -> api::Command {
+        api::Command::Cluster(api::ClusterCommand::Reset(Reset { hard: self.hard }))
+    }
+}
+
+// CLUSTER RESET [HARD|SOFT], soft being the default
+fn parse_cluster_reset_command(args: &[Value]) -> Command {
+    let hard = match args.get(2).map(|v| v.try_as_str().unwrap().to_uppercase()) {
+        Some(mode) => match mode.as_str() {
+            CMD_CLUSTER_RESET_HARD => true,
+            CMD_CLUSTER_RESET_SOFT => false,
+            _ => todo!(),
+        },
+        None => false,
+    };
+
+    Command::Cluster(ClusterCmd::Reset(ResetCmd { hard }))
+}
+
+#[derive(Debug, Clone)]
+pub struct GetKeysInSlotCmd {
+    pub slot: u16,
+    pub count: usize,
+}
+
+const CMD_CLUSTER_GETKEYSINSLOT: &str = "GETKEYSINSLOT";
+// CLUSTER GETKEYSINSLOT slot count
+fn parse_cluster_getkeysinslot_command(args: &[Value]) -> Command {
+    let slot: u16 = args[2].try_as_str().unwrap().parse().unwrap();
+    assert!(slot < topology::MAX_RANGE, "Invalid slot");
+    let count = args[3].try_as_str().unwrap().parse().unwrap();
+
+    Command::Cluster(ClusterCmd::GetKeysInSlot(GetKeysInSlotCmd { slot, count }))
+}
+
 const CMD_CLUSTER: &str = "CLUSTER";
 fn parse_cluster_command(args: &[Value]) -> Command {
     let sub_command = args[1].try_as_str().unwrap();
     match sub_command {
         CMD_CLUSTER_SLOT => Command::Cluster(ClusterCmd::Slots()),
         CMD_CLUSTER_INFO => Command::Cluster(ClusterCmd::Info()),
+        CMD_CLUSTER_NODES => Command::Cluster(ClusterCmd::Nodes()),
+        CMD_CLUSTER_MYID => Command::Cluster(ClusterCmd::MyId()),
+        // CLUSTER KEYSLOT key
+        CMD_CLUSTER_KEYSLOT => Command::Cluster(ClusterCmd::KeySlot(String::from(args[2].try_as_str().unwrap()))),
+        // CLUSTER COUNTKEYSINSLOT slot
+        CMD_CLUSTER_COUNTKEYSINSLOT => {
+            let slot: u16 = args[2].try_as_str().unwrap().parse().unwrap();
+            assert!(slot < topology::MAX_RANGE, "Invalid slot");
+            Command::Cluster(ClusterCmd::CountKeysInSlot(slot))
+        }
+        CMD_CLUSTER_GETKEYSINSLOT => parse_cluster_getkeysinslot_command(args),
         CMD_CLUSTER_JOIN => parse_cluster_join_command(args),
+        CMD_CLUSTER_RESET => parse_cluster_reset_command(args),
         _ => todo!(),
     }
 }
 
+#[derive(Debug, Clone)]
+pub enum CommandCmd {
+    /// Every command of the registry
+    List(),
+    Count(),
+    Info(Vec<String>),
+    /// Documentation of the given commands, or of all of them if empty
+    Docs(Vec<String>),
+}
+
 const CMD_COMMAND: &str = "COMMAND";
-fn parse_command_command(_: &[Value]) -> Command {
-    Command::Command()
+// COMMAND [COUNT | INFO name [name ...] | DOCS [name ...]]
+fn parse_command_command(args: &[Value]) -> Command {
+    let names = || args[2..].iter().map(|arg| String::from(arg.try_as_str().unwrap())).collect();
+    let command_cmd = match args.get(1).map(|arg| arg.try_as_str().unwrap().to_uppercase()) {
+        None => CommandCmd::List(),
+        Some(sub_command) => match sub_command.as_str() {
+            "COUNT" => CommandCmd::Count(),
+            "INFO" => CommandCmd::Info(names()),
+            "DOCS" => CommandCmd::Docs(names()),
+            sub_command => return Command::Invalid(format!("unknown subcommand '{}'", sub_command)),
+        },
+    };
+    Command::Command(command_cmd)
+}
+
+/// Description of a command, used to dispatch it and to answer COMMAND
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// Number of arguments, the name included. Negative means at least
+    /// that many
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+    /// Position of the first and last key in the arguments and the step
+    /// between keys, 0 if there are no keys and -1 for the last argument
+    pub first_key: i64,
+    pub last_key: i64,
+    pub step: i64,
+    pub group: &'static str,
+    pub summary: &'static str,
+    parse: fn(&[Value]) -> Command,
+}
+
+impl CommandSpec {
+    pub fn accepts(&self, args_count: usize) -> bool {
+        match self.arity {
+            arity if arity < 0 => args_count as i64 >= -arity,
+            arity => args_count as i64 == arity,
+        }
+    }
+
+    /// Keys of the arguments of the command, according to its key positions
+    pub fn keys(&self, args: &[Value]) -> Vec<String> {
+        if self.first_key == 0 {
+            return vec![];
+        }
+        let last_key = match self.last_key {
+            last_key if last_key < 0 => args.len() as i64 + last_key,
+            last_key => last_key,
+        };
+        (self.first_key..=last_key)
+            .step_by(self.step as usize)
+            .filter_map(|position| args.get(position as usize))
+            .map(|key| String::from(key.try_as_str().unwrap()))
+            .collect()
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.flags.contains(&"write")
+    }
+
+    pub fn is_read(&self) -> bool {
+        self.flags.contains(&"readonly")
+    }
+}
+
+const WRITE: &[&str] = &["write", "denyoom"];
+const WRITE_FAST: &[&str] = &["write", "denyoom", "fast"];
+const DELETE: &[&str] = &["write"];
+const DELETE_FAST: &[&str] = &["write", "fast"];
+const READ: &[&str] = &["readonly"];
+const READ_FAST: &[&str] = &["readonly", "fast"];
+const ADMIN: &[&str] = &["admin", "noscript"];
+const PUBSUB: &[&str] = &["pubsub", "noscript", "loading", "stale"];
+const CONNECTION: &[&str] = &["noscript", "loading", "stale", "fast"];
+const STATUS: &[&str] = &["loading", "stale"];
+
+macro_rules! command {
+    ($name:expr, $arity:expr, $flags:expr, $keys:expr, $group:expr, $summary:expr, $parse:expr) => {
+        CommandSpec {
+            name: $name,
+            arity: $arity,
+            flags: $flags,
+            first_key: $keys.0,
+            last_key: $keys.1,
+            step: $keys.2,
+            group: $group,
+            summary: $summary,
+            parse: $parse,
+        }
+    };
+}
+
+const NO_KEY: (i64, i64, i64) = (0, 0, 0);
+const ONE_KEY: (i64, i64, i64) = (1, 1, 1);
+
+/// Every command supported by the RESP server
+#[rustfmt::skip]
+pub static COMMANDS: &[CommandSpec] = &[
+    command!(CMD_HELLO, -1, CONNECTION, NO_KEY, "connection", "Handshake with the server", parse_hello_command),
+    command!(CMD_CLIENT, -2, CONNECTION, NO_KEY, "connection", "Manage the client connections", parse_client_command),
+    command!(CMD_COMMAND, -1, CONNECTION, NO_KEY, "server", "Describe the supported commands", parse_command_command),
+    command!(CMD_CLUSTER, -2, CONNECTION, NO_KEY, "cluster", "Cluster topology and management", parse_cluster_command),
+    command!(CMD_CONFIG, -2, ADMIN, NO_KEY, "server", "Get or set runtime configuration parameters", parse_config_command),
+    command!(CMD_DEBUG, -2, ADMIN, NO_KEY, "server", "Debugging commands", parse_debug_command),
+    command!(CMD_SET, -3, WRITE, ONE_KEY, "string", "Set the string value of a key", parse_set_command),
+    command!(CMD_GET, 2, READ_FAST, ONE_KEY, "string", "Get the string value of a key", parse_get_command),
+    command!(CMD_SETEX, 4, WRITE, ONE_KEY, "string", "Set the value and the ttl in seconds of a key", |args| parse_setex_command(args, 1000)),
+    command!(CMD_PSETEX, 4, WRITE, ONE_KEY, "string", "Set the value and the ttl in milliseconds of a key", |args| parse_setex_command(args, 1)),
+    command!(CMD_GETRANGE, 4, READ, ONE_KEY, "string", "Get a substring of a string value", parse_getrange_command),
+    command!(CMD_SETRANGE, 4, WRITE, ONE_KEY, "string", "Overwrite part of a string value", parse_setrange_command),
+    command!(CMD_STRLEN, 2, READ_FAST, ONE_KEY, "string", "Get the length of a string value", parse_strlen_command),
+    command!(CMD_INCR, 2, WRITE_FAST, ONE_KEY, "string", "Increment an integer value by one", |args| parse_incr_command(args, CMD_INCR)),
+    command!(CMD_DECR, 2, WRITE_FAST, ONE_KEY, "string", "Decrement an integer value by one", |args| parse_incr_command(args, CMD_DECR)),
+    command!(CMD_INCRBY, 3, WRITE_FAST, ONE_KEY, "string", "Increment an integer value", |args| parse_incr_command(args, CMD_INCRBY)),
+    command!(CMD_DECRBY, 3, WRITE_FAST, ONE_KEY, "string", "Decrement an integer value", |args| parse_incr_command(args, CMD_DECRBY)),
+    command!(CMD_INCRBYFLOAT, 3, WRITE_FAST, ONE_KEY, "string", "Increment a float value", |args| parse_incr_command(args, CMD_INCRBYFLOAT)),
+    command!(CMD_MGET, -2, READ_FAST, (1, -1, 1), "string", "Get the values of several keys", parse_mget_command),
+    command!(CMD_MSET, -3, WRITE, (1, -1, 2), "string", "Set the values of several keys", parse_mset_command),
+    command!(CMD_EXPIRE, 3, DELETE_FAST, ONE_KEY, "generic", "Set the ttl of a key in seconds", |args| parse_expire_command(args, 1000, false)),
+    command!(CMD_PEXPIRE, 3, DELETE_FAST, ONE_KEY, "generic", "Set the ttl of a key in milliseconds", |args| parse_expire_command(args, 1, false)),
+    command!(CMD_EXPIREAT, 3, DELETE_FAST, ONE_KEY, "generic", "Expire a key at a unix time", |args| parse_expire_command(args, 1000, true)),
+    command!(CMD_PEXPIREAT, 3, DELETE_FAST, ONE_KEY, "generic", "Expire a key at a unix time in ms", |args| parse_expire_command(args, 1, true)),
+    command!(CMD_PERSIST, 2, DELETE_FAST, ONE_KEY, "generic", "Remove the ttl of a key", parse_persist_command),
+    command!(CMD_TTL, 2, READ_FAST, ONE_KEY, "generic", "Get the ttl of a key in seconds", |args| parse_ttl_command(args, false, false)),
+    command!(CMD_PTTL, 2, READ_FAST, ONE_KEY, "generic", "Get the ttl of a key in milliseconds", |args| parse_ttl_command(args, true, false)),
+    command!(CMD_EXPIRETIME, 2, READ_FAST, ONE_KEY, "generic", "Get the expiration unix time of a key", |args| parse_ttl_command(args, false, true)),
+    command!(CMD_PEXPIRETIME, 2, READ_FAST, ONE_KEY, "generic", "Get the expiration unix time in ms", |args| parse_ttl_command(args, true, true)),
+    command!(CMD_DEL, -2, DELETE, (1, -1, 1), "generic", "Delete keys", |args| parse_del_command( args, false )),
+    command!(CMD_UNLINK, -2, DELETE_FAST, (1, -1, 1), "generic", "Delete keys in the background", |args| parse_del_command(args, true)),
+    command!(CMD_SCAN, -2, READ, NO_KEY, "generic", "Iterate over the keys", parse_scan_command),
+    command!(CMD_KEYS, 2, READ, NO_KEY, "generic", "Find the keys matching a pattern", parse_keys_command),
+    command!(CMD_TYPE, 2, READ_FAST, ONE_KEY, "generic", "Get the type of the value of a key", parse_type_command),
+    command!(CMD_RENAME, 3, DELETE, (1, 2, 1), "generic", "Rename a key", |args| parse_rename_command( args, false )),
+    command!(CMD_RENAMENX, 3, DELETE_FAST, (1, 2, 1), "generic", "Rename a key if the new name is free", |args| parse_rename_command(args, true)),
+    command!(CMD_COPY, -3, WRITE, (1, 2, 1), "generic", "Copy the value of a key to another key", parse_copy_command),
+    command!(CMD_OBJECT, -2, READ, (2, 2, 1), "generic", "Inspect the internals of a key", parse_object_command),
+    command!(CMD_DUMP, 2, READ, ONE_KEY, "generic", "Serialize the value of a key", parse_dump_command),
+    command!(CMD_RESTORE, -4, WRITE, ONE_KEY, "generic", "Create a key from a serialized value", parse_restore_command),
+    command!(CMD_FLUSHDB, -1, DELETE, NO_KEY, "server", "Delete every key of the node", parse_flush_command),
+    command!(CMD_FLUSHALL, -1, DELETE, NO_KEY, "server", "Delete every key of the node", parse_flush_command),
+    command!(CMD_SHUTDOWN, -1, ADMIN, NO_KEY, "server", "Flush the memtables and stop the node", parse_shutdown_command),
+    command!(CMD_SAVE, 1, ADMIN, NO_KEY, "server", "Flush the memtables of the reactor", |_| Command::Save(SaveCmd::Save())),
+    // BGSAVE SCHEDULE is accepted and ignored
+    command!(CMD_BGSAVE, -1, ADMIN, NO_KEY, "server", "Flush the memtables of the reactor in the background", |_| Command::Save(SaveCmd::BgSave())),
+    command!(CMD_LASTSAVE, 1, READ_FAST, NO_KEY, "server", "Get the unix time of the last save", |_| Command::Save(SaveCmd::LastSave())),
+    command!(CMD_INFO, -1, STATUS, NO_KEY, "server", "Get the metrics of the datastores", parse_info_command),
+    command!(CMD_HSET, -4, WRITE_FAST, ONE_KEY, "hash", "Set fields of a hash", parse_hset_command),
+    command!(CMD_HGET, 3, READ_FAST, ONE_KEY, "hash", "Get a field of a hash", parse_hget_command),
+    command!(CMD_HGETALL, 2, READ, ONE_KEY, "hash", "Get every field and value of a hash", parse_hgetall_command),
+    command!(CMD_HDEL, -3, DELETE_FAST, ONE_KEY, "hash", "Delete fields of a hash", parse_hdel_command),
+    command!(CMD_LPUSH, -3, WRITE_FAST, ONE_KEY, "list", "Prepend elements to a list", |args| parse_push_command(args, true)),
+    command!(CMD_RPUSH, -3, WRITE_FAST, ONE_KEY, "list", "Append elements to a list", |args| parse_push_command(args, false)),
+    command!(CMD_LPOP, -2, DELETE_FAST, ONE_KEY, "list", "Remove the first elements of a list", |args| parse_pop_command(args, true)),
+    command!(CMD_RPOP, -2, DELETE_FAST, ONE_KEY, "list", "Remove the last elements of a list", |args| parse_pop_command(args, false)),
+    command!(CMD_LRANGE, 4, READ, ONE_KEY, "list", "Get a range of elements of a list", parse_lrange_command),
+    command!(CMD_SADD, -3, WRITE_FAST, ONE_KEY, "set", "Add members to a set", parse_sadd_command),
+    command!(CMD_SREM, -3, DELETE_FAST, ONE_KEY, "set", "Remove members from a set", parse_srem_command),
+    command!(CMD_SMEMBERS, 2, READ, ONE_KEY, "set", "Get every member of a set", parse_smembers_command),
+    command!(CMD_SISMEMBER, 3, READ_FAST, ONE_KEY, "set", "Check if a value is a member of a set", parse_sismember_command),
+    command!(CMD_ZADD, -4, WRITE_FAST, ONE_KEY, "sorted-set", "Add members to a sorted set", parse_zadd_command),
+    command!(CMD_ZSCORE, 3, READ_FAST, ONE_KEY, "sorted-set", "Get the score of a member of a sorted set", parse_zscore_command),
+    command!(CMD_ZRANGE, -4, READ, ONE_KEY, "sorted-set", "Get a range of members of a sorted set", parse_zrange_command),
+    command!(CMD_GEOADD, -5, WRITE, ONE_KEY, "geo", "Add positions to a geospatial index", parse_geoadd_command),
+    command!(CMD_GEOPOS, -2, READ, ONE_KEY, "geo", "Get the positions of members of a geospatial index", parse_geopos_command),
+    command!(CMD_GEODIST, -4, READ, ONE_KEY, "geo", "Get the distance between two members of a geospatial index", parse_geodist_command),
+    command!(CMD_GEOSEARCH, -7, READ, ONE_KEY, "geo", "Find the members of a geospatial index within an area", parse_geosearch_command),
+    command!(CMD_PFADD, -2, WRITE_FAST, ONE_KEY, "hyperloglog", "Add elements to a HyperLogLog", parse_pfadd_command),
+    command!(CMD_PFCOUNT, -2, READ, (1, -1, 1), "hyperloglog", "Estimate the cardinality of the union of HyperLogLogs", parse_pfcount_command),
+    command!(CMD_PFMERGE, -2, WRITE, (1, -1, 1), "hyperloglog", "Merge HyperLogLogs into one", parse_pfmerge_command),
+    command!(CMD_XADD, -5, WRITE_FAST, ONE_KEY, "stream", "Append an entry to a stream", parse_xadd_command),
+    command!(CMD_XLEN, 2, READ_FAST, ONE_KEY, "stream", "Get the number of entries of a stream", parse_xlen_command),
+    command!(CMD_XRANGE, -4, READ, ONE_KEY, "stream", "Get the entries of a stream within a range of ids", parse_xrange_command),
+    command!(CMD_XREAD, -4, READ, NO_KEY, "stream", "Get the entries of streams after the given ids", parse_xread_command),
+    command!(CMD_SUBSCRIBE, -2, PUBSUB, NO_KEY, "pubsub", "Listen to channels", |args| parse_pubsub_command(args, CMD_SUBSCRIBE)),
+    command!(CMD_UNSUBSCRIBE, -1, PUBSUB, NO_KEY, "pubsub", "Stop listening to channels", |args| parse_pubsub_command(args, CMD_UNSUBSCRIBE)),
+    command!(CMD_PSUBSCRIBE, -2, PUBSUB, NO_KEY, "pubsub", "Listen to channels matching patterns", |args| parse_pubsub_command(args, CMD_PSUBSCRIBE)),
+    command!(CMD_PUNSUBSCRIBE, -1, PUBSUB, NO_KEY, "pubsub", "Stop listening to patterns", |args| parse_pubsub_command(args, CMD_PUNSUBSCRIBE)),
+    command!(CMD_PUBLISH, 3, PUBSUB, NO_KEY, "pubsub", "Post a message to a channel", |args| parse_pubsub_command(args, CMD_PUBLISH)),
+];
+
+/// Look up a command by name, ignoring the case
+pub fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name.eq_ignore_ascii_case(name))
 }
 
 pub struct RESPHandler {
     pub stream: BufReader<monoio::net::TcpStream>,
+    /// Name of the last decoded command
+    pub last_command: String,
+    /// Spec and keys of the last decoded command, None if it is unknown
+    pub last_spec: Option<&'static CommandSpec>,
+    pub last_keys: Vec<String>,
+    /// Received bytes, commands can span several reads
+    buffer: Vec<u8>,
+    /// Start of the bytes of `buffer` not decoded yet
+    position: usize,
 }
 
 // Handle parsing for the Redis serialization protocol (RESP)
 impl RESPHandler {
-    // pub async fn decode_command(&mut self) -> Result<Command, std::io::Error> {
+    pub fn new(stream: BufReader<monoio::net::TcpStream>) -> RESPHandler {
+        RESPHandler {
+            stream,
+            last_command: String::new(),
+            last_spec: None,
+            last_keys: Vec::new(),
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+
     pub async fn decode_command(&mut self) -> Result<Command, std::io::Error> {
-        let buffer = self.stream.fill_buf().await.unwrap();
-        if buffer.is_empty() {
-            return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "empty buffer"));
+        loop {
+            if let Some(cmd) = self.decode_buffered_command() {
+                return Ok(cmd);
+            }
+            // The buffer is extended without yielding in between, so the
+            // future can be dropped (e.g. in a select) without losing data
+            let buffer = self.stream.fill_buf().await.unwrap();
+            if buffer.is_empty() {
+                return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "empty buffer"));
+            }
+            let read = buffer.len();
+            self.buffer.drain(..self.position);
+            self.position = 0;
+            self.buffer.extend_from_slice(buffer);
+            self.stream.consume(read);
         }
-        let (remaining_buffer, val) = parse(buffer).unwrap();
+    }
+
+    /// Decode the next command if it was already fully received, without
+    /// reading from the stream. Used to execute pipelined commands in a batch
+    pub fn decode_buffered_command(&mut self) -> Option<Command> {
+        let buffer = &self.buffer[self.position..];
+        let (remaining_buffer, val) = match parse(buffer) {
+            Ok(parsed) => parsed,
+            Err(Error::Partial) => return None,
+            Err(err) => panic!("Invalid command: {:?}", err),
+        };
         let args = match val {
             Value::HashableValue(_) => todo!(),
             Value::NonHashableValue(non_hashable_value) => match non_hashable_value {
@@ -203,22 +2386,24 @@ impl RESPHandler {
             Value::Null => todo!(),
         };
 
-        let cmd = match str::from_utf8(blob).unwrap() {
-            CMD_HELLO => parse_hello_command(&args),
-            CMD_CLIENT => parse_client_command(&args),
-            CMD_SET => parse_set_command(&args),
-            CMD_GET => parse_get_command(&args),
-            CMD_CLUSTER => parse_cluster_command(&args),
-            CMD_COMMAND => parse_command_command(&args),
-            unsuported_cmd => panic!("Command not supported: {}", unsuported_cmd),
+        let name = str::from_utf8(blob).unwrap();
+        self.last_command = String::from(name);
+        self.last_spec = None;
+        self.last_keys.clear();
+        let cmd = match find_command(name) {
+            Some(spec) if spec.accepts(args.len()) => {
+                self.last_spec = Some(spec);
+                self.last_keys = spec.keys(&args);
+                (spec.parse)(&args)
+            }
+            Some(spec) => Command::Invalid(format!("wrong number of arguments for '{}' command", spec.name.to_lowercase())),
+            None => Command::Invalid(format!("unknown command '{}'", name)),
         };
 
         // println!("Command: {:?}", cmd);
-        let consummed_buffer_length = buffer.len() - remaining_buffer.len();
-        // println!("consommed buffer size: {}", consummed_buffer_length);
-        self.stream.consume(consummed_buffer_length);
+        self.position += buffer.len() - remaining_buffer.len();
 
-        Ok(cmd)
+        Some(cmd)
     }
 
     pub async fn decode_response<T: FromResp>(&mut self) -> Result<T, std::io::Error> {
@@ -240,3 +2425,116 @@ impl RESPHandler {
         res.unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_registry() {
+        for (i, spec) in COMMANDS.iter().enumerate() {
+            assert!(
+                COMMANDS[i + 1..].iter().all(|other| other.name != spec.name),
+                "{} is registered twice",
+                spec.name
+            );
+        }
+        let get = find_command("get").unwrap();
+        assert_eq!(get.name, CMD_GET);
+        assert!(get.accepts(2));
+        assert!(!get.accepts(3));
+        let del = find_command("DEL").unwrap();
+        assert!(!del.accepts(1));
+        assert!(del.accepts(4));
+        assert!(find_command("FOO").is_none());
+    }
+
+    fn parse_args(args: &[&str]) -> Command {
+        let args: Vec<Value> = args.iter().map(|arg| Value::HashableValue(HashableValue::Blob(arg.as_bytes()))).collect();
+        (find_command(args[0].try_as_str().unwrap()).unwrap().parse)(&args)
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let invalid = |args: &[&str]| match parse_args(args) {
+            Command::Invalid(message) => message,
+            command => panic!("{:?} is accepted: {:?}", args, command),
+        };
+        assert_eq!(invalid(&["SET", "k", "v", "FOO"]), "syntax error");
+        assert_eq!(invalid(&["SET", "k", "v", "EX"]), "syntax error");
+        assert_eq!(invalid(&["SET", "k", "v", "EX", "10", "KEEPTTL"]), "syntax error");
+        assert!(matches!(parse_args(&["SET", "k", "v", "PXAT", "10"]), Command::Set(set) if set.absolute_ttl && set.ttl_ms == Some(10)));
+        assert_eq!(invalid(&["SET", "k", "v", "EX", "0"]), "invalid expire time in 'set' command");
+        assert_eq!(invalid(&["SET", "k", "v", "EX", "-1"]), "invalid expire time in 'set' command");
+        assert_eq!(invalid(&["SETEX", "k", "abc", "v"]), "value is not an integer or out of range");
+        assert_eq!(invalid(&["EXPIRE", "k", "abc"]), "value is not an integer or out of range");
+
+        for seconds in ["-1", "NaN", "inf", "1e300", "abc"] {
+            assert_eq!(invalid(&["DEBUG", "SLEEP", seconds]), "value is not a valid float");
+        }
+        assert_eq!(invalid(&["DEBUG", "SLEEP"]), "wrong number of arguments for 'debug|sleep' command");
+        assert_eq!(invalid(&["DEBUG", "FOO"]), "unknown subcommand 'FOO'");
+
+        assert_eq!(invalid(&["CONFIG", "SET", "a"]), "wrong number of arguments for 'config|set' command");
+        assert_eq!(invalid(&["CONFIG", "GET"]), "wrong number of arguments for 'config|get' command");
+        assert_eq!(invalid(&["CONFIG", "FOO"]), "unknown subcommand 'FOO'");
+
+        assert_eq!(invalid(&["ZADD", "k", "1", "a", "2"]), "syntax error");
+        assert_eq!(invalid(&["ZADD", "k", "one", "a"]), "value is not a valid float");
+        assert_eq!(invalid(&["ZADD", "k", "nan", "a"]), "value is not a valid float");
+        assert_eq!(invalid(&["ZRANGE", "k", "0", "1", "REV"]), "syntax error");
+        assert_eq!(invalid(&["ZRANGE", "k", "a", "1"]), "value is not an integer or out of range");
+        assert_eq!(invalid(&["ZRANGE", "k", "a", "1", "BYSCORE"]), "min or max is not a float");
+
+        assert_eq!(invalid(&["RESTORE", "k", "-1", "payload"]), "Invalid TTL value, must be >= 0");
+        assert_eq!(invalid(&["RESTORE", "k", "abc", "payload"]), "value is not an integer or out of range");
+        assert_eq!(invalid(&["RESTORE", "k", "0", "payload", "IDLETIME"]), "syntax error");
+
+        assert_eq!(invalid(&["CLIENT", "FOO"]), "unknown subcommand 'FOO'");
+        assert_eq!(invalid(&["CLIENT", "SETNAME"]), "wrong number of arguments for 'client|setname' command");
+        assert_eq!(invalid(&["CLIENT", "KILL", "nowhere"]), "No such client");
+        assert_eq!(invalid(&["CLIENT", "KILL", "ID", "x"]), "client-id should be greater than 0");
+        assert_eq!(invalid(&["CLIENT", "KILL", "ID", "1", "ADDR"]), "syntax error");
+        assert_eq!(invalid(&["CLIENT", "KILL", "USER", "bob"]), "syntax error");
+
+        assert_eq!(invalid(&["FLUSHALL", "LATER"]), "syntax error");
+        assert_eq!(invalid(&["FLUSHDB", "ASYNC", "SYNC"]), "syntax error");
+
+        assert_eq!(invalid(&["OBJECT", "FOO", "k"]), "unknown subcommand 'FOO'");
+        assert_eq!(invalid(&["OBJECT", "FREQ"]), "wrong number of arguments for 'object|freq' command");
+
+        assert_eq!(invalid(&["XRANGE", "k", "-", "+", "COUNT"]), "syntax error");
+        assert_eq!(
+            invalid(&["XRANGE", "k", "-", "+", "COUNT", "x"]),
+            "value is not an integer or out of range"
+        );
+        assert_eq!(invalid(&["XRANGE", "k", "-", "+", "LIMIT", "1"]), "syntax error");
+        assert_eq!(invalid(&["XREAD", "COUNT", "1", "COUNT"]), "syntax error");
+        assert_eq!(
+            invalid(&["XREAD", "COUNT", "x", "STREAMS", "k", "0"]),
+            "value is not an integer or out of range"
+        );
+        assert_eq!(invalid(&["XREAD", "NOACK", "STREAMS", "k", "0"]), "syntax error");
+
+        let geosearch = |options: &[&str]| invalid(&[&["GEOSEARCH", "k", "FROMMEMBER", "m", "BYRADIUS", "1", "km"], options].concat());
+        assert_eq!(geosearch(&["COUNT"]), "syntax error");
+        assert_eq!(geosearch(&["COUNT", "x"]), "COUNT must be > 0");
+        assert_eq!(geosearch(&["FROMLONLAT", "x", "1"]), "value is not a valid float");
+        assert_eq!(geosearch(&["BYBOX", "1", "nan", "m"]), "value is not a valid float");
+        assert_eq!(geosearch(&["STORE"]), "syntax error");
+
+        assert_eq!(invalid(&["COMMAND", "GETKEYS", "GET", "k"]), "unknown subcommand 'GETKEYS'");
+
+        assert_eq!(invalid(&["GETRANGE", "k", "0", "x"]), "value is not an integer or out of range");
+        assert_eq!(invalid(&["SETRANGE", "k", "-1", "v"]), "offset is out of range");
+        assert_eq!(
+            invalid(&["SETRANGE", "k", &usize::MAX.to_string(), "v"]),
+            "string exceeds maximum allowed size (proto-max-bulk-len)"
+        );
+
+        assert_eq!(invalid(&["SCAN", "x"]), "invalid cursor");
+        assert_eq!(invalid(&["SCAN", "0", "COUNT"]), "syntax error");
+        assert_eq!(invalid(&["SCAN", "0", "COUNT", "x"]), "value is not an integer or out of range");
+        assert_eq!(invalid(&["SCAN", "0", "TYPE", "string"]), "syntax error");
+    }
+}
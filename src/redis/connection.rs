@@ -0,0 +1,288 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+    net::SocketAddr,
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use super::serde::ToResp;
+
+/// Unsolicited frame sent to a connection (e.g. a RESP3 push)
+pub type Push = Box<dyn ToResp>;
+
+/// Connection ids are unique across the reactors of the node
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+struct ConnectionInfo {
+    addr: SocketAddr,
+    name: Option<String>,
+    created: Instant,
+    last_interaction: Instant,
+    last_command: String,
+    kill: async_channel::Sender<()>,
+    push: async_channel::Sender<Push>,
+    tracking: Option<Tracking>,
+}
+
+/// Client side caching options of a connection (CLIENT TRACKING)
+#[derive(Debug, Clone, Copy)]
+struct Tracking {
+    no_loop: bool,
+}
+
+/// Push telling a client with tracking enabled to drop keys from its cache,
+/// None meaning every key (e.g. after FLUSHALL)
+pub struct Invalidation {
+    pub keys: Option<Vec<String>>,
+}
+
+/// Which connections CLIENT KILL applies to, every set field must match
+#[derive(Debug, Clone, Default)]
+pub struct KillFilter {
+    pub id: Option<u64>,
+    pub addr: Option<SocketAddr>,
+}
+
+/// Connections of the RESP server of a reactor
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    connections: RefCell<BTreeMap<u64, ConnectionInfo>>,
+    /// Connections that read each key since its last change, only
+    /// connections with tracking enabled are recorded. Closed connections are
+    /// removed when the key is invalidated
+    tracked_keys: RefCell<HashMap<String, BTreeSet<u64>>>,
+}
+
+/// Entry of a connection in the registry, removed when dropped
+pub struct Registration {
+    pub id: u64,
+    registry: Rc<ConnectionRegistry>,
+    /// Receives a value when the connection is killed
+    pub killed: async_channel::Receiver<()>,
+    /// Frames to write to the connection between replies
+    pub pushes: async_channel::Receiver<Push>,
+}
+
+impl Registration {
+    pub fn new(registry: Rc<ConnectionRegistry>, addr: SocketAddr) -> Registration {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let (kill, killed) = async_channel::bounded(1);
+        let (push, pushes) = async_channel::unbounded();
+        let now = Instant::now();
+        registry.connections.borrow_mut().insert(
+            id,
+            ConnectionInfo {
+                addr,
+                name: None,
+                created: now,
+                last_interaction: now,
+                last_command: String::from("NULL"),
+                kill,
+                push,
+                tracking: None,
+            },
+        );
+        Registration {
+            id,
+            registry,
+            killed,
+            pushes,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.borrow_mut().remove(&self.id);
+    }
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> ConnectionRegistry {
+        ConnectionRegistry::default()
+    }
+
+    /// Record a command received by the connection
+    pub fn touch(&self, id: u64, command: &str) {
+        if let Some(info) = self.connections.borrow_mut().get_mut(&id) {
+            info.last_interaction = Instant::now();
+            info.last_command = command.to_lowercase();
+        }
+    }
+
+    pub fn set_name(&self, id: u64, name: Option<String>) {
+        if let Some(info) = self.connections.borrow_mut().get_mut(&id) {
+            info.name = name;
+        }
+    }
+
+    pub fn name(&self, id: u64) -> Option<String> {
+        self.connections.borrow().get(&id).and_then(|info| info.name.clone())
+    }
+
+    /// One line per connection, in the CLIENT LIST format
+    pub fn list(&self) -> String {
+        let mut list = String::new();
+        for (id, info) in self.connections.borrow().iter() {
+            writeln!(
+                list,
+                "id={} addr={} name={} age={} idle={} cmd={}",
+                id,
+                info.addr,
+                info.name.as_deref().unwrap_or_default(),
+                info.created.elapsed().as_secs(),
+                info.last_interaction.elapsed().as_secs(),
+                info.last_command
+            )
+            .unwrap();
+        }
+        list
+    }
+
+    /// Send a frame to a connection, return false if it is closed
+    pub fn push(&self, id: u64, frame: Push) -> bool {
+        match self.connections.borrow().get(&id) {
+            Some(info) => info.push.try_send(frame).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn set_tracking(&self, id: u64, enabled: bool, no_loop: bool) {
+        if let Some(info) = self.connections.borrow_mut().get_mut(&id) {
+            info.tracking = enabled.then_some(Tracking { no_loop });
+        }
+    }
+
+    /// Remember that a connection read `keys`, if it has tracking enabled
+    pub fn track(&self, id: u64, keys: &[String]) {
+        if !self.connections.borrow().get(&id).is_some_and(|info| info.tracking.is_some()) {
+            return;
+        }
+        let mut tracked_keys = self.tracked_keys.borrow_mut();
+        for key in keys {
+            tracked_keys.entry(key.clone()).or_default().insert(id);
+        }
+    }
+
+    /// Send an invalidation to the connections that read `keys`, which were
+    /// modified by the connection `by`
+    pub fn invalidate(&self, keys: &[String], by: u64) {
+        let connections = self.connections.borrow();
+        let mut tracked_keys = self.tracked_keys.borrow_mut();
+        for key in keys {
+            let Some(ids) = tracked_keys.remove(key) else {
+                continue;
+            };
+            for id in ids {
+                let Some(info) = connections.get(&id) else {
+                    continue;
+                };
+                match info.tracking {
+                    Some(tracking) if tracking.no_loop && id == by => (),
+                    Some(_) => {
+                        let invalidation = Invalidation {
+                            keys: Some(vec![key.clone()]),
+                        };
+                        let _ = info.push.try_send(Box::new(invalidation));
+                    }
+                    None => (),
+                }
+            }
+        }
+    }
+
+    /// Tell every connection with tracking enabled to drop its whole cache
+    pub fn invalidate_all(&self) {
+        self.tracked_keys.borrow_mut().clear();
+        for info in self.connections.borrow().values().filter(|info| info.tracking.is_some()) {
+            let _ = info.push.try_send(Box::new(Invalidation { keys: None }));
+        }
+    }
+
+    /// Close the connections matching `filter`, return how many were killed
+    pub fn kill(&self, filter: &KillFilter) -> usize {
+        let connections = self.connections.borrow();
+        let matching = connections
+            .iter()
+            .filter(|(id, _)| filter.id.map_or(true, |filter_id| filter_id == **id))
+            .filter(|(_, info)| filter.addr.map_or(true, |addr| addr == info.addr));
+        let mut killed = 0;
+        for (_, info) in matching {
+            // The channel is full if the connection was already killed
+            let _ = info.kill.try_send(());
+            killed += 1;
+        }
+        killed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_registry() {
+        let registry = Rc::new(ConnectionRegistry::new());
+        let c1 = Registration::new(registry.clone(), "127.0.0.1:1000".parse().unwrap());
+        let c2 = Registration::new(registry.clone(), "127.0.0.1:2000".parse().unwrap());
+        registry.set_name(c1.id, Some(String::from("worker")));
+        registry.touch(c2.id, "GET");
+        assert_eq!(registry.name(c1.id).as_deref(), Some("worker"));
+        assert_eq!(registry.name(c2.id), None);
+
+        let list = registry.list();
+        assert!(list.contains(&format!("id={} addr=127.0.0.1:1000 name=worker age=0 idle=0 cmd=NULL\n", c1.id)));
+        assert!(list.contains(&format!("id={} addr=127.0.0.1:2000 name= age=0 idle=0 cmd=get\n", c2.id)));
+
+        let filter = KillFilter {
+            addr: Some("127.0.0.1:2000".parse().unwrap()),
+            ..KillFilter::default()
+        };
+        let message = crate::redis::pubsub::Message::Message {
+            channel: b"news".to_vec(),
+            payload: b"hello".to_vec(),
+        };
+        assert!(registry.push(c1.id, Box::new(message)));
+        assert!(c1.pushes.try_recv().is_ok());
+
+        assert_eq!(registry.kill(&filter), 1);
+        assert!(c2.killed.try_recv().is_ok());
+        assert!(c1.killed.try_recv().is_err());
+
+        drop(c2);
+        assert_eq!(registry.list().lines().count(), 1);
+    }
+
+    #[test]
+    fn test_tracking() {
+        let registry = Rc::new(ConnectionRegistry::new());
+        let reader = Registration::new(registry.clone(), "127.0.0.1:1000".parse().unwrap());
+        let writer = Registration::new(registry.clone(), "127.0.0.1:2000".parse().unwrap());
+        let keys = vec![String::from("foo")];
+
+        // Reads are only tracked once tracking is enabled
+        registry.track(reader.id, &keys);
+        registry.invalidate(&keys, writer.id);
+        assert!(reader.pushes.try_recv().is_err());
+
+        registry.set_tracking(reader.id, true, true);
+        registry.track(reader.id, &keys);
+        // No loop: its own writes are not sent back
+        registry.invalidate(&keys, reader.id);
+        assert!(reader.pushes.try_recv().is_err());
+
+        registry.track(reader.id, &keys);
+        registry.invalidate(&keys, writer.id);
+        assert!(reader.pushes.try_recv().is_ok());
+        // Invalidated keys must be read again to be tracked
+        registry.invalidate(&keys, writer.id);
+        assert!(reader.pushes.try_recv().is_err());
+
+        registry.invalidate_all();
+        assert!(reader.pushes.try_recv().is_ok());
+        assert!(writer.pushes.try_recv().is_err());
+    }
+}
@@ -1,5 +1,9 @@
 pub mod client;
 pub mod command;
+pub mod connection;
+pub mod pattern;
+pub mod pubsub;
 pub mod resp;
 pub mod serde;
 pub mod server;
+pub mod types;
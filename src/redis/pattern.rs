@@ -0,0 +1,123 @@
+/// Glob-style matching used by KEYS and SCAN MATCH, with the same rules as redis:
+/// `*` matches any sequence, `?` any character, `[abc]`, `[^abc]` and `[a-z]`
+/// match a class of characters and `\` escapes a special character.
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Position after the last `*` and of the first character it didn't absorb
+    let mut backtrack = None;
+    while s < string.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            backtrack = Some((p, s));
+            continue;
+        }
+        if p < pattern.len() {
+            if let Some(next) = match_token(pattern, p, string[s]) {
+                p = next;
+                s += 1;
+                continue;
+            }
+        }
+        match backtrack {
+            // Let the last `*` absorb one more character
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Characters every string matched by `pattern` starts with, the part before
+/// the first special character
+pub fn literal_prefix(pattern: &[u8]) -> Vec<u8> {
+    let mut prefix = vec![];
+    let mut p = 0;
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' | b'?' | b'[' => break,
+            b'\\' if p + 1 < pattern.len() => {
+                prefix.push(pattern[p + 1]);
+                p += 2;
+            }
+            c => {
+                prefix.push(c);
+                p += 1;
+            }
+        }
+    }
+    prefix
+}
+
+/// Match one character against the token starting at `p`, return the
+/// position of the next token
+fn match_token(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match pattern[p] {
+        b'?' => Some(p + 1),
+        b'[' => match_class(pattern, p + 1, c),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        token => (token == c).then_some(p + 1),
+    }
+}
+
+fn match_class(pattern: &[u8], mut p: usize, c: u8) -> Option<usize> {
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (low, high) = (pattern[p].min(pattern[p + 2]), pattern[p].max(pattern[p + 2]));
+            matched |= low <= c && c <= high;
+            p += 3;
+        } else {
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+    // Skip the closing bracket, an unterminated class ends with the pattern
+    (matched != negate).then_some((p + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(pattern: &str, string: &str) -> bool {
+        matches(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(m("*", ""));
+        assert!(m("*", "foo"));
+        assert!(m("foo", "foo"));
+        assert!(!m("foo", "foobar"));
+        assert!(m("foo*", "foobar"));
+        assert!(m("*bar", "foobar"));
+        assert!(m("f*o*r", "foobar"));
+        assert!(!m("f*o*z", "foobar"));
+        assert!(m("h?llo", "hello"));
+        assert!(!m("h?llo", "hllo"));
+        assert!(m("h[ae]llo", "hallo"));
+        assert!(!m("h[ae]llo", "hillo"));
+        assert!(m("h[^e]llo", "hallo"));
+        assert!(!m("h[^e]llo", "hello"));
+        assert!(m("h[a-c]llo", "hbllo"));
+        assert!(!m("h[a-c]llo", "hdllo"));
+        assert!(m("user:\\*", "user:*"));
+        assert!(!m("user:\\*", "user:1"));
+        assert!(m("user:[\\]]", "user:]"));
+
+        assert_eq!(literal_prefix(b"user:*"), b"user:");
+        assert_eq!(literal_prefix(b"user:\\*:[ab]"), b"user:*:");
+        assert_eq!(literal_prefix(b"*:1"), b"");
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, HashMap},
+    rc::Rc,
+};
+
+use crate::cluster::MeshMessage;
+
+use super::pattern;
+
+/// Message pushed to a subscribed connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Message { channel: Vec<u8>, payload: Vec<u8> },
+    PMessage { pattern: Vec<u8>, channel: Vec<u8>, payload: Vec<u8> },
+}
+
+/// Message published on another reactor of the node, `receivers` gets the
+/// number of local subscribers it was delivered to
+#[derive(Debug)]
+pub struct PublishMessage {
+    pub channel: Vec<u8>,
+    pub payload: Vec<u8>,
+    pub receivers: async_channel::Sender<usize>,
+}
+
+type Subscribers = HashMap<Vec<u8>, HashMap<u64, async_channel::Sender<Message>>>;
+
+/// Publish/subscribe broker of a reactor. Subscribers only register on the
+/// reactor handling their connection, publications are forwarded to the
+/// other reactors of the node over the mesh.
+pub struct Broker {
+    /// Mesh channels of the other reactors of the node
+    peers: Vec<async_channel::Sender<MeshMessage>>,
+    channels: RefCell<Subscribers>,
+    patterns: RefCell<Subscribers>,
+    next_id: Cell<u64>,
+}
+
+/// Subscriptions of a connection, removed from the broker when dropped (the
+/// connection ended or its task panicked)
+pub struct Subscriber {
+    id: u64,
+    broker: Rc<Broker>,
+    sender: async_channel::Sender<Message>,
+    receiver: async_channel::Receiver<Message>,
+    channels: BTreeSet<Vec<u8>>,
+    patterns: BTreeSet<Vec<u8>>,
+}
+
+impl Subscriber {
+    /// Number of channels and patterns subscribed to
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.count() > 0
+    }
+
+    pub fn channels(&self) -> Vec<Vec<u8>> {
+        self.channels.iter().cloned().collect()
+    }
+
+    pub fn patterns(&self) -> Vec<Vec<u8>> {
+        self.patterns.iter().cloned().collect()
+    }
+
+    pub async fn recv(&self) -> Message {
+        // The subscriber holds a sender so the channel is never closed
+        self.receiver.recv().await.unwrap()
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let broker = self.broker.clone();
+        broker.unsubscribe_all(self);
+    }
+}
+
+fn add(subscribers: &RefCell<Subscribers>, name: &[u8], subscriber: &Subscriber) {
+    subscribers
+        .borrow_mut()
+        .entry(name.to_vec())
+        .or_default()
+        .insert(subscriber.id, subscriber.sender.clone());
+}
+
+fn remove(subscribers: &RefCell<Subscribers>, name: &[u8], subscriber: &Subscriber) {
+    let mut subscribers = subscribers.borrow_mut();
+    if let Some(senders) = subscribers.get_mut(name) {
+        senders.remove(&subscriber.id);
+        if senders.is_empty() {
+            subscribers.remove(name);
+        }
+    }
+}
+
+impl Broker {
+    pub fn new(peers: Vec<async_channel::Sender<MeshMessage>>) -> Broker {
+        Broker {
+            peers,
+            channels: RefCell::new(HashMap::new()),
+            patterns: RefCell::new(HashMap::new()),
+            next_id: Cell::new(0),
+        }
+    }
+
+    pub fn new_subscriber(self: &Rc<Self>) -> Subscriber {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let (sender, receiver) = async_channel::unbounded();
+        Subscriber {
+            id,
+            broker: self.clone(),
+            sender,
+            receiver,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+        }
+    }
+
+    pub fn subscribe(&self, subscriber: &mut Subscriber, channel: &[u8]) {
+        if subscriber.channels.insert(channel.to_vec()) {
+            add(&self.channels, channel, subscriber);
+        }
+    }
+
+    pub fn unsubscribe(&self, subscriber: &mut Subscriber, channel: &[u8]) {
+        if subscriber.channels.remove(channel) {
+            remove(&self.channels, channel, subscriber);
+        }
+    }
+
+    pub fn psubscribe(&self, subscriber: &mut Subscriber, pattern: &[u8]) {
+        if subscriber.patterns.insert(pattern.to_vec()) {
+            add(&self.patterns, pattern, subscriber);
+        }
+    }
+
+    pub fn punsubscribe(&self, subscriber: &mut Subscriber, pattern: &[u8]) {
+        if subscriber.patterns.remove(pattern) {
+            remove(&self.patterns, pattern, subscriber);
+        }
+    }
+
+    /// Remove all the subscriptions, done when the subscriber is dropped
+    pub fn unsubscribe_all(&self, subscriber: &mut Subscriber) {
+        for channel in subscriber.channels() {
+            self.unsubscribe(subscriber, &channel);
+        }
+        for pattern in subscriber.patterns() {
+            self.punsubscribe(subscriber, &pattern);
+        }
+    }
+
+    /// Deliver a message to the subscribers of this reactor, return the number
+    /// of subscribers that received it
+    pub fn deliver(&self, channel: &[u8], payload: &[u8]) -> usize {
+        let mut receivers = 0;
+        if let Some(senders) = self.channels.borrow().get(channel) {
+            for sender in senders.values() {
+                let message = Message::Message {
+                    channel: channel.to_vec(),
+                    payload: payload.to_vec(),
+                };
+                receivers += sender.try_send(message).is_ok() as usize;
+            }
+        }
+        for (pattern, senders) in self.patterns.borrow().iter() {
+            if !pattern::matches(pattern, channel) {
+                continue;
+            }
+            for sender in senders.values() {
+                let message = Message::PMessage {
+                    pattern: pattern.clone(),
+                    channel: channel.to_vec(),
+                    payload: payload.to_vec(),
+                };
+                receivers += sender.try_send(message).is_ok() as usize;
+            }
+        }
+        receivers
+    }
+
+    /// Deliver a message to the subscribers of every reactor of the node,
+    /// return the number of subscribers that received it
+    pub async fn publish(&self, channel: &[u8], payload: &[u8]) -> usize {
+        let mut receivers = self.deliver(channel, payload);
+        let (sender, receiver) = async_channel::bounded(self.peers.len().max(1));
+        for peer in &self.peers {
+            let msg = MeshMessage::Publish(PublishMessage {
+                channel: channel.to_vec(),
+                payload: payload.to_vec(),
+                receivers: sender.clone(),
+            });
+            peer.send(msg).await.unwrap();
+        }
+        for _ in &self.peers {
+            receivers += receiver.recv().await.unwrap();
+        }
+        receivers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_broker_delivery() {
+        let broker = Rc::new(Broker::new(vec![]));
+        let mut s1 = broker.new_subscriber();
+        let mut s2 = broker.new_subscriber();
+        broker.subscribe(&mut s1, b"news");
+        broker.subscribe(&mut s1, b"news");
+        broker.psubscribe(&mut s2, b"n*");
+        assert_eq!(s1.count(), 1);
+
+        assert_eq!(broker.deliver(b"news", b"hello"), 2);
+        assert_eq!(
+            s1.receiver.try_recv().unwrap(),
+            Message::Message {
+                channel: b"news".to_vec(),
+                payload: b"hello".to_vec()
+            }
+        );
+        assert_eq!(
+            s2.receiver.try_recv().unwrap(),
+            Message::PMessage {
+                pattern: b"n*".to_vec(),
+                channel: b"news".to_vec(),
+                payload: b"hello".to_vec()
+            }
+        );
+        assert_eq!(broker.deliver(b"other", b"hello"), 0);
+
+        broker.unsubscribe_all(&mut s1);
+        assert!(!s1.is_subscribed());
+        assert_eq!(broker.deliver(b"news", b"hello"), 1);
+        assert!(broker.channels.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_broker_dropped_subscriber() {
+        let broker = Rc::new(Broker::new(vec![]));
+        let mut subscriber = broker.new_subscriber();
+        broker.subscribe(&mut subscriber, b"news");
+        broker.psubscribe(&mut subscriber, b"n*");
+        assert_eq!(broker.deliver(b"news", b"hello"), 2);
+
+        // E.g. its connection task panicked
+        drop(subscriber);
+        assert_eq!(broker.deliver(b"news", b"hello"), 0);
+        assert!(broker.channels.borrow().is_empty());
+        assert!(broker.patterns.borrow().is_empty());
+    }
+}
@@ -2,65 +2,13 @@ use core::str;
 
 #[macro_use]
 mod macros;
+pub mod writer;
 
 use std::{borrow::Cow, cmp::Ordering, collections::HashMap};
 
-const SEPARATOR: &[u8] = "\r\n".as_bytes();
+use writer::{Protocol, RespWriter};
 
-pub fn redis_hashable_value_to_bytes(value: &HashableValue, buffer: &mut Vec<u8>) {
-    match value {
-        HashableValue::Blob(blob) => {
-            buffer.push(b'$');
-            buffer.extend_from_slice(blob.len().to_string().as_bytes());
-            buffer.extend_from_slice(SEPARATOR);
-            buffer.extend_from_slice(blob);
-            buffer.extend_from_slice(SEPARATOR);
-        }
-        HashableValue::String(cow) => {
-            buffer.push(b'+');
-            buffer.extend_from_slice(cow.as_bytes());
-            buffer.extend_from_slice(SEPARATOR);
-        }
-        HashableValue::Error(prefix, msg) => {
-            buffer.push(b'-');
-            buffer.extend_from_slice(prefix.as_bytes());
-            buffer.push(b'-');
-            buffer.extend_from_slice(msg.as_bytes());
-            buffer.extend_from_slice(SEPARATOR);
-        }
-        HashableValue::Integer(i) => {
-            buffer.push(b':');
-            buffer.extend_from_slice(format!("{i}").as_bytes());
-            buffer.extend_from_slice(SEPARATOR);
-        }
-        HashableValue::Boolean(_) => todo!(),
-        HashableValue::BigInteger(_) => todo!(),
-    }
-}
-
-pub fn redis_non_hashable_value_to_bytes(value: &NonHashableValue, buffer: &mut Vec<u8>) {
-    match value {
-        NonHashableValue::Array(vec) => {
-            buffer.push(b'*');
-            // TODO: hopefully this doesn't create an actual string
-            buffer.extend_from_slice(vec.len().to_string().as_bytes());
-            buffer.extend_from_slice(SEPARATOR);
-            vec.iter().for_each(|val| val.write_bytes(buffer));
-            // buffer.extend_from_slice(SEPARATOR);
-        }
-        NonHashableValue::Float(_) => todo!(),
-        NonHashableValue::Map(map) => {
-            buffer.push(b'%');
-            // TODO: hopefully this doesn't create an actual string
-            buffer.extend_from_slice(map.len().to_string().as_bytes());
-            buffer.extend_from_slice(SEPARATOR);
-            map.iter().for_each(|(key, val)| {
-                redis_hashable_value_to_bytes(key, buffer);
-                val.write_bytes(buffer)
-            });
-        }
-    }
-}
+const SEPARATOR: &[u8] = "\r\n".as_bytes();
 
 /// Redis Value.
 #[derive(Debug, Clone)]
@@ -89,21 +37,19 @@ impl<'a> Value<'a> {
         }
     }
 
-    fn write_bytes(&self, buffer: &mut Vec<u8>) {
+    /// Like `try_as_str` for binary data (e.g. DUMP payloads)
+    pub fn try_as_bytes(&self) -> Option<&'a [u8]> {
         match self {
-            Value::HashableValue(hashable_value) => redis_hashable_value_to_bytes(hashable_value, buffer),
-            Value::NonHashableValue(non_hashable_value) => redis_non_hashable_value_to_bytes(non_hashable_value, buffer),
-            Value::Null => {
-                buffer.push(b'_');
-                buffer.extend_from_slice(SEPARATOR);
-            }
-        };
+            Value::HashableValue(HashableValue::Blob(blob)) => Some(blob),
+            _ => todo!(),
+        }
     }
 
+    /// Serialize the value using RESP3
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut resp_bytes = vec![];
-        self.write_bytes(&mut resp_bytes);
-        resp_bytes
+        let mut writer = RespWriter::new(Protocol::Resp3);
+        writer.write_value(self);
+        writer.into_bytes()
     }
 }
 
@@ -116,6 +62,8 @@ pub enum NonHashableValue<'a> {
     Float(f64),
     /// Map
     Map(HashMap<HashableValue<'a>, Value<'a>>),
+    /// Out of band data sent by the server (RESP3), an array with RESP2
+    Push(Vec<Value<'a>>),
 }
 
 /// Redis Value.
@@ -172,6 +120,7 @@ pub fn parse(bytes: &[u8]) -> Result<(&[u8], Value), Error> {
         b'+' => parse_str(bytes),
         b'-' => parse_error(bytes),
         b'%' => parse_map(bytes),
+        b'>' => parse_push(bytes),
         _ => Err(Error::InvalidPrefix),
     };
     var_name
@@ -260,6 +209,13 @@ fn parse_map(bytes: &[u8]) -> Result<(&[u8], Value), Error> {
     return Ok((bytes, Value::NonHashableValue(NonHashableValue::Map(v))));
 }
 
+fn parse_push(bytes: &[u8]) -> Result<(&[u8], Value), Error> {
+    match parse_array(bytes)? {
+        (bytes, Value::NonHashableValue(NonHashableValue::Array(v))) => ret!(bytes, Value::NonHashableValue(NonHashableValue::Push(v))),
+        (bytes, value) => ret!(bytes, value),
+    }
+}
+
 fn parse_array(bytes: &[u8]) -> Result<(&[u8], Value), Error> {
     let (bytes, len) = read_line_number!(bytes, i32);
     if len <= 0 {
@@ -0,0 +1,270 @@
+use std::io::Write;
+
+use super::{HashableValue, NonHashableValue, Value, SEPARATOR};
+
+/// Version of the protocol negotiated with the client (using HELLO)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+/// Incremental RESP serializer writing directly into a byte buffer.
+///
+/// Aggregates are written by first writing their header (e.g. `write_array_header`)
+/// followed by their elements. RESP3 only types are downgraded to their
+/// RESP2 equivalent when the connection uses RESP2.
+pub struct RespWriter {
+    buffer: Vec<u8>,
+    protocol: Protocol,
+}
+
+impl RespWriter {
+    pub fn new(protocol: Protocol) -> RespWriter {
+        RespWriter::with_capacity(protocol, 64)
+    }
+
+    pub fn with_capacity(protocol: Protocol, capacity: usize) -> RespWriter {
+        RespWriter {
+            buffer: Vec::with_capacity(capacity),
+            protocol,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Number of bytes waiting to be sent
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Return the serialized bytes and leave the writer empty
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    fn write_number<T: std::fmt::Display>(&mut self, prefix: u8, n: T) {
+        self.buffer.push(prefix);
+        // Writing to a Vec cannot fail
+        write!(self.buffer, "{}", n).unwrap();
+        self.buffer.extend_from_slice(SEPARATOR);
+    }
+
+    pub fn write_simple_string(&mut self, s: &str) {
+        self.buffer.push(b'+');
+        self.buffer.extend_from_slice(s.as_bytes());
+        self.buffer.extend_from_slice(SEPARATOR);
+    }
+
+    /// Write an error, `prefix` being the error code (e.g. `ERR`, `WRONGTYPE`)
+    pub fn write_error(&mut self, prefix: &str, msg: &str) {
+        self.buffer.push(b'-');
+        self.buffer.extend_from_slice(prefix.as_bytes());
+        self.buffer.push(b' ');
+        self.buffer.extend_from_slice(msg.as_bytes());
+        self.buffer.extend_from_slice(SEPARATOR);
+    }
+
+    pub fn write_int(&mut self, i: i64) {
+        self.write_number(b':', i);
+    }
+
+    pub fn write_big_int(&mut self, i: i128) {
+        match self.protocol {
+            Protocol::Resp2 => self.write_bulk(i.to_string().as_bytes()),
+            Protocol::Resp3 => self.write_number(b'(', i),
+        }
+    }
+
+    pub fn write_bulk(&mut self, blob: &[u8]) {
+        self.write_number(b'$', blob.len());
+        self.buffer.extend_from_slice(blob);
+        self.buffer.extend_from_slice(SEPARATOR);
+    }
+
+    pub fn write_null(&mut self) {
+        match self.protocol {
+            Protocol::Resp2 => self.buffer.extend_from_slice(b"$-1\r\n"),
+            Protocol::Resp3 => self.buffer.extend_from_slice(b"_\r\n"),
+        }
+    }
+
+    pub fn write_bool(&mut self, b: bool) {
+        match self.protocol {
+            Protocol::Resp2 => self.write_int(b as i64),
+            Protocol::Resp3 => self.buffer.extend_from_slice(if b { b"#t\r\n" } else { b"#f\r\n" }),
+        }
+    }
+
+    pub fn write_double(&mut self, f: f64) {
+        match self.protocol {
+            Protocol::Resp2 => self.write_bulk(f.to_string().as_bytes()),
+            Protocol::Resp3 => self.write_number(b',', f),
+        }
+    }
+
+    /// Must be followed by `len` values
+    pub fn write_array_header(&mut self, len: usize) {
+        self.write_number(b'*', len);
+    }
+
+    /// Must be followed by `len` values. With RESP2 it is sent as an array
+    pub fn write_set_header(&mut self, len: usize) {
+        match self.protocol {
+            Protocol::Resp2 => self.write_number(b'*', len),
+            Protocol::Resp3 => self.write_number(b'~', len),
+        }
+    }
+
+    /// Out of band data (e.g. pub/sub messages), must be followed by `len`
+    /// values. With RESP2 it is sent as an array
+    pub fn write_push_header(&mut self, len: usize) {
+        match self.protocol {
+            Protocol::Resp2 => self.write_number(b'*', len),
+            Protocol::Resp3 => self.write_number(b'>', len),
+        }
+    }
+
+    /// Must be followed by `len` key/value pairs. With RESP2 it is sent as a
+    /// flat array of `2 * len` elements
+    pub fn write_map_header(&mut self, len: usize) {
+        match self.protocol {
+            Protocol::Resp2 => self.write_number(b'*', len * 2),
+            Protocol::Resp3 => self.write_number(b'%', len),
+        }
+    }
+
+    pub fn write_hashable_value(&mut self, value: &HashableValue) {
+        match value {
+            HashableValue::Blob(blob) => self.write_bulk(blob),
+            HashableValue::String(s) => self.write_simple_string(s),
+            HashableValue::Error(prefix, msg) => self.write_error(prefix, msg),
+            HashableValue::Integer(i) => self.write_int(*i),
+            HashableValue::BigInteger(i) => self.write_big_int(*i),
+            HashableValue::Boolean(b) => self.write_bool(*b),
+        }
+    }
+
+    /// Write a whole value tree
+    pub fn write_value(&mut self, value: &Value) {
+        match value {
+            Value::HashableValue(hashable_value) => self.write_hashable_value(hashable_value),
+            Value::NonHashableValue(non_hashable_value) => match non_hashable_value {
+                NonHashableValue::Array(vec) => {
+                    self.write_array_header(vec.len());
+                    vec.iter().for_each(|val| self.write_value(val));
+                }
+                NonHashableValue::Push(vec) => {
+                    self.write_push_header(vec.len());
+                    vec.iter().for_each(|val| self.write_value(val));
+                }
+                NonHashableValue::Float(f) => self.write_double(*f),
+                NonHashableValue::Map(map) => {
+                    self.write_map_header(map.len());
+                    map.iter().for_each(|(key, val)| {
+                        self.write_hashable_value(key);
+                        self.write_value(val)
+                    });
+                }
+            },
+            Value::Null => self.write_null(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, collections::HashMap};
+
+    use super::*;
+
+    fn assert_bytes(writer: RespWriter, expected: &str) {
+        assert_eq!(std::str::from_utf8(&writer.into_bytes()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_write_scalars() {
+        for protocol in [Protocol::Resp2, Protocol::Resp3] {
+            let mut w = RespWriter::new(protocol);
+            w.write_simple_string("OK");
+            w.write_error("ERR", "unknown command");
+            w.write_int(-42);
+            w.write_bulk(b"foo\r\nbar");
+            w.write_bulk(b"");
+            assert_bytes(w, "+OK\r\n-ERR unknown command\r\n:-42\r\n$8\r\nfoo\r\nbar\r\n$0\r\n\r\n");
+        }
+    }
+
+    #[test]
+    fn test_write_resp2_downgrades() {
+        let mut w = RespWriter::new(Protocol::Resp2);
+        w.write_null();
+        w.write_bool(true);
+        w.write_double(1.5);
+        w.write_big_int(12345678901234567890);
+        w.write_map_header(1);
+        w.write_bulk(b"a");
+        w.write_int(1);
+        assert_bytes(w, "$-1\r\n:1\r\n$3\r\n1.5\r\n$20\r\n12345678901234567890\r\n*2\r\n$1\r\na\r\n:1\r\n");
+    }
+
+    #[test]
+    fn test_write_resp3() {
+        let mut w = RespWriter::new(Protocol::Resp3);
+        w.write_null();
+        w.write_bool(false);
+        w.write_double(1.5);
+        w.write_big_int(12345678901234567890);
+        w.write_map_header(1);
+        w.write_bulk(b"a");
+        w.write_int(1);
+        assert_bytes(w, "_\r\n#f\r\n,1.5\r\n(12345678901234567890\r\n%1\r\n$1\r\na\r\n:1\r\n");
+    }
+
+    #[test]
+    fn test_write_value() {
+        let value = Value::NonHashableValue(NonHashableValue::Array(vec![
+            Value::HashableValue(HashableValue::String(Cow::from("SET"))),
+            Value::HashableValue(HashableValue::Integer(3)),
+            Value::NonHashableValue(NonHashableValue::Map(HashMap::from([(HashableValue::Blob(b"k"), Value::Null)]))),
+        ]));
+        let mut w = RespWriter::new(Protocol::Resp3);
+        w.write_value(&value);
+        assert_bytes(w, "*3\r\n+SET\r\n:3\r\n%1\r\n$1\r\nk\r\n_\r\n");
+    }
+
+    #[test]
+    fn test_write_push() {
+        let (_, value) = crate::redis::resp::parse(b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nfoo\r\n").unwrap();
+        let mut w = RespWriter::new(Protocol::Resp3);
+        w.write_value(&value);
+        assert_bytes(w, ">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nfoo\r\n");
+        let mut w = RespWriter::new(Protocol::Resp2);
+        w.write_value(&value);
+        assert_bytes(w, "*2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nfoo\r\n");
+    }
+
+    #[test]
+    fn test_take_resets_buffer() {
+        let mut w = RespWriter::new(Protocol::Resp3);
+        w.write_int(1);
+        assert_eq!(w.take(), b":1\r\n");
+        assert!(w.is_empty());
+        w.write_int(2);
+        assert_eq!(w.take(), b":2\r\n");
+    }
+}
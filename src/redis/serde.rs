@@ -1,7 +1,7 @@
 use std::{borrow::Cow, collections::HashMap};
 
 use crate::{
-    redis::resp::NonHashableValue,
+    redis::{connection, pubsub, resp::NonHashableValue},
     topology::{ReactorMetadata, ShardRange, Topology},
 };
 
@@ -15,6 +15,30 @@ pub trait FromResp {
     fn from_resp(value: &Value) -> Self;
 }
 
+fn bulk(blob: &[u8]) -> Value {
+    Value::HashableValue(HashableValue::Blob(blob))
+}
+
+impl ToResp for pubsub::Message {
+    fn to_resp(&self) -> Value {
+        let values = match self {
+            pubsub::Message::Message { channel, payload } => vec![bulk(b"message"), bulk(channel), bulk(payload)],
+            pubsub::Message::PMessage { pattern, channel, payload } => vec![bulk(b"pmessage"), bulk(pattern), bulk(channel), bulk(payload)],
+        };
+        Value::NonHashableValue(NonHashableValue::Push(values))
+    }
+}
+
+impl ToResp for connection::Invalidation {
+    fn to_resp(&self) -> Value {
+        let keys = match &self.keys {
+            Some(keys) => Value::NonHashableValue(NonHashableValue::Array(keys.iter().map(|key| bulk(key.as_bytes())).collect())),
+            None => Value::Null,
+        };
+        Value::NonHashableValue(NonHashableValue::Push(vec![bulk(b"invalidate"), keys]))
+    }
+}
+
 impl ToResp for ReactorMetadata {
     fn to_resp(&self) -> Value {
         let mut map = HashMap::with_capacity(4);
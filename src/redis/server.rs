@@ -1,75 +1,1285 @@
-use std::{borrow::Cow, collections::HashMap, rc::Rc, vec};
+use std::{net::SocketAddr, pin::pin, rc::Rc};
 
+use futures::future::{join_all, pending, select, Either};
 use monoio::{io::BufReader, net::TcpListener};
 
 use crate::{
-    api,
+    api, config,
+    datastore::{expiry::Ttl, metrics, Stats},
+    reactor::supervisor,
+    record::{Key, ValueType},
     redis::{
-        command::{ClientCmd, Command, RESPHandler},
-        resp::{HashableValue, NonHashableValue, Value},
+        command::{
+            find_command, ClientCmd, ClusterCmd, Command, CommandCmd, CommandSpec, ConfigCmd, DebugCmd, GeoOrigin, GeoSearchCmd, ObjectSubCmd,
+            PubSubCmd, RESPHandler, SaveCmd, ZRangeBy, COMMANDS,
+        },
+        connection::{ConnectionRegistry, Push, Registration},
+        pattern,
+        pubsub::{Broker, Subscriber},
+        resp::writer::{Protocol, RespWriter},
+        types::{
+            dump, geo,
+            hash::Hash,
+            hyperloglog::HyperLogLog,
+            list::List,
+            set::Set,
+            stream::{Entry, Stream, StreamId},
+            zset::ZSet,
+        },
     },
     storageproxy::StorageProxy,
-    topology::Topology,
+    topology::{ReactorMetadata, Topology},
 };
 
 use super::serde::ToResp;
 
 // Serve the Redis serialization protocol (RESP)
 pub struct RESPServer {
-    pub host_port: String,
+    pub addrs: Vec<SocketAddr>,
     pub storage_proxy: Rc<StorageProxy>,
+    pub broker: Rc<Broker>,
+    pub connections: Rc<ConnectionRegistry>,
 }
 
-// Return a redis compatible topology
-fn cluster_shards_response(topology: &Topology) -> Value {
-    let shards = topology
-        .reactor_allocations
-        .iter()
-        .flat_map(|(reactor, ranges)| {
-            let resp_ranges: Vec<Value> = ranges
-                .iter()
-                .map(|range| {
-                    Value::NonHashableValue(NonHashableValue::Array(vec![
-                        // Range start
-                        Value::HashableValue(HashableValue::Integer(range.start as i64)),
-                        // Range end
-                        Value::HashableValue(HashableValue::Integer(range.end as i64)),
-                        // Primary node
-                        Value::NonHashableValue(NonHashableValue::Array(vec![
-                            // TODO fix this :'(
-                            // Cannot borrow reactor data as it create temporaty value
-                            // Value::HashableValue(HashableValue::Blob(reactor.ip.to_string().clone().as_bytes())),
-                            Value::HashableValue(HashableValue::Blob("127.0.0.1".as_bytes())),
-                            Value::HashableValue(HashableValue::Integer(reactor.port as i64)),
-                            Value::HashableValue(HashableValue::String(Cow::from(format!("{}", range.start)))),
-                            Value::NonHashableValue(NonHashableValue::Array(vec![
-                                Value::HashableValue(HashableValue::String(Cow::from("hostname"))),
-                                Value::HashableValue(HashableValue::String(Cow::from(reactor.ip.to_string()))),
-                            ])),
-                        ])),
-                    ]))
-                })
-                .collect();
-            resp_ranges
-        })
+/// Replies bigger than this are sent in several writes
+const STREAMING_CHUNK_SIZE: usize = 64 * 1024;
+
+// Write a redis compatible topology
+fn write_cluster_slots(w: &mut RespWriter, topology: &Topology) {
+    let ranges_count = topology.reactor_allocations.values().map(|ranges| ranges.len()).sum();
+    w.write_array_header(ranges_count);
+    for (reactor, ranges) in &topology.reactor_allocations {
+        let ip = reactor.ip.to_string();
+        for range in ranges {
+            w.write_array_header(3);
+            // Range start
+            w.write_int(range.start as i64);
+            // Range end
+            w.write_int(range.end as i64);
+            // Primary node
+            w.write_array_header(4);
+            // Advertised address of the reactor
+            w.write_bulk(ip.as_bytes());
+            w.write_int(reactor.port as i64);
+            w.write_bulk(reactor.cluster_node_id().as_bytes());
+            w.write_array_header(2);
+            w.write_simple_string("hostname");
+            w.write_simple_string(&ip);
+        }
+    }
+}
+
+// Write the CLUSTER NODES table, one line per reactor:
+// <id> <ip:port@cport> <flags> <master> <ping-sent> <pong-recv> <config-epoch> <link-state> <slot ranges...>
+fn write_cluster_nodes(w: &mut RespWriter, topology: &Topology, myself: &ReactorMetadata) {
+    let mut reactors: Vec<_> = topology.reactor_allocations.iter().collect();
+    reactors.sort_by_key(|(reactor, _)| (reactor.node_id, reactor.id));
+    let mut nodes = String::new();
+    for (reactor, ranges) in reactors {
+        let flags = match reactor == myself {
+            true => "myself,master",
+            false => "master",
+        };
+        // There is no cluster bus, the conventional port is advertised
+        let address = SocketAddr::new(reactor.ip, reactor.port);
+        nodes.push_str(&format!(
+            "{} {}@{} {} - 0 0 1 connected",
+            reactor.cluster_node_id(),
+            address,
+            reactor.port as u32 + 10000,
+            flags
+        ));
+        let mut ranges = ranges.clone();
+        ranges.sort_by_key(|range| range.start);
+        for range in ranges {
+            match range.start == range.end {
+                true => nodes.push_str(&format!(" {}", range.start)),
+                false => nodes.push_str(&format!(" {}-{}", range.start, range.end)),
+            }
+        }
+        nodes.push('\n');
+    }
+    w.write_bulk(nodes.as_bytes());
+}
+
+fn write_wrong_type(w: &mut RespWriter) {
+    w.write_error("WRONGTYPE", api::WRONG_TYPE_MESSAGE);
+}
+
+fn write_cross_slot(w: &mut RespWriter) {
+    w.write_error("CROSSSLOT", api::CROSS_SLOT_MESSAGE);
+}
+
+// Between a CLUSTER RESET and the broadcast of the new topology
+fn write_cluster_down(w: &mut RespWriter) {
+    w.write_error("CLUSTERDOWN", "The cluster topology is not known yet");
+}
+
+/// Confirmation of a (un)subscription, `name` is None when unsubscribing
+/// without any subscription
+fn write_subscription(w: &mut RespWriter, kind: &str, name: Option<&[u8]>, count: usize) {
+    w.write_push_header(3);
+    w.write_bulk(kind.as_bytes());
+    match name {
+        Some(name) => w.write_bulk(name),
+        None => w.write_null(),
+    }
+    w.write_int(count as i64);
+}
+
+/// Client side caching: remember the keys about to be read by a connection
+/// with tracking enabled, so a write racing with the read still invalidates
+fn track_read_keys(handler: &RESPHandler, connections: &ConnectionRegistry, id: u64) {
+    if handler.last_spec.is_some_and(|spec| spec.is_read()) {
+        connections.track(id, &handler.last_keys);
+    }
+}
+
+/// Client side caching: invalidate the keys written by a command. Writes
+/// without keys (e.g. FLUSHALL) invalidate everything
+fn invalidate_written_keys(handler: &RESPHandler, connections: &ConnectionRegistry, id: u64) {
+    match handler.last_spec {
+        Some(spec) if spec.is_write() && spec.first_key == 0 => connections.invalidate_all(),
+        Some(spec) if spec.is_write() => connections.invalidate(&handler.last_keys, id),
+        _ => (),
+    }
+}
+
+fn handle_client_command(client_cmd: ClientCmd, connections: &ConnectionRegistry, id: u64, w: &mut RespWriter) {
+    match client_cmd {
+        ClientCmd::SetInfo(_) => w.write_simple_string("OK"),
+        ClientCmd::Id => w.write_int(id as i64),
+        ClientCmd::List => w.write_bulk(connections.list().as_bytes()),
+        ClientCmd::SetName(name) => {
+            if name.chars().any(|c| !c.is_ascii_graphic()) {
+                w.write_error("ERR", "Client names cannot contain spaces, newlines or special characters.");
+                return;
+            }
+            // An empty name removes it
+            connections.set_name(id, Some(name).filter(|name| !name.is_empty()));
+            w.write_simple_string("OK");
+        }
+        ClientCmd::GetName => match connections.name(id) {
+            Some(name) => w.write_bulk(name.as_bytes()),
+            None => w.write_null(),
+        },
+        // Invalidations are pushes, there is no RESP2 redirection
+        ClientCmd::Tracking(_) if w.protocol() == Protocol::Resp2 => w.write_error("ERR", "client tracking requires RESP3, switch with HELLO 3"),
+        ClientCmd::Tracking(tracking_cmd) => {
+            connections.set_tracking(id, tracking_cmd.enabled, tracking_cmd.no_loop);
+            w.write_simple_string("OK");
+        }
+        ClientCmd::Kill(kill_cmd) => {
+            let killed = connections.kill(&kill_cmd.filter);
+            match kill_cmd.legacy {
+                true if killed == 0 => w.write_error("ERR", "No such client"),
+                true => w.write_simple_string("OK"),
+                false => w.write_int(killed as i64),
+            }
+        }
+    }
+}
+
+async fn handle_pubsub_command(pubsub_cmd: PubSubCmd, broker: &Broker, subscriber: &mut Subscriber, w: &mut RespWriter) {
+    match pubsub_cmd {
+        PubSubCmd::Subscribe(channels) => {
+            for channel in channels {
+                broker.subscribe(subscriber, &channel);
+                write_subscription(w, "subscribe", Some(&channel), subscriber.count());
+            }
+        }
+        PubSubCmd::PSubscribe(patterns) => {
+            for pattern in patterns {
+                broker.psubscribe(subscriber, &pattern);
+                write_subscription(w, "psubscribe", Some(&pattern), subscriber.count());
+            }
+        }
+        PubSubCmd::Unsubscribe(mut channels) => {
+            if channels.is_empty() {
+                channels = subscriber.channels();
+            }
+            if channels.is_empty() {
+                write_subscription(w, "unsubscribe", None, subscriber.count());
+            }
+            for channel in channels {
+                broker.unsubscribe(subscriber, &channel);
+                write_subscription(w, "unsubscribe", Some(&channel), subscriber.count());
+            }
+        }
+        PubSubCmd::PUnsubscribe(mut patterns) => {
+            if patterns.is_empty() {
+                patterns = subscriber.patterns();
+            }
+            if patterns.is_empty() {
+                write_subscription(w, "punsubscribe", None, subscriber.count());
+            }
+            for pattern in patterns {
+                broker.punsubscribe(subscriber, &pattern);
+                write_subscription(w, "punsubscribe", Some(&pattern), subscriber.count());
+            }
+        }
+        PubSubCmd::Publish { channel, payload } => {
+            let receivers = broker.publish(&channel, &payload).await;
+            w.write_int(receivers as i64);
+        }
+    }
+}
+
+enum ConnectionEvent {
+    Command(Result<Command, std::io::Error>),
+    /// Frame to write out of band (pub/sub message, push sent through the registry)
+    Push(Push),
+    Killed,
+}
+
+/// Wait for the next command of the connection while frames are pushed to
+/// it (e.g. messages if subscribed). Reading is cancelled when a frame comes
+/// first or the connection is killed, no received data is lost at that point.
+async fn next_event(handler: &mut RESPHandler, subscriber: &Subscriber, registration: &Registration) -> ConnectionEvent {
+    let command = pin!(handler.decode_command());
+    let message = pin!(async {
+        match subscriber.is_subscribed() {
+            true => subscriber.recv().await,
+            false => pending().await,
+        }
+    });
+    // The registry holds the sender until the registration is dropped
+    let push = pin!(async { registration.pushes.recv().await.unwrap() });
+    let killed = pin!(registration.killed.recv());
+    match select(command, select(select(message, push), killed)).await {
+        Either::Left((command, _)) => ConnectionEvent::Command(command),
+        Either::Right((Either::Left((Either::Left((message, _)), _)), _)) => ConnectionEvent::Push(Box::new(message)),
+        Either::Right((Either::Left((Either::Right((push, _)), _)), _)) => ConnectionEvent::Push(push),
+        Either::Right((Either::Right(_), _)) => ConnectionEvent::Killed,
+    }
+}
+
+fn write_hello(w: &mut RespWriter) {
+    w.write_map_header(6);
+    w.write_simple_string("server");
+    w.write_simple_string("redis");
+    w.write_simple_string("version");
+    w.write_simple_string("0");
+    w.write_simple_string("proto");
+    w.write_int(match w.protocol() {
+        Protocol::Resp2 => 2,
+        Protocol::Resp3 => 3,
+    });
+    w.write_simple_string("id");
+    w.write_int(0);
+    w.write_simple_string("mode");
+    w.write_simple_string("cluster");
+    w.write_simple_string("modules");
+    w.write_array_header(0);
+}
+
+fn write_cluster_info(w: &mut RespWriter) {
+    let fields = [
+        ("cluster_shards_assigned", 16384),
+        ("cluster_shards_ok", 16384),
+        ("cluster_shards_pfail", 0),
+        ("cluster_shards_fail", 0),
+        ("cluster_known_nodes", 1),
+        ("cluster_size", 1),
+        ("cluster_current_epoch", 1),
+        ("cluster_my_epoch", 1),
+    ];
+    w.write_map_header(fields.len() + 1);
+    w.write_simple_string("cluster_state");
+    w.write_simple_string("ok");
+    for (name, value) in fields {
+        w.write_simple_string(name);
+        w.write_int(value);
+    }
+}
+
+/// INFO reply: the metrics summed over the shards of the reactor, then the
+/// usage of each disktable
+fn info(stats: &[(u16, Stats)], sections: &[String]) -> String {
+    let wants = |section: &str| sections.is_empty() || sections.iter().any(|s| s == section || s == "all" || s == "everything");
+    let mut info = String::new();
+    if wants("stats") {
+        let total = metrics::sum(stats.iter().map(|(_, stats)| stats.metrics()));
+        info.push_str("# Stats\r\n");
+        for (name, value) in &total {
+            info.push_str(&format!("{}:{}\r\n", name, value));
+        }
+        let ratio = |hits, misses| metrics::hit_ratio(metrics::get(&total, hits), metrics::get(&total, misses));
+        info.push_str(&format!("read_hit_ratio:{:.2}\r\n", ratio("read_hits", "read_misses")));
+        info.push_str(&format!(
+            "block_cache_hit_ratio:{:.2}\r\n",
+            ratio("block_cache_hits", "block_cache_misses")
+        ));
+    }
+    if wants("disktables") {
+        if !info.is_empty() {
+            info.push_str("\r\n");
+        }
+        info.push_str("# Disktables\r\n");
+        for (shard_id, stats) in stats {
+            for (name, usage_ratio, records) in stats.disktables() {
+                info.push_str(&format!(
+                    "shard{}:name={},usage_ratio={:.2},records={}\r\n",
+                    shard_id, name, usage_ratio, records
+                ));
+            }
+        }
+    }
+    info
+}
+
+// Describe a command the way COMMAND and COMMAND INFO do
+fn write_command_info(w: &mut RespWriter, spec: &CommandSpec) {
+    w.write_array_header(10);
+    w.write_simple_string(&spec.name.to_lowercase());
+    // Arity is the number of arguments a command expects
+    w.write_int(spec.arity);
+    w.write_array_header(spec.flags.len());
+    for flag in spec.flags {
+        w.write_simple_string(flag);
+    }
+    w.write_int(spec.first_key);
+    w.write_int(spec.last_key);
+    w.write_int(spec.step);
+    // ACLs categories
+    w.write_array_header(0);
+    // Tips
+    w.write_array_header(0);
+    // Key specs
+    w.write_array_header(0);
+    // Sub commands
+    w.write_array_header(0);
+}
+
+// Entry of a command in the COMMAND DOCS map
+fn write_command_docs(w: &mut RespWriter, spec: &CommandSpec) {
+    w.write_bulk(spec.name.to_lowercase().as_bytes());
+    w.write_map_header(2);
+    w.write_bulk(b"summary");
+    w.write_bulk(spec.summary.as_bytes());
+    w.write_bulk(b"group");
+    w.write_bulk(spec.group.as_bytes());
+}
+
+fn write_geo_position(w: &mut RespWriter, (longitude, latitude): (f64, f64)) {
+    w.write_array_header(2);
+    w.write_bulk(longitude.to_string().as_bytes());
+    w.write_bulk(latitude.to_string().as_bytes());
+}
+
+// Every member is checked: the whole sorted set is in the record anyway
+fn write_geo_search(w: &mut RespWriter, cmd: &GeoSearchCmd, zset: &[u8]) {
+    let entries = ZSet::range_by_rank(zset, 0, -1);
+    let center = match &cmd.origin {
+        GeoOrigin::Position(longitude, latitude) => (*longitude, *latitude),
+        GeoOrigin::Member(member) => match entries.iter().find(|(m, _)| m == member) {
+            Some((_, score)) => geo::decode(*score),
+            None => return w.write_error("ERR", "could not decode requested zset member"),
+        },
+    };
+    let mut matches: Vec<(&[u8], f64, f64)> = entries
+        .into_iter()
+        .filter_map(|(member, score)| cmd.shape.contains(center, geo::decode(score)).map(|distance| (member, score, distance)))
         .collect();
+    matches.sort_by(|(_, _, d1), (_, _, d2)| d1.total_cmp(d2));
+    if cmd.descending {
+        matches.reverse();
+    }
+    matches.truncate(cmd.count.unwrap_or(usize::MAX));
+
+    let fields = 1 + cmd.with_dist as usize + cmd.with_hash as usize + cmd.with_coord as usize;
+    w.write_array_header(matches.len());
+    for (member, score, distance) in matches {
+        if fields > 1 {
+            w.write_array_header(fields);
+        }
+        w.write_bulk(member);
+        if cmd.with_dist {
+            w.write_bulk(format!("{:.4}", distance / cmd.unit).as_bytes());
+        }
+        if cmd.with_hash {
+            w.write_int(score as i64);
+        }
+        if cmd.with_coord {
+            write_geo_position(w, geo::decode(score));
+        }
+    }
+}
+
+// Entries as returned by XRANGE and XREAD: [id, [field, value, ...]]
+fn write_stream_entries(w: &mut RespWriter, entries: &[Entry]) {
+    w.write_array_header(entries.len());
+    for (id, fields) in entries {
+        w.write_array_header(2);
+        w.write_bulk(id.to_string().as_bytes());
+        w.write_array_header(fields.len());
+        for field in fields {
+            w.write_bulk(field);
+        }
+    }
+}
+
+/// Merge the HyperLogLogs read with Get commands, missing keys are empty.
+/// The error is the code and message to reply
+fn hyperloglog_union(responses: Vec<api::Response>) -> Result<HyperLogLog, (&'static str, &'static str)> {
+    let wrong_type = ("WRONGTYPE", api::WRONG_TYPE_MESSAGE);
+    let mut union = HyperLogLog::default();
+    for response in responses {
+        match response {
+            api::Response::Get(resp) => match resp.record {
+                Err(e) => return Err(("ERR", e.message())),
+                Ok(Some(r)) if r.value_type != ValueType::String => return Err(wrong_type),
+                Ok(Some(r)) => union.merge(&HyperLogLog::decode(&r.value).ok_or(wrong_type)?),
+                Ok(None) => false,
+            },
+            _ => panic!("Unexpected response"),
+        };
+    }
+    Ok(union)
+}
 
-    return Value::NonHashableValue(NonHashableValue::Array(shards));
+/// Execute a command and write its reply. Large replies can be partly sent
+/// through `handler` before returning
+async fn handle_command(redis_command: Command, storage_proxy: &StorageProxy, handler: &mut RESPHandler, w: &mut RespWriter) {
+    match redis_command {
+        Command::Hello(hello_cmd) => match hello_cmd.version {
+            '2' => {
+                w.set_protocol(Protocol::Resp2);
+                write_hello(w);
+            }
+            '3' => {
+                w.set_protocol(Protocol::Resp3);
+                write_hello(w);
+            }
+            _ => w.write_error("NOPROTO", "sorry, this protocol version is not supported."),
+        },
+        Command::Client(_) => panic!("client commands are handled by the connection"),
+        Command::Set(set_cmd) => {
+            if let api::Response::Set(resp) = storage_proxy.dispatch(set_cmd.to_api_command()).await {
+                match (resp.applied, resp.old_value) {
+                    (Err(e), _) => w.write_error(e.code(), e.message()),
+                    (_, Err(_)) => write_wrong_type(w),
+                    (_, Ok(Some(old_value))) => w.write_bulk(&old_value),
+                    (_, Ok(None)) if set_cmd.options.get => w.write_null(),
+                    (Ok(true), Ok(None)) => w.write_simple_string("OK"),
+                    (Ok(false), Ok(None)) => w.write_null(),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::Get(get_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(get_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::String => write_wrong_type(w),
+                    Ok(Some(r)) => w.write_bulk(&r.value),
+                    Ok(None) => w.write_null(),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::Expire(expire_cmd) => {
+            if let api::Response::Expire(resp) = storage_proxy.dispatch(expire_cmd.to_api_command()).await {
+                match resp.updated {
+                    Ok(updated) => w.write_int(updated as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::Persist(persist_cmd) => {
+            if let api::Response::Expire(resp) = storage_proxy.dispatch(persist_cmd.to_api_command()).await {
+                match resp.updated {
+                    Ok(updated) => w.write_int(updated as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::Ttl(ttl_cmd) => {
+            if let api::Response::Ttl(resp) = storage_proxy.dispatch(ttl_cmd.to_api_command()).await {
+                match resp.ttl {
+                    Ttl::Missing => w.write_int(-2),
+                    Ttl::Persistent => w.write_int(-1),
+                    Ttl::Expiring(ns) if ttl_cmd.absolute => {
+                        let ms = crate::time::to_unix_ms(crate::time::now().saturating_add(ns)) as i64;
+                        w.write_int(if ttl_cmd.millis { ms } else { ms / 1000 })
+                    }
+                    Ttl::Expiring(ns) => {
+                        let ms = (ns / 1_000_000) as i64;
+                        // Like redis, TTL rounds to the closest second
+                        w.write_int(if ttl_cmd.millis { ms } else { (ms + 500) / 1000 })
+                    }
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::Type(type_cmd) => {
+            if let api::Response::Type(resp) = storage_proxy.dispatch(type_cmd.to_api_command()).await {
+                w.write_simple_string(resp.value_type.map(|t| t.name()).unwrap_or("none"));
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::HSet(hset_cmd) => {
+            if let api::Response::HSet(resp) = storage_proxy.dispatch(hset_cmd.to_api_command()).await {
+                match resp.added {
+                    Ok(added) => w.write_int(added as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::HGet(hget_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(hget_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::Hash => write_wrong_type(w),
+                    Ok(Some(r)) => match Hash::decode(&r.value).get(&hget_cmd.field) {
+                        Some(value) => w.write_bulk(value),
+                        None => w.write_null(),
+                    },
+                    Ok(None) => w.write_null(),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::HGetAll(hgetall_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(hgetall_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::Hash => write_wrong_type(w),
+                    Ok(Some(r)) => {
+                        let hash = Hash::decode(&r.value);
+                        w.write_map_header(hash.len());
+                        for (field, value) in hash.iter() {
+                            w.write_bulk(field);
+                            w.write_bulk(value);
+                        }
+                    }
+                    Ok(None) => w.write_map_header(0),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::HDel(hdel_cmd) => {
+            if let api::Response::HDel(resp) = storage_proxy.dispatch(hdel_cmd.to_api_command()).await {
+                match resp.removed {
+                    Ok(removed) => w.write_int(removed as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::Push(push_cmd) => {
+            if let api::Response::Push(resp) = storage_proxy.dispatch(push_cmd.to_api_command()).await {
+                match resp.len {
+                    Ok(len) => w.write_int(len as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::Pop(pop_cmd) => {
+            if let api::Response::Pop(resp) = storage_proxy.dispatch(pop_cmd.to_api_command()).await {
+                match (resp.values, pop_cmd.count) {
+                    (Err(e), _) => w.write_error(e.code(), e.message()),
+                    (Ok(None), _) => w.write_null(),
+                    (Ok(Some(values)), None) => w.write_bulk(&values[0]),
+                    (Ok(Some(values)), Some(_)) => {
+                        w.write_array_header(values.len());
+                        values.iter().for_each(|value| w.write_bulk(value));
+                    }
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::LRange(lrange_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(lrange_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::List => write_wrong_type(w),
+                    Ok(Some(r)) => {
+                        let values = List::range(&r.value, lrange_cmd.start, lrange_cmd.stop);
+                        w.write_array_header(values.len());
+                        for value in values {
+                            w.write_bulk(value);
+                            if w.len() >= STREAMING_CHUNK_SIZE {
+                                handler.write_resp(w.take()).await;
+                            }
+                        }
+                    }
+                    Ok(None) => w.write_array_header(0),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::SAdd(sadd_cmd) => {
+            if let api::Response::SAdd(resp) = storage_proxy.dispatch(sadd_cmd.to_api_command()).await {
+                match resp.added {
+                    Ok(added) => w.write_int(added as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::SRem(srem_cmd) => {
+            if let api::Response::SRem(resp) = storage_proxy.dispatch(srem_cmd.to_api_command()).await {
+                match resp.removed {
+                    Ok(removed) => w.write_int(removed as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::SMembers(smembers_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(smembers_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::Set => write_wrong_type(w),
+                    Ok(Some(r)) => {
+                        let members = Set::members(&r.value);
+                        w.write_set_header(members.len());
+                        for member in members {
+                            w.write_bulk(member);
+                            if w.len() >= STREAMING_CHUNK_SIZE {
+                                handler.write_resp(w.take()).await;
+                            }
+                        }
+                    }
+                    Ok(None) => w.write_set_header(0),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::SIsMember(sismember_cmd) => {
+            if let api::Response::SIsMember(resp) = storage_proxy.dispatch(sismember_cmd.to_api_command()).await {
+                match resp.is_member {
+                    Ok(is_member) => w.write_int(is_member as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::ZAdd(zadd_cmd) => {
+            if let api::Response::ZAdd(resp) = storage_proxy.dispatch(zadd_cmd.to_api_command()).await {
+                match resp.added {
+                    Ok(added) => w.write_int(added as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::ZScore(zscore_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(zscore_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::ZSet => write_wrong_type(w),
+                    Ok(Some(r)) => match ZSet::score(&r.value, &zscore_cmd.member) {
+                        Some(score) => w.write_double(score),
+                        None => w.write_null(),
+                    },
+                    Ok(None) => w.write_null(),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::ZRange(zrange_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(zrange_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::ZSet => write_wrong_type(w),
+                    Ok(Some(r)) => {
+                        let entries = match zrange_cmd.by {
+                            ZRangeBy::Rank(start, stop) => ZSet::range_by_rank(&r.value, start, stop),
+                            ZRangeBy::Score(min, max) => ZSet::range_by_score(&r.value, min, max),
+                        };
+                        // RESP2 flattens the member/score pairs
+                        let pairs = zrange_cmd.with_scores && w.protocol() == Protocol::Resp3;
+                        match zrange_cmd.with_scores && !pairs {
+                            true => w.write_array_header(entries.len() * 2),
+                            false => w.write_array_header(entries.len()),
+                        }
+                        for (member, score) in entries {
+                            if pairs {
+                                w.write_array_header(2);
+                            }
+                            w.write_bulk(member);
+                            if zrange_cmd.with_scores {
+                                w.write_double(score);
+                            }
+                            if w.len() >= STREAMING_CHUNK_SIZE {
+                                handler.write_resp(w.take()).await;
+                            }
+                        }
+                    }
+                    Ok(None) => w.write_array_header(0),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::GetRange(getrange_cmd) => {
+            if let api::Response::GetRange(resp) = storage_proxy.dispatch(getrange_cmd.to_api_command()).await {
+                match resp.value {
+                    Ok(value) => w.write_bulk(&value),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::SetRange(setrange_cmd) => {
+            if let api::Response::SetRange(resp) = storage_proxy.dispatch(setrange_cmd.to_api_command()).await {
+                match resp.len {
+                    Ok(len) => w.write_int(len as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::StrLen(strlen_cmd) => {
+            if let api::Response::StrLen(resp) = storage_proxy.dispatch(strlen_cmd.to_api_command()).await {
+                match resp.len {
+                    Ok(len) => w.write_int(len as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::Incr(incr_cmd) => {
+            if let api::Response::Incr(resp) = storage_proxy.dispatch(incr_cmd.to_api_command()).await {
+                match resp.value {
+                    Ok(api::Number::Integer(i)) => w.write_int(i),
+                    // INCRBYFLOAT replies with a bulk string
+                    Ok(value @ api::Number::Float(_)) => w.write_bulk(value.to_string().as_bytes()),
+                    Err(err) => w.write_error(err.code(), err.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::MGet(mget_cmd) if !api::same_slot(mget_cmd.keys.iter().map(String::as_str)) => write_cross_slot(w),
+        Command::MSet(mset_cmd) if !api::same_slot(mset_cmd.pairs.iter().map(|(key, _)| key.as_str())) => write_cross_slot(w),
+        Command::Del(del_cmd) if !api::same_slot(del_cmd.keys.iter().map(String::as_str)) => write_cross_slot(w),
+        Command::XRead(xread_cmd) if !api::same_slot(xread_cmd.streams.iter().map(|(key, _)| key.as_str())) => write_cross_slot(w),
+        Command::PfCount(pfcount_cmd) if !api::same_slot(pfcount_cmd.keys.iter().map(String::as_str)) => write_cross_slot(w),
+        Command::PfMerge(pfmerge_cmd) if !api::same_slot(pfmerge_cmd.sources.iter().chain([&pfmerge_cmd.destination]).map(String::as_str)) => {
+            write_cross_slot(w)
+        }
+        Command::MGet(mget_cmd) => {
+            let responses = storage_proxy.get_many(mget_cmd.to_keys()).await;
+            w.write_array_header(responses.len());
+            for resp in responses {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    // Like redis, values of other types are returned as missing
+                    Ok(Some(r)) if r.value_type == ValueType::String => w.write_bulk(&r.value),
+                    Ok(_) => w.write_null(),
+                }
+            }
+        }
+        Command::MSet(mset_cmd) => {
+            let responses = storage_proxy.dispatch_many(mset_cmd.to_api_commands()).await;
+            let mut error = None;
+            for response in responses {
+                match response {
+                    api::Response::Set(resp) => {
+                        if let Err(e) = resp.applied {
+                            error = error.or(Some(e));
+                        }
+                    }
+                    _ => panic!("Unexpected response"),
+                }
+            }
+            match error {
+                Some(e) => w.write_error(e.code(), e.message()),
+                None => w.write_simple_string("OK"),
+            }
+        }
+        Command::Del(del_cmd) => {
+            let responses = storage_proxy.dispatch_many(del_cmd.to_api_commands()).await;
+            let mut deleted = 0;
+            let mut error = None;
+            for response in responses {
+                match response {
+                    api::Response::Delete(resp) => match resp.deleted {
+                        Ok(true) => deleted += 1,
+                        Ok(false) => (),
+                        Err(e) => error = Some(e),
+                    },
+                    _ => panic!("Unexpected response"),
+                }
+            }
+            match error {
+                Some(e) => w.write_error("ERR", e.message()),
+                None => w.write_int(deleted),
+            }
+        }
+        Command::Scan(scan_cmd) => {
+            let (cursor, mut keys) = storage_proxy.scan(scan_cmd.cursor, scan_cmd.count).await;
+            // Like redis, the pattern is applied after the iteration step
+            if let Some(pattern) = &scan_cmd.pattern {
+                keys.retain(|key| pattern::matches(pattern.as_bytes(), key.string.as_bytes()));
+            }
+            w.write_array_header(2);
+            w.write_bulk(cursor.to_string().as_bytes());
+            w.write_array_header(keys.len());
+            for key in keys {
+                w.write_bulk(key.string.as_bytes());
+            }
+        }
+        Command::Keys(keys_cmd) => {
+            let pattern = keys_cmd.pattern.as_bytes();
+            // Only the keys starting with the literal part of the pattern are matched
+            let prefix = String::from_utf8(pattern::literal_prefix(pattern)).unwrap_or_default();
+            let keys = storage_proxy.keys(&prefix, |key| pattern::matches(pattern, key.string.as_bytes()));
+            w.write_array_header(keys.len());
+            for key in keys {
+                w.write_bulk(key.string.as_bytes());
+                // Stream large replies instead of buffering them whole
+                if w.len() >= STREAMING_CHUNK_SIZE {
+                    handler.write_resp(w.take()).await;
+                }
+            }
+        }
+        Command::PubSub(_) => panic!("pub/sub commands are handled by the connection"),
+        Command::Object(object_cmd) => {
+            if let api::Response::Object(resp) = storage_proxy.dispatch(object_cmd.to_api_command()).await {
+                match (resp.info, object_cmd.sub_command) {
+                    (Err(e), _) => w.write_error(e.code(), e.message()),
+                    (Ok(None), _) => w.write_null(),
+                    (Ok(Some(_)), ObjectSubCmd::Encoding) => w.write_bulk(resp.encoding.as_bytes()),
+                    // Values are never shared
+                    (Ok(Some(_)), ObjectSubCmd::RefCount) => w.write_int(1),
+                    (Ok(Some(info)), ObjectSubCmd::IdleTime) => w.write_int(info.access.idle_time() as i64),
+                    (Ok(Some(info)), ObjectSubCmd::Freq) => w.write_int(info.access.frequency() as i64),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::Rename(rename_cmd) => {
+            let key = Key::new(rename_cmd.key);
+            let new_key = Key::new(rename_cmd.new_key);
+            match storage_proxy.rename(&key, &new_key, rename_cmd.only_if_missing).await {
+                Ok(renamed) if rename_cmd.only_if_missing => w.write_int(renamed as i64),
+                Ok(_) => w.write_simple_string("OK"),
+                Err(e) => w.write_error(e.code(), e.message()),
+            }
+        }
+        Command::Debug(debug_cmd) => match debug_cmd {
+            DebugCmd::Sleep(duration) => {
+                monoio::time::sleep(duration).await;
+                w.write_simple_string("OK");
+            }
+            DebugCmd::Object(object_cmd) => {
+                if let api::Response::Object(resp) = storage_proxy.dispatch(object_cmd.to_api_command()).await {
+                    match resp.info {
+                        Err(e) => w.write_error(e.code(), e.message()),
+                        Ok(None) => w.write_error("ERR", "no such key"),
+                        Ok(Some(info)) => w.write_simple_string(&format!(
+                            "Value at:{} refcount:1 encoding:{} serializedlength:{} lru_seconds_idle:{}",
+                            info.location.name(),
+                            resp.encoding,
+                            info.value_size,
+                            info.access.idle_time()
+                        )),
+                    }
+                } else {
+                    panic!("Unexpected response")
+                }
+            }
+            DebugCmd::Jmap() => {
+                let mut stats = String::new();
+                for (shard_id, shard_stats) in storage_proxy.local_stats() {
+                    stats.push_str(&format!("shard:{} {:?}\n", shard_id, shard_stats));
+                }
+                w.write_bulk(stats.as_bytes());
+            }
+            DebugCmd::Reload() => {
+                storage_proxy.reload().await;
+                w.write_simple_string("OK");
+            }
+            DebugCmd::Verify() => {
+                let mut report = String::new();
+                for (shard_id, shard_report) in storage_proxy.verify().await {
+                    report.push_str(&format!(
+                        "shard:{} tables:{} records:{} problems:{}\n",
+                        shard_id,
+                        shard_report.tables,
+                        shard_report.records,
+                        shard_report.problems.len()
+                    ));
+                    for problem in shard_report.problems {
+                        report.push_str(&format!("{:?}\n", problem));
+                    }
+                }
+                w.write_bulk(report.as_bytes());
+            }
+        },
+        Command::Dump(dump_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(dump_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) => w.write_bulk(&dump::serialize(r.value_type, &r.value)),
+                    Ok(None) => w.write_null(),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::Restore(restore_cmd) => match dump::deserialize(&restore_cmd.payload) {
+            Err(_) => w.write_error("ERR", "DUMP payload version or checksum are wrong"),
+            Ok((value_type, value)) => {
+                if let api::Response::Set(resp) = storage_proxy.dispatch(restore_cmd.to_api_command(value_type, value)).await {
+                    match resp.applied {
+                        Ok(true) => w.write_simple_string("OK"),
+                        Ok(false) => w.write_error("BUSYKEY", "Target key name already exists."),
+                        Err(e) => w.write_error("ERR", e.message()),
+                    }
+                } else {
+                    panic!("Unexpected response")
+                }
+            }
+        },
+        Command::Copy(copy_cmd) => {
+            let source = Key::new(copy_cmd.source);
+            let destination = Key::new(copy_cmd.destination);
+            match storage_proxy.copy(&source, &destination, copy_cmd.replace).await {
+                Ok(copied) => w.write_int(copied as i64),
+                Err(e) => w.write_error(e.code(), e.message()),
+            }
+        }
+        Command::PfAdd(pfadd_cmd) => {
+            if let api::Response::PfMerge(resp) = storage_proxy.dispatch(pfadd_cmd.to_api_command()).await {
+                match resp.updated {
+                    Ok(updated) => w.write_int(updated as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::PfCount(pfcount_cmd) => {
+            let responses = storage_proxy.dispatch_many(pfcount_cmd.to_api_commands()).await;
+            match hyperloglog_union(responses) {
+                Ok(union) => w.write_int(union.count() as i64),
+                Err((code, message)) => w.write_error(code, message),
+            }
+        }
+        Command::PfMerge(pfmerge_cmd) => {
+            let responses = storage_proxy.dispatch_many(pfmerge_cmd.to_api_commands()).await;
+            let union = match hyperloglog_union(responses) {
+                Ok(union) => union,
+                Err((code, message)) => return w.write_error(code, message),
+            };
+            if let api::Response::PfMerge(resp) = storage_proxy.dispatch(pfmerge_cmd.to_api_command(&union)).await {
+                match resp.updated {
+                    Ok(_) => w.write_simple_string("OK"),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::GeoAdd(geoadd_cmd) => {
+            if let api::Response::ZAdd(resp) = storage_proxy.dispatch(geoadd_cmd.to_api_command()).await {
+                match resp.added {
+                    Ok(added) => w.write_int(added as i64),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::GeoPos(geopos_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(geopos_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::ZSet => write_wrong_type(w),
+                    Ok(record) => {
+                        w.write_array_header(geopos_cmd.members.len());
+                        for member in &geopos_cmd.members {
+                            match record.as_ref().and_then(|r| ZSet::score(&r.value, member)) {
+                                Some(score) => write_geo_position(w, geo::decode(score)),
+                                None => w.write_null(),
+                            }
+                        }
+                    }
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::GeoDist(geodist_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(geodist_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::ZSet => write_wrong_type(w),
+                    Ok(Some(r)) => {
+                        let (member1, member2) = &geodist_cmd.members;
+                        match (ZSet::score(&r.value, member1), ZSet::score(&r.value, member2)) {
+                            (Some(score1), Some(score2)) => {
+                                let ((longitude1, latitude1), (longitude2, latitude2)) = (geo::decode(score1), geo::decode(score2));
+                                let distance = geo::distance(longitude1, latitude1, longitude2, latitude2) / geodist_cmd.unit;
+                                w.write_bulk(format!("{:.4}", distance).as_bytes());
+                            }
+                            _ => w.write_null(),
+                        }
+                    }
+                    Ok(None) => w.write_null(),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::GeoSearch(geosearch_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(geosearch_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::ZSet => write_wrong_type(w),
+                    Ok(Some(r)) => write_geo_search(w, &geosearch_cmd, &r.value),
+                    Ok(None) => w.write_array_header(0),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::XAdd(xadd_cmd) => {
+            if let api::Response::XAdd(resp) = storage_proxy.dispatch(xadd_cmd.to_api_command()).await {
+                match resp.id {
+                    Ok(Some(id)) => w.write_bulk(id.to_string().as_bytes()),
+                    Ok(None) => w.write_null(),
+                    Err(e) => w.write_error(e.code(), e.message()),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::XLen(xlen_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(xlen_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::Stream => write_wrong_type(w),
+                    Ok(Some(r)) => w.write_int(Stream::len(&r.value) as i64),
+                    Ok(None) => w.write_int(0),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::XRange(xrange_cmd) => {
+            if let api::Response::Get(resp) = storage_proxy.dispatch(xrange_cmd.to_api_command()).await {
+                match resp.record {
+                    Err(e) => w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::Stream => write_wrong_type(w),
+                    Ok(Some(r)) => write_stream_entries(w, &Stream::range(&r.value, xrange_cmd.start, xrange_cmd.end, xrange_cmd.count)),
+                    Ok(None) => w.write_array_header(0),
+                }
+            } else {
+                panic!("Unexpected response")
+            }
+        }
+        Command::XRead(xread_cmd) => {
+            let responses = storage_proxy.dispatch_many(xread_cmd.to_api_commands()).await;
+            let mut streams = Vec::with_capacity(responses.len());
+            for ((key, id), response) in xread_cmd.streams.iter().zip(responses) {
+                let record = match response {
+                    api::Response::Get(resp) => resp.record,
+                    _ => panic!("Unexpected response"),
+                };
+                match record {
+                    Err(e) => return w.write_error("ERR", e.message()),
+                    Ok(Some(r)) if r.value_type != ValueType::Stream => return write_wrong_type(w),
+                    Ok(Some(r)) => streams.push((key, id.and_then(|id| id.next()), r.value)),
+                    Ok(None) => (),
+                }
+            }
+            let entries: Vec<(&String, Vec<Entry>)> = streams
+                .iter()
+                .filter_map(|(key, start, value)| start.map(|start| (*key, Stream::range(value, start, StreamId::MAX, xread_cmd.count))))
+                .filter(|(_, entries)| !entries.is_empty())
+                .collect();
+            if entries.is_empty() {
+                w.write_null();
+                return;
+            }
+            match w.protocol() {
+                Protocol::Resp2 => w.write_array_header(entries.len()),
+                Protocol::Resp3 => w.write_map_header(entries.len()),
+            }
+            for (key, entries) in entries {
+                if w.protocol() == Protocol::Resp2 {
+                    w.write_array_header(2);
+                }
+                w.write_bulk(key.as_bytes());
+                write_stream_entries(w, &entries);
+            }
+        }
+        Command::Flush(flush_cmd) => {
+            storage_proxy.flush_all(flush_cmd.background).await;
+            w.write_simple_string("OK");
+        }
+        // Like redis, nothing is replied: the connection is closed when the
+        // reactor stops
+        Command::Shutdown(shutdown_cmd) => {
+            storage_proxy.dispatch(shutdown_cmd.to_api_command()).await;
+        }
+        Command::Info(info_cmd) => w.write_bulk(info(&storage_proxy.local_stats(), &info_cmd.sections).as_bytes()),
+        Command::Save(save_cmd) => match save_cmd {
+            SaveCmd::Save() if storage_proxy.is_saving() => w.write_error("ERR", "Background save already in progress"),
+            SaveCmd::Save() => match storage_proxy.save().await {
+                Ok(()) => w.write_simple_string("OK"),
+                Err(e) => w.write_error("ERR", e.message()),
+            },
+            SaveCmd::BgSave() => match storage_proxy.background_save() {
+                true => w.write_simple_string("Background saving started"),
+                false => w.write_error("ERR", "Background save already in progress"),
+            },
+            SaveCmd::LastSave() => w.write_int(storage_proxy.last_save() as i64),
+        },
+        Command::Cluster(cluster_cmd) => match cluster_cmd {
+            ClusterCmd::Join(join_cmd) => {
+                if let api::Response::ClusterTopology(resp) = storage_proxy.dispatch(join_cmd.to_api_command()).await {
+                    // Internal command: the topology is always sent using RESP3 maps
+                    let protocol = w.protocol();
+                    w.set_protocol(Protocol::Resp3);
+                    w.write_value(&resp.topology.to_resp());
+                    w.set_protocol(protocol);
+                } else {
+                    panic!("Unexpected response")
+                }
+            }
+            ClusterCmd::Info() => write_cluster_info(w),
+            ClusterCmd::KeySlot(key) => w.write_int(api::key_slot(&Key::new(key)) as i64),
+            ClusterCmd::CountKeysInSlot(slot) => w.write_int(storage_proxy.count_keys_in_slot(slot) as i64),
+            ClusterCmd::GetKeysInSlot(getkeysinslot_cmd) => {
+                let keys = storage_proxy.keys_in_slot(getkeysinslot_cmd.slot, getkeysinslot_cmd.count).await;
+                w.write_array_header(keys.len());
+                for key in keys {
+                    w.write_bulk(key.string.as_bytes());
+                }
+            }
+            ClusterCmd::Nodes() => match storage_proxy.get_topology() {
+                Some(topology) => write_cluster_nodes(w, &topology, &storage_proxy.reactor_metadata()),
+                None => write_cluster_down(w),
+            },
+            ClusterCmd::MyId() => w.write_bulk(storage_proxy.reactor_metadata().cluster_node_id().as_bytes()),
+            ClusterCmd::Slots() => match storage_proxy.get_topology() {
+                Some(topology) => write_cluster_slots(w, &topology),
+                None => write_cluster_down(w),
+            },
+            ClusterCmd::Reset(reset_cmd) => {
+                if let api::Response::ClusterTopology(_) = storage_proxy.dispatch(reset_cmd.to_api_command()).await {
+                    w.write_simple_string("OK");
+                } else {
+                    panic!("Unexpected response")
+                }
+            }
+        },
+        Command::Config(config_cmd) => match config_cmd {
+            ConfigCmd::Get(patterns) => {
+                let runtime_config = storage_proxy.runtime_config();
+                let names: Vec<&str> = config::PARAMETERS
+                    .into_iter()
+                    .filter(|name| patterns.iter().any(|p| pattern::matches(p.to_lowercase().as_bytes(), name.as_bytes())))
+                    .collect();
+                w.write_map_header(names.len());
+                for name in names {
+                    w.write_bulk(name.as_bytes());
+                    w.write_bulk(runtime_config.get(name).unwrap().as_bytes());
+                }
+            }
+            ConfigCmd::Set(pairs) => match storage_proxy.runtime_config().set(&pairs) {
+                Ok(()) => w.write_simple_string("OK"),
+                Err(err) => w.write_error("ERR", &err.message()),
+            },
+        },
+        Command::Command(command_cmd) => match command_cmd {
+            CommandCmd::List() => {
+                w.write_array_header(COMMANDS.len());
+                for spec in COMMANDS {
+                    write_command_info(w, spec);
+                }
+            }
+            CommandCmd::Count() => w.write_int(COMMANDS.len() as i64),
+            CommandCmd::Info(names) => {
+                w.write_array_header(names.len());
+                for name in names {
+                    match find_command(&name) {
+                        Some(spec) => write_command_info(w, spec),
+                        None => w.write_null(),
+                    }
+                }
+            }
+            CommandCmd::Docs(names) => {
+                let specs: Vec<&CommandSpec> = match names.is_empty() {
+                    true => COMMANDS.iter().collect(),
+                    false => names.iter().filter_map(|name| find_command(name)).collect(),
+                };
+                w.write_map_header(specs.len());
+                for spec in specs {
+                    write_command_docs(w, spec);
+                }
+            }
+        },
+        Command::Invalid(message) => w.write_error("ERR", &message),
+    }
 }
 
 impl RESPServer {
-    pub async fn listen(self) -> ! {
-        let listener = TcpListener::bind(self.host_port.clone()).unwrap();
+    /// Listen on every configured address
+    pub async fn listen(&self) {
+        let listeners: Vec<TcpListener> = self.addrs.iter().map(|addr| TcpListener::bind(addr).unwrap()).collect();
+        join_all(listeners.iter().map(|listener| self.accept(listener))).await;
+    }
 
+    async fn accept(&self, listener: &TcpListener) {
         println!("Listening on {}", listener.local_addr().unwrap());
         loop {
-            let (stream, _) = listener.accept().await.unwrap();
+            let (stream, addr) = listener.accept().await.unwrap();
             let storage_proxy = self.storage_proxy.clone();
+            let broker = self.broker.clone();
+            let registration = Registration::new(self.connections.clone(), addr);
+            let connections = self.connections.clone();
             let reader = BufReader::new(stream);
-            monoio::spawn(async move {
-                let mut handler = RESPHandler { stream: reader };
+            monoio::spawn(supervisor::isolate(format!("resp connection {}", addr), async move {
+                let mut handler = RESPHandler::new(reader);
+                // Connections start with RESP2 until the client sends HELLO
+                let mut writer = RespWriter::new(Protocol::Resp2);
+                let mut subscriber = broker.new_subscriber();
                 loop {
-                    let redis_command = match handler.decode_command().await {
+                    let result = match next_event(&mut handler, &subscriber, &registration).await {
+                        ConnectionEvent::Command(result) => result,
+                        ConnectionEvent::Push(push) => {
+                            writer.write_value(&push.to_resp());
+                            handler.write_resp(writer.take()).await;
+                            continue;
+                        }
+                        ConnectionEvent::Killed => break,
+                    };
+                    let mut redis_command = match result {
                         Ok(c) => c,
                         Err(err) => match err.kind() {
                             std::io::ErrorKind::ConnectionReset => break,
@@ -80,161 +1290,28 @@ impl RESPServer {
                         },
                     };
 
-                    // let tmp_record: record::Record;
-                    let resp_bytes: Vec<u8> = match redis_command {
-                        Command::Hello(hello_cmd) => {
-                            if hello_cmd.version != '3' {
-                                Value::HashableValue(HashableValue::Error(
-                                    Cow::from("NOPROTO"),
-                                    Cow::from("sorry, this protocol version is not supported."),
-                                ))
-                                .to_bytes()
-                            } else {
-                                Value::NonHashableValue(NonHashableValue::Map(HashMap::from([
-                                    (
-                                        HashableValue::String(Cow::from("server")),
-                                        Value::HashableValue(HashableValue::String(Cow::from("redis"))),
-                                    ),
-                                    (
-                                        HashableValue::String(Cow::from("version")),
-                                        Value::HashableValue(HashableValue::String(Cow::from("0"))),
-                                    ),
-                                    (HashableValue::String(Cow::from("proto")), Value::HashableValue(HashableValue::Integer(3))),
-                                    (HashableValue::String(Cow::from("id")), Value::HashableValue(HashableValue::Integer(0))),
-                                    (
-                                        HashableValue::String(Cow::from("mode")),
-                                        Value::HashableValue(HashableValue::String(Cow::from("cluster"))),
-                                    ),
-                                    (HashableValue::String(Cow::from("modules")), Value::Null),
-                                ])))
-                                .to_bytes()
-                            }
+                    // Pipelined commands already received are executed right
+                    // away and their replies sent in a single write
+                    loop {
+                        connections.touch(registration.id, &handler.last_command);
+                        track_read_keys(&handler, &connections, registration.id);
+                        match redis_command {
+                            Command::Client(client_cmd) => handle_client_command(client_cmd, &connections, registration.id, &mut writer),
+                            Command::PubSub(pubsub_cmd) => handle_pubsub_command(pubsub_cmd, &broker, &mut subscriber, &mut writer).await,
+                            redis_command => handle_command(redis_command, &storage_proxy, &mut handler, &mut writer).await,
                         }
-                        Command::Client(client_cmd) => match client_cmd {
-                            ClientCmd::SetInfo(_) => Value::HashableValue(HashableValue::String(Cow::from("OK"))).to_bytes(),
-                        },
-                        Command::Set(set_cmd) => {
-                            // TODO: should return result
-                            let _ = storage_proxy.dispatch(set_cmd.to_api_command()).await;
-                            Value::HashableValue(HashableValue::String(Cow::from("OK"))).to_bytes()
+                        invalidate_written_keys(&handler, &connections, registration.id);
+                        redis_command = match handler.decode_buffered_command() {
+                            Some(redis_command) => redis_command,
+                            None => break,
+                        };
+                        if writer.len() >= STREAMING_CHUNK_SIZE {
+                            handler.write_resp(writer.take()).await;
                         }
-                        Command::Get(get_cmd) => {
-                            if let api::Response::Get(resp) = storage_proxy.dispatch(get_cmd.to_api_command()).await {
-                                match resp.record {
-                                    Some(r) => Value::HashableValue(HashableValue::Blob(&r.value)).to_bytes(),
-                                    None => Value::Null.to_bytes(),
-                                }
-                            } else {
-                                panic!("Unexpected response")
-                            }
-                        }
-                        Command::Cluster(cluster_cmd) => match cluster_cmd {
-                            crate::redis::command::ClusterCmd::Join(join_cmd) => {
-                                if let api::Response::ClusterTopology(resp) = storage_proxy.dispatch(join_cmd.to_api_command()).await {
-                                    resp.topology.to_resp().to_bytes()
-                                } else {
-                                    panic!("Unexpected response")
-                                }
-                            }
-                            crate::redis::command::ClusterCmd::Info() => Value::NonHashableValue(NonHashableValue::Map(HashMap::from([
-                                (
-                                    HashableValue::String(Cow::from("cluster_state")),
-                                    Value::HashableValue(HashableValue::String(Cow::from("ok"))),
-                                ),
-                                (
-                                    HashableValue::String(Cow::from("cluster_shards_assigned")),
-                                    Value::HashableValue(HashableValue::Integer(16384)),
-                                ),
-                                (
-                                    HashableValue::String(Cow::from("cluster_shards_ok")),
-                                    Value::HashableValue(HashableValue::Integer(16384)),
-                                ),
-                                (
-                                    HashableValue::String(Cow::from("cluster_shards_pfail")),
-                                    Value::HashableValue(HashableValue::Integer(0)),
-                                ),
-                                (
-                                    HashableValue::String(Cow::from("cluster_shards_fail")),
-                                    Value::HashableValue(HashableValue::Integer(0)),
-                                ),
-                                (
-                                    HashableValue::String(Cow::from("cluster_known_nodes")),
-                                    Value::HashableValue(HashableValue::Integer(1)),
-                                ),
-                                (
-                                    HashableValue::String(Cow::from("cluster_size")),
-                                    Value::HashableValue(HashableValue::Integer(1)),
-                                ),
-                                (
-                                    HashableValue::String(Cow::from("cluster_current_epoch")),
-                                    Value::HashableValue(HashableValue::Integer(1)),
-                                ),
-                                (
-                                    HashableValue::String(Cow::from("cluster_my_epoch")),
-                                    Value::HashableValue(HashableValue::Integer(1)),
-                                ),
-                            ])))
-                            .to_bytes(),
-
-                            crate::redis::command::ClusterCmd::Slots() => {
-                                let topology = storage_proxy.get_topology().unwrap();
-                                cluster_shards_response(&topology).to_bytes()
-                            }
-                        },
-                        Command::Command() => Value::NonHashableValue(NonHashableValue::Array(vec![
-                            // TODO: get that through reflection
-                            Value::NonHashableValue(NonHashableValue::Array(vec![
-                                // Name
-                                Value::HashableValue(HashableValue::String(Cow::from("SET"))),
-                                // Arity is the number of arguments a command expects
-                                Value::HashableValue(HashableValue::Integer(3)),
-                                // Flags
-                                Value::NonHashableValue(NonHashableValue::Array(vec![])),
-                                // First key
-                                Value::HashableValue(HashableValue::Integer(1)),
-                                // Last Key
-                                Value::HashableValue(HashableValue::Integer(1)),
-                                // Step
-                                Value::HashableValue(HashableValue::Integer(1)),
-                                // ACLs categories
-                                Value::NonHashableValue(NonHashableValue::Array(vec![])),
-                                // Tips
-                                Value::NonHashableValue(NonHashableValue::Array(vec![])),
-                                // Key specs
-                                Value::NonHashableValue(NonHashableValue::Array(vec![])),
-                                // Sub commands
-                                Value::NonHashableValue(NonHashableValue::Array(vec![])),
-                            ])),
-                            Value::NonHashableValue(NonHashableValue::Array(vec![
-                                // Name
-                                Value::HashableValue(HashableValue::String(Cow::from("GET"))),
-                                // Arity is the number of arguments a command expects
-                                Value::HashableValue(HashableValue::Integer(2)),
-                                // Flags
-                                Value::NonHashableValue(NonHashableValue::Array(vec![])),
-                                // First key
-                                Value::HashableValue(HashableValue::Integer(1)),
-                                // Last Key
-                                Value::HashableValue(HashableValue::Integer(1)),
-                                // Step
-                                Value::HashableValue(HashableValue::Integer(1)),
-                                // ACLs categories
-                                Value::NonHashableValue(NonHashableValue::Array(vec![])),
-                                // Tips
-                                Value::NonHashableValue(NonHashableValue::Array(vec![])),
-                                // Key specs
-                                Value::NonHashableValue(NonHashableValue::Array(vec![])),
-                                // Sub commands
-                                Value::NonHashableValue(NonHashableValue::Array(vec![])),
-                            ])),
-                        ]))
-                        .to_bytes(),
-                    };
-
-                    // println!("Answering: {:?}", str::from_utf8(&resp_bytes).unwrap());
-                    handler.write_resp(resp_bytes).await;
+                    }
+                    handler.write_resp(writer.take()).await;
                 }
-            });
+            }));
         }
     }
 }
@@ -0,0 +1,64 @@
+use crypto::{digest::Digest, sha1::Sha1};
+
+use crate::record::ValueType;
+
+/// Version of the payload format, payloads from newer versions are refused
+pub const DUMP_VERSION: u16 = 1;
+const CHECKSUM_SIZE: usize = 8;
+
+/// The payload is truncated, corrupted or from a newer version
+#[derive(Debug, PartialEq)]
+pub struct BadPayload;
+
+fn checksum(buf: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut hasher = Sha1::new();
+    let mut digest = [0u8; 20];
+    hasher.input(buf);
+    hasher.result(&mut digest);
+    digest[..CHECKSUM_SIZE].try_into().unwrap()
+}
+
+/// Serialize a value for DUMP: the value type (u8), the value as stored in
+/// the record, the format version (u16le) and a checksum of all of it
+pub fn serialize(value_type: ValueType, value: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(1 + value.len() + 2 + CHECKSUM_SIZE);
+    payload.push(value_type as u8);
+    payload.extend_from_slice(value);
+    payload.extend(DUMP_VERSION.to_le_bytes());
+    let checksum = checksum(&payload);
+    payload.extend(checksum);
+    payload
+}
+
+/// Check and decode a payload written by `serialize`
+pub fn deserialize(payload: &[u8]) -> Result<(ValueType, Vec<u8>), BadPayload> {
+    if payload.len() < 1 + 2 + CHECKSUM_SIZE {
+        return Err(BadPayload);
+    }
+    let (content, expected) = payload.split_at(payload.len() - CHECKSUM_SIZE);
+    if checksum(content) != expected {
+        return Err(BadPayload);
+    }
+    let (content, version) = content.split_at(content.len() - 2);
+    if u16::from_le_bytes(version.try_into().unwrap()) > DUMP_VERSION || content[0] > ValueType::Stream as u8 {
+        return Err(BadPayload);
+    }
+    Ok((ValueType::from_u8(content[0]), content[1..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_payload() {
+        let payload = serialize(ValueType::List, b"\x01\x00\x00\x00");
+        assert_eq!(deserialize(&payload), Ok((ValueType::List, b"\x01\x00\x00\x00".to_vec())));
+
+        let mut corrupted = payload.clone();
+        corrupted[2] ^= 1;
+        assert_eq!(deserialize(&corrupted), Err(BadPayload));
+        assert_eq!(deserialize(&payload[1..]), Err(BadPayload));
+        assert_eq!(deserialize(b"foo"), Err(BadPayload));
+    }
+}
@@ -0,0 +1,134 @@
+//! Geospatial indexing on top of sorted sets: like redis, positions are
+//! stored as the 52 bits geohash of the member, used as its score
+
+const STEP: u32 = 26;
+const LONGITUDE_MIN: f64 = -180.0;
+const LONGITUDE_MAX: f64 = 180.0;
+/// Limits of the web mercator projection
+const LATITUDE_MIN: f64 = -85.05112878;
+const LATITUDE_MAX: f64 = 85.05112878;
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+
+/// Interleave the bits of x (even positions) and y (odd positions)
+fn interleave(x: u32, y: u32) -> u64 {
+    let mut bits = 0u64;
+    for i in 0..STEP {
+        bits |= (((x >> i) & 1) as u64) << (2 * i);
+        bits |= (((y >> i) & 1) as u64) << (2 * i + 1);
+    }
+    bits
+}
+
+fn deinterleave(bits: u64) -> (u32, u32) {
+    let (mut x, mut y) = (0u32, 0u32);
+    for i in 0..STEP {
+        x |= (((bits >> (2 * i)) & 1) as u32) << i;
+        y |= (((bits >> (2 * i + 1)) & 1) as u32) << i;
+    }
+    (x, y)
+}
+
+/// Score of a position, None if it can't be indexed
+pub fn encode(longitude: f64, latitude: f64) -> Option<f64> {
+    if !(LONGITUDE_MIN..=LONGITUDE_MAX).contains(&longitude) || !(LATITUDE_MIN..=LATITUDE_MAX).contains(&latitude) {
+        return None;
+    }
+    let cells = (1u64 << STEP) as f64;
+    let latitude_offset = ((latitude - LATITUDE_MIN) / (LATITUDE_MAX - LATITUDE_MIN) * cells) as u64;
+    let longitude_offset = ((longitude - LONGITUDE_MIN) / (LONGITUDE_MAX - LONGITUDE_MIN) * cells) as u64;
+    let max_offset = (1u64 << STEP) - 1;
+    Some(interleave(latitude_offset.min(max_offset) as u32, longitude_offset.min(max_offset) as u32) as f64)
+}
+
+/// Center of the geohash cell of a score, as (longitude, latitude)
+pub fn decode(score: f64) -> (f64, f64) {
+    let (latitude_offset, longitude_offset) = deinterleave(score as u64);
+    let cells = (1u64 << STEP) as f64;
+    let center = |offset: u32, min: f64, max: f64| {
+        let low = min + (offset as f64 / cells) * (max - min);
+        let high = min + ((offset as f64 + 1.0) / cells) * (max - min);
+        ((low + high) / 2.0).clamp(min, max)
+    };
+    (
+        center(longitude_offset, LONGITUDE_MIN, LONGITUDE_MAX),
+        center(latitude_offset, LATITUDE_MIN, LATITUDE_MAX),
+    )
+}
+
+/// Distance in meters between two positions (haversine formula)
+pub fn distance(longitude1: f64, latitude1: f64, longitude2: f64, latitude2: f64) -> f64 {
+    let (latitude1, latitude2) = (latitude1.to_radians(), latitude2.to_radians());
+    let u = ((latitude2 - latitude1) / 2.0).sin();
+    let v = ((longitude2 - longitude1).to_radians() / 2.0).sin();
+    let a = u * u + latitude1.cos() * latitude2.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+/// Number of meters in a unit (m, km, ft or mi)
+pub fn unit_in_meters(unit: &str) -> Option<f64> {
+    match unit.to_lowercase().as_str() {
+        "m" => Some(1.0),
+        "km" => Some(1000.0),
+        "ft" => Some(0.3048),
+        "mi" => Some(1609.34),
+        _ => None,
+    }
+}
+
+/// Area searched by GEOSEARCH, in meters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+impl Shape {
+    /// Distance from the center to the position if it is inside the shape
+    pub fn contains(&self, center: (f64, f64), position: (f64, f64)) -> Option<f64> {
+        let ((longitude1, latitude1), (longitude2, latitude2)) = (center, position);
+        if let Shape::Box { width, height } = *self {
+            let latitude_distance = EARTH_RADIUS_IN_METERS * (latitude2.to_radians() - latitude1.to_radians()).abs();
+            if latitude_distance > height / 2.0 || distance(longitude1, latitude2, longitude2, latitude2) > width / 2.0 {
+                return None;
+            }
+        }
+        let distance = distance(longitude1, latitude1, longitude2, latitude2);
+        match *self {
+            Shape::Radius(radius) if distance > radius => None,
+            _ => Some(distance),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geo() {
+        // Same score as redis for Palermo
+        let score = encode(13.361389, 38.115556).unwrap();
+        assert_eq!(score, 3479099956230698.0);
+        let (longitude, latitude) = decode(score);
+        assert!((longitude - 13.361389).abs() < 0.0001 && (latitude - 38.115556).abs() < 0.0001);
+        assert_eq!(encode(0.0, 90.0), None);
+
+        let catania = (15.087269, 37.502669);
+        let palermo = (longitude, latitude);
+        let d = distance(palermo.0, palermo.1, catania.0, catania.1);
+        assert!((d - 166274.0).abs() < 10.0, "distance: {}", d);
+        assert!(Shape::Radius(200_000.0).contains(palermo, catania).is_some());
+        assert!(Shape::Radius(100_000.0).contains(palermo, catania).is_none());
+        let wide = Shape::Box {
+            width: 400_000.0,
+            height: 200_000.0,
+        };
+        assert!(wide.contains(palermo, catania).is_some());
+        let narrow = Shape::Box {
+            width: 100_000.0,
+            height: 200_000.0,
+        };
+        assert!(narrow.contains(palermo, catania).is_none());
+        assert_eq!(unit_in_meters("KM"), Some(1000.0));
+    }
+}
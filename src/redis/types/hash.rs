@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+
+use super::{decode_items, encode_items};
+
+/// Value of a hash key, stored as alternating fields and values
+#[derive(Debug, Default, PartialEq)]
+pub struct Hash {
+    fields: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Hash {
+    pub fn decode(buf: &[u8]) -> Hash {
+        let items = decode_items(buf);
+        Hash {
+            fields: items.chunks_exact(2).map(|pair| (pair[0].to_vec(), pair[1].to_vec())).collect(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode_items(self.fields.iter().flat_map(|(field, value)| [field.as_slice(), value.as_slice()]))
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&[u8]> {
+        self.fields.get(field).map(|value| value.as_slice())
+    }
+
+    /// Return true if the field is new
+    pub fn set(&mut self, field: Vec<u8>, value: Vec<u8>) -> bool {
+        self.fields.insert(field, value).is_none()
+    }
+
+    /// Return true if the field existed
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        self.fields.remove(field).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.fields.iter().map(|(field, value)| (field.as_slice(), value.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_encoding() {
+        let mut hash = Hash::default();
+        assert!(hash.set(b"field1".to_vec(), b"value1".to_vec()));
+        assert!(hash.set(b"field2".to_vec(), b"".to_vec()));
+        assert!(!hash.set(b"field1".to_vec(), b"value2".to_vec()));
+
+        let decoded = Hash::decode(&hash.encode());
+        assert_eq!(decoded, hash);
+        assert_eq!(decoded.get(b"field1"), Some(&b"value2"[..]));
+        assert_eq!(decoded.len(), 2);
+
+        let mut decoded = decoded;
+        assert!(decoded.remove(b"field2"));
+        assert!(!decoded.remove(b"field2"));
+        assert_eq!(Hash::decode(&decoded.encode()).len(), 1);
+    }
+}
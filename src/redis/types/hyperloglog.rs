@@ -0,0 +1,195 @@
+const MAGIC: &[u8] = b"HYLL";
+/// Magic, encoding (always dense) and unused bytes
+const HEADER_SIZE: usize = 16;
+const PRECISION: u32 = 14;
+const REGISTERS: usize = 1 << PRECISION;
+const REGISTER_BITS: usize = 6;
+const REGISTER_MASK: u8 = (1 << REGISTER_BITS) - 1;
+/// Number of bits of the hash used to compute the run of zeroes
+const Q: u32 = 64 - PRECISION;
+const DENSE_SIZE: usize = HEADER_SIZE + (REGISTERS * REGISTER_BITS).div_ceil(8);
+const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+const HASH_SEED: u64 = 0xadc83b19;
+
+/// Value of a HyperLogLog key, stored as a string like in redis: a header
+/// followed by 16384 registers of 6 bits (dense encoding)
+#[derive(Debug, PartialEq)]
+pub struct HyperLogLog {
+    buf: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        let mut buf = vec![0u8; DENSE_SIZE];
+        buf[..MAGIC.len()].copy_from_slice(MAGIC);
+        HyperLogLog { buf }
+    }
+}
+
+/// MurmurHash64A, as used by redis for its HyperLogLogs
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, b) in tail.iter().enumerate() {
+            h ^= (*b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if z == previous {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z == previous {
+            return z / 3.0;
+        }
+    }
+}
+
+impl HyperLogLog {
+    /// Return None if the buffer is not a HyperLogLog
+    pub fn decode(buf: &[u8]) -> Option<HyperLogLog> {
+        if buf.len() != DENSE_SIZE || &buf[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        Some(HyperLogLog { buf: buf.to_vec() })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.buf.clone()
+    }
+
+    fn get_register(&self, index: usize) -> u8 {
+        let bit = index * REGISTER_BITS;
+        let (byte, shift) = (HEADER_SIZE + bit / 8, bit % 8);
+        let low = self.buf[byte] as u16;
+        let high = self.buf.get(byte + 1).copied().unwrap_or(0) as u16;
+        (((low | (high << 8)) >> shift) as u8) & REGISTER_MASK
+    }
+
+    fn set_register(&mut self, index: usize, value: u8) {
+        let bit = index * REGISTER_BITS;
+        let (byte, shift) = (HEADER_SIZE + bit / 8, bit % 8);
+        let mask = (REGISTER_MASK as u16) << shift;
+        let value = (value as u16) << shift;
+        self.buf[byte] = (self.buf[byte] & !(mask as u8)) | value as u8;
+        if let Some(high) = self.buf.get_mut(byte + 1) {
+            *high = (*high & !((mask >> 8) as u8)) | (value >> 8) as u8;
+        }
+    }
+
+    /// Return true if a register was updated
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let hash = murmurhash64a(element, HASH_SEED);
+        let index = (hash & (REGISTERS as u64 - 1)) as usize;
+        // The added bit bounds the run of zeroes to Q
+        let run = (((hash >> PRECISION) | (1 << Q)).trailing_zeros() + 1) as u8;
+        if run > self.get_register(index) {
+            self.set_register(index, run);
+            return true;
+        }
+        false
+    }
+
+    /// Keep the highest value of each register, return true if one changed
+    pub fn merge(&mut self, other: &HyperLogLog) -> bool {
+        let mut updated = false;
+        for index in 0..REGISTERS {
+            let value = other.get_register(index);
+            if value > self.get_register(index) {
+                self.set_register(index, value);
+                updated = true;
+            }
+        }
+        updated
+    }
+
+    /// Estimate the cardinality with the improved estimator of Otmar Ertl
+    /// (the one redis uses)
+    pub fn count(&self) -> u64 {
+        let mut histogram = [0u32; 64];
+        for index in 0..REGISTERS {
+            histogram[self.get_register(index) as usize] += 1;
+        }
+        let m = REGISTERS as f64;
+        let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+        for j in (1..=Q as usize).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        (ALPHA_INF * m * m / z).round() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hyperloglog() {
+        let mut hll = HyperLogLog::default();
+        assert_eq!(hll.count(), 0);
+        for i in 0..10000 {
+            hll.add(format!("element:{}", i).as_bytes());
+        }
+        assert!(!hll.add(b"element:42"));
+        let count = hll.count();
+        assert!((9800..=10200).contains(&count), "count: {}", count);
+
+        let mut other = HyperLogLog::default();
+        for i in 5000..15000 {
+            other.add(format!("element:{}", i).as_bytes());
+        }
+        assert!(hll.merge(&other));
+        assert!(!hll.merge(&other));
+        let count = hll.count();
+        assert!((14700..=15300).contains(&count), "count: {}", count);
+
+        assert_eq!(HyperLogLog::decode(&hll.encode()), Some(hll));
+        assert_eq!(HyperLogLog::decode(b"foo"), None);
+    }
+}
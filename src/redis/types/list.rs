@@ -0,0 +1,94 @@
+use std::collections::VecDeque;
+
+use super::{decode_items, encode_items};
+
+/// Value of a list key, stored as its items in order
+#[derive(Debug, Default, PartialEq)]
+pub struct List {
+    items: VecDeque<Vec<u8>>,
+}
+
+/// Convert redis style `start`/`stop` indexes (negative ones counting from the
+/// end, both inclusive) to a range clamped to a list of `len` items
+pub fn range_bounds(len: usize, start: i64, stop: i64) -> std::ops::Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        return 0..0;
+    }
+    start as usize..stop as usize + 1
+}
+
+impl List {
+    pub fn decode(buf: &[u8]) -> List {
+        List {
+            items: decode_items(buf).into_iter().map(|item| item.to_vec()).collect(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode_items(self.items.iter().map(|item| item.as_slice()))
+    }
+
+    /// Items between the redis style `start` and `stop` indexes, decoded from
+    /// the record without copying the whole list
+    pub fn range(buf: &[u8], start: i64, stop: i64) -> Vec<&[u8]> {
+        let mut items = decode_items(buf);
+        let range = range_bounds(items.len(), start, stop);
+        items.truncate(range.end);
+        items.drain(..range.start);
+        items
+    }
+
+    pub fn push_front(&mut self, item: Vec<u8>) {
+        self.items.push_front(item)
+    }
+
+    pub fn push_back(&mut self, item: Vec<u8>) {
+        self.items.push_back(item)
+    }
+
+    pub fn pop_front(&mut self) -> Option<Vec<u8>> {
+        self.items.pop_front()
+    }
+
+    pub fn pop_back(&mut self) -> Option<Vec<u8>> {
+        self.items.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_encoding() {
+        let mut list = List::default();
+        list.push_back(b"b".to_vec());
+        list.push_back(b"c".to_vec());
+        list.push_front(b"a".to_vec());
+        let buf = list.encode();
+        assert_eq!(List::decode(&buf), list);
+
+        assert_eq!(List::range(&buf, 0, -1), vec![&b"a"[..], b"b", b"c"]);
+        assert_eq!(List::range(&buf, 1, 1), vec![&b"b"[..]]);
+        assert_eq!(List::range(&buf, -2, 100), vec![&b"b"[..], b"c"]);
+        assert!(List::range(&buf, 2, 1).is_empty());
+        assert!(List::range(&buf, 5, 10).is_empty());
+        assert!(List::range(&buf, 0, -4).is_empty());
+
+        let mut list = List::decode(&buf);
+        assert_eq!(list.pop_front(), Some(b"a".to_vec()));
+        assert_eq!(list.pop_back(), Some(b"c".to_vec()));
+        assert_eq!(list.len(), 1);
+    }
+}
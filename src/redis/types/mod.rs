@@ -0,0 +1,40 @@
+//! Encodings of the redis data structures stored as record values
+
+pub mod dump;
+pub mod geo;
+pub mod hash;
+pub mod hyperloglog;
+pub mod list;
+pub mod set;
+pub mod stream;
+pub mod string;
+pub mod zset;
+
+/// Encode byte strings as their count (u32le) followed by each of them
+/// prefixed by its length (u32le)
+pub fn encode_items<'a, I: IntoIterator<Item = &'a [u8]>>(items: I) -> Vec<u8> {
+    // The count is written once known
+    let mut buf = vec![0u8; 4];
+    let mut count: u32 = 0;
+    for item in items {
+        buf.extend((item.len() as u32).to_le_bytes());
+        buf.extend_from_slice(item);
+        count += 1;
+    }
+    buf[0..4].copy_from_slice(&count.to_le_bytes());
+    buf
+}
+
+/// Decode byte strings written by `encode_items`
+pub fn decode_items(buf: &[u8]) -> Vec<&[u8]> {
+    let count = u32::from_le_bytes(buf[0..4].try_into().expect("incorrect length")) as usize;
+    let mut items = Vec::with_capacity(count);
+    let mut cursor = 4;
+    for _ in 0..count {
+        let len = u32::from_le_bytes(buf[cursor..cursor + 4].try_into().expect("incorrect length")) as usize;
+        cursor += 4;
+        items.push(&buf[cursor..cursor + len]);
+        cursor += len;
+    }
+    items
+}
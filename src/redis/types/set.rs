@@ -0,0 +1,72 @@
+use std::collections::BTreeSet;
+
+use super::{decode_items, encode_items};
+
+/// Value of a set key, stored as its members in sorted order
+#[derive(Debug, Default, PartialEq)]
+pub struct Set {
+    members: BTreeSet<Vec<u8>>,
+}
+
+impl Set {
+    pub fn decode(buf: &[u8]) -> Set {
+        Set {
+            members: decode_items(buf).into_iter().map(|member| member.to_vec()).collect(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode_items(self.members.iter().map(|member| member.as_slice()))
+    }
+
+    /// Check membership directly on the encoded record, members being sorted
+    pub fn contains(buf: &[u8], member: &[u8]) -> bool {
+        decode_items(buf).binary_search(&member).is_ok()
+    }
+
+    /// Members of the encoded record, without copying them
+    pub fn members(buf: &[u8]) -> Vec<&[u8]> {
+        decode_items(buf)
+    }
+
+    /// Return true if the member is new
+    pub fn insert(&mut self, member: Vec<u8>) -> bool {
+        self.members.insert(member)
+    }
+
+    /// Return true if the member existed
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        self.members.remove(member)
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_encoding() {
+        let mut set = Set::default();
+        assert!(set.insert(b"b".to_vec()));
+        assert!(set.insert(b"a".to_vec()));
+        assert!(!set.insert(b"b".to_vec()));
+        let buf = set.encode();
+        assert_eq!(Set::decode(&buf), set);
+        assert_eq!(Set::members(&buf), vec![&b"a"[..], b"b"]);
+        assert!(Set::contains(&buf, b"a"));
+        assert!(!Set::contains(&buf, b"c"));
+
+        let mut set = Set::decode(&buf);
+        assert!(set.remove(b"a"));
+        assert!(!set.remove(b"a"));
+        assert_eq!(set.len(), 1);
+    }
+}
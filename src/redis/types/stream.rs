@@ -0,0 +1,223 @@
+use std::fmt;
+
+use super::{decode_items, encode_items};
+
+/// Id of a stream entry, `ms-seq`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    /// Parse `ms-seq`, or `ms` in which case the sequence is `missing_seq`
+    pub fn parse(s: &str, missing_seq: u64) -> Option<StreamId> {
+        match s.split_once('-') {
+            Some((ms, seq)) => Some(StreamId {
+                ms: ms.parse().ok()?,
+                seq: seq.parse().ok()?,
+            }),
+            None => Some(StreamId {
+                ms: s.parse().ok()?,
+                seq: missing_seq,
+            }),
+        }
+    }
+
+    /// Parse the start of a XRANGE interval: `-`, an id or an exclusive `(id`
+    pub fn parse_range_start(s: &str) -> Option<StreamId> {
+        match s {
+            "-" => Some(StreamId::MIN),
+            _ => match s.strip_prefix('(') {
+                Some(s) => StreamId::parse(s, 0)?.next(),
+                None => StreamId::parse(s, 0),
+            },
+        }
+    }
+
+    /// Parse the end of a XRANGE interval: `+`, an id or an exclusive `(id`
+    pub fn parse_range_end(s: &str) -> Option<StreamId> {
+        match s {
+            "+" => Some(StreamId::MAX),
+            _ => match s.strip_prefix('(') {
+                Some(s) => StreamId::parse(s, u64::MAX)?.previous(),
+                None => StreamId::parse(s, u64::MAX),
+            },
+        }
+    }
+
+    pub fn next(&self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_add(1)?,
+                seq: 0,
+            }),
+        }
+    }
+
+    pub fn previous(&self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => Some(StreamId {
+                ms: self.ms.checked_sub(1)?,
+                seq: u64::MAX,
+            }),
+        }
+    }
+
+    fn encode(&self) -> [u8; 16] {
+        let mut buf = [0u8; 16];
+        buf[..8].copy_from_slice(&self.ms.to_le_bytes());
+        buf[8..].copy_from_slice(&self.seq.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> StreamId {
+        StreamId {
+            ms: u64::from_le_bytes(buf[..8].try_into().expect("incorrect length")),
+            seq: u64::from_le_bytes(buf[8..16].try_into().expect("incorrect length")),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// Entry as read from an encoded stream: its id and flattened field/value pairs
+pub type Entry<'a> = (StreamId, Vec<&'a [u8]>);
+
+/// Id given to XADD
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewId {
+    /// `*`, generated from the current time
+    Auto,
+    /// `ms-*`, only the sequence is generated
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+/// Value of a stream key. The record is the id of the last entry ever added
+/// followed by the id and the fields of each entry, so entries are only
+/// appended at the end of the encoding
+#[derive(Debug, Default, PartialEq)]
+pub struct Stream {
+    last_id: StreamId,
+    entries: Vec<(StreamId, Vec<Vec<u8>>)>,
+}
+
+impl Stream {
+    pub fn decode(buf: &[u8]) -> Stream {
+        let items = decode_items(buf);
+        Stream {
+            last_id: StreamId::decode(items[0]),
+            entries: items[1..]
+                .chunks(2)
+                .map(|entry| {
+                    (
+                        StreamId::decode(entry[0]),
+                        decode_items(entry[1]).into_iter().map(|f| f.to_vec()).collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let ids: Vec<[u8; 16]> = self.entries.iter().map(|(id, _)| id.encode()).collect();
+        let fields: Vec<Vec<u8>> = self
+            .entries
+            .iter()
+            .map(|(_, fields)| encode_items(fields.iter().map(|f| f.as_slice())))
+            .collect();
+        let last_id = self.last_id.encode();
+        let entries = ids.iter().zip(fields.iter()).flat_map(|(id, fields)| [id.as_slice(), fields.as_slice()]);
+        encode_items([last_id.as_slice()].into_iter().chain(entries))
+    }
+
+    /// Number of entries of the encoded record
+    pub fn len(buf: &[u8]) -> usize {
+        (decode_items(buf).len() - 1) / 2
+    }
+
+    /// Entries of the encoded record with an id in `start..=end`
+    pub fn range(buf: &[u8], start: StreamId, end: StreamId, count: usize) -> Vec<Entry> {
+        decode_items(buf)[1..]
+            .chunks(2)
+            .map(|entry| (StreamId::decode(entry[0]), entry[1]))
+            .skip_while(|(id, _)| *id < start)
+            .take_while(|(id, _)| *id <= end)
+            .take(count)
+            .map(|(id, fields)| (id, decode_items(fields)))
+            .collect()
+    }
+
+    /// Append an entry, return its id or None if the id is not greater than
+    /// the one of the last entry ever added
+    pub fn add(&mut self, id: NewId, now_ms: u64, fields: Vec<Vec<u8>>) -> Option<StreamId> {
+        let id = match id {
+            NewId::Auto if now_ms > self.last_id.ms => StreamId { ms: now_ms, seq: 0 },
+            NewId::Auto => self.last_id.next()?,
+            NewId::AutoSeq(ms) if ms == self.last_id.ms => self.last_id.next().filter(|id| id.ms == ms)?,
+            NewId::AutoSeq(ms) => StreamId { ms, seq: 0 },
+            NewId::Explicit(id) => id,
+        };
+        if id <= self.last_id {
+            return None;
+        }
+        self.last_id = id;
+        self.entries.push((id, fields));
+        Some(id)
+    }
+
+    /// Only keep the `max_len` most recent entries
+    pub fn trim(&mut self, max_len: usize) {
+        let len = self.entries.len();
+        if len > max_len {
+            self.entries.drain(..len - max_len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(field: &str, value: &str) -> Vec<Vec<u8>> {
+        vec![field.as_bytes().to_vec(), value.as_bytes().to_vec()]
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut stream = Stream::default();
+        let first = stream.add(NewId::Auto, 1000, fields("a", "1")).unwrap();
+        assert_eq!(first, StreamId { ms: 1000, seq: 0 });
+        // The clock went back
+        assert_eq!(stream.add(NewId::Auto, 900, fields("b", "2")), Some(StreamId { ms: 1000, seq: 1 }));
+        assert_eq!(stream.add(NewId::Explicit(first), 0, fields("c", "3")), None);
+        assert_eq!(stream.add(NewId::AutoSeq(1000), 0, fields("c", "3")), Some(StreamId { ms: 1000, seq: 2 }));
+        assert_eq!(stream.add(NewId::AutoSeq(2000), 0, fields("d", "4")), Some(StreamId { ms: 2000, seq: 0 }));
+
+        let buf = stream.encode();
+        assert_eq!(Stream::decode(&buf), stream);
+        assert_eq!(Stream::len(&buf), 4);
+
+        let start = StreamId::parse_range_start("(1000-0").unwrap();
+        let end = StreamId::parse_range_end("1000").unwrap();
+        let range = Stream::range(&buf, start, end, usize::MAX);
+        assert_eq!(range.iter().map(|(id, _)| id.to_string()).collect::<Vec<_>>(), vec!["1000-1", "1000-2"]);
+        assert_eq!(range[0].1, vec![&b"b"[..], b"2"]);
+        assert_eq!(Stream::range(&buf, StreamId::MIN, StreamId::MAX, 1).len(), 1);
+
+        stream.trim(1);
+        let buf = stream.encode();
+        assert_eq!(Stream::len(&buf), 1);
+        assert_eq!(StreamId::parse("12-x", 0), None);
+    }
+}
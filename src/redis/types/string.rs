@@ -0,0 +1,48 @@
+/// Convert GETRANGE `start`/`end` offsets (negative ones counting from the
+/// end, both inclusive) to a range clamped to a string of `len` bytes.
+/// Unlike list ranges, an `end` before the string start is clamped to 0
+pub fn range_bounds(len: usize, start: i64, end: i64) -> std::ops::Range<usize> {
+    let len = len as i64;
+    if len == 0 || (start < 0 && end < 0 && start > end) {
+        return 0..0;
+    }
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { (len + end).max(0) } else { end.min(len - 1) };
+    if start > end {
+        return 0..0;
+    }
+    start as usize..end as usize + 1
+}
+
+/// Overwrite `value` from `offset` with `data`, padding with zeros when
+/// `offset` is past the end
+pub fn set_range(value: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    let end = offset + data.len();
+    if value.len() < end {
+        value.resize(end, 0);
+    }
+    value[offset..end].copy_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_ranges() {
+        assert_eq!(range_bounds(5, 0, -1), 0..5);
+        assert_eq!(range_bounds(5, 1, 2), 1..3);
+        assert_eq!(range_bounds(5, -3, 100), 2..5);
+        assert_eq!(range_bounds(5, -100, -100), 0..1);
+        assert_eq!(range_bounds(5, -1, -2), 0..0);
+        assert_eq!(range_bounds(5, 3, 1), 0..0);
+        assert_eq!(range_bounds(0, 0, -1), 0..0);
+
+        let mut value = b"Hello World".to_vec();
+        set_range(&mut value, 6, b"Redis");
+        assert_eq!(value, b"Hello Redis");
+        let mut value = Vec::new();
+        set_range(&mut value, 2, b"ab");
+        assert_eq!(value, b"\0\0ab");
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use super::{decode_items, encode_items, list::range_bounds};
+
+/// Value of a sorted set key, stored as member/score pairs ordered by score
+/// then member so ranges can be read directly from the record
+#[derive(Debug, Default, PartialEq)]
+pub struct ZSet {
+    scores: HashMap<Vec<u8>, f64>,
+}
+
+/// One end of a score range, `(` prefixed bounds are exclusive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreBound {
+    pub score: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    pub fn parse(s: &str) -> Option<ScoreBound> {
+        let (s, exclusive) = match s.strip_prefix('(') {
+            Some(s) => (s, true),
+            None => (s, false),
+        };
+        let score: f64 = s.parse().ok()?;
+        if score.is_nan() {
+            return None;
+        }
+        Some(ScoreBound { score, exclusive })
+    }
+
+    fn below(&self, score: f64) -> bool {
+        match self.exclusive {
+            true => self.score < score,
+            false => self.score <= score,
+        }
+    }
+
+    fn above(&self, score: f64) -> bool {
+        match self.exclusive {
+            true => score < self.score,
+            false => score <= self.score,
+        }
+    }
+}
+
+fn decode_entries(buf: &[u8]) -> Vec<(&[u8], f64)> {
+    decode_items(buf)
+        .chunks(2)
+        .map(|pair| (pair[0], f64::from_le_bytes(pair[1].try_into().expect("incorrect length"))))
+        .collect()
+}
+
+impl ZSet {
+    pub fn decode(buf: &[u8]) -> ZSet {
+        ZSet {
+            scores: decode_entries(buf).into_iter().map(|(member, score)| (member.to_vec(), score)).collect(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut entries: Vec<(&Vec<u8>, [u8; 8])> = self.scores.iter().map(|(member, score)| (member, score.to_le_bytes())).collect();
+        entries.sort_by(|(m1, s1), (m2, s2)| f64::from_le_bytes(*s1).total_cmp(&f64::from_le_bytes(*s2)).then(m1.cmp(m2)));
+        encode_items(entries.iter().flat_map(|(member, score)| [member.as_slice(), score.as_slice()]))
+    }
+
+    /// Score of a member read from the encoded record
+    pub fn score(buf: &[u8], member: &[u8]) -> Option<f64> {
+        decode_entries(buf).into_iter().find(|(m, _)| *m == member).map(|(_, score)| score)
+    }
+
+    /// Members between the redis style `start` and `stop` ranks
+    pub fn range_by_rank(buf: &[u8], start: i64, stop: i64) -> Vec<(&[u8], f64)> {
+        let mut entries = decode_entries(buf);
+        let range = range_bounds(entries.len(), start, stop);
+        entries.truncate(range.end);
+        entries.drain(..range.start);
+        entries
+    }
+
+    /// Members with a score between `min` and `max`
+    pub fn range_by_score(buf: &[u8], min: ScoreBound, max: ScoreBound) -> Vec<(&[u8], f64)> {
+        decode_entries(buf)
+            .into_iter()
+            .skip_while(|(_, score)| !min.below(*score))
+            .take_while(|(_, score)| max.above(*score))
+            .collect()
+    }
+
+    /// Set the score of a member, return true if the member is new
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        self.scores.insert(member, score).is_none()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bound(s: &str) -> ScoreBound {
+        ScoreBound::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_zset_encoding() {
+        let mut zset = ZSet::default();
+        assert!(zset.insert(b"c".to_vec(), 2.0));
+        assert!(zset.insert(b"b".to_vec(), 1.0));
+        assert!(zset.insert(b"a".to_vec(), 1.0));
+        assert!(!zset.insert(b"c".to_vec(), 3.5));
+        let buf = zset.encode();
+        assert_eq!(ZSet::decode(&buf), zset);
+
+        assert_eq!(ZSet::score(&buf, b"c"), Some(3.5));
+        assert_eq!(ZSet::score(&buf, b"d"), None);
+        assert_eq!(ZSet::range_by_rank(&buf, 0, -1), vec![(&b"a"[..], 1.0), (b"b", 1.0), (b"c", 3.5)]);
+        assert_eq!(ZSet::range_by_rank(&buf, -1, -1), vec![(&b"c"[..], 3.5)]);
+        assert_eq!(ZSet::range_by_score(&buf, bound("-inf"), bound("+inf")).len(), 3);
+        assert_eq!(ZSet::range_by_score(&buf, bound("(1"), bound("inf")), vec![(&b"c"[..], 3.5)]);
+        assert_eq!(ZSet::range_by_score(&buf, bound("1"), bound("(3.5")), vec![(&b"a"[..], 1.0), (b"b", 1.0)]);
+        assert!(ZSet::range_by_score(&buf, bound("4"), bound("2")).is_empty());
+        assert!(ScoreBound::parse("nan").is_none());
+        assert!(ScoreBound::parse("abc").is_none());
+    }
+}
@@ -0,0 +1,193 @@
+//! Files of a shard, in the directory named by the first shard of its range
+//!
+//! |  path       |                                                             |
+//! | meta.json   | shard id and format version of the directory                |
+//! | LOCK        | locked by the process serving the shard                     |
+//! | tables/     | disktables, their MANIFEST and VERSION, quarantined files   |
+//! | wal/        | WAL segments, index checkpoint, WAL of the other keyspaces  |
+
+use std::{
+    fs::{self, File},
+    os::fd::AsRawFd,
+    path::PathBuf,
+};
+
+use crate::datastore::{checkpoint, upgrade, wal};
+
+pub const META_FILE: &str = "meta.json";
+pub const LOCK_FILE: &str = "LOCK";
+pub const TABLES_DIRECTORY: &str = "tables";
+pub const WAL_DIRECTORY: &str = "wal";
+
+/// Content of `META_FILE`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShardMeta {
+    pub shard_id: u16,
+    /// Version of the files of the directory, see `upgrade::CURRENT_VERSION`
+    pub format_version: u32,
+}
+
+impl ShardMeta {
+    fn to_json(self) -> String {
+        format!("{{\"shard_id\": {}, \"format_version\": {}}}\n", self.shard_id, self.format_version)
+    }
+
+    fn parse(json: &str) -> Option<ShardMeta> {
+        Some(ShardMeta {
+            shard_id: json_number(json, "shard_id")?.try_into().ok()?,
+            format_version: json_number(json, "format_version")?.try_into().ok()?,
+        })
+    }
+}
+
+/// Value of the numeric field `name` of a flat JSON object
+fn json_number(json: &str, name: &str) -> Option<u64> {
+    let start = json.find(&format!("\"{}\"", name))? + name.len() + 2;
+    let value = json[start..].trim_start().strip_prefix(':')?.trim_start();
+    let end = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    value[..end].parse().ok()
+}
+
+pub struct ShardLayout {
+    root: PathBuf,
+}
+
+impl ShardLayout {
+    pub fn new(root: PathBuf) -> ShardLayout {
+        ShardLayout { root }
+    }
+
+    pub fn tables(&self) -> PathBuf {
+        self.root.join(TABLES_DIRECTORY)
+    }
+
+    pub fn wal(&self) -> PathBuf {
+        self.root.join(WAL_DIRECTORY)
+    }
+
+    /// Meta file, None if the directory was never used by a shard
+    pub fn meta(&self) -> Option<ShardMeta> {
+        match fs::read_to_string(self.root.join(META_FILE)) {
+            Ok(json) => Some(ShardMeta::parse(&json).unwrap_or_else(|| panic!("invalid {:?}", self.root.join(META_FILE)))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => panic!("Can't read {:?}: {}", self.root.join(META_FILE), e),
+        }
+    }
+
+    /// Create the directories of shard `shard_id` and lock it, the returned
+    /// file holds the lock. Files of a shard written before the layout (all
+    /// in the root) are moved to their directory, the meta file is written
+    /// once they all are so an interrupted move is resumed on the next start
+    pub fn create(&self, shard_id: u16) -> File {
+        fs::create_dir_all(&self.root).unwrap();
+        let lock = self.lock();
+        if let Some(meta) = self.meta() {
+            assert_eq!(meta.shard_id, shard_id, "{:?} holds the data of another shard", self.root);
+        }
+        fs::create_dir_all(self.tables()).unwrap();
+        fs::create_dir_all(self.wal()).unwrap();
+        self.move_flat_files();
+        let meta = ShardMeta {
+            shard_id,
+            format_version: upgrade::CURRENT_VERSION,
+        };
+        upgrade::write_atomically(&self.root.join(META_FILE), meta.to_json().as_bytes()).unwrap();
+        lock
+    }
+
+    /// Lock the directory so no other process serves the shard
+    fn lock(&self) -> File {
+        let lock = File::create(self.root.join(LOCK_FILE)).unwrap();
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            panic!("{:?} is used by another process: {}", self.root, std::io::Error::last_os_error());
+        }
+        lock
+    }
+
+    /// Move the files of the tables and of the WAL left in the root (every
+    /// file but the ones of the layout), whatever version wrote them
+    fn move_flat_files(&self) {
+        for entry in fs::read_dir(&self.root).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let directory = match path.extension().and_then(|e| e.to_str()) {
+                _ if [META_FILE, LOCK_FILE, TABLES_DIRECTORY, WAL_DIRECTORY].contains(&name.as_str()) => continue,
+                _ if name.starts_with("keyspace-") || name == checkpoint::CHECKPOINT_FILE => self.wal(),
+                Some(wal::SEGMENT_EXTENSION) => self.wal(),
+                _ => self.tables(),
+            };
+            println!("Moving {:?} to {:?}", path, directory);
+            fs::rename(&path, directory.join(&name)).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::{disktable, Config, DataStore};
+    use crate::record::Key;
+
+    #[test]
+    fn test_shard_layout() {
+        let root = PathBuf::from(r"./data/test/test_shard_layout");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("keyspace-1")).unwrap();
+        for name in [disktable::MANIFEST, "1-v10.data", "2-0.wal"] {
+            fs::write(root.join(name), "").unwrap();
+        }
+
+        let layout = ShardLayout::new(root.clone());
+        let lock = layout.create(3);
+        assert_eq!(
+            layout.meta(),
+            Some(ShardMeta {
+                shard_id: 3,
+                format_version: upgrade::CURRENT_VERSION
+            })
+        );
+        assert!(layout.tables().join(disktable::MANIFEST).exists());
+        assert!(layout.tables().join("1-v10.data").exists());
+        assert!(layout.wal().join("2-0.wal").exists());
+        assert!(layout.wal().join("keyspace-1").is_dir());
+        assert!(!root.join("1-v10.data").exists());
+
+        // Locked until the file is closed
+        let locked = std::panic::catch_unwind(|| ShardLayout::new(root.clone()).create(3));
+        assert!(locked.is_err());
+        drop(lock);
+        drop(ShardLayout::new(root.clone()).create(3));
+        assert_eq!(
+            ShardMeta::parse("{ \"format_version\" : 7, \"shard_id\":12 }").map(|m| m.shard_id),
+            Some(12)
+        );
+    }
+
+    #[test]
+    fn test_shard_layout_from_v1() {
+        let root = PathBuf::from(r"./data/test/test_shard_layout_from_v1");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        // One record "key" => "value" written with the v1 format, no manifest
+        let mut table = vec![];
+        table.extend(1u16.to_le_bytes());
+        table.extend(42u64.to_le_bytes());
+        table.extend(3u16.to_le_bytes());
+        table.extend(5u32.to_le_bytes());
+        table.extend(7u64.to_le_bytes());
+        table.extend(b"keyvalue");
+        fs::write(root.join("42-v1.data"), &table).unwrap();
+
+        let layout = ShardLayout::new(root.clone());
+        let _lock = layout.create(0);
+        assert!(layout.tables().join("42-v1.data").exists());
+        let mut rt = monoio::RuntimeBuilder::<monoio::IoUringDriver>::new().build().unwrap();
+        rt.block_on(async {
+            let mut datastore = DataStore::new_with_directories(layout.tables(), layout.wal(), Config::default()).await;
+            datastore.init().await;
+            datastore.rebuild_index_from_disk().await;
+            let record = datastore.get(&Key::new("key".to_string())).await.unwrap().unwrap();
+            assert_eq!(record.value, b"value");
+        });
+    }
+}
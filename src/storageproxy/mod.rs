@@ -1,17 +1,39 @@
+pub mod layout;
 mod shard;
 
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::{HashMap, HashSet},
+    ops::RangeInclusive,
     path::PathBuf,
     rc::Rc,
+    sync::Arc,
 };
 
+use futures::future::join_all;
 use shard::Shard;
 
 use crate::{
-    api::{ClusterCommand, Command, DataCommand, DeleteResp, GetResp, Response, SetResp},
+    api::{
+        self, ClusterCommand, Command, Concat, ConcatResp, Counter, CounterResp, DataCommand, DeleteResp, ExpireResp, GetRange, GetRangeResp,
+        GetResp, HDel, HDelResp, HSet, HSetResp, Incr, IncrError, IncrResp, Number, Object, ObjectResp, PfMerge, PfMergeResp, Pop, PopResp, Push,
+        PushResp, RenameError, Response, SAdd, SAddResp, SIsMember, SIsMemberResp, SRem, SRemResp, SetCondition, SetOptions, SetRange, SetRangeResp,
+        SetResp, StrLen, StrLenResp, TtlResp, TypeResp, ValueError, WrongType, XAdd, XAddError, XAddResp, ZAdd, ZAddResp,
+    },
     cluster::ClusterMessage,
+    config::RuntimeConfig,
+    datastore::{error::DataStoreError, index::SCAN_POSITION_BITS, verify::Report, SizeEstimate, Stats},
+    reactor::supervisor,
+    record::{Key, Record, ValueType},
+    redis::types::{
+        hash::Hash,
+        hyperloglog::HyperLogLog,
+        list::List,
+        set::Set,
+        stream::{Stream, StreamId},
+        string,
+        zset::ZSet,
+    },
     topology::{self, ReactorMetadata, Topology},
 };
 
@@ -21,6 +43,15 @@ pub struct CommandHandle {
     // pub sender: SharedSender<Response>,
 }
 
+/// Change computed by a read-modify-write
+enum Update {
+    Keep,
+    Set(ValueType, Vec<u8>),
+    /// Write the record as is, its ttl included
+    Replace(Record),
+    Delete,
+}
+
 /// Provide safe access to shards
 struct Shards {
     shards: RefCell<HashMap<u16, Rc<Shard>>>,
@@ -55,13 +86,32 @@ impl Shards {
     }
 }
 
+/// State of SAVE and BGSAVE on this reactor
+struct SaveStatus {
+    in_progress: Cell<bool>,
+    /// Unix time in seconds of the last completed save
+    last_save: Cell<u64>,
+}
+
+impl SaveStatus {
+    /// The time of the last save is only updated if it succeeded
+    fn end(&self, saved: bool) {
+        if saved {
+            self.last_save.set(crate::time::to_unix_ms(crate::time::now()) / 1000);
+        }
+        self.in_progress.set(false);
+    }
+}
+
 pub struct StorageProxy {
     shards: Shards,
     pub shards_count: u16,
     data_dir: PathBuf,
-    reactor_metadata: ReactorMetadata,
+    reactor_metadata: RefCell<ReactorMetadata>,
     topology: RefCell<Option<Rc<Topology>>>,
     cluster_sender: async_channel::Sender<ClusterMessage>,
+    runtime_config: Arc<RuntimeConfig>,
+    save_status: Rc<SaveStatus>,
 }
 
 impl StorageProxy {
@@ -70,19 +120,27 @@ impl StorageProxy {
         shards_count: u16,
         cluster_sender: async_channel::Sender<ClusterMessage>,
         data_dir: &PathBuf,
+        runtime_config: Arc<RuntimeConfig>,
     ) -> StorageProxy {
         StorageProxy {
-            reactor_metadata,
+            runtime_config,
+            reactor_metadata: RefCell::from(reactor_metadata),
             shards: Shards::new(),
             shards_count,
             data_dir: data_dir.clone(),
             topology: RefCell::from(None),
             cluster_sender,
+            // Like redis, the start of the node counts as a save
+            save_status: Rc::new(SaveStatus {
+                in_progress: Cell::new(false),
+                last_save: Cell::new(crate::time::to_unix_ms(crate::time::now()) / 1000),
+            }),
         }
     }
 
     pub async fn apply_new_topology(&self, topology: &Topology) {
-        let shard_ranges = topology.reactor_allocations.get(&self.reactor_metadata).unwrap();
+        let reactor_metadata = self.reactor_metadata.borrow().clone();
+        let shard_ranges = topology.reactor_allocations.get(&reactor_metadata).unwrap();
 
         let mut incoming_shards = HashSet::with_capacity(shard_ranges.len());
         shard_ranges.iter().for_each(|sr| {
@@ -100,7 +158,7 @@ impl StorageProxy {
         for start in shards_to_add {
             let mut shard_path = PathBuf::new();
             shard_path.push(format!("{}", start));
-            let shard = Shard::new(self.reactor_metadata.id, self.data_dir.join(shard_path)).await;
+            let shard = Shard::new(*start, reactor_metadata.id, self.data_dir.join(shard_path), self.runtime_config.clone()).await;
             self.shards.insert_shard(*start, shard);
         }
 
@@ -114,23 +172,497 @@ impl StorageProxy {
         let _ = self.topology.borrow_mut().insert(Rc::from(topology.clone()));
     }
 
+    /// Forget the current topology (a new one is expected right after) and
+    /// optionally delete all the data of the shards
+    pub async fn reset(&self, reactor_metadata: ReactorMetadata, wipe_data: bool) {
+        *self.reactor_metadata.borrow_mut() = reactor_metadata;
+        self.topology.borrow_mut().take();
+        if wipe_data {
+            for shard_id in self.shards.keys() {
+                let shard = self.shards.get_shard(&shard_id).unwrap();
+                shard.datastore.truncate().await;
+            }
+        }
+    }
+
     pub async fn dispatch_local_data(&self, shard: Rc<Shard>, cmd: DataCommand) -> Response {
+        // The index entry of the key may have been evicted
+        shard.datastore.load(cmd.get_key()).await;
+        // Only sets report a rejection, the other writes are just delayed (by
+        // at most `Config::write_stall_timeout_ms`)
+        let budget = match cmd.writes_value() {
+            true => shard.datastore.wait_for_memtable_budget().await,
+            false => Ok(()),
+        };
+        let cmd = match cmd {
+            DataCommand::Set(mut c) if c.relative_ttl => {
+                c.record.expire_at = c.record.expire_at.map(|e| shard.datastore.jitter_expiration(c.record.timestamp, e));
+                DataCommand::Set(c)
+            }
+            DataCommand::Expire(mut c) if c.relative_ttl => {
+                c.expire_at = c.expire_at.map(|e| shard.datastore.jitter_expiration(crate::time::now(), e));
+                DataCommand::Expire(c)
+            }
+            cmd => cmd,
+        };
         match cmd {
             DataCommand::Get(c) => {
                 let record = shard.datastore.get(&c.key).await;
                 Response::Get(GetResp { record })
             }
             DataCommand::Delete(c) => {
-                shard.datastore.delete(&c.key);
-                Response::Delete(DeleteResp {})
+                let deleted = shard.datastore.delete(&c.key);
+                Response::Delete(DeleteResp { deleted })
+            }
+            DataCommand::Unlink(c) => {
+                let deleted = Ok(shard.datastore.unlink(&c.key).await);
+                Response::Delete(DeleteResp { deleted })
+            }
+            DataCommand::PfMerge(c) => Response::PfMerge(PfMergeResp {
+                updated: Self::pfmerge(&shard, &c).await,
+            }),
+            DataCommand::XAdd(c) => Response::XAdd(XAddResp {
+                id: Self::xadd(&shard, &c).await,
+            }),
+            DataCommand::Counter(c) => Response::Counter(CounterResp {
+                value: Self::counter(&shard, &c).await,
+            }),
+            DataCommand::Concat(c) => Response::Concat(ConcatResp {
+                len: Self::concat(&shard, &c).await,
+            }),
+            DataCommand::Set(_) if budget.is_err() => Response::Set(SetResp {
+                applied: budget.map(|_| false),
+                old_value: Ok(None),
+            }),
+            DataCommand::Set(c) if c.options == SetOptions::default() => {
+                let applied = shard.datastore.set(c.record).map(|_| true);
+                Response::Set(SetResp {
+                    applied,
+                    old_value: Ok(None),
+                })
+            }
+            DataCommand::Set(c) => Response::Set(Self::set_with_options(&shard, &c).await),
+            DataCommand::Expire(c) => {
+                let updated = shard.datastore.expire(&c.key, c.expire_at).await;
+                Response::Expire(ExpireResp { updated })
+            }
+            DataCommand::Ttl(c) => Response::Ttl(TtlResp {
+                ttl: shard.datastore.ttl(&c.key).await,
+            }),
+            DataCommand::Type(c) => Response::Type(TypeResp {
+                value_type: shard.datastore.value_type(&c.key).await,
+            }),
+            DataCommand::HSet(c) => Response::HSet(HSetResp {
+                added: Self::hset(&shard, &c).await,
+            }),
+            DataCommand::HDel(c) => Response::HDel(HDelResp {
+                removed: Self::hdel(&shard, &c).await,
+            }),
+            DataCommand::Push(c) => Response::Push(PushResp {
+                len: Self::push(&shard, &c).await,
+            }),
+            DataCommand::Pop(c) => Response::Pop(PopResp {
+                values: Self::pop(&shard, &c).await,
+            }),
+            DataCommand::SAdd(c) => Response::SAdd(SAddResp {
+                added: Self::sadd(&shard, &c).await,
+            }),
+            DataCommand::SRem(c) => Response::SRem(SRemResp {
+                removed: Self::srem(&shard, &c).await,
+            }),
+            DataCommand::SIsMember(c) => Response::SIsMember(SIsMemberResp {
+                is_member: Self::sismember(&shard, &c).await,
+            }),
+            DataCommand::ZAdd(c) => Response::ZAdd(ZAddResp {
+                added: Self::zadd(&shard, &c).await,
+            }),
+            DataCommand::GetRange(c) => Response::GetRange(GetRangeResp {
+                value: Self::getrange(&shard, &c).await,
+            }),
+            DataCommand::SetRange(c) => Response::SetRange(SetRangeResp {
+                len: Self::setrange(&shard, &c).await,
+            }),
+            DataCommand::StrLen(c) => Response::StrLen(StrLenResp {
+                len: Self::strlen(&shard, &c).await,
+            }),
+            DataCommand::Object(c) => Response::Object(Self::object(&shard, &c).await),
+            DataCommand::Incr(c) => Response::Incr(IncrResp {
+                value: Self::incr(&shard, &c).await,
+            }),
+        }
+    }
+
+    /// Atomic read-modify-write of a key: `update` computes the change to apply
+    /// and the result from the current record. Reading can yield on disk I/O so the
+    /// write only happens if the key wasn't modified meanwhile, otherwise retry.
+    /// A value that can't be read or written is an error, it is never
+    /// overwritten as if it were missing
+    async fn read_modify_write<T, U, E, F>(shard: &Shard, key: &Key, update: F) -> Result<T, E>
+    where
+        F: Fn(Option<&Record>) -> Result<(Update, T), U>,
+        E: From<U> + From<DataStoreError>,
+    {
+        loop {
+            let version = shard.datastore.version(key).await;
+            let current = shard.datastore.get(key).await?;
+            let (change, result) = update(current.as_ref())?;
+            let done = match change {
+                Update::Keep => Ok(true),
+                Update::Set(value_type, value) => {
+                    let record = Record {
+                        key: key.clone(),
+                        value,
+                        timestamp: crate::time::now(),
+                        value_type,
+                        // Like redis, the ttl is kept (and the memcached flags)
+                        expire_at: current.as_ref().and_then(|r| r.expire_at),
+                        flags: current.as_ref().map_or(0, |r| r.flags),
+                    };
+                    shard.datastore.set_if_version(record, version)
+                }
+                Update::Replace(record) => shard.datastore.set_if_version(
+                    Record {
+                        timestamp: crate::time::now(),
+                        ..record
+                    },
+                    version,
+                ),
+                Update::Delete => shard.datastore.delete_if_version(key, version),
+            }?;
+            if done {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// SET with options, the condition is checked and the record written atomically
+    async fn set_with_options(shard: &Shard, c: &api::Set) -> SetResp {
+        let result = Self::read_modify_write(shard, &c.record.key, |current| {
+            let old_value = match current {
+                Some(r) if c.options.get && r.value_type != ValueType::String => return Err(WrongType),
+                Some(r) if c.options.get => Some(r.value.clone()),
+                _ => None,
+            };
+            let applied = match c.options.condition {
+                SetCondition::Always => true,
+                SetCondition::IfMissing => current.is_none(),
+                SetCondition::IfExists => current.is_some(),
+            };
+            let change = match applied {
+                true if c.options.keep_ttl => Update::Replace(Record {
+                    expire_at: current.and_then(|r| r.expire_at),
+                    ..c.record.clone()
+                }),
+                true => Update::Replace(c.record.clone()),
+                false => Update::Keep,
+            };
+            Ok((change, (applied, old_value)))
+        })
+        .await;
+        match result {
+            Ok((applied, old_value)) => SetResp {
+                applied: Ok(applied),
+                old_value: Ok(old_value),
+            },
+            Err(ValueError::WrongType) => SetResp {
+                applied: Ok(false),
+                old_value: Err(WrongType),
+            },
+            Err(ValueError::Storage(e)) => SetResp {
+                applied: Err(e),
+                old_value: Ok(None),
+            },
+        }
+    }
+
+    async fn getrange(shard: &Shard, c: &GetRange) -> Result<Vec<u8>, ValueError> {
+        let len = match shard.datastore.value_size(&c.key).await? {
+            Some((value_type, _)) if value_type != ValueType::String => return Err(ValueError::WrongType),
+            Some((_, len)) => len,
+            None => return Ok(Vec::new()),
+        };
+        let range = string::range_bounds(len, c.start, c.end);
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(shard.datastore.get_value_range(&c.key, range).await?.unwrap_or_default())
+    }
+
+    async fn setrange(shard: &Shard, c: &SetRange) -> Result<usize, ValueError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let mut value = match current {
+                Some(r) if r.value_type != ValueType::String => return Err(WrongType),
+                Some(r) => r.value.clone(),
+                None => Vec::new(),
+            };
+            // Like redis, an empty update doesn't create the key
+            if c.value.is_empty() {
+                return Ok((Update::Keep, value.len()));
+            }
+            string::set_range(&mut value, c.offset, &c.value);
+            let len = value.len();
+            Ok((Update::Set(ValueType::String, value), len))
+        })
+        .await
+    }
+
+    async fn strlen(shard: &Shard, c: &StrLen) -> Result<usize, ValueError> {
+        match shard.datastore.value_size(&c.key).await? {
+            Some((value_type, _)) if value_type != ValueType::String => Err(ValueError::WrongType),
+            Some((_, len)) => Ok(len),
+            None => Ok(0),
+        }
+    }
+
+    /// Describe a key like OBJECT, without counting it as an access
+    async fn object(shard: &Shard, c: &Object) -> ObjectResp {
+        let info = match shard.datastore.object_info(&c.key).await {
+            Some(info) => info,
+            None => {
+                return ObjectResp {
+                    info: Ok(None),
+                    encoding: "",
+                }
             }
-            DataCommand::Set(c) => {
-                shard.datastore.set(c.record);
-                Response::Set(SetResp {})
+        };
+        // Collections are always serialized in a single buffer, like small
+        // redis collections
+        let encoding = match info.value_type {
+            ValueType::String if info.value_size <= 20 => {
+                let value = match shard.datastore.get_value_range(&c.key, 0..info.value_size).await {
+                    Ok(value) => value.unwrap_or_default(),
+                    Err(e) => return ObjectResp { info: Err(e), encoding: "" },
+                };
+                match std::str::from_utf8(&value).ok().and_then(|v| v.parse::<i64>().ok()) {
+                    Some(_) => "int",
+                    None => "embstr",
+                }
             }
+            ValueType::String if info.value_size <= 44 => "embstr",
+            ValueType::String | ValueType::Merge => "raw",
+            ValueType::Hash | ValueType::List | ValueType::Set | ValueType::ZSet => "listpack",
+            ValueType::Stream => "stream",
+            ValueType::RangeTombstone => unreachable!("range tombstones are not in the index"),
+        };
+        ObjectResp {
+            info: Ok(Some(info)),
+            encoding,
         }
     }
 
+    async fn incr(shard: &Shard, c: &Incr) -> Result<Number, IncrError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let current = match current {
+                Some(r) if r.value_type != ValueType::String => return Err(IncrError::WrongType),
+                Some(r) => Some(r.value.as_slice()),
+                None => None,
+            };
+            let value = c.apply(current)?;
+            Ok((Update::Set(ValueType::String, value.to_string().into_bytes()), value))
+        })
+        .await
+    }
+
+    async fn counter(shard: &Shard, c: &Counter) -> Result<Option<u64>, IncrError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let current = match current {
+                Some(r) if r.value_type != ValueType::String => return Err(IncrError::WrongType),
+                Some(r) => std::str::from_utf8(&r.value).ok().and_then(|v| v.parse::<u64>().ok()),
+                None => return Ok((Update::Keep, None)),
+            };
+            let current = current.ok_or(IncrError::NotAnInteger)?;
+            let value = match c.decrement {
+                true => current.saturating_sub(c.delta),
+                false => current.wrapping_add(c.delta),
+            };
+            Ok((Update::Set(ValueType::String, value.to_string().into_bytes()), Some(value)))
+        })
+        .await
+    }
+
+    async fn concat(shard: &Shard, c: &Concat) -> Result<Option<usize>, ValueError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let current = match current {
+                Some(r) if r.value_type != ValueType::String => return Err(WrongType),
+                Some(r) => &r.value,
+                None => return Ok((Update::Keep, None)),
+            };
+            let value = match c.prepend {
+                true => [c.value.as_slice(), current].concat(),
+                false => [current, c.value.as_slice()].concat(),
+            };
+            let len = value.len();
+            Ok((Update::Set(ValueType::String, value), Some(len)))
+        })
+        .await
+    }
+
+    async fn push(shard: &Shard, c: &Push) -> Result<usize, ValueError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let mut list = match current {
+                Some(r) if r.value_type != ValueType::List => return Err(WrongType),
+                Some(r) => List::decode(&r.value),
+                None => List::default(),
+            };
+            for value in &c.values {
+                match c.front {
+                    true => list.push_front(value.clone()),
+                    false => list.push_back(value.clone()),
+                }
+            }
+            Ok((Update::Set(ValueType::List, list.encode()), list.len()))
+        })
+        .await
+    }
+
+    async fn pop(shard: &Shard, c: &Pop) -> Result<Option<Vec<Vec<u8>>>, ValueError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let mut list = match current {
+                Some(r) if r.value_type != ValueType::List => return Err(WrongType),
+                Some(r) => List::decode(&r.value),
+                None => return Ok((Update::Keep, None)),
+            };
+            let values: Vec<Vec<u8>> = (0..c.count)
+                .map_while(|_| match c.front {
+                    true => list.pop_front(),
+                    false => list.pop_back(),
+                })
+                .collect();
+            let change = match list.is_empty() {
+                // Like redis, empty lists are deleted
+                true => Update::Delete,
+                false => Update::Set(ValueType::List, list.encode()),
+            };
+            Ok((change, Some(values)))
+        })
+        .await
+    }
+
+    async fn sadd(shard: &Shard, c: &SAdd) -> Result<usize, ValueError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let mut set = match current {
+                Some(r) if r.value_type != ValueType::Set => return Err(WrongType),
+                Some(r) => Set::decode(&r.value),
+                None => Set::default(),
+            };
+            let added = c.members.iter().filter(|member| set.insert(member.to_vec())).count();
+            let change = match added {
+                0 => Update::Keep,
+                _ => Update::Set(ValueType::Set, set.encode()),
+            };
+            Ok((change, added))
+        })
+        .await
+    }
+
+    async fn xadd(shard: &Shard, c: &XAdd) -> Result<Option<StreamId>, XAddError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let mut stream = match current {
+                Some(r) if r.value_type != ValueType::Stream => return Err(XAddError::WrongType),
+                Some(r) => Stream::decode(&r.value),
+                None if !c.create => return Ok((Update::Keep, None)),
+                None => Stream::default(),
+            };
+            let now_ms = crate::time::now() / 1_000_000;
+            let id = stream.add(c.id, now_ms, c.fields.clone()).ok_or(XAddError::IdTooSmall)?;
+            if let Some(max_len) = c.max_len {
+                stream.trim(max_len);
+            }
+            Ok((Update::Set(ValueType::Stream, stream.encode()), Some(id)))
+        })
+        .await
+    }
+
+    async fn pfmerge(shard: &Shard, c: &PfMerge) -> Result<bool, ValueError> {
+        let source = HyperLogLog::decode(&c.hll).unwrap();
+        Self::read_modify_write(shard, &c.key, |current| {
+            let (mut hll, created) = match current {
+                Some(r) if r.value_type != ValueType::String => return Err(WrongType),
+                Some(r) => (HyperLogLog::decode(&r.value).ok_or(WrongType)?, false),
+                None => (HyperLogLog::default(), true),
+            };
+            let updated = hll.merge(&source) || created;
+            let change = match updated {
+                true => Update::Set(ValueType::String, hll.encode()),
+                false => Update::Keep,
+            };
+            Ok((change, updated))
+        })
+        .await
+    }
+
+    async fn srem(shard: &Shard, c: &SRem) -> Result<usize, ValueError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let mut set = match current {
+                Some(r) if r.value_type != ValueType::Set => return Err(WrongType),
+                Some(r) => Set::decode(&r.value),
+                None => return Ok((Update::Keep, 0)),
+            };
+            let removed = c.members.iter().filter(|member| set.remove(member)).count();
+            let change = match removed {
+                0 => Update::Keep,
+                // Like redis, empty sets are deleted
+                _ if set.is_empty() => Update::Delete,
+                _ => Update::Set(ValueType::Set, set.encode()),
+            };
+            Ok((change, removed))
+        })
+        .await
+    }
+
+    async fn sismember(shard: &Shard, c: &SIsMember) -> Result<bool, ValueError> {
+        match shard.datastore.get(&c.key).await? {
+            Some(r) if r.value_type != ValueType::Set => Err(ValueError::WrongType),
+            Some(r) => Ok(Set::contains(&r.value, &c.member)),
+            None => Ok(false),
+        }
+    }
+
+    async fn zadd(shard: &Shard, c: &ZAdd) -> Result<usize, ValueError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let mut zset = match current {
+                Some(r) if r.value_type != ValueType::ZSet => return Err(WrongType),
+                Some(r) => ZSet::decode(&r.value),
+                None => ZSet::default(),
+            };
+            let added = c.members.iter().filter(|(score, member)| zset.insert(member.clone(), *score)).count();
+            Ok((Update::Set(ValueType::ZSet, zset.encode()), added))
+        })
+        .await
+    }
+
+    async fn hset(shard: &Shard, c: &HSet) -> Result<usize, ValueError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let mut hash = match current {
+                Some(r) if r.value_type != ValueType::Hash => return Err(WrongType),
+                Some(r) => Hash::decode(&r.value),
+                None => Hash::default(),
+            };
+            let added = c.pairs.iter().filter(|(field, value)| hash.set(field.clone(), value.clone())).count();
+            Ok((Update::Set(ValueType::Hash, hash.encode()), added))
+        })
+        .await
+    }
+
+    async fn hdel(shard: &Shard, c: &HDel) -> Result<usize, ValueError> {
+        Self::read_modify_write(shard, &c.key, |current| {
+            let mut hash = match current {
+                Some(r) if r.value_type != ValueType::Hash => return Err(WrongType),
+                Some(r) => Hash::decode(&r.value),
+                None => return Ok((Update::Keep, 0)),
+            };
+            let removed = c.fields.iter().filter(|field| hash.remove(field)).count();
+            let change = match removed {
+                0 => Update::Keep,
+                // Like redis, empty hashes are deleted
+                _ if hash.is_empty() => Update::Delete,
+                _ => Update::Set(ValueType::Hash, hash.encode()),
+            };
+            Ok((change, removed))
+        })
+        .await
+    }
+
     pub async fn dispatch(&self, cmd: Command) -> Response {
         match cmd {
             Command::Data(data_command) => self.dispatch_data(data_command).await,
@@ -161,7 +693,7 @@ impl StorageProxy {
             None => {
                 println!(
                     "[reactor {}] shard {} not managed by this reactor (slot: {}, crc16: {}, cmd: {:?})",
-                    self.reactor_metadata.id,
+                    self.reactor_metadata.borrow().id,
                     shard_id,
                     cmd_slot,
                     cmd.get_crc16(),
@@ -172,6 +704,327 @@ impl StorageProxy {
         }
     }
 
+    /// Dispatch several commands at once. Commands are grouped per shard, groups
+    /// run concurrently while commands of a group run in order.
+    /// Responses are returned in the order of `cmds`.
+    pub async fn dispatch_many(&self, cmds: Vec<DataCommand>) -> Vec<Response> {
+        let count = cmds.len();
+        let mut groups: HashMap<u16, Vec<(usize, DataCommand)>> = HashMap::new();
+        for (position, cmd) in cmds.into_iter().enumerate() {
+            let shard_id = topology::compute_shard_id(cmd.get_slot(), self.shards_count);
+            groups.entry(shard_id).or_default().push((position, cmd));
+        }
+
+        let results = join_all(groups.into_values().map(|group| async move {
+            let mut responses = Vec::with_capacity(group.len());
+            for (position, cmd) in group {
+                responses.push((position, self.dispatch_data(cmd).await));
+            }
+            responses
+        }))
+        .await;
+
+        let mut responses: Vec<Option<Response>> = (0..count).map(|_| None).collect();
+        for (position, response) in results.into_iter().flatten() {
+            responses[position] = Some(response);
+        }
+        responses.into_iter().map(|r| r.unwrap()).collect()
+    }
+
+    /// Get several keys at once. Keys are grouped per shard and groups run
+    /// concurrently, the reads of a local shard are batched by its datastore.
+    /// Responses are returned in the order of `keys`.
+    pub async fn get_many(&self, keys: Vec<Key>) -> Vec<GetResp> {
+        let count = keys.len();
+        let mut groups: HashMap<u16, Vec<(usize, Key)>> = HashMap::new();
+        for (position, key) in keys.into_iter().enumerate() {
+            let shard_id = topology::compute_shard_id(api::key_slot(&key), self.shards_count);
+            groups.entry(shard_id).or_default().push((position, key));
+        }
+
+        let results = join_all(groups.into_iter().map(|(shard_id, group)| async move {
+            let (positions, keys): (Vec<usize>, Vec<Key>) = group.into_iter().unzip();
+            let responses = match self.shards.get_shard(&shard_id) {
+                Some(shard) => shard
+                    .datastore
+                    .get_many(&keys)
+                    .await
+                    .into_iter()
+                    .map(|record| GetResp { record })
+                    .collect(),
+                None => {
+                    let mut responses = Vec::with_capacity(keys.len());
+                    for key in keys {
+                        match self.dispatch_data(DataCommand::Get(api::Get { key })).await {
+                            Response::Get(resp) => responses.push(resp),
+                            _ => panic!("Unexpected response"),
+                        }
+                    }
+                    responses
+                }
+            };
+            positions.into_iter().zip(responses).collect::<Vec<(usize, GetResp)>>()
+        }))
+        .await;
+
+        let mut responses: Vec<Option<GetResp>> = (0..count).map(|_| None).collect();
+        for (position, response) in results.into_iter().flatten() {
+            responses[position] = Some(response);
+        }
+        responses.into_iter().map(|r| r.unwrap()).collect()
+    }
+
+    fn local_shard(&self, key: &Key) -> Option<Rc<Shard>> {
+        let shard_id = topology::compute_shard_id(api::key_slot(key), self.shards_count);
+        self.shards.get_shard(&shard_id)
+    }
+
+    /// Rename `src` to `dst`, overwriting it unless `only_if_missing` in which
+    /// case false is returned if `dst` exists. Like redis cluster the keys must
+    /// be in the same slot: across shards the write of `dst` and the delete of
+    /// `src` could not be undone together if one of them fails.
+    pub async fn rename(&self, src: &Key, dst: &Key, only_if_missing: bool) -> Result<bool, RenameError> {
+        if !api::same_slot([src.string.as_str(), dst.string.as_str()]) {
+            return Err(RenameError::CrossSlot);
+        }
+        let shard = self.local_shard(src).ok_or(RenameError::CrossSlot)?;
+        shard.datastore.load(src).await;
+        shard.datastore.load(dst).await;
+        loop {
+            let version = shard.datastore.version(src).await;
+            let record = shard.datastore.get(src).await?.ok_or(RenameError::NoSuchKey)?;
+            // Reading may have yielded, once the source is known to be unchanged
+            // nothing else runs until the end of the rename
+            if shard.datastore.version(src).await != version {
+                continue;
+            }
+            if src.hash == dst.hash {
+                return Ok(!only_if_missing);
+            }
+            if only_if_missing && shard.datastore.value_type(dst).await.is_some() {
+                return Ok(false);
+            }
+            // Like redis, the ttl is moved with the value
+            shard.datastore.set(Record {
+                key: dst.clone(),
+                timestamp: crate::time::now(),
+                ..record
+            })?;
+            shard.datastore.delete(src)?;
+            return Ok(true);
+        }
+    }
+
+    /// Copy the value and ttl of `src` to `dst`, return false if `src` doesn't
+    /// exist or if `dst` exists and `replace` is not set. Like `rename` both
+    /// keys must be in the same slot.
+    pub async fn copy(&self, src: &Key, dst: &Key, replace: bool) -> Result<bool, RenameError> {
+        if src.hash == dst.hash {
+            return Err(RenameError::SameKey);
+        }
+        if !api::same_slot([src.string.as_str(), dst.string.as_str()]) {
+            return Err(RenameError::CrossSlot);
+        }
+        let shard = self.local_shard(src).ok_or(RenameError::CrossSlot)?;
+        shard.datastore.load(src).await;
+        shard.datastore.load(dst).await;
+        loop {
+            let version = shard.datastore.version(src).await;
+            let record = match shard.datastore.get(src).await? {
+                Some(record) => record,
+                None => return Ok(false),
+            };
+            if shard.datastore.version(src).await != version {
+                continue;
+            }
+            if !replace && shard.datastore.value_type(dst).await.is_some() {
+                return Ok(false);
+            }
+            // Records hold their key so the index entry of `src` can't be
+            // shared, the value is written again under `dst`
+            shard.datastore.set(Record {
+                key: dst.clone(),
+                timestamp: crate::time::now(),
+                ..record
+            })?;
+            return Ok(true);
+        }
+    }
+
+    /// Return the keys of all the shards of this reactor starting with
+    /// `prefix` and accepted by `filter`
+    pub fn keys<F: Fn(&Key) -> bool>(&self, prefix: &str, filter: F) -> Vec<Key> {
+        let mut keys = vec![];
+        for shard_id in self.shards.keys() {
+            let shard = self.shards.get_shard(&shard_id).unwrap();
+            keys.extend(shard.datastore.keys(prefix, &filter));
+        }
+        keys
+    }
+
+    /// Iterate over the keys of the shards of this reactor. The cursor holds the
+    /// shard id in its upper bits and the position in the shard index in the
+    /// lower ones. Iterations start with cursor 0 and end when 0 is returned.
+    pub async fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Key>) {
+        let position_mask = (1 << SCAN_POSITION_BITS) - 1;
+        let start_shard = (cursor >> SCAN_POSITION_BITS) as u16;
+        let mut position = cursor & position_mask;
+
+        let mut shard_ids = self.shards.keys();
+        shard_ids.sort_unstable();
+        let mut keys = Vec::with_capacity(count);
+        for shard_id in shard_ids.into_iter().filter(|id| *id >= start_shard) {
+            if shard_id != start_shard {
+                position = 0;
+            }
+            let shard = self.shards.get_shard(&shard_id).unwrap();
+            let (shard_keys, next) = shard.datastore.scan(position, count - keys.len()).await;
+            keys.extend(shard_keys);
+            match next {
+                Some(next) => return (((shard_id as u64) << SCAN_POSITION_BITS) | next, keys),
+                // Continue with the next shard
+                None if keys.len() >= count => return (((shard_id as u64 + 1) << SCAN_POSITION_BITS), keys),
+                None => (),
+            }
+        }
+        (0, keys)
+    }
+
+    /// Remove the data of every shard of this reactor. With `background`, the
+    /// data is dropped right away but the disktable files are deleted by a
+    /// background task
+    pub async fn flush_all(&self, background: bool) {
+        for shard_id in self.shards.keys() {
+            let shard = self.shards.get_shard(&shard_id).unwrap();
+            if !background {
+                shard.datastore.truncate().await;
+                continue;
+            }
+            let paths = shard.datastore.truncate_detached();
+            monoio::spawn(supervisor::isolate(format!("flush of shard {}", shard_id), async move {
+                for path in paths {
+                    std::fs::remove_file(path).unwrap();
+                }
+            }));
+        }
+    }
+
+    /// Write the memtables of every shard of this reactor to disk
+    pub async fn flush_memtables(&self) -> Result<(), DataStoreError> {
+        for shard_id in self.shards.keys() {
+            let shard = self.shards.get_shard(&shard_id).unwrap();
+            shard.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Flush the memtables of this reactor (SAVE)
+    pub async fn save(&self) -> Result<(), DataStoreError> {
+        self.save_status.in_progress.set(true);
+        let saved = self.flush_memtables().await;
+        self.save_status.end(saved.is_ok());
+        saved
+    }
+
+    /// Flush the memtables of this reactor in a background task (BGSAVE),
+    /// return false if a save is already in progress
+    pub fn background_save(&self) -> bool {
+        if self.save_status.in_progress.get() {
+            return false;
+        }
+        self.save_status.in_progress.set(true);
+        let shards: Vec<Rc<Shard>> = self.shards.keys().iter().filter_map(|shard_id| self.shards.get_shard(shard_id)).collect();
+        let save_status = self.save_status.clone();
+        monoio::spawn(supervisor::isolate(String::from("background save"), async move {
+            let mut saved = true;
+            for shard in shards {
+                if let Err(e) = shard.flush().await {
+                    println!("Background save failed: {:?}", e);
+                    saved = false;
+                    break;
+                }
+            }
+            save_status.end(saved);
+        }));
+        true
+    }
+
+    pub fn is_saving(&self) -> bool {
+        self.save_status.in_progress.get()
+    }
+
+    /// Unix time in seconds of the last completed save (LASTSAVE)
+    pub fn last_save(&self) -> u64 {
+        self.save_status.last_save.get()
+    }
+
+    /// Storage statistics of the shards of this reactor, sorted by shard
+    pub fn local_stats(&self) -> Vec<(u16, Stats)> {
+        let mut shard_ids = self.shards.keys();
+        shard_ids.sort();
+        shard_ids
+            .into_iter()
+            .map(|shard_id| (shard_id, self.shards.get_shard(&shard_id).unwrap().datastore.get_stats()))
+            .collect()
+    }
+
+    /// Check the disktables of the shards of this reactor, see `DataStore::verify`
+    pub async fn verify(&self) -> Vec<(u16, Report)> {
+        let mut shard_ids = self.shards.keys();
+        shard_ids.sort();
+        let mut reports = Vec::with_capacity(shard_ids.len());
+        for shard_id in shard_ids {
+            let shard = self.shards.get_shard(&shard_id).unwrap();
+            reports.push((shard_id, shard.datastore.verify().await));
+        }
+        reports
+    }
+
+    /// Rebuild the state of the shards of this reactor from their disktables
+    pub async fn reload(&self) {
+        for shard_id in self.shards.keys() {
+            let shard = self.shards.get_shard(&shard_id).unwrap();
+            shard.datastore.reload().await;
+        }
+    }
+
+    /// Number of keys of `slot`, 0 if it is not owned by this reactor
+    pub fn count_keys_in_slot(&self, slot: u16) -> usize {
+        let shard_id = topology::compute_shard_id(slot, self.shards_count);
+        self.shards
+            .get_shard(&shard_id)
+            .map_or(0, |shard| shard.datastore.count_keys_in_slot(slot))
+    }
+
+    /// Estimated keys and bytes of `slots`, only the slots owned by this
+    /// reactor are counted
+    pub fn approximate_size_of_slots(&self, slots: RangeInclusive<u16>) -> SizeEstimate {
+        let shard_ids: HashSet<u16> = slots.clone().map(|slot| topology::compute_shard_id(slot, self.shards_count)).collect();
+        shard_ids
+            .into_iter()
+            .filter_map(|shard_id| self.shards.get_shard(&shard_id))
+            .map(|shard| shard.datastore.approximate_size_of_slots(slots.clone()))
+            .fold(SizeEstimate::default(), |total, estimate| total + estimate)
+    }
+
+    /// Return up to `count` keys of `slot`, none if it is not owned by this reactor
+    pub async fn keys_in_slot(&self, slot: u16, count: usize) -> Vec<Key> {
+        let shard_id = topology::compute_shard_id(slot, self.shards_count);
+        match self.shards.get_shard(&shard_id) {
+            Some(shard) => shard.datastore.keys_in_slot(slot, count).await,
+            None => vec![],
+        }
+    }
+
+    /// Configuration shared by the reactors of the node
+    pub fn runtime_config(&self) -> &RuntimeConfig {
+        &self.runtime_config
+    }
+
+    pub fn reactor_metadata(&self) -> ReactorMetadata {
+        self.reactor_metadata.borrow().clone()
+    }
+
     pub fn get_topology(&self) -> Option<Rc<Topology>> {
         return self.topology.borrow().clone();
     }
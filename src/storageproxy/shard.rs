@@ -1,51 +1,119 @@
-use std::{path::PathBuf, rc::Rc, time::Duration};
+use std::{fs::File, path::PathBuf, rc::Rc, sync::Arc, time::Duration};
 
 use monoio::time::sleep;
 
-use crate::datastore::DataStore;
+use crate::{
+    config::RuntimeConfig,
+    datastore::{encryption::Encryption, error::DataStoreError, Config, DataStore},
+    reactor::supervisor,
+};
 
-pub fn start_compaction_manager(shard: Rc<Shard>) {
-    monoio::spawn(async move {
-        loop {
-            shard.datastore.maybe_run_one_reclaim().await;
-            shard.datastore.get_stats().assert_not_corrupted();
-            sleep(Duration::from_millis(200)).await
+use super::layout::ShardLayout;
+
+pub fn start_compaction_manager(shard: Rc<Shard>, reactor: u8) {
+    supervisor::spawn_supervised(format!("compaction manager (reactor {reactor})"), move || {
+        let shard = shard.clone();
+        async move {
+            loop {
+                shard.datastore.maybe_run_one_reclaim().await;
+                shard.datastore.get_stats().assert_not_corrupted();
+                sleep(Duration::from_millis(200)).await
+            }
+        }
+    });
+}
+
+pub fn start_flush_manager(shard: Rc<Shard>, reactor: u8) {
+    supervisor::spawn_supervised(format!("flush manager (reactor {reactor})"), move || {
+        let shard = shard.clone();
+        async move {
+            loop {
+                shard.apply_runtime_config();
+                // Retried on the next round
+                if let Err(e) = shard.datastore.flush_all_flushable_memtables().await {
+                    println!("Can't flush the memtables: {:?}", e);
+                }
+                shard.datastore.clean_unused_disktables().await;
+                shard.datastore.evict_cold_entries();
+                shard.datastore.maybe_checkpoint_index();
+                sleep(Duration::from_millis(200)).await
+            }
         }
     });
 }
 
-pub fn start_flush_manager(shard: Rc<Shard>) {
-    monoio::spawn(async move {
-        loop {
-            shard.datastore.flush_all_flushable_memtables().await;
-            shard.datastore.clean_unused_disktables().await;
-            sleep(Duration::from_millis(200)).await
+pub fn start_expiry_manager(shard: Rc<Shard>, reactor: u8) {
+    supervisor::spawn_supervised(format!("expiry manager (reactor {reactor})"), move || {
+        let shard = shard.clone();
+        async move {
+            loop {
+                shard.datastore.sweep_expired().await;
+                sleep(Duration::from_millis(100)).await
+            }
         }
     });
 }
 
 pub fn start_stat_manager(shard: Rc<Shard>, reactor: u8) {
-    monoio::spawn(async move {
-        loop {
-            let stats = shard.datastore.get_stats();
-            println!("stats reactor:{reactor}: {:?}", stats);
-            sleep(Duration::from_millis(1000)).await
+    supervisor::spawn_supervised(format!("stat manager (reactor {reactor})"), move || {
+        let shard = shard.clone();
+        async move {
+            loop {
+                let stats = shard.datastore.get_stats();
+                println!("stats reactor:{reactor}: {:?} (task panics: {})", stats, supervisor::task_panics());
+                sleep(Duration::from_millis(1000)).await
+            }
         }
     });
 }
 
 pub struct Shard {
     pub datastore: DataStore,
+    runtime_config: Arc<RuntimeConfig>,
+    /// Lock of the directory of the shard, held while it is served
+    _lock: File,
 }
 
 impl Shard {
-    pub async fn new(reactor_id: u8, data_dir: PathBuf) -> Rc<Shard> {
-        let datastore = DataStore::new(data_dir).await;
-        let shard = Rc::from(Shard { datastore });
-        start_compaction_manager(shard.clone());
-        start_flush_manager(shard.clone());
+    /// Open the shard `shard_id` in `data_dir`, see `layout` for its files
+    pub async fn new(shard_id: u16, reactor_id: u8, data_dir: PathBuf, runtime_config: Arc<RuntimeConfig>) -> Rc<Shard> {
+        let layout = ShardLayout::new(data_dir);
+        let lock = layout.create(shard_id);
+        let config = Config {
+            memtable_max_size_bytes: runtime_config.memtable_max_size_bytes(),
+            disktable_target_usage_ratio: runtime_config.disktable_target_usage_ratio(),
+            background_io_bytes_per_sec: runtime_config.background_io_limit(),
+            encryption: Encryption::from_env().expect("encryption keys checked at startup"),
+            ..Config::default()
+        };
+        let datastore = DataStore::new_with_directories(layout.tables(), layout.wal(), config).await;
+        let shard = Rc::from(Shard {
+            datastore,
+            runtime_config,
+            _lock: lock,
+        });
+        start_compaction_manager(shard.clone(), reactor_id);
+        start_flush_manager(shard.clone(), reactor_id);
+        start_expiry_manager(shard.clone(), reactor_id);
         start_stat_manager(shard.clone(), reactor_id);
         println!("datastore inited");
         shard
     }
+
+    /// Write every memtable to disk, waiting for the flushes already running
+    pub async fn flush(&self) -> Result<(), DataStoreError> {
+        self.datastore.force_flush().await?;
+        while self.datastore.is_flushing() {
+            sleep(Duration::from_millis(10)).await
+        }
+        Ok(())
+    }
+
+    /// Pick up the changes made with CONFIG SET
+    fn apply_runtime_config(&self) {
+        self.datastore.set_memtable_max_size_bytes(self.runtime_config.memtable_max_size_bytes());
+        self.datastore
+            .set_disktable_target_usage_ratio(self.runtime_config.disktable_target_usage_ratio());
+        self.datastore.set_background_io_bytes_per_sec(self.runtime_config.background_io_limit());
+    }
 }
@@ -20,6 +20,16 @@ pub fn now() -> u64 {
     })
 }
 
+/// Timestamps are nanoseconds since the unix epoch, so absolute times given
+/// by clients (e.g. EXPIREAT) map directly to the clock
+pub fn from_unix_ms(ms: u64) -> u64 {
+    ms.saturating_mul(1_000_000)
+}
+
+pub fn to_unix_ms(timestamp: u64) -> u64 {
+    timestamp / 1_000_000
+}
+
 /// Sync make sure external timestamp are correctly taken into account
 /// This is useful in case of a restart where the time is now older than before the restart
 /// When reading from disk, timestamp could be seen in the future so it's important to sync them
@@ -18,6 +18,14 @@ pub struct ReactorMetadata {
     pub port: u16,
 }
 
+impl ReactorMetadata {
+    /// Every reactor is a node for redis clients, its id has the 40 hex
+    /// characters of a redis node id
+    pub fn cluster_node_id(&self) -> String {
+        format!("{}{:08x}", self.node_id.simple(), self.id)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Topology {
     pub shards_count: u16,